@@ -1,8 +1,9 @@
 use crate::{shm::ShmAllocator, HashMap};
-use aici_abi::{ProcessResultOffset, StorageCmd, TokenId};
+use aici_abi::{Event, ProcessResultOffset, ScriptError, StorageCmd, SubGeneration, TokenId};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::BTreeMap;
 
 pub type ModuleInstId = usize;
 
@@ -20,6 +21,9 @@ pub struct InferenceCapabilities {
 pub struct AiciMidProcessReq {
     pub ops: Vec<AiciMidOp>,
     pub freed: Vec<ModuleInstId>,
+    /// Sub-generations that finished since the last step.
+    #[serde(default)]
+    pub sub_gen_results: Vec<SubGenResult>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,6 +34,64 @@ pub struct AiciMidProcessResp {
     pub mask_num_bytes: usize,
     pub mask_num_elts: usize,
     pub num_masks: usize,
+    /// Set when the LLM is connected over a socket, and thus can't read the masks from shared memory.
+    /// The decoded bytes go at first_mask_byte_offset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline_masks: Option<InlineMasks>,
+    /// Sequences to finish, as requested by their siblings.
+    #[serde(default)]
+    pub stop_seqs: Vec<ModuleInstId>,
+    /// Child generations to start, see aici_abi::spawn_generation().
+    #[serde(default)]
+    pub spawns: Vec<SpawnReq>,
+    /// Sequences waiting for a variable to change (see StorageCmd::WatchVar).
+    /// They should get no AiciMidOp until listed in `resumed`.
+    #[serde(default)]
+    pub suspended: Vec<ModuleInstId>,
+    /// Suspended sequences whose variable has changed.
+    #[serde(default)]
+    pub resumed: Vec<ModuleInstId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpawnReq {
+    /// The request of the sequence that spawned it.
+    pub parent_req_id: String,
+    /// User of the parent request; the child runs on their behalf.
+    pub user: String,
+    pub gen: SubGeneration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubGenResult {
+    pub parent_req_id: String,
+    /// Copied from SubGeneration::result_var.
+    pub result_var: String,
+    /// See SubGeneration::error_var_name().
+    pub error_var: String,
+    /// Generated text, possibly partial on failure.
+    pub text: String,
+    /// Set if the child failed or couldn't be started.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskEncoding {
+    /// Plain bytes.
+    Raw,
+    /// Run-length encoded: (run_length, word) pairs of little endian u32s.
+    Rle,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InlineMasks {
+    pub encoding: MaskEncoding,
+    /// Length of the decoded data.
+    pub num_bytes: usize,
+    /// Base64-encoded.
+    pub data: String,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -57,10 +119,31 @@ pub struct AiciMidOp {
 pub struct SequenceResult<T = ()> {
     pub result: Option<T>,
     pub error: String,
-    // StorageCmd::ReadVar are not recorded
+    /// Set when error is an exception in a script controller.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script_error: Option<ScriptError>,
+    // only writes are recorded (increments as the resulting WriteVar)
     pub storage: Vec<StorageCmd>,
     pub logs: String,
+    /// Structured output of the controller, see aici_abi::emit().
+    #[serde(default)]
+    pub events: Vec<Event>,
     pub micros: u64,
+    /// Fuel (instructions) used, when fuel metering is enabled.
+    #[serde(default)]
+    pub fuel: u64,
+    /// Set when the error is running out of the fuel budget.
+    #[serde(default)]
+    pub out_of_fuel: bool,
+    /// Sequences the controller asked to stop, see aici_abi::stop_seqs().
+    #[serde(default)]
+    pub stop_seqs: Vec<ModuleInstId>,
+    /// Child generations the controller asked for, see aici_abi::spawn_generation().
+    #[serde(default)]
+    pub spawns: Vec<SubGeneration>,
+    /// Set when the controller is waiting for a variable to change, see StorageCmd::WatchVar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watch: Option<StorageCmd>,
 }
 
 impl<T> SequenceResult<T> {
@@ -68,18 +151,32 @@ impl<T> SequenceResult<T> {
         SequenceResult {
             logs: error.clone(),
             error,
+            script_error: None,
             result: None,
             storage: vec![],
+            events: vec![],
             micros: 0,
+            fuel: 0,
+            out_of_fuel: false,
+            stop_seqs: vec![],
+            spawns: vec![],
+            watch: None,
         }
     }
     pub fn clone_with<S>(&self, result: Option<S>) -> SequenceResult<S> {
         SequenceResult {
             error: self.error.clone(),
+            script_error: self.script_error.clone(),
             result,
             storage: self.storage.clone(),
             logs: self.logs.clone(),
+            events: self.events.clone(),
             micros: self.micros,
+            fuel: self.fuel,
+            out_of_fuel: self.out_of_fuel,
+            stop_seqs: self.stop_seqs.clone(),
+            spawns: self.spawns.clone(),
+            watch: self.watch.clone(),
         }
     }
     pub fn map_result<S, F>(self, f: F) -> SequenceResult<S>
@@ -88,10 +185,17 @@ impl<T> SequenceResult<T> {
     {
         SequenceResult {
             error: self.error,
+            script_error: self.script_error,
             result: self.result.map(f),
             storage: self.storage,
             logs: self.logs,
+            events: self.events,
             micros: self.micros,
+            fuel: self.fuel,
+            out_of_fuel: self.out_of_fuel,
+            stop_seqs: self.stop_seqs,
+            spawns: self.spawns,
+            watch: self.watch,
         }
     }
 }
@@ -99,6 +203,75 @@ impl<T> SequenceResult<T> {
 #[derive(Serialize, Deserialize)]
 pub struct MkModuleReq {
     pub binary: String,
+    #[serde(default)]
+    pub meta: ModuleMeta,
+    /// Read-only files for the controller, visible under /data; path -> base64 content.
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+}
+
+/// Module settings, stored with the module; they are part of the module id.
+/// The same struct is used for admin bounds (see SetLimitsReq).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleMeta {
+    /// Fuel budget for each mid_process() call; capped by --wasm-max-step-fuel.
+    #[serde(default)]
+    pub step_fuel: Option<u64>,
+    /// Fuel budget for initialization calls; capped by --wasm-max-init-fuel.
+    #[serde(default)]
+    pub init_fuel: Option<u64>,
+    /// Maximum size of module memory in megabytes; capped by --wasm-max-memory.
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    /// Maximum time of a step in milliseconds; capped by --wasm-max-step-time.
+    #[serde(default)]
+    pub step_ms: Option<u64>,
+    /// Maximum time of initialization in milliseconds; capped by --wasm-max-init-time.
+    #[serde(default)]
+    pub init_ms: Option<u64>,
+    /// Maximum number of forks in a request; capped by --wasm-max-forks.
+    #[serde(default)]
+    pub forks: Option<u64>,
+}
+
+/// Effective limits of instances of a module.
+/// Fuel budgets of 0 mean unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ModuleLimits {
+    pub step_fuel: u64,
+    pub init_fuel: u64,
+    pub max_memory_bytes: usize,
+    pub max_step_ms: u64,
+    pub max_init_ms: u64,
+    pub max_forks: usize,
+}
+
+/// Set upper bounds on limits of modules run by `user`, or through `tag`.
+/// Admin only; fields not set in `limits` are not bounded.
+#[derive(Serialize, Deserialize)]
+pub struct SetLimitsReq {
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    pub limits: ModuleMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModuleInfoReq {
+    pub module_id: String, // or tag name
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModuleInfoResp {
+    pub module_id: String,
+    pub created_at: u64, // unix time
+    pub created_by: String,
+    pub wasm_size: u64,
+    pub compiled_size: u64,
+    /// As requested on upload.
+    pub meta: ModuleMeta,
+    pub limits: ModuleLimits,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -123,6 +296,9 @@ pub struct TagInfo {
     pub updated_by: String,
     pub wasm_size: u64,
     pub compiled_size: u64,
+    /// Effective limits for the requesting user; filled in by get_tags.
+    #[serde(default)]
+    pub limits: Option<ModuleLimits>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -173,7 +349,9 @@ pub enum BiasType {
     F32,
     F16,
     BF16,
-    Bool,
+    /// Packed bitset, one bit per token (set = allowed), in little endian u32 words;
+    /// the LLM has to expand it into the logits itself.
+    Bits,
 }
 
 impl BiasType {
@@ -182,7 +360,7 @@ impl BiasType {
             1 => Ok(BiasType::F32),
             2 => Ok(BiasType::F16),
             3 => Ok(BiasType::BF16),
-            4 => Ok(BiasType::Bool),
+            4 => Ok(BiasType::Bits),
             _ => Err(anyhow!("invalid BiasType")),
         }
     }
@@ -192,7 +370,7 @@ impl BiasType {
             BiasType::F32 => 1,
             BiasType::F16 => 2,
             BiasType::BF16 => 3,
-            BiasType::Bool => 4,
+            BiasType::Bits => 4,
         }
     }
 
@@ -201,20 +379,20 @@ impl BiasType {
             BiasType::F32 => Some(4),
             BiasType::F16 => Some(2),
             BiasType::BF16 => Some(2),
-            BiasType::Bool => None,
+            BiasType::Bits => None,
         }
     }
 
     pub fn bytes_to_elts(&self, bytes: usize) -> usize {
         match self {
-            BiasType::Bool => bytes * 8,
+            BiasType::Bits => bytes * 8,
             _ => bytes / self.elt_size().unwrap(),
         }
     }
 
     pub fn size_in_bytes(&self, vocab_size: usize) -> usize {
         match self {
-            BiasType::Bool => 4 * (vocab_size + 31) / 32,
+            BiasType::Bits => 4 * ((vocab_size + 31) / 32),
             _ => vocab_size * self.elt_size().unwrap(),
         }
     }
@@ -224,7 +402,8 @@ impl BiasType {
             "f32" => Ok(BiasType::F32),
             "f16" => Ok(BiasType::F16),
             "bf16" => Ok(BiasType::BF16),
-            "bool" => Ok(BiasType::Bool),
+            // "bool" is the old name
+            "bits" | "bool" => Ok(BiasType::Bits),
             _ => Err(anyhow!("invalid BiasType")),
         }
     }
@@ -234,7 +413,7 @@ impl BiasType {
             BiasType::F32 => "f32".to_string(),
             BiasType::F16 => "f16".to_string(),
             BiasType::BF16 => "bf16".to_string(),
-            BiasType::Bool => "bool".to_string(),
+            BiasType::Bits => "bits".to_string(),
         }
     }
 
@@ -267,9 +446,11 @@ impl BiasType {
                 Self::LOGIT_BIAS_ALLOW_BF16,
                 Self::LOGIT_BIAS_DISALLOW_BF16,
             ),
-            BiasType::Bool => {
+            BiasType::Bits => {
                 let trg = shm.slice_at_byte_offset::<u8>(off, self.size_in_bytes(vocab_size));
                 trg[0..src.len()].copy_from_slice(src);
+                // the slot may have been used before
+                trg[src.len()..].fill(0);
             }
         }
     }
@@ -227,6 +227,14 @@ impl ServerChannel {
         // self.channel.read_msg(busy_spin, None).unwrap()
     }
 
+    pub fn recv_req_timeout(
+        &mut self,
+        busy_spin: Duration,
+        futex_timeout: Duration,
+    ) -> Option<Vec<u8>> {
+        self.channel.read_msg(busy_spin, Some(futex_timeout))
+    }
+
     pub fn send_resp(&mut self, msg: &[u8]) -> Result<()> {
         self.channel.write_msg(msg)
     }
@@ -256,6 +264,16 @@ where
         bincode::deserialize(&msg).unwrap()
     }
 
+    /// Spin for `busy_spin`, then wait on the futex; None if nothing arrived by then.
+    pub fn recv_req_timeout(
+        &mut self,
+        busy_spin: Duration,
+        futex_timeout: Duration,
+    ) -> Option<Cmd> {
+        let msg = self.channel.recv_req_timeout(busy_spin, futex_timeout)?;
+        Some(bincode::deserialize(&msg).unwrap())
+    }
+
     pub fn send_resp(&mut self, resp: Resp) {
         let msg = bincode::serialize(&resp).unwrap();
         self.channel.send_resp(&msg).unwrap();
@@ -294,6 +312,12 @@ where
         let msg = self.channel.recv_resp(timeout)?;
         Some(bincode::deserialize(&msg).unwrap())
     }
+
+    /// Spin for `busy_timeout`, then wait on the futex (without using CPU).
+    pub fn recv_resp2(&mut self, busy_timeout: Duration, futex_timeout: Duration) -> Option<Resp> {
+        let msg = self.channel.recv_resp2(busy_timeout, futex_timeout)?;
+        Some(bincode::deserialize(&msg).unwrap())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
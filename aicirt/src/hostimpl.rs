@@ -1,16 +1,20 @@
-use crate::worker::{GroupCmd, GroupHandle, GroupResp, RtMidProcessArg};
+use crate::{
+    vfs::{self, Vfs},
+    worker::{GroupCmd, GroupHandle, GroupResp, RtMidProcessArg},
+};
 use aici_abi::{
     bytes::{clone_vec_as_bytes, limit_str, vec_from_bytes, U32Pair},
     toktrie::{TokRxInfo, TokTrie},
-    StorageCmd,
+    Event, ScriptError, StorageCmd, StorageOp, StorageResp, SubGeneration,
 };
 use aicirt::{
-    api::{BiasType, InferenceCapabilities},
+    api::{BiasType, InferenceCapabilities, ModuleLimits, ModuleMeta},
     shm::ShmAllocator,
     user_error,
 };
 use anyhow::Result;
 use std::{
+    path::PathBuf,
     rc::Rc,
     sync::Arc,
     time::{Duration, Instant},
@@ -30,11 +34,70 @@ pub struct AiciLimits {
     pub logit_memory_bytes: usize,
     pub busy_wait_duration: Duration,
     pub max_forks: usize,
+    /// Fuel budgets (0 for unlimited); fuel metering is enabled if any of them is set.
+    pub max_step_fuel: u64,
+    pub max_init_fuel: u64,
+
+    /// Total size of aici_preinit() snapshot files kept on disk.
+    pub snapshot_cache_bytes: usize,
+
+    pub storage_dir: Option<PathBuf>,
+    pub storage_max_bytes: usize,
+    pub storage_ttl_secs: u64,
+    pub storage_max_namespaces: usize,
 
     pub module_upload: bool,
     pub gh_download: bool,
 }
 
+/// Wall-clock limit of calls that are limited by fuel.
+const FUEL_BACKSTOP_MS: u64 = 60_000;
+
+impl AiciLimits {
+    pub fn fuel_metering(&self) -> bool {
+        self.max_step_fuel > 0 || self.max_init_fuel > 0
+    }
+
+    /// Wall-clock limit of a call with the given fuel budget and time limit.
+    /// With a fuel budget, fuel is the limit (so that it doesn't depend on the load of the host),
+    /// and the wall clock is only a backstop against e.g. hanging host calls.
+    pub fn wall_clock_ms(&self, fuel: u64, ms: u64) -> u64 {
+        if self.fuel_metering() && fuel > 0 {
+            ms.max(FUEL_BACKSTOP_MS)
+        } else {
+            ms
+        }
+    }
+
+    /// Limits requested by the module, capped by the host limits and then by admin `bounds`.
+    pub fn module_limits(&self, meta: &ModuleMeta, bounds: &[ModuleMeta]) -> ModuleLimits {
+        // max of 0 means unlimited (only for fuel); so does a request of 0
+        fn cap(req: Option<u64>, max: u64) -> u64 {
+            match req {
+                Some(r) if r > 0 && (max == 0 || r < max) => r,
+                _ => max,
+            }
+        }
+        let limit = |field: fn(&ModuleMeta) -> Option<u64>, max: u64| {
+            bounds
+                .iter()
+                .fold(cap(field(meta), max), |acc, b| cap(field(b), acc))
+        };
+        const MEGABYTE: u64 = 1024 * 1024;
+        ModuleLimits {
+            step_fuel: limit(|m| m.step_fuel, self.max_step_fuel),
+            init_fuel: limit(|m| m.init_fuel, self.max_init_fuel),
+            max_memory_bytes: limit(
+                |m| m.memory_mb.map(|mb| mb.saturating_mul(MEGABYTE)),
+                self.max_memory_bytes as u64,
+            ) as usize,
+            max_step_ms: limit(|m| m.step_ms, self.max_step_ms),
+            max_init_ms: limit(|m| m.init_ms, self.max_init_ms),
+            max_forks: limit(|m| m.forks, self.max_forks as u64) as usize,
+        }
+    }
+}
+
 type ModuleInstId = crate::api::ModuleInstId;
 
 // this is available to functions called from wasm
@@ -55,11 +118,23 @@ pub struct ModuleData {
     pub store_limits: wasmtime::StoreLimits,
     pub had_error: bool,
     pub storage_log: Vec<StorageCmd>,
+    // last WatchVar command that returned Watching, see SequenceResult::watch
+    pub watch: Option<StorageCmd>,
+    pub script_error: Option<ScriptError>,
+    pub events: Vec<Event>,
+    events_size: usize,
+    // sequences to stop, see aici_abi::stop_seqs()
+    pub stop_seqs: Vec<ModuleInstId>,
+    // see aici_abi::spawn_generation()
+    pub spawns: Vec<SubGeneration>,
+    // files uploaded with the module, under /data
+    pub vfs: Vfs,
     pub start_time: Instant,
     blobs: Vec<Rc<Vec<u8>>>,
 }
 
 const MAXLOG: usize = 64 * 1024;
+const MAXEVENTS: usize = 64 * 1024;
 
 pub struct BlobId(u32);
 
@@ -80,16 +155,18 @@ impl ModuleData {
     pub fn new(
         id: ModuleInstId,
         limits: &AiciLimits,
+        module_limits: &ModuleLimits,
         module: &wasmtime::Module,
         module_arg: String,
         linker: &Arc<wasmtime::Linker<ModuleData>>,
         globals: GlobalInfo,
         group_channel: GroupHandle,
         logit_shm: Rc<ShmAllocator>,
+        data_dir: Option<PathBuf>,
     ) -> Self {
         let store_limits = wasmtime::StoreLimitsBuilder::new()
             .memories(1)
-            .memory_size(limits.max_memory_bytes)
+            .memory_size(module_limits.max_memory_bytes)
             .tables(2)
             .table_elements(100000)
             .instances(1)
@@ -112,6 +189,13 @@ impl ModuleData {
             logit_offsets: Vec::new(),
             had_error: false,
             storage_log: Vec::new(),
+            watch: None,
+            script_error: None,
+            events: Vec::new(),
+            events_size: 0,
+            stop_seqs: Vec::new(),
+            spawns: Vec::new(),
+            vfs: Vfs::new(data_dir),
             start_time: Instant::now(),
             blobs: vec![Rc::new(Vec::new()); BlobId::MAX_BLOB_ID as usize],
         };
@@ -185,6 +269,29 @@ impl ModuleData {
         logs
     }
 
+    pub fn emit(&mut self, kind: &[u8], data: &[u8]) {
+        let size = kind.len() + data.len();
+        if self.events_size + size > MAXEVENTS {
+            self.warn("emit: too much event data; dropping event");
+            return;
+        }
+        match serde_json::from_slice(data) {
+            Ok(data) => {
+                self.events_size += size;
+                self.events.push(Event {
+                    kind: String::from_utf8_lossy(kind).to_string(),
+                    data,
+                });
+            }
+            Err(e) => self.warn(&format!("emit: invalid JSON: {e}")),
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events_size = 0;
+        std::mem::take(&mut self.events)
+    }
+
     pub fn flush_logs(&mut self, name: &str) {
         if !log::log_enabled!(log::Level::Debug) {
             return;
@@ -207,15 +314,30 @@ impl ModuleData {
         self.clear_blob(BlobId::STORAGE_RESULT);
         match serde_json::from_slice(&m) {
             Ok(cmd) => {
-                let save = match &cmd {
-                    StorageCmd::WriteVar { .. } => Some(cmd.clone()),
-                    StorageCmd::ReadVar { .. } => None,
-                };
+                let cmd_copy = cmd.clone();
                 let res = self.group_channel.send_cmd(GroupCmd::StorageCmd { cmd });
                 match res {
                     Ok(GroupResp::StorageResp { resp }) => {
-                        if let Some(log) = save {
-                            self.storage_log.push(log)
+                        match (cmd_copy, &resp) {
+                            (cmd @ StorageCmd::WriteVar { .. }, _)
+                            | (cmd @ StorageCmd::DeleteVar { .. }, StorageResp::DeleteVar {}) => {
+                                self.storage_log.push(cmd)
+                            }
+                            // logged as the resulting write, so clients can track values
+                            (
+                                StorageCmd::IncrementVar { name, scope, .. },
+                                StorageResp::IncrementVar { value, .. },
+                            ) => self.storage_log.push(StorageCmd::WriteVar {
+                                name,
+                                value: value.to_string().into_bytes(),
+                                op: StorageOp::Set,
+                                when_version_is: None,
+                                scope,
+                            }),
+                            (cmd @ StorageCmd::WatchVar { .. }, StorageResp::Watching {}) => {
+                                self.watch = Some(cmd)
+                            }
+                            _ => {}
                         }
                         let res_bytes = serde_json::to_vec(&resp).unwrap();
                         self.set_blob(BlobId::STORAGE_RESULT, res_bytes);
@@ -256,10 +378,23 @@ fn fatal_error(caller: &mut wasmtime::Caller<'_, ModuleData>, msg: &str) {
     }
 }
 
-fn read_caller_mem(caller: &wasmtime::Caller<'_, ModuleData>, ptr: u32, len: u32) -> Vec<u8> {
-    let mem = caller.data().memory.unwrap();
+fn caller_mem_slice<'a>(
+    caller: &'a wasmtime::Caller<'_, ModuleData>,
+    ptr: u32,
+    len: usize,
+) -> Result<&'a [u8], vfs::Errno> {
+    let mem = caller.data().memory.ok_or(vfs::ERRNO_FAULT)?;
     let ptr = ptr as usize;
-    Vec::from(&mem.data(&caller)[ptr..(ptr + len as usize)])
+    let end = ptr.checked_add(len).ok_or(vfs::ERRNO_FAULT)?;
+    mem.data(caller).get(ptr..end).ok_or(vfs::ERRNO_FAULT)
+}
+
+fn read_caller_mem(
+    caller: &wasmtime::Caller<'_, ModuleData>,
+    ptr: u32,
+    len: u32,
+) -> Result<Vec<u8>, vfs::Errno> {
+    Ok(Vec::from(caller_mem_slice(caller, ptr, len as usize)?))
 }
 
 fn write_caller_mem(
@@ -267,13 +402,48 @@ fn write_caller_mem(
     ptr: u32,
     len: u32,
     src: &[u8],
-) -> u32 {
+) -> Result<u32, vfs::Errno> {
     if len > 0 {
-        let mem = caller.data().memory.unwrap();
+        let mem = caller.data().memory.ok_or(vfs::ERRNO_FAULT)?;
         let min_len = std::cmp::min(len as usize, src.len());
-        mem.write(caller, ptr as usize, &src[..min_len]).unwrap();
+        mem.write(caller, ptr as usize, &src[..min_len])
+            .map_err(|_| vfs::ERRNO_FAULT)?;
     }
-    src.len() as u32
+    Ok(src.len() as u32)
+}
+
+// the aici_host_* functions trap on bad pointers instead of returning an errno
+fn mem_fault(name: &str, ptr: u32, len: usize) -> anyhow::Error {
+    user_error!("{name}: invalid memory access at {ptr}+{len}")
+}
+
+fn read_host_mem(
+    caller: &wasmtime::Caller<'_, ModuleData>,
+    name: &str,
+    ptr: u32,
+    len: u32,
+) -> Result<Vec<u8>> {
+    read_caller_mem(caller, ptr, len).map_err(|_| mem_fault(name, ptr, len as usize))
+}
+
+fn write_host_mem(
+    caller: &mut wasmtime::Caller<'_, ModuleData>,
+    name: &str,
+    ptr: u32,
+    len: u32,
+    src: &[u8],
+) -> Result<u32> {
+    write_caller_mem(caller, ptr, len, src).map_err(|_| mem_fault(name, ptr, len as usize))
+}
+
+// like `?`, but for WASI functions, which return the errno
+macro_rules! wasi_try {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return e,
+        }
+    };
 }
 
 macro_rules! fake_wasi {
@@ -289,29 +459,219 @@ macro_rules! fake_wasi {
     };
 }
 
+fn setup_vfs(linker: &mut wasmtime::Linker<ModuleData>) -> Result<()> {
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_prestat_get",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, fd: i32, ptr: u32| {
+            if fd as u32 != vfs::DATA_DIR_FD {
+                return vfs::ERRNO_BADF;
+            }
+            // tag 0 is directory
+            let mut prestat = vec![0u8; 8];
+            prestat[4..8].copy_from_slice(&(vfs::DATA_DIR_NAME.len() as u32).to_le_bytes());
+            wasi_try!(write_caller_mem(&mut caller, ptr, 8, &prestat));
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_prestat_dir_name",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, fd: i32, ptr: u32, len: u32| {
+            if fd as u32 != vfs::DATA_DIR_FD {
+                return vfs::ERRNO_BADF;
+            }
+            wasi_try!(write_caller_mem(
+                &mut caller,
+                ptr,
+                len,
+                vfs::DATA_DIR_NAME.as_bytes()
+            ));
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "path_open",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         dirfd: i32,
+         _dirflags: i32,
+         path: u32,
+         path_len: u32,
+         oflags: i32,
+         rights_base: i64,
+         _rights_inheriting: i64,
+         _fdflags: i32,
+         fd_ptr: u32| {
+            let path = wasi_try!(read_caller_mem(&caller, path, path_len));
+            let res =
+                caller
+                    .data_mut()
+                    .vfs
+                    .open(dirfd as u32, &path, oflags as u32, rights_base as u64);
+            match res {
+                Ok(fd) => {
+                    wasi_try!(write_caller_mem(&mut caller, fd_ptr, 4, &fd.to_le_bytes()));
+                    0
+                }
+                Err(e) => e,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_close",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, fd: i32| match caller
+            .data_mut()
+            .vfs
+            .close(fd as u32)
+        {
+            Ok(()) => 0,
+            Err(e) => e,
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_read",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         fd: i32,
+         iovs_ptr: u32,
+         niovs: u32,
+         nread_ptr: u32| {
+            let iovs_len = wasi_try!(niovs.checked_mul(8).ok_or(vfs::ERRNO_INVAL));
+            let iovs = wasi_try!(read_caller_mem(&caller, iovs_ptr, iovs_len));
+            let ptr_lens = vec_from_bytes::<U32Pair>(&iovs);
+            let mut nread = 0u32;
+            for U32Pair(ptr, len) in ptr_lens {
+                match caller.data_mut().vfs.read(fd as u32, len as usize) {
+                    Ok(data) => {
+                        nread += wasi_try!(write_caller_mem(&mut caller, ptr, len, &data));
+                        if data.len() < len as usize {
+                            break;
+                        }
+                    }
+                    Err(e) => return e,
+                }
+            }
+            wasi_try!(write_caller_mem(
+                &mut caller,
+                nread_ptr,
+                4,
+                &nread.to_le_bytes()
+            ));
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_seek",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         fd: i32,
+         offset: i64,
+         whence: i32,
+         newoffset_ptr: u32| {
+            match caller.data_mut().vfs.seek(fd as u32, offset, whence as u8) {
+                Ok(pos) => {
+                    wasi_try!(write_caller_mem(
+                        &mut caller,
+                        newoffset_ptr,
+                        8,
+                        &pos.to_le_bytes()
+                    ));
+                    0
+                }
+                Err(e) => e,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_filestat_get",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, fd: i32, ptr: u32| match caller
+            .data()
+            .vfs
+            .stat_fd(fd as u32)
+        {
+            Ok(st) => {
+                wasi_try!(write_caller_mem(&mut caller, ptr, 64, &st.to_bytes()));
+                0
+            }
+            Err(e) => e,
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "path_filestat_get",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         dirfd: i32,
+         _flags: i32,
+         path: u32,
+         path_len: u32,
+         ptr: u32| {
+            let path = wasi_try!(read_caller_mem(&caller, path, path_len));
+            match caller.data().vfs.stat_path(dirfd as u32, &path) {
+                Ok(st) => {
+                    wasi_try!(write_caller_mem(&mut caller, ptr, 64, &st.to_bytes()));
+                    0
+                }
+                Err(e) => e,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_readdir",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         fd: i32,
+         buf: u32,
+         buf_len: u32,
+         cookie: i64,
+         bufused_ptr: u32| {
+            match caller.data().vfs.readdir(fd as u32, cookie as u64) {
+                Ok(entries) => {
+                    // a full buffer tells the caller to retry with a larger one
+                    let used = std::cmp::min(entries.len() as u32, buf_len);
+                    wasi_try!(write_caller_mem(&mut caller, buf, used, &entries));
+                    wasi_try!(write_caller_mem(
+                        &mut caller,
+                        bufused_ptr,
+                        4,
+                        &used.to_le_bytes()
+                    ));
+                    0
+                }
+                Err(e) => e,
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
 pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<ModuleData>>> {
     let mut linker = wasmtime::Linker::<ModuleData>::new(engine);
 
     fake_wasi!(linker, environ_get, i32 i32);
     fake_wasi!(linker, path_create_directory, i32 i32 i32);
-    fake_wasi!(linker, path_filestat_get, i32 i32 i32 i32 i32);
     fake_wasi!(linker, path_link, i32 i32 i32 i32 i32 i32 i32);
-    fake_wasi!(linker, path_open, i32 i32 i32 i32 i32 i64 i64 i32 i32);
     fake_wasi!(linker, path_readlink, i32 i32 i32 i32 i32 i32);
     fake_wasi!(linker, path_remove_directory, i32 i32 i32);
     fake_wasi!(linker, path_rename, i32 i32 i32 i32 i32 i32);
     fake_wasi!(linker, path_unlink_file, i32 i32 i32);
     fake_wasi!(linker, poll_oneoff, i32 i32 i32 i32);
     fake_wasi!(linker, fd_filestat_set_size, i32 i64);
-    fake_wasi!(linker, fd_read, i32 i32 i32 i32);
-    fake_wasi!(linker, fd_readdir, i32 i32 i32 i64 i32);
-    fake_wasi!(linker, fd_close, i32);
-    fake_wasi!(linker, fd_filestat_get, i32 i32);
-    fake_wasi!(linker, fd_prestat_get, i32 i32);
-    fake_wasi!(linker, fd_prestat_dir_name, i32 i32 i32);
-    fake_wasi!(linker, fd_seek, i32 i64 i32 i32);
     fake_wasi!(linker, path_filestat_set_times, i32 i32 i32 i32 i64 i64 i32);
 
+    setup_vfs(&mut linker)?;
+
     linker.func_wrap("wasi_snapshot_preview1", "sched_yield", || 0)?;
     linker.func_wrap("wasi_snapshot_preview1", "fd_sync", |_: i32| 0)?;
 
@@ -325,13 +685,21 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "wasi_snapshot_preview1",
         "fd_fdstat_get",
         |mut caller: wasmtime::Caller<'_, ModuleData>, fd: i32, stat_ptr: u32| -> Result<i32> {
-            if fd != 0 && fd != 1 && fd != 2 {
-                return Ok(8); // BADF
+            let mut fdstat = vec![0u8; 24];
+            if fd == 0 || fd == 1 || fd == 2 {
+                // pretend file isatty()
+                fdstat[0] = 2;
+            } else {
+                match caller.data().vfs.filetype(fd as u32) {
+                    Ok(filetype) => fdstat[0] = filetype,
+                    Err(e) => return Ok(e),
+                }
+                // all rights; the Vfs refuses writes itself
+                fdstat[8..24].fill(0xff);
+            }
+            if let Err(e) = write_caller_mem(&mut caller, stat_ptr, 24, &fdstat) {
+                return Ok(e);
             }
-            // pretend file isatty()
-            let mut char_device = vec![0u8; 24];
-            char_device[0] = 2;
-            write_caller_mem(&mut caller, stat_ptr, 24, &char_device);
             Ok(0)
         },
     )?;
@@ -352,7 +720,9 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
             let nanos = now.duration_since(caller.data().start_time).as_nanos() as u64;
             let nanos = if res == 0 { 0 } else { nanos / res * res };
             let bytes = nanos.to_le_bytes();
-            write_caller_mem(&mut caller, dst_ptr, 8, &bytes);
+            if let Err(e) = write_caller_mem(&mut caller, dst_ptr, 8, &bytes) {
+                return Ok(e);
+            }
             Ok(0)
         },
     )?;
@@ -368,16 +738,22 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
             if fd != 1 && fd != 2 {
                 return 8; // BADF
             }
-            let iovs = read_caller_mem(&caller, iovs_ptr, niovs * 8);
+            let iovs_len = wasi_try!(niovs.checked_mul(8).ok_or(vfs::ERRNO_INVAL));
+            let iovs = wasi_try!(read_caller_mem(&caller, iovs_ptr, iovs_len));
             let ptr_lens = vec_from_bytes::<U32Pair>(&iovs);
             let mut nwr = 0;
             for U32Pair(ptr, len) in ptr_lens {
-                let m = read_caller_mem(&caller, ptr, len);
+                let m = wasi_try!(read_caller_mem(&caller, ptr, len));
                 nwr += m.len();
                 caller.data_mut().write_log(&m);
             }
             if nwrittenptr != 0 {
-                write_caller_mem(&mut caller, nwrittenptr, 4, &nwr.to_le_bytes());
+                wasi_try!(write_caller_mem(
+                    &mut caller,
+                    nwrittenptr,
+                    4,
+                    &nwr.to_le_bytes()
+                ));
             }
             0
         },
@@ -387,7 +763,7 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "wasi_snapshot_preview1",
         "random_get",
         |mut caller: wasmtime::Caller<'_, ModuleData>, ptr: u32, len: u32| {
-            write_caller_mem(&mut caller, ptr, len, &[]);
+            wasi_try!(write_caller_mem(&mut caller, ptr, len, &[]));
             0
         },
     )?;
@@ -397,8 +773,8 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "args_sizes_get",
         |mut caller: wasmtime::Caller<'_, ModuleData>, p1: u32, p2: u32| {
             let z = vec![0u8; 4];
-            write_caller_mem(&mut caller, p1, 4, &z);
-            write_caller_mem(&mut caller, p2, 4, &z);
+            wasi_try!(write_caller_mem(&mut caller, p1, 4, &z));
+            wasi_try!(write_caller_mem(&mut caller, p2, 4, &z));
             0
         },
     )?;
@@ -408,8 +784,8 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "environ_sizes_get",
         |mut caller: wasmtime::Caller<'_, ModuleData>, p1: u32, p2: u32| {
             let z = vec![0u8; 4];
-            write_caller_mem(&mut caller, p1, 4, &z);
-            write_caller_mem(&mut caller, p2, 4, &z);
+            wasi_try!(write_caller_mem(&mut caller, p1, 4, &z));
+            wasi_try!(write_caller_mem(&mut caller, p2, 4, &z));
             0
         },
     )?;
@@ -418,16 +794,20 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
     linker.func_wrap(
         "env",
         "aici_host_read_blob",
-        |mut caller: wasmtime::Caller<'_, ModuleData>, blob_id: u32, ptr: u32, len: u32| {
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         blob_id: u32,
+         ptr: u32,
+         len: u32|
+         -> Result<u32> {
             if blob_id == BlobId::TRIE.0 {
                 let trie_bytes = caller.data().globals.trie_bytes.clone();
-                write_caller_mem(&mut caller, ptr, len, &trie_bytes)
+                write_host_mem(&mut caller, "read_blob", ptr, len, &trie_bytes)
             } else if blob_id < BlobId::MAX_BLOB_ID {
                 let blob = caller.data().blobs[blob_id as usize].clone();
-                write_caller_mem(&mut caller, ptr, len, &blob)
+                write_host_mem(&mut caller, "read_blob", ptr, len, &blob)
             } else {
                 fatal_error(&mut caller, "invalid blob_id");
-                0
+                Ok(0)
             }
         },
     )?;
@@ -441,8 +821,8 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
     linker.func_wrap(
         "env",
         "aici_host_tokenize",
-        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
-            let m = read_caller_mem(&caller, src, src_size);
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| -> Result<u32> {
+            let m = read_host_mem(&caller, "tokenize", src, src_size)?;
             let tokens = caller.data_mut().tokenize_bytes(&m);
             match tokens {
                 Err(e) => {
@@ -455,23 +835,22 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
                         .set_blob(BlobId::TOKENIZE, clone_vec_as_bytes(&tokens));
                 }
             }
-            BlobId::TOKENIZE.0
+            Ok(BlobId::TOKENIZE.0)
         },
     )?;
 
     linker.func_wrap(
         "env",
         "aici_host_return_logit_bias",
-        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32| {
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32| -> Result<u32> {
             let data = caller.data();
 
             let numtok = data.globals.tokrx_info.vocab_size as usize;
             let shm = data.logit_shm.clone();
             let id: u32 = data.id.try_into().unwrap();
             let numbytes = 4 * ((numtok + 31) / 32);
-            let mem = caller.data().memory.unwrap();
-            let sptr = src as usize;
-            let slice = &mem.data(&caller)[sptr..sptr + numbytes];
+            let slice = caller_mem_slice(&caller, src, numbytes)
+                .map_err(|_| mem_fault("return_logit_bias", src, numbytes))?;
 
             let bias_type = BiasType::from_u32(shm.elt_type() & 0xf).unwrap();
             let off = shm.alloc(id).unwrap();
@@ -480,7 +859,7 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
 
             let off32: u32 = off.try_into().unwrap();
             caller.data_mut().logit_offsets.push(off32);
-            off32
+            Ok(off32)
         },
     )?;
 
@@ -493,14 +872,14 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
     linker.func_wrap(
         "env",
         "aici_host_get_config",
-        |caller: wasmtime::Caller<'_, ModuleData>, name: u32, name_size: u32| {
-            let m = read_caller_mem(&caller, name, name_size);
+        |caller: wasmtime::Caller<'_, ModuleData>, name: u32, name_size: u32| -> Result<i32> {
+            let m = read_host_mem(&caller, "get_config", name, name_size)?;
             let name = String::from_utf8_lossy(&m);
             let caps = serde_json::to_value(caller.data().globals.inference_caps.clone()).unwrap();
             if caps[name.as_ref()].as_bool().unwrap_or(false) {
-                return 1;
+                return Ok(1);
             }
-            return 0;
+            Ok(0)
         },
     )?;
 
@@ -513,20 +892,81 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
     linker.func_wrap(
         "env",
         "aici_host_return_process_result",
-        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
-            let m = read_caller_mem(&caller, src, src_size);
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| -> Result<()> {
+            let m = read_host_mem(&caller, "return_process_result", src, src_size)?;
             caller.data_mut().process_result = m;
+            Ok(())
         },
     )?;
 
     linker.func_wrap(
         "env",
         "aici_host_storage_cmd",
-        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
-            let m = read_caller_mem(&caller, src, src_size);
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| -> Result<u32> {
+            let m = read_host_mem(&caller, "storage_cmd", src, src_size)?;
             let r = caller.data_mut().aici_host_storage_cmd(m);
             check_fatal(&mut caller);
-            r.0
+            Ok(r.0)
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_script_error",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| -> Result<()> {
+            let m = read_host_mem(&caller, "script_error", src, src_size)?;
+            match serde_json::from_slice(&m) {
+                Ok(err) => caller.data_mut().script_error = Some(err),
+                Err(e) => caller.data_mut().warn(&format!("script_error: {e:?}")),
+            }
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_emit",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         kind: u32,
+         kind_size: u32,
+         data: u32,
+         data_size: u32|
+         -> Result<()> {
+            let kind = read_host_mem(&caller, "emit", kind, kind_size)?;
+            let data = read_host_mem(&caller, "emit", data, data_size)?;
+            caller.data_mut().emit(&kind, &data);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_stop_seqs",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, ids: u32, num_ids: u32| -> Result<()> {
+            let num_bytes = num_ids
+                .checked_mul(4)
+                .ok_or_else(|| user_error!("stop_seqs: too many ids ({num_ids})"))?;
+            let ids: Vec<u32> =
+                vec_from_bytes(&read_host_mem(&caller, "stop_seqs", ids, num_bytes)?);
+            // validated against the current request in Stepper::aici_mid_process()
+            caller
+                .data_mut()
+                .stop_seqs
+                .extend(ids.into_iter().map(|id| id as ModuleInstId));
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_spawn_generation",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| -> Result<()> {
+            let m = read_host_mem(&caller, "spawn_generation", src, src_size)?;
+            match serde_json::from_slice(&m) {
+                Ok(gen) => caller.data_mut().spawns.push(gen),
+                Err(e) => caller.data_mut().warn(&format!("spawn_generation: {e:?}")),
+            }
+            Ok(())
         },
     )?;
 
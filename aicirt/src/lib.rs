@@ -4,6 +4,7 @@ pub mod futexshm;
 pub mod msgchannel;
 pub mod semaphore;
 pub mod shm;
+pub mod sockchannel;
 
 pub use aici_native::*;
 
@@ -1,5 +1,7 @@
 mod hostimpl;
 mod moduleinstance;
+mod store;
+mod vfs;
 mod worker;
 
 use crate::{
@@ -8,12 +10,13 @@ use crate::{
     moduleinstance::*,
     msgchannel::MessageChannel,
     shm::Shm,
+    sockchannel::{SockAddr, SockStream},
     worker::{RtMidProcessArg, WorkerForker},
     TimerSet,
 };
 use aici_abi::{
     bytes::limit_str, toktrie::TokTrie, Branch, MidProcessArg, ProcessResultOffset, SeqId,
-    TokenizerEnv,
+    StorageCmd, StorageOp, StorageResp, StorageScope, TokenizerEnv,
 };
 use aicirt::{bintokens::find_tokenizer, futexshm::ServerChannel, shm::ShmAllocator, *};
 use anyhow::{anyhow, ensure, Result};
@@ -27,6 +30,7 @@ use serde::Serialize;
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use std::{
+    collections::{BTreeMap, HashSet},
     fs,
     ops::Sub,
     path::PathBuf,
@@ -75,6 +79,10 @@ struct Cli {
     #[arg(long)]
     save_tokenizer: Option<String>,
 
+    /// Report conflicts, unreachable rules and unproductive terminals in a yacc grammar
+    #[arg(long)]
+    check_grammar: Option<PathBuf>,
+
     /// Run main() from the module just added
     #[arg(short, long)]
     run: bool,
@@ -107,7 +115,7 @@ struct Cli {
     #[arg(long)]
     cap_ff_tokens: bool,
 
-    /// Specify the type of bias to pass using shared memory (f32, f16, bf16, bool)
+    /// Specify the type of bias to pass using shared memory (f32, f16, bf16, bits)
     #[arg(long, default_value = "f32")]
     bias_dtype: String,
 
@@ -115,6 +123,15 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     futex: bool,
 
+    /// Talk to the LLM over a socket instead of POSIX shared memory; unix:PATH, tcp:PORT
+    /// (loopback only) or tcp:HOST:PORT. The LLM has to know the token in $AICIRT_TOKEN.
+    #[arg(long)]
+    listen: Option<String>,
+
+    /// How to send logit biases with --listen (raw, rle)
+    #[arg(long, default_value = "rle")]
+    mask_encoding: String,
+
     /// Size of JSON comm buffer in megabytes
     #[arg(long, default_value = "128")]
     json_size: usize,
@@ -131,19 +148,51 @@ struct Cli {
     #[arg(long, default_value = "16")]
     wasm_max_forks: usize,
 
+    /// Maximum total size of aici_preinit() memory snapshots kept on disk, in megabytes
+    #[arg(long, default_value = "4096")]
+    snapshot_cache_size: usize,
+
+    /// Directory to persist user- and global-scoped variables in; kept in memory only if not set
+    #[arg(long)]
+    storage_dir: Option<PathBuf>,
+
+    /// Maximum size of a single user- or global-scoped variable namespace in kilobytes; 0 for no limit
+    #[arg(long, default_value = "1024")]
+    storage_max_size: usize,
+
+    /// Expire user- and global-scoped variables not written for this many seconds; 0 to never expire
+    #[arg(long, default_value = "0")]
+    storage_ttl: u64,
+
+    /// Maximum number of non-empty variable namespaces of each user (and of global ones); 0 for no limit
+    #[arg(long, default_value = "100")]
+    storage_max_namespaces: usize,
+
+    /// Fuel (roughly, WASM instructions) budget for each mid_process() call; 0 for unlimited.
+    /// Setting this or --wasm-max-init-fuel enables fuel metering, which makes limits
+    /// deterministic; modules can ask for smaller budgets in their meta.
+    #[arg(long, default_value = "0")]
+    wasm_max_step_fuel: u64,
+
+    /// Fuel budget for each initialization call (aici_init(), aici_create(), init_prompt()); 0 for unlimited
+    #[arg(long, default_value = "0")]
+    wasm_max_init_fuel: u64,
+
     /// Maximum size of WASM module memory in megabytes
     #[arg(long, default_value = "64")]
     wasm_max_memory: usize,
 
-    /// Maximum time WASM module can execute step in milliseconds
+    /// Maximum time WASM module can execute step in milliseconds;
+    /// with a step fuel budget, fuel is the limit, and this is raised to 60s
     #[arg(long, default_value = "25")]
     wasm_max_step_time: u64,
 
-    /// How many steps have to timeout before the sequenace is terminated
+    /// How many steps have to timeout before the sequenace is terminated (without step fuel budget)
     #[arg(long, default_value = "10")]
     wasm_max_timeout_steps: usize,
 
-    /// Maximum time WASM module can execute initialization code in milliseconds
+    /// Maximum time WASM module can execute initialization code in milliseconds;
+    /// with an init fuel budget, fuel is the limit, and this is raised to 60s
     #[arg(long, default_value = "1000")]
     wasm_max_init_time: u64,
 
@@ -178,16 +227,31 @@ struct ModuleRegistry {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     // not sure Mutex is needed
     forker: Arc<Mutex<WorkerForker>>,
+    // names of variables written outside of mid_process(), see Stepper::wake_watchers()
+    written_vars: Arc<Mutex<HashSet<String>>>,
 }
 
 struct Stepper {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     instances: HashMap<ModuleInstId, SeqWorkerHandle>,
     num_timeouts: HashMap<ModuleInstId, usize>,
+    // suspended sequences, with the WatchVar command they are waiting on
+    watching: HashMap<ModuleInstId, StorageCmd>,
+    written_vars: Arc<Mutex<HashSet<String>>>,
     limits: AiciLimits,
     globals: GlobalInfo,
     shm: Rc<ShmAllocator>,
     token_bytes: Vec<Vec<u8>>,
+    // set when the LLM is not on the same host
+    inline_masks: Option<MaskEncoding>,
+}
+
+// what controllers return while waiting on WatchVar
+fn is_noop(r: &ProcessResultOffset) -> bool {
+    r.branches.len() == 1
+        && r.branches[0].sample_mask.is_none()
+        && !r.branches[0].has_backtrack()
+        && !r.branches[0].has_ff_tokens()
 }
 
 fn hex_hash_string(s: &str) -> String {
@@ -216,6 +280,7 @@ impl ModuleRegistry {
             wasm_ctx: Arc::new(wasm_ctx),
             modules: Arc::new(Mutex::new(HashMap::default())),
             req_instances: Arc::new(Mutex::new(HashMap::default())),
+            written_vars: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
@@ -241,6 +306,55 @@ impl ModuleRegistry {
         self.cache_path.join(format!("{}-sys.json", module_id))
     }
 
+    fn module_meta(&self, module_id: &str) -> ModuleMeta {
+        // modules uploaded before meta was introduced (or installed from gh) have none
+        read_json(&self.sys_meta_path(module_id))
+            .ok()
+            .and_then(|v| serde_json::from_value(v["meta"].clone()).ok())
+            .unwrap_or_default()
+    }
+
+    fn limits_path(&self, kind: &str, name: &str) -> PathBuf {
+        // user names can be anything, so hash them
+        let hex = hex_hash_string(name);
+        self.cache_path
+            .join(format!("limits/{}-{}.json", kind, hex))
+    }
+
+    // no file means no bounds; a broken one must not lift them, so refuse to run instead
+    fn read_bounds(&self, kind: &str, name: &str) -> Result<Option<ModuleMeta>> {
+        let path = self.limits_path(kind, name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        match read_json(&path).and_then(|v| Ok(serde_json::from_value(v)?)) {
+            Ok(bounds) => Ok(Some(bounds)),
+            Err(e) => {
+                log::error!("limits of {kind} {name} ({}): {e}", path.display());
+                Err(anyhow!("limits of {kind} {name} are invalid; ask an admin"))
+            }
+        }
+    }
+
+    /// Effective limits of the module, when run by `user` through `tag` (if any).
+    fn module_limits(
+        &self,
+        module_id: &str,
+        tag: Option<&str>,
+        user: &str,
+    ) -> Result<ModuleLimits> {
+        // bounds of the user running the module; the uploader could be anyone
+        let mut bounds = vec![];
+        bounds.extend(self.read_bounds("user", user)?);
+        if let Some(tag) = tag {
+            bounds.extend(self.read_bounds("tag", tag)?);
+        }
+        Ok(self
+            .wasm_ctx
+            .limits
+            .module_limits(&self.module_meta(module_id), &bounds))
+    }
+
     fn wasm_path(&self, module_id: &str) -> PathBuf {
         self.cache_path.join(format!("{}.wasm", module_id))
     }
@@ -298,19 +412,48 @@ impl ModuleRegistry {
         Ok(self.elf_path(module_id))
     }
 
-    fn create_module(&self, wasm_bytes: Vec<u8>, auth: AuthInfo) -> Result<MkModuleResp> {
+    fn create_module(
+        &self,
+        wasm_bytes: Vec<u8>,
+        data: BTreeMap<String, Vec<u8>>,
+        meta: ModuleMeta,
+        auth: AuthInfo,
+    ) -> Result<MkModuleResp> {
         ensure_user!(self.wasm_ctx.limits.module_upload, "module upload disabled");
 
         let timer = Instant::now();
 
         let mut hasher = <Sha256 as Digest>::new();
         hasher.update(&wasm_bytes);
+        // the same binary with different data files is a different module
+        for (path, content) in &data {
+            hasher.update(&(path.len() as u64).to_le_bytes());
+            hasher.update(path.as_bytes());
+            hasher.update(&(content.len() as u64).to_le_bytes());
+            hasher.update(content);
+        }
+        // same for different meta, so that each uploader gets the limits they asked for;
+        // without meta, the id stays as before
+        if meta != ModuleMeta::default() {
+            let meta_bytes = serde_json::to_vec(&meta)?;
+            hasher.update(b"meta");
+            hasher.update(&(meta_bytes.len() as u64).to_le_bytes());
+            hasher.update(&meta_bytes);
+        }
 
         let module_id = hex::encode(hasher.finalize());
         let module_id = &module_id;
 
+        // only modules uploaded before meta was hashed can still conflict
+        if self.sys_meta_path(module_id).exists() {
+            ensure_user!(
+                self.module_meta(module_id) == meta,
+                "module {module_id} was already uploaded with a different meta"
+            );
+        }
+
         if self.module_needs_check(module_id) {
-            match self.write_and_compile(module_id, &wasm_bytes, &auth) {
+            match self.write_and_compile(module_id, &wasm_bytes, &data, &meta, &auth) {
                 Err(e) => {
                     let mut lck = self.modules.lock().unwrap();
                     lck.remove(module_id);
@@ -343,18 +486,25 @@ impl ModuleRegistry {
         &self,
         module_id: &String,
         wasm_bytes: &Vec<u8>,
+        data: &BTreeMap<String, Vec<u8>>,
+        meta: &ModuleMeta,
         auth: &AuthInfo,
     ) -> Result<()> {
         fs::create_dir_all(&self.cache_path)?;
-        let meta = self.wasm_path(module_id).metadata();
+        let wasm_meta = self.wasm_path(module_id).metadata();
         Ok(
-            if meta.is_err() || meta.unwrap().len() != wasm_bytes.len() as u64 {
+            if wasm_meta.is_err() || wasm_meta.unwrap().len() != wasm_bytes.len() as u64 {
+                // data goes first, so that the .wasm file is only there when the data is
+                if data.len() > 0 {
+                    self.write_data(module_id, data)?;
+                }
                 fs::write(self.wasm_path(module_id), wasm_bytes)?;
                 write_json(
                     &self.sys_meta_path(module_id),
                     &json!({
                         "created": get_unix_time(),
                         "auth": auth,
+                        "meta": meta,
                     }),
                 )?;
                 self.compile_module(module_id, true)?
@@ -364,10 +514,42 @@ impl ModuleRegistry {
         )
     }
 
+    fn write_data(&self, module_id: &str, data: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+        let data_dir = vfs::data_dir_for(&self.elf_path(module_id));
+        let tmp_dir = data_dir.with_extension("data.tmp");
+        let _ = fs::remove_dir_all(&tmp_dir);
+        for (path, content) in data {
+            let file_path = tmp_dir.join(path);
+            fs::create_dir_all(file_path.parent().unwrap())?;
+            fs::write(file_path, content)?;
+        }
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::rename(&tmp_dir, &data_dir)?;
+        Ok(())
+    }
+
     fn mk_module(&self, req: MkModuleReq, auth: AuthInfo) -> Result<Value> {
         let wasm_bytes = base64::engine::general_purpose::STANDARD.decode(req.binary)?;
+        ensure_user!(
+            req.data.len() <= vfs::MAX_DATA_FILES,
+            "too many data files (max {})",
+            vfs::MAX_DATA_FILES
+        );
+        let mut data = BTreeMap::new();
+        let mut total_bytes = 0;
+        for (path, content) in req.data {
+            ensure_user!(vfs::valid_data_path(&path), "invalid data path: {:?}", path);
+            let content = base64::engine::general_purpose::STANDARD.decode(content)?;
+            total_bytes += content.len();
+            ensure_user!(
+                total_bytes <= vfs::MAX_DATA_BYTES,
+                "data files too large (max {} bytes)",
+                vfs::MAX_DATA_BYTES
+            );
+            data.insert(path, content);
+        }
         Ok(serde_json::to_value(
-            &self.create_module(wasm_bytes, auth)?,
+            &self.create_module(wasm_bytes, data, req.meta, auth)?,
         )?)
     }
 
@@ -408,6 +590,7 @@ impl ModuleRegistry {
             updated_by: auth.user.clone(),
             wasm_size: self.wasm_path(&req.module_id).metadata()?.len(),
             compiled_size: self.elf_path(&req.module_id).metadata()?.len(),
+            limits: None,
         };
 
         let mut resp = GetTagsResp { tags: vec![] };
@@ -416,6 +599,8 @@ impl ModuleRegistry {
             let mut info = info.clone();
             info.tag = tagname.clone();
             write_json(&self.tag_path(tagname), &info)?;
+            info.limits =
+                Some(self.module_limits(&info.module_id, Some(tagname.as_str()), &auth.user)?);
             resp.tags.push(info)
         }
 
@@ -430,7 +615,7 @@ impl ModuleRegistry {
         }
     }
 
-    fn get_tags(&self, _req: Value) -> Result<Value> {
+    fn get_tags(&self, _req: Value, auth: AuthInfo) -> Result<Value> {
         let tagspath = self.cache_path.join("tags");
         fs::create_dir_all(&tagspath)?;
         let mut resp = GetTagsResp { tags: vec![] };
@@ -438,7 +623,13 @@ impl ModuleRegistry {
             let file = file?.path();
             if file.to_string_lossy().ends_with(".json") {
                 let bytes = fs::read(file)?;
-                resp.tags.push(serde_json::from_slice(&bytes)?);
+                let mut info: TagInfo = serde_json::from_slice(&bytes)?;
+                info.limits = Some(self.module_limits(
+                    &info.module_id,
+                    Some(info.tag.as_str()),
+                    &auth.user,
+                )?);
+                resp.tags.push(info);
             }
         }
         resp.tags.sort_by_key(|e| e.updated_at);
@@ -446,6 +637,62 @@ impl ModuleRegistry {
         Ok(json!(resp))
     }
 
+    fn set_limits(&self, req: SetLimitsReq, auth: AuthInfo) -> Result<Value> {
+        ensure_user!(auth.is_admin, "only admins can set limits");
+        let path = match (&req.user, &req.tag) {
+            (Some(user), None) => self.limits_path("user", user),
+            (None, Some(tag)) => {
+                ensure_user!(valid_tagname(tag), "tag name not identifier");
+                self.limits_path("tag", tag)
+            }
+            _ => bail_user!("exactly one of user and tag has to be set"),
+        };
+        fs::create_dir_all(&self.cache_path.join("limits"))?;
+        log::info!(
+            "limits for user={:?} tag={:?}: {:?} by {}",
+            req.user,
+            req.tag,
+            req.limits,
+            auth.user
+        );
+        write_json(&path, &req.limits)?;
+        Ok(json!({}))
+    }
+
+    fn module_info(&self, req: ModuleInfoReq, auth: AuthInfo) -> Result<Value> {
+        let (module_id, tag) = self.resolve_module_id(&req.module_id)?;
+        let wasm_size = match self.wasm_path(&module_id).metadata() {
+            Ok(m) => m.len(),
+            Err(_) => bail_user!("module {} not found", module_id),
+        };
+        let sys_meta = read_json(&self.sys_meta_path(&module_id)).unwrap_or_default();
+        let resp = ModuleInfoResp {
+            created_at: sys_meta["created"].as_u64().unwrap_or(0),
+            created_by: sys_meta["auth"]["user"].as_str().unwrap_or("").to_string(),
+            wasm_size,
+            compiled_size: self
+                .elf_path(&module_id)
+                .metadata()
+                .map(|m| m.len())
+                .unwrap_or(0),
+            meta: self.module_meta(&module_id),
+            limits: self.module_limits(&module_id, tag.as_deref(), &auth.user)?,
+            module_id,
+        };
+        Ok(json!(resp))
+    }
+
+    /// Resolve gh: and tag names to module id; also returns the tag name if any.
+    fn resolve_module_id(&self, module_id: &str) -> Result<(String, Option<String>)> {
+        let module_id = self.resolve_gh_module(module_id, None)?;
+        if valid_tagname(&module_id) {
+            let taginfo = self.read_tag(&module_id)?;
+            return Ok((taginfo.module_id, Some(taginfo.tag)));
+        }
+        ensure!(is_hex_string(&module_id), "invalid module_id");
+        Ok((module_id, None))
+    }
+
     fn resolve_gh_module(&self, module_id: &str, wasm_override: Option<Vec<u8>>) -> Result<String> {
         if !module_id.starts_with("gh:") {
             return Ok(module_id.to_string());
@@ -563,6 +810,8 @@ impl ModuleRegistry {
         }
         let resp = self.create_module(
             wasm_bytes,
+            BTreeMap::new(),
+            ModuleMeta::default(),
             AuthInfo {
                 user: wasm_url.to_string(),
                 is_admin: true,
@@ -572,20 +821,22 @@ impl ModuleRegistry {
         Ok(resp.module_id)
     }
 
-    fn instantiate(&mut self, mut req: InstantiateReq) -> Result<Value> {
-        req.module_id = self.resolve_gh_module(&req.module_id, None)?;
-        if valid_tagname(&req.module_id) {
-            let taginfo = self.read_tag(&req.module_id)?;
-            req.module_id = taginfo.module_id;
-        }
-        ensure!(is_hex_string(&req.module_id), "invalid module_id");
+    fn instantiate(&mut self, mut req: InstantiateReq, auth: AuthInfo) -> Result<Value> {
+        let (module_id, tag) = self.resolve_module_id(&req.module_id)?;
+        req.module_id = module_id;
         let module_path = self.ensure_module_in_fs(&req.module_id)?;
+        let module_limits = self.module_limits(&req.module_id, tag.as_deref(), &auth.user)?;
         log::debug!("instance {} -> {}", req.module_id, req.req_id);
-        let (handle, res) = self
-            .forker
+        let (handle, res) = self.forker.lock().unwrap().instantiate(
+            req.clone(),
+            module_path,
+            module_limits,
+            &auth,
+        )?;
+        self.written_vars
             .lock()
             .unwrap()
-            .instantiate(req.clone(), module_path)?;
+            .extend(res.storage.iter().map(|cmd| cmd.name().to_string()));
         let mut req_instances = self.req_instances.lock().unwrap();
         req_instances.insert(req.req_id, handle);
         Ok(serde_json::to_value(res)?)
@@ -629,6 +880,14 @@ impl ModuleRegistry {
                             .unwrap();
                     });
                 }
+                CmdRespChannel::Socket { resp_ch, .. } => {
+                    let resp_ch = resp_ch.clone();
+                    rayon::spawn(move || {
+                        let r = s2.exec_wrapped(&msg);
+                        let r = serde_json::to_vec(&r).unwrap();
+                        CmdRespChannel::send_sock(&resp_ch, &r);
+                    });
+                }
             }
         }
     }
@@ -640,15 +899,19 @@ impl Stepper {
         limits: AiciLimits,
         shm: Rc<ShmAllocator>,
         token_bytes: Vec<Vec<u8>>,
+        inline_masks: Option<MaskEncoding>,
     ) -> Result<Self> {
         Ok(Self {
             req_instances: reg.req_instances.clone(),
             instances: HashMap::default(),
             num_timeouts: HashMap::default(),
+            watching: HashMap::default(),
+            written_vars: reg.written_vars.clone(),
             limits,
             globals: reg.wasm_ctx.globals.clone(),
             shm,
             token_bytes,
+            inline_masks,
         })
     }
 
@@ -686,8 +949,8 @@ impl Stepper {
                 .values()
                 .filter(|r| r.req_id == parent.req_id)
                 .count();
-            if num_forks + 1 > self.limits.max_forks {
-                anyhow::bail!("too many forks (max={})", self.limits.max_forks)
+            if num_forks + 1 > parent.limits.max_forks {
+                anyhow::bail!("too many forks (max={})", parent.limits.max_forks)
             }
             log::debug!("fork {} -> ({})", parent_id, id);
             // TODO the forks should be done in parallel, best in tree-like fashion
@@ -716,6 +979,40 @@ impl Stepper {
         Ok(())
     }
 
+    // the variables are request-scoped, so any sequence of the parent request will do;
+    // the error goes first, so that it's there when watchers of the result wake up
+    fn deliver_sub_gen_result(&self, res: SubGenResult) {
+        let h = self
+            .instances
+            .iter()
+            .find(|(id, h)| h.req_id == res.parent_req_id && !self.num_timeouts.contains_key(*id));
+        match h {
+            Some((_, h)) => {
+                let vars = [
+                    (res.error_var, res.error.unwrap_or_default()),
+                    (res.result_var, res.text),
+                ];
+                for (name, value) in vars {
+                    let cmd = StorageCmd::WriteVar {
+                        name,
+                        value: value.into_bytes(),
+                        op: StorageOp::Set,
+                        when_version_is: None,
+                        scope: StorageScope::Request,
+                    };
+                    if let Err(e) = h.storage_cmd(cmd) {
+                        log::warn!("sub-generation result for {}: {e}", res.parent_req_id);
+                        break;
+                    }
+                }
+            }
+            None => log::debug!(
+                "sub-generation result for {}: request is gone",
+                res.parent_req_id
+            ),
+        }
+    }
+
     fn aici_mid_process(&mut self, req: AiciMidProcessReq) -> Result<AiciMidProcessResp> {
         let block_elts = self.globals.tokrx_info.vocab_size as usize;
         let mut outputs = HashMap::default();
@@ -729,6 +1026,8 @@ impl Stepper {
             assert!(op.req_id.is_none() || op.clone_id.is_none());
 
             let id = op.id;
+            // rllm only schedules suspended sequences once they are resumed
+            self.watching.remove(&id);
             match self.maybe_fork(op) {
                 Ok(parent_id) => {
                     let lst = child_lists.entry(parent_id).or_insert_with(Vec::new);
@@ -757,6 +1056,13 @@ impl Stepper {
             "shm size too small"
         );
 
+        let mut written = HashSet::new();
+        for res in req.sub_gen_results {
+            written.insert(res.error_var.clone());
+            written.insert(res.result_var.clone());
+            self.deliver_sub_gen_result(res);
+        }
+
         let mut used_ids = Vec::new();
 
         let start_time = Instant::now();
@@ -796,15 +1102,24 @@ impl Stepper {
             }
         }
 
-        let deadline = Instant::now() + std::time::Duration::from_millis(self.limits.max_step_ms);
+        // each module has its own deadline, counted from here
+        let step_start = Instant::now();
         let mut max_offset = 0;
         let mut max_idx = 0;
+        let mut stop_seqs = Vec::new();
+        let mut spawns = Vec::new();
+        let mut suspended = Vec::new();
         let first_mask_byte_offset = self.shm.data_off();
         let mask_num_bytes = self.shm.elt_size();
 
         for id in used_ids {
             let prev_timeout = self.num_timeouts.remove(&id).unwrap_or(0);
             let h = self.get_worker(id).unwrap();
+            let fuel_limited = self.limits.fuel_metering() && h.limits.step_fuel > 0;
+            let max_step_ms = self
+                .limits
+                .wall_clock_ms(h.limits.step_fuel, h.limits.max_step_ms);
+            let deadline = step_start + std::time::Duration::from_millis(max_step_ms);
             let timeout = deadline.saturating_duration_since(Instant::now());
             match h.check_process(timeout) {
                 Ok(mut data) => {
@@ -862,25 +1177,72 @@ impl Stepper {
                             })
                             .collect();
                     }
+
+                    // only allow stopping sequences of the same request
+                    let req_id = self.get_worker(id).unwrap().req_id.clone();
+                    for target in std::mem::take(&mut data.stop_seqs) {
+                        match self.instances.get(&target) {
+                            Some(h) if h.req_id == req_id => stop_seqs.push(target),
+                            _ => data.logs.push_str(&format!(
+                                "\nstop_seqs: {target} is not a sequence of this request\n"
+                            )),
+                        }
+                    }
+
+                    let user = &self.get_worker(id).unwrap().user;
+                    spawns.extend(std::mem::take(&mut data.spawns).into_iter().map(|gen| {
+                        SpawnReq {
+                            parent_req_id: req_id.clone(),
+                            user: user.clone(),
+                            gen,
+                        }
+                    }));
+
+                    written.extend(data.storage.iter().map(|cmd| cmd.name().to_string()));
+                    if let Some(cmd) = data.watch.take() {
+                        match &data.result {
+                            Some(r) if is_noop(r) => {
+                                self.watching.insert(id, cmd);
+                                suspended.push(id);
+                            }
+                            Some(_) => data
+                                .logs
+                                .push_str("\nwatch ignored: mid_process() didn't return noop\n"),
+                            None => {}
+                        }
+                    }
+
                     outputs.insert(id, data);
                 }
                 Err(e) => {
-                    if e.to_string() == "timeout" && prev_timeout < self.limits.max_timeout_steps {
+                    // steps limited by fuel are not resumed after hitting the wall-clock backstop
+                    if e.to_string() == "timeout"
+                        && !fuel_limited
+                        && prev_timeout < self.limits.max_timeout_steps
+                    {
                         outputs.insert(
                             id,
                             SequenceResult {
                                 result: Some(ProcessResultOffset {
                                     branches: vec![Branch::noop()],
+                                    attention_masks: vec![],
                                 }),
                                 error: String::new(),
+                                script_error: None,
                                 storage: vec![],
+                                events: vec![],
                                 logs: format!(
                                     "⏲ timeout [deadline: {}ms; step {}/{}]\n",
-                                    self.limits.max_step_ms,
+                                    max_step_ms,
                                     prev_timeout + 1,
                                     self.limits.max_timeout_steps
                                 ),
                                 micros: start_time.elapsed().as_micros() as u64,
+                                fuel: 0,
+                                out_of_fuel: false,
+                                stop_seqs: vec![],
+                                spawns: vec![],
+                                watch: None,
                             },
                         );
                         self.num_timeouts.insert(id, prev_timeout + 1);
@@ -894,8 +1256,20 @@ impl Stepper {
         for id in req.freed {
             log::debug!("free module {}", id);
             self.instances.remove(&id);
+            self.watching.remove(&id);
         }
 
+        let resumed = self.wake_watchers(written);
+        suspended.retain(|id| !resumed.contains(id));
+
+        let num_masks = max_idx + 1;
+        let inline_masks = self.inline_masks.map(|encoding| {
+            let bytes = self
+                .shm
+                .bytes_at(first_mask_byte_offset, num_masks * mask_num_bytes);
+            InlineMasks::encode(bytes, encoding)
+        });
+
         self.shm.free(max_offset, |client_id| {
             let id = client_id as ModuleInstId;
             !self.num_timeouts.contains_key(&id)
@@ -907,12 +1281,46 @@ impl Stepper {
             seqs: outputs,
             mask_num_bytes,
             first_mask_byte_offset,
-            num_masks: max_idx + 1,
+            num_masks,
             dtype: bias_type.to_string(),
             mask_num_elts: bias_type.bytes_to_elts(mask_num_bytes),
+            inline_masks,
+            stop_seqs,
+            spawns,
+            suspended,
+            resumed,
         })
     }
 
+    // re-check sequences watching variables that were written; returns the ones to resume
+    fn wake_watchers(&mut self, mut written: HashSet<String>) -> Vec<ModuleInstId> {
+        written.extend(self.written_vars.lock().unwrap().drain());
+        let mut resumed = Vec::new();
+        if written.is_empty() {
+            return resumed;
+        }
+        for (id, cmd) in self.watching.iter() {
+            if !written.contains(cmd.name()) {
+                continue;
+            }
+            match self
+                .get_worker(*id)
+                .and_then(|h| h.storage_cmd(cmd.clone()))
+            {
+                Ok(StorageResp::Watching {}) => {}
+                Ok(_) => resumed.push(*id),
+                Err(e) => {
+                    log::warn!("watch {id}: {e}");
+                    resumed.push(*id)
+                }
+            }
+        }
+        for id in resumed.iter() {
+            self.watching.remove(id);
+        }
+        resumed
+    }
+
     fn worker_error<T>(
         &mut self,
         instid: usize,
@@ -923,6 +1331,7 @@ impl Stepper {
         log::warn!("error: {err}");
         map.insert(instid, SequenceResult::from_error(err));
         self.instances.remove(&instid);
+        self.watching.remove(&instid);
     }
 }
 
@@ -947,9 +1356,11 @@ impl Exec for ModuleRegistry {
     fn exec(&mut self, json: Value, auth: AuthInfo) -> Result<Value> {
         match json["op"].as_str() {
             Some("set_tags") => self.set_tags(serde_json::from_value(json)?, auth),
-            Some("get_tags") => self.get_tags(serde_json::from_value(json)?),
+            Some("get_tags") => self.get_tags(serde_json::from_value(json)?, auth),
             Some("mk_module") => self.mk_module(serde_json::from_value(json)?, auth),
-            Some("instantiate") => self.instantiate(serde_json::from_value(json)?),
+            Some("set_limits") => self.set_limits(serde_json::from_value(json)?, auth),
+            Some("module_info") => self.module_info(serde_json::from_value(json)?, auth),
+            Some("instantiate") => self.instantiate(serde_json::from_value(json)?, auth),
             _ => return Err(anyhow!("bad op")),
         }
     }
@@ -1034,12 +1445,23 @@ enum CmdRespChannel {
         resp_ch: Arc<Mutex<ServerChannel>>,
         busy_wait_duration: Duration,
     },
+    Socket {
+        cmd_ch: SockStream,
+        resp_ch: Arc<Mutex<SockStream>>,
+        max_size: usize,
+    },
 }
 
 impl CmdRespChannel {
-    pub fn new(suff: &str, cli: &Cli) -> Result<Self> {
+    pub fn new(suff: &str, cli: &Cli, sock: Option<SockStream>) -> Result<Self> {
         let busy_wait_duration = Duration::from_millis(cli.busy_wait_time);
-        if cli.futex {
+        if let Some(sock) = sock {
+            Ok(Self::Socket {
+                resp_ch: Arc::new(Mutex::new(sock.try_clone()?)),
+                cmd_ch: sock,
+                max_size: cli.json_size * MEGABYTE,
+            })
+        } else if cli.futex {
             let cmd_shm = Shm::new(
                 &cli.prefixed_name("cmd", suff),
                 cli.json_size * MEGABYTE,
@@ -1080,7 +1502,7 @@ impl CmdRespChannel {
                 cmd_ch.busy_reset();
                 resp_ch.lock().unwrap().busy_reset();
             }
-            Self::Futex { .. } => {}
+            Self::Futex { .. } | Self::Socket { .. } => {}
         }
     }
 
@@ -1093,6 +1515,15 @@ impl CmdRespChannel {
             Self::Futex { resp_ch, .. } => {
                 resp_ch.lock().unwrap().send_resp(&slice).unwrap();
             }
+            Self::Socket { resp_ch, .. } => Self::send_sock(resp_ch, &slice),
+        }
+    }
+
+    // the LLM may be gone; recv() notices that and stops the process,
+    // so just drop the response instead of panicking in the meantime
+    fn send_sock(resp_ch: &Mutex<SockStream>, msg: &[u8]) {
+        if let Err(e) = resp_ch.lock().unwrap().send_frame(msg) {
+            log::warn!("dropping response ({} bytes): {e}", msg.len());
         }
     }
 
@@ -1108,6 +1539,16 @@ impl CmdRespChannel {
                 busy_wait_duration,
                 ..
             } => cmd_ch.recv_req(busy_wait_duration.clone()),
+            Self::Socket {
+                cmd_ch, max_size, ..
+            } => match cmd_ch.recv_frame(*max_size) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // there is no way to re-connect, so take the workers down with us
+                    log::info!("LLM connection lost: {e}");
+                    worker::stop_process()
+                }
+            },
         }
     }
 
@@ -1185,6 +1626,29 @@ fn save_tokenizer(cli: &Cli) {
     }
 }
 
+fn check_grammar(cli: &Cli, trie: &TokTrie) {
+    let path = cli.check_grammar.as_ref().unwrap();
+    let yacc = match fs::read_to_string(path) {
+        Ok(yacc) => yacc,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    match aici_abi::cfg::analyze_yacc(&yacc, Some(trie)) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.has_errors() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>) {
     let name = cli.module.as_deref().unwrap();
     let mut reg = ModuleRegistry::new(wasm_ctx, shm).unwrap();
@@ -1194,7 +1658,12 @@ fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>)
             reg.resolve_gh_module(gh, Some(wasm_bytes)).unwrap()
         } else {
             let json = reg
-                .create_module(wasm_bytes, AuthInfo::admin_user())
+                .create_module(
+                    wasm_bytes,
+                    BTreeMap::new(),
+                    ModuleMeta::default(),
+                    AuthInfo::admin_user(),
+                )
                 .unwrap();
             json.module_id
         }
@@ -1219,12 +1688,15 @@ fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>)
             Some(ref path) => json!(fs::read_to_string(path).unwrap()),
             None => json!({"steps":[]}),
         };
-        reg.instantiate(InstantiateReq {
-            req_id: req_id.clone(),
-            prompt: json!(""),
-            module_id: module_id.clone(),
-            module_arg: arg,
-        })
+        reg.instantiate(
+            InstantiateReq {
+                req_id: req_id.clone(),
+                prompt: json!(""),
+                module_id: module_id.clone(),
+                module_arg: arg,
+            },
+            AuthInfo::local_user(),
+        )
         .unwrap();
         reg.run_main(&req_id).unwrap();
     }
@@ -1261,6 +1733,14 @@ fn main() -> () {
         logit_memory_bytes: cli.bin_size * MEGABYTE,
         busy_wait_duration: Duration::from_millis(cli.busy_wait_time),
         max_forks: cli.wasm_max_forks,
+        max_step_fuel: cli.wasm_max_step_fuel,
+        max_init_fuel: cli.wasm_max_init_fuel,
+        snapshot_cache_bytes: cli.snapshot_cache_size * MEGABYTE,
+
+        storage_dir: cli.storage_dir.clone(),
+        storage_max_bytes: cli.storage_max_size * 1024,
+        storage_ttl_secs: cli.storage_ttl,
+        storage_max_namespaces: cli.storage_max_namespaces,
 
         module_upload: !cli.restricted,
         gh_download: !cli.restricted,
@@ -1289,10 +1769,18 @@ fn main() -> () {
         return ();
     }
 
+    if cli.check_grammar.is_some() {
+        check_grammar(&cli, &wasm_ctx.globals.tok_trie);
+        return ();
+    }
+
     let bin_shm = Shm::new(
         &MessageChannel::shm_name(&cli.prefixed_name("bin", "")),
         limits.logit_memory_bytes,
-        if cli.module.is_none() {
+        if cli.listen.is_some() {
+            // nobody else maps it; the workers inherit it
+            shm::Unlink::Post
+        } else if cli.module.is_none() {
             shm::Unlink::None
         } else {
             shm::Unlink::Pre
@@ -1327,14 +1815,30 @@ fn main() -> () {
     // needs to be done after WorkerForker is spawned
     setup_bg_worker_pool();
 
-    let exec = Stepper::new(&reg, limits, shm_alloc, token_bytes).unwrap();
+    let inline_masks = if cli.listen.is_some() {
+        Some(cli.mask_encoding.parse().unwrap())
+    } else {
+        None
+    };
+
+    let (main_sock, side_sock) = match &cli.listen {
+        Some(addr) => {
+            let addr: SockAddr = addr.parse().unwrap();
+            let token = sockchannel::token_from_env().unwrap();
+            let (main, side) = sockchannel::accept_channels(&addr, &token).unwrap();
+            (Some(main), Some(side))
+        }
+        None => (None, None),
+    };
+
+    let exec = Stepper::new(&reg, limits, shm_alloc, token_bytes, inline_masks).unwrap();
     let cli2 = cli.clone();
     rayon::spawn(move || {
-        let reg_disp = CmdRespChannel::new("-side", &cli2).unwrap();
+        let reg_disp = CmdRespChannel::new("-side", &cli2, side_sock).unwrap();
         reg.dispatch_loop(reg_disp);
     });
 
-    let mut exec_disp = CmdRespChannel::new("", &cli).unwrap();
+    let mut exec_disp = CmdRespChannel::new("", &cli, main_sock).unwrap();
     exec_disp.dispatch_loop(exec);
 }
 
@@ -6,14 +6,14 @@ use crate::{
 };
 use aici_abi::{toktrie::TokTrie, InitPromptArg, InitPromptResult, ProcessResultOffset, TokenId};
 use aicirt::{
-    api::{InferenceCapabilities, SequenceResult},
+    api::{InferenceCapabilities, ModuleLimits, SequenceResult},
     bail_user,
     bintokens::ByteTokenizer,
     shm::ShmAllocator,
     user_error,
 };
 use anyhow::{anyhow, ensure, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, rc::Rc, sync::Arc, time::Instant};
 use wasmtime;
 
@@ -41,7 +41,8 @@ impl WasmContext {
         cfg.debug_info(false)
             .wasm_backtrace(true)
             .native_unwind_info(true)
-            .consume_fuel(false)
+            // fuel makes limits deterministic, at the cost of some speed
+            .consume_fuel(limits.fuel_metering())
             .max_wasm_stack(512 * 1024)
             .wasm_tail_call(false)
             .wasm_threads(false)
@@ -98,13 +99,36 @@ impl WasmContext {
     }
 }
 
+/// Contents of the linear memory after `aici_preinit()`.
+/// Globals (like the stack pointer) are not saved, since they are reset between calls.
+#[derive(Serialize, Deserialize)]
+pub struct MemorySnapshot {
+    size: usize,
+    /// (offset, data) for non-zero chunks
+    chunks: Vec<(usize, Vec<u8>)>,
+}
+
+impl MemorySnapshot {
+    /// Size of the non-zero data.
+    pub fn num_bytes(&self) -> usize {
+        self.chunks.iter().map(|(_, c)| c.len()).sum()
+    }
+}
+
+const SNAPSHOT_CHUNK: usize = 64 * 1024;
+
 pub struct ModuleInstance {
     store: wasmtime::Store<ModuleData>,
     memory: wasmtime::Memory,
     instance: wasmtime::Instance,
     handle: WasmAici,
+    initialized: bool,
     #[allow(dead_code)]
     limits: AiciLimits,
+    module_limits: ModuleLimits,
+    // since last seq_result()
+    fuel_used: u64,
+    out_of_fuel: bool,
 }
 type WasmPtr = u32;
 type WasmAici = u32;
@@ -121,14 +145,36 @@ impl ModuleInstance {
         let f = self
             .instance
             .get_typed_func::<Params, Results>(&mut self.store, name)?;
+        let budget = if self.limits.fuel_metering() {
+            let budget = match name {
+                "aici_mid_process" => self.module_limits.step_fuel,
+                _ => self.module_limits.init_fuel,
+            };
+            let budget = if budget == 0 { u64::MAX } else { budget };
+            self.store.set_fuel(budget)?;
+            Some(budget)
+        } else {
+            None
+        };
         let r = f.call(&mut self.store, params);
+        if let Some(budget) = budget {
+            let left = self.store.get_fuel().unwrap_or(0);
+            self.fuel_used += budget - left;
+        }
         let ctx = self.store.data_mut();
         ctx.flush_logs(name);
         match r {
             Ok(r) => Ok(r),
             Err(e) => {
                 ctx.had_error = true;
-                if let Some(e) = e.downcast_ref::<UserError>() {
+                if let Some(wasmtime::Trap::OutOfFuel) = e.downcast_ref::<wasmtime::Trap>() {
+                    self.out_of_fuel = true;
+                    Err(user_error!(
+                        "{}\nout of fuel in {name}() [budget: {}]",
+                        ctx.string_log(),
+                        budget.unwrap_or(0)
+                    ))
+                } else if let Some(e) = e.downcast_ref::<UserError>() {
                     Err(user_error!("{}\n{}", ctx.string_log(), e))
                 } else if let Some(bt) = e.downcast_ref::<wasmtime::WasmBacktrace>() {
                     Err(user_error!(
@@ -188,6 +234,9 @@ impl ModuleInstance {
         module_arg: String,
         group_channel: GroupHandle,
         shm: Rc<ShmAllocator>,
+        snapshot: Option<&MemorySnapshot>,
+        module_limits: ModuleLimits,
+        data_dir: Option<PathBuf>,
     ) -> Result<Self> {
         let engine = module.engine();
 
@@ -196,12 +245,14 @@ impl ModuleInstance {
             ModuleData::new(
                 id,
                 &ctx.limits,
+                &module_limits,
                 &module,
                 module_arg,
                 &ctx.linker,
                 ctx.globals,
                 group_channel,
                 shm,
+                data_dir,
             ),
         );
         store.limiter(|state| &mut state.store_limits);
@@ -213,13 +264,78 @@ impl ModuleInstance {
         store.data_mut().instance = Some(instance);
         store.data_mut().memory = Some(memory);
 
-        Ok(ModuleInstance {
+        let mut r = ModuleInstance {
             handle: 0,
             store,
             memory,
             instance,
+            initialized: false,
             limits: ctx.limits,
-        })
+            module_limits,
+            fuel_used: 0,
+            out_of_fuel: false,
+        };
+        if let Some(snapshot) = snapshot {
+            r.restore_snapshot(snapshot)?;
+        }
+        Ok(r)
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &MemorySnapshot) -> Result<()> {
+        let curr = self.memory.data_size(&self.store);
+        ensure!(
+            snapshot.size >= curr,
+            "snapshot smaller than initial memory"
+        );
+        if snapshot.size > curr {
+            let pages = (snapshot.size - curr) / (64 * 1024);
+            self.memory.grow(&mut self.store, pages as u64)?;
+        }
+        let data = self.memory.data_mut(&mut self.store);
+        // data segments may have been overwritten with zeros, which are not in the snapshot
+        data.fill(0);
+        for (off, chunk) in &snapshot.chunks {
+            data[*off..*off + chunk.len()].copy_from_slice(chunk);
+        }
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn take_snapshot(&self) -> MemorySnapshot {
+        let data = self.memory.data(&self.store);
+        let chunks = data
+            .chunks(SNAPSHOT_CHUNK)
+            .enumerate()
+            .filter(|(_, c)| c.iter().any(|&b| b != 0))
+            .map(|(idx, c)| (idx * SNAPSHOT_CHUNK, c.to_vec()))
+            .collect();
+        MemorySnapshot {
+            size: data.len(),
+            chunks,
+        }
+    }
+
+    /// Run `aici_init()` and `aici_preinit()`, and return the resulting memory.
+    /// Returns `None` when the module doesn't export `aici_preinit()`.
+    pub fn preinit(&mut self) -> Result<Option<MemorySnapshot>> {
+        if self
+            .instance
+            .get_export(&mut self.store, "aici_preinit")
+            .is_none()
+        {
+            return Ok(None);
+        }
+        self.run_init()?;
+        self.call_func::<(), ()>("aici_preinit", ())?;
+        Ok(Some(self.take_snapshot()))
+    }
+
+    /// Whether the module asks for a snapshot on the first use of an argument,
+    /// see `aici_expose_preinit!`.
+    pub fn preinit_on_first_use(&mut self) -> bool {
+        self.instance
+            .get_export(&mut self.store, "aici_preinit_first_use")
+            .is_some()
     }
 
     pub fn set_id(&mut self, id: ModuleInstId) {
@@ -227,7 +343,10 @@ impl ModuleInstance {
     }
 
     fn run_init(&mut self) -> Result<()> {
-        self.call_func::<(), ()>("aici_init", ())?;
+        if !self.initialized {
+            self.call_func::<(), ()>("aici_init", ())?;
+            self.initialized = true;
+        }
         Ok(())
     }
 
@@ -265,6 +384,12 @@ impl ModuleInstance {
         self.store.data_mut().set_mid_process_data(op);
         self.call_func::<WasmAici, ()>("aici_mid_process", self.handle)?;
         let res: ProcessResultOffset = self.proc_result()?;
+        ensure!(
+            res.attention_masks.len() == 0 || res.attention_masks.len() == res.branches.len(),
+            "attention_masks: expected {} masks, got {}",
+            res.branches.len(),
+            res.attention_masks.len()
+        );
         let offs = &self.store.data().logit_offsets;
         let res = ProcessResultOffset {
             branches: res
@@ -280,6 +405,7 @@ impl ModuleInstance {
                     })
                 })
                 .collect(),
+            attention_masks: res.attention_masks,
         };
         Ok(res)
     }
@@ -289,12 +415,26 @@ impl ModuleInstance {
         let micros = (t0.elapsed().as_micros() as u64 / 10) * 10;
         let logs = self.store.data_mut().string_log();
         let storage = std::mem::take(&mut self.store.data_mut().storage_log);
+        let script_error = std::mem::take(&mut self.store.data_mut().script_error);
+        let events = self.store.data_mut().take_events();
+        let fuel = std::mem::take(&mut self.fuel_used);
+        let out_of_fuel = std::mem::take(&mut self.out_of_fuel);
+        let stop_seqs = std::mem::take(&mut self.store.data_mut().stop_seqs);
+        let spawns = std::mem::take(&mut self.store.data_mut().spawns);
+        let watch = std::mem::take(&mut self.store.data_mut().watch);
         match res {
             Ok(r) => SequenceResult {
                 error: String::new(),
+                script_error: None,
                 logs,
                 storage,
+                events,
                 micros,
+                fuel,
+                out_of_fuel,
+                stop_seqs,
+                spawns,
+                watch,
                 result: Some(r),
             },
 
@@ -304,9 +444,16 @@ impl ModuleInstance {
                 log::warn!("exec: {error}");
                 SequenceResult {
                     error,
+                    script_error,
                     logs,
                     storage,
+                    events,
                     micros,
+                    fuel,
+                    out_of_fuel,
+                    stop_seqs,
+                    spawns,
+                    watch: None,
                     result: None,
                 }
             }
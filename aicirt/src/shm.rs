@@ -248,6 +248,12 @@ impl ShmAllocator {
         self.shm.slice_at_byte_offset(off, num_elts)
     }
 
+    /// Raw bytes of possibly several consecutive elements.
+    pub fn bytes_at(&self, off: usize, len: usize) -> &'static [u8] {
+        assert!(off >= self.data_off());
+        self.shm.slice_at_byte_offset(off, len)
+    }
+
     pub fn alloc(&self, client_id: u32) -> Result<usize> {
         assert!(client_id != Self::FREE);
         let table = self.alloc_table();
@@ -0,0 +1,365 @@
+// Socket transport between the LLM and aicirt, for when they don't share a host.
+// It carries the same JSON messages as the shared memory channels, each prefixed with
+// its length as u32 little endian.
+// The mask (logit bias) region can't be shared either, so it's sent inline in
+// the mid_process response (see InlineMasks).
+// Each connection starts with the channel name and the shared token (see TOKEN_ENV).
+
+use crate::api::{InlineMasks, MaskEncoding};
+use anyhow::{anyhow, ensure, Result};
+use base64::Engine as _;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+pub const MAIN_CHANNEL: &str = "main";
+pub const SIDE_CHANNEL: &str = "side";
+
+/// Environment variable with the token both sides need to know;
+/// it's not passed on the command line, where other users could see it.
+pub const TOKEN_ENV: &str = "AICIRT_TOKEN";
+const MIN_TOKEN_LEN: usize = 16;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn token_from_env() -> Result<String> {
+    let token = std::env::var(TOKEN_ENV).unwrap_or_default();
+    ensure!(
+        token.len() >= MIN_TOKEN_LEN,
+        "{TOKEN_ENV} needs to be set to a secret of at least {MIN_TOKEN_LEN} characters"
+    );
+    Ok(token)
+}
+
+// don't leak the length of the matching prefix through timing
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Clone)]
+pub enum SockAddr {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for SockAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(SockAddr::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp:") {
+            // without a host, only listen on (and connect to) the loopback interface
+            let port = addr.strip_prefix(':').unwrap_or(addr);
+            if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) {
+                Ok(SockAddr::Tcp(format!("127.0.0.1:{port}")))
+            } else {
+                Ok(SockAddr::Tcp(addr.to_string()))
+            }
+        } else {
+            Err(anyhow!(
+                "invalid socket address {s:?}; expecting unix:PATH, tcp:PORT or tcp:HOST:PORT"
+            ))
+        }
+    }
+}
+
+pub enum SockStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl SockStream {
+    pub fn connect(addr: &SockAddr) -> Result<Self> {
+        Ok(match addr {
+            SockAddr::Unix(path) => SockStream::Unix(UnixStream::connect(path)?),
+            SockAddr::Tcp(addr) => {
+                let s = TcpStream::connect(addr)?;
+                s.set_nodelay(true)?;
+                SockStream::Tcp(s)
+            }
+        })
+    }
+
+    /// Connect, name the channel (MAIN_CHANNEL or SIDE_CHANNEL) this connection is for,
+    /// and authenticate with the token.
+    pub fn connect_channel(addr: &SockAddr, channel: &str, token: &str) -> Result<Self> {
+        let mut s = Self::connect(addr)?;
+        s.send_frame(channel.as_bytes())?;
+        s.send_frame(token.as_bytes())?;
+        Ok(s)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            SockStream::Unix(s) => s.set_read_timeout(timeout)?,
+            SockStream::Tcp(s) => s.set_read_timeout(timeout)?,
+        }
+        Ok(())
+    }
+
+    // returns the channel name, if the token matches
+    fn handshake(&mut self, token: &str) -> Result<Vec<u8>> {
+        // so that a silent client doesn't block other connections
+        self.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let name = self.recv_frame(100)?;
+        let client_token = self.recv_frame(1000)?;
+        ensure!(token_eq(&client_token, token.as_bytes()), "invalid token");
+        self.set_read_timeout(None)?;
+        Ok(name)
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            SockStream::Unix(s) => SockStream::Unix(s.try_clone()?),
+            SockStream::Tcp(s) => SockStream::Tcp(s.try_clone()?),
+        })
+    }
+
+    pub fn send_frame(&mut self, msg: &[u8]) -> Result<()> {
+        let len: u32 = msg.len().try_into()?;
+        // single write, so that we don't trigger Nagle on the length prefix
+        let mut buf = Vec::with_capacity(msg.len() + 4);
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(msg);
+        self.write_all(&buf)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn recv_frame(&mut self, max_size: usize) -> Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        ensure!(len <= max_size, "frame too large; {} > {}", len, max_size);
+        let mut msg = vec![0u8; len];
+        self.read_exact(&mut msg)?;
+        Ok(msg)
+    }
+}
+
+impl Read for SockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            SockStream::Unix(s) => s.read(buf),
+            SockStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for SockStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SockStream::Unix(s) => s.write(buf),
+            SockStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SockStream::Unix(s) => s.flush(),
+            SockStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+/// Listen on addr and wait for the LLM to connect both the main and the side channel.
+/// Connections without the right token are dropped.
+/// Returns (main, side).
+pub fn accept_channels(addr: &SockAddr, token: &str) -> Result<(SockStream, SockStream)> {
+    let listener = match addr {
+        SockAddr::Unix(path) => {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            Listener::Unix(UnixListener::bind(path)?)
+        }
+        SockAddr::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+    };
+    log::info!("listening on {:?}", addr);
+
+    let mut main = None;
+    let mut side = None;
+    while main.is_none() || side.is_none() {
+        let mut s = match &listener {
+            Listener::Unix(l) => SockStream::Unix(l.accept()?.0),
+            Listener::Tcp(l) => {
+                let (s, peer) = l.accept()?;
+                log::info!("connection from {peer}");
+                s.set_nodelay(true)?;
+                SockStream::Tcp(s)
+            }
+        };
+        match s.handshake(token) {
+            Ok(name) if name == MAIN_CHANNEL.as_bytes() => main = Some(s),
+            Ok(name) if name == SIDE_CHANNEL.as_bytes() => side = Some(s),
+            Ok(name) => log::warn!("unknown channel: {}", String::from_utf8_lossy(&name)),
+            Err(e) => log::warn!("rejected connection: {e}"),
+        }
+    }
+
+    Ok((main.unwrap(), side.unwrap()))
+}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl FromStr for MaskEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(MaskEncoding::Raw),
+            "rle" => Ok(MaskEncoding::Rle),
+            _ => Err(anyhow!("invalid mask encoding {s:?}; expecting raw or rle")),
+        }
+    }
+}
+
+impl InlineMasks {
+    pub fn encode(bytes: &[u8], encoding: MaskEncoding) -> Self {
+        let data = match encoding {
+            MaskEncoding::Raw => bytes.to_vec(),
+            MaskEncoding::Rle => {
+                // masks are mostly long runs of allowed or disallowed tokens;
+                // store (run_length, word) pairs of u32s
+                let mut out = Vec::new();
+                let mut words = bytes.chunks(4).map(|c| {
+                    let mut w = [0u8; 4];
+                    w[0..c.len()].copy_from_slice(c);
+                    w
+                });
+                let mut curr = words.next();
+                while let Some(w) = curr {
+                    let mut count = 1u32;
+                    loop {
+                        curr = words.next();
+                        if curr != Some(w) || count == u32::MAX {
+                            break;
+                        }
+                        count += 1;
+                    }
+                    out.extend_from_slice(&count.to_le_bytes());
+                    out.extend_from_slice(&w);
+                }
+                out
+            }
+        };
+        InlineMasks {
+            encoding,
+            num_bytes: bytes.len(),
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+        }
+    }
+
+    pub fn decode(&self) -> Result<Vec<u8>> {
+        let data = base64::engine::general_purpose::STANDARD.decode(&self.data)?;
+        let mut bytes = match self.encoding {
+            MaskEncoding::Raw => data,
+            MaskEncoding::Rle => {
+                ensure!(data.len() % 8 == 0, "invalid RLE mask data");
+                let mut out = Vec::with_capacity(self.num_bytes + 4);
+                for pair in data.chunks_exact(8) {
+                    let count = u32::from_le_bytes(pair[0..4].try_into().unwrap()) as usize;
+                    ensure!(
+                        out.len() + count * 4 <= self.num_bytes + 3,
+                        "RLE mask data too long"
+                    );
+                    for _ in 0..count {
+                        out.extend_from_slice(&pair[4..8]);
+                    }
+                }
+                out
+            }
+        };
+        ensure!(
+            bytes.len() >= self.num_bytes,
+            "mask data too short; {} < {}",
+            bytes.len(),
+            self.num_bytes
+        );
+        bytes.truncate(self.num_bytes);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_bytes(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn roundtrip(bytes: &[u8]) {
+        for encoding in [MaskEncoding::Raw, MaskEncoding::Rle] {
+            let m = InlineMasks::encode(bytes, encoding);
+            assert_eq!(m.num_bytes, bytes.len());
+            assert_eq!(m.decode().unwrap(), bytes, "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn mask_roundtrip() {
+        roundtrip(&[]);
+        roundtrip(&mask_bytes(&[0x1234]));
+        roundtrip(&mask_bytes(&[u32::MAX, u32::MAX, 0, 0, 0, 7, u32::MAX]));
+        // two masks (rows) for a vocab of 1000 tokens, not a multiple of 32;
+        // the last word of each row is partially used
+        let mut words = vec![u32::MAX; 32];
+        words[31] = 0xff;
+        words.extend(vec![0; 31]);
+        words.push(0x80);
+        roundtrip(&mask_bytes(&words));
+        // not a multiple of 4 bytes
+        roundtrip(&[1, 2, 3, 4, 5, 6]);
+        roundtrip(&[0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rle_is_compact() {
+        let bytes = mask_bytes(&[vec![u32::MAX; 1000], vec![0; 1000]].concat());
+        let m = InlineMasks::encode(&bytes, MaskEncoding::Rle);
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(&m.data)
+            .unwrap();
+        assert_eq!(data, mask_bytes(&[1000, u32::MAX, 1000, 0]));
+    }
+
+    #[test]
+    fn rle_invalid() {
+        let mk = |words: &[u32], num_bytes| InlineMasks {
+            encoding: MaskEncoding::Rle,
+            num_bytes,
+            data: base64::engine::general_purpose::STANDARD.encode(mask_bytes(words)),
+        };
+        assert_eq!(mk(&[2, 5], 8).decode().unwrap(), mask_bytes(&[5, 5]));
+        // runs past num_bytes
+        assert!(mk(&[u32::MAX, 5], 8).decode().is_err());
+        // too short
+        assert!(mk(&[1, 5], 8).decode().is_err());
+        // not whole pairs
+        assert!(mk(&[1], 4).decode().is_err());
+    }
+
+    #[test]
+    fn parse_addr() {
+        let tcp = |s: &str| match s.parse::<SockAddr>().unwrap() {
+            SockAddr::Tcp(a) => a,
+            SockAddr::Unix(_) => panic!(),
+        };
+        assert_eq!(tcp("tcp:8080"), "127.0.0.1:8080");
+        assert_eq!(tcp("tcp::8080"), "127.0.0.1:8080");
+        assert_eq!(tcp("tcp:0.0.0.0:8080"), "0.0.0.0:8080");
+        assert!("foo:8080".parse::<SockAddr>().is_err());
+        assert!(token_eq(b"secret", b"secret"));
+        assert!(!token_eq(b"secret", b"secreT"));
+        assert!(!token_eq(b"secret", b"secret2"));
+    }
+}
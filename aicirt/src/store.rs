@@ -0,0 +1,511 @@
+// Storage for variables that outlive a single request (StorageScope::User and ::Global).
+// Request-scoped variables live in the comms process of each request (see GroupCtx),
+// while these live in the main aicirt process.
+// Each comms process gets its own IPC channel, served by a thread of the main process,
+// so that a killed worker can't block or confuse the other ones.
+
+use crate::hostimpl::AiciLimits;
+use aici_abi::{StorageCmd, StorageResp, StorageScope};
+use aicirt::{
+    futexshm::{TypedClient, TypedClientHandle, TypedServer},
+    shm::{Shm, Unlink},
+    variables::Variables,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const MAX_NAMESPACE_LEN: usize = 100;
+// how long the client waits for the store; the store only does short file operations
+const STORE_TIMEOUT: Duration = Duration::from_secs(10);
+// how often the serving thread checks if its channel was closed
+const CLOSED_CHECK: Duration = Duration::from_secs(1);
+// with storage_dir, namespaces not used for this long are dropped from memory
+const IDLE_SECS: u64 = 600;
+const MAX_LOADED_NAMESPACES: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreReq {
+    /// Echoed in StoreResp, so that a late response to a timed-out request is skipped.
+    pub id: u64,
+    pub user: String,
+    pub cmd: StorageCmd,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreResp {
+    pub id: u64,
+    pub resp: StorageResp,
+}
+
+pub type StoreClientHandle = TypedClientHandle<StoreReq, StoreResp>;
+
+pub struct StoreClient {
+    client: TypedClient<StoreReq, StoreResp>,
+    busy_wait: Duration,
+    next_id: u64,
+}
+
+impl StoreClient {
+    pub fn new(handle: StoreClientHandle, busy_wait: Duration) -> Self {
+        StoreClient {
+            client: handle.to_client(),
+            busy_wait,
+            next_id: 1,
+        }
+    }
+
+    pub fn send(&mut self, user: &str, cmd: StorageCmd) -> StorageResp {
+        let id = self.next_id;
+        self.next_id += 1;
+        let req = StoreReq {
+            id,
+            user: user.to_string(),
+            cmd,
+        };
+        if let Err(e) = self.client.send_req(req) {
+            return StorageResp::Error {
+                message: e.to_string(),
+            };
+        }
+        let deadline = Instant::now() + STORE_TIMEOUT;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            // this only spins for busy_wait, and then sleeps on the futex
+            match self.client.recv_resp2(self.busy_wait, deadline - now) {
+                Some(r) if r.id == id => return r.resp,
+                Some(r) => log::warn!("store: skipping stale response {}", r.id),
+                None => {}
+            }
+        }
+        StorageResp::Error {
+            message: "no response from store".to_string(),
+        }
+    }
+}
+
+fn unique_name(prefix: &str) -> String {
+    let name = format!(
+        "/{prefix}-{}",
+        uuid::Uuid::new_v4().to_string().replace('-', "")
+    );
+    // 31 is max length for shm name on macos
+    name[0..31].to_string()
+}
+
+/// Create a channel for one comms process, and a thread serving it.
+/// The thread exits (and the channel is removed) once `closed` is set.
+pub fn store_channel(
+    store: &Arc<Mutex<Store>>,
+    limits: &AiciLimits,
+    closed: Arc<AtomicBool>,
+) -> Result<StoreClientHandle> {
+    let shm_name = unique_name("aici-st");
+    let mut server: TypedServer<StoreReq, StoreResp> =
+        TypedServer::new(Shm::new(&shm_name, limits.ipc_shm_bytes, Unlink::Pre)?);
+    let handle = StoreClientHandle::new(shm_name.clone(), limits.ipc_shm_bytes);
+    let store = store.clone();
+    let busy_wait = limits.busy_wait_duration;
+    std::thread::Builder::new()
+        .name("aicirt-store".to_string())
+        .spawn(move || {
+            while !closed.load(Ordering::Relaxed) {
+                let req = match server.recv_req_timeout(busy_wait, CLOSED_CHECK) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                let resp = store.lock().unwrap().handle_req(&req.user, req.cmd);
+                server.send_resp(StoreResp { id: req.id, resp });
+            }
+            // normally already unlinked by the client, unless it never connected
+            let c_name = std::ffi::CString::new(shm_name).unwrap();
+            unsafe { libc::shm_unlink(c_name.as_ptr()) };
+        })?;
+    Ok(handle)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Keys are "user/<user>/<namespace>" or "global/<namespace>"; the parts are escaped,
+// so that e.g. user "a/b" can't get at namespace "b/ns" of user "a".
+fn escape_part(s: &str) -> String {
+    s.replace('%', "%25").replace('/', "%2F")
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedVar {
+    name: String,
+    version: u64,
+    #[serde(with = "hex_bytes")]
+    value: Vec<u8>,
+    written: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedNamespace {
+    key: String,
+    /// "user/<user>" or "global"; missing in files written by older versions
+    #[serde(default)]
+    owner: String,
+    variables: Vec<PersistedVar>,
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(v))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        hex::decode(s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Default)]
+struct Namespace {
+    variables: Variables,
+    // unix time of last write, for TTL expiry
+    written: HashMap<String, u64>,
+    // unix time of last command, for dropping idle namespaces from memory
+    last_used: u64,
+}
+
+impl Namespace {
+    fn size(&self) -> usize {
+        self.variables
+            .variables
+            .iter()
+            .map(|(k, (_, v))| k.len() + v.len())
+            .sum()
+    }
+
+    fn expire(&mut self, ttl_secs: u64) -> bool {
+        if ttl_secs == 0 {
+            return false;
+        }
+        let deadline = now_secs().saturating_sub(ttl_secs);
+        let expired = self
+            .written
+            .iter()
+            .filter(|(_, t)| **t < deadline)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        for k in &expired {
+            self.written.remove(k);
+            self.variables.variables.remove(k);
+        }
+        expired.len() > 0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.variables.variables.is_empty()
+    }
+
+    fn to_persisted(&self, owner: &str, key: &str) -> PersistedNamespace {
+        PersistedNamespace {
+            key: key.to_string(),
+            owner: owner.to_string(),
+            variables: self
+                .variables
+                .variables
+                .iter()
+                .map(|(name, (version, value))| PersistedVar {
+                    name: name.clone(),
+                    version: *version,
+                    value: value.clone(),
+                    written: self.written.get(name).copied().unwrap_or(0),
+                })
+                .collect(),
+        }
+    }
+
+    fn from_persisted(p: PersistedNamespace) -> Self {
+        let mut ns = Namespace::default();
+        for v in p.variables {
+            ns.written.insert(v.name.clone(), v.written);
+            ns.variables.variables.insert(v.name, (v.version, v.value));
+        }
+        ns
+    }
+}
+
+pub struct Store {
+    // loaded namespaces; with `dir` these are only a cache
+    namespaces: HashMap<String, Namespace>,
+    // keys of non-empty namespaces (loaded or not) of each owner
+    owned: HashMap<String, HashSet<String>>,
+    dir: Option<PathBuf>,
+    max_bytes: usize,
+    max_namespaces: usize,
+    ttl_secs: u64,
+    last_evict: u64,
+}
+
+impl Store {
+    pub fn new(limits: &AiciLimits) -> Result<Self> {
+        let mut owned: HashMap<String, HashSet<String>> = HashMap::new();
+        if let Some(dir) = &limits.storage_dir {
+            std::fs::create_dir_all(dir)?;
+            for ent in std::fs::read_dir(dir)? {
+                let path = ent?.path();
+                if path.extension().map_or(true, |e| e != "json") {
+                    continue;
+                }
+                let p: PersistedNamespace = match std::fs::read(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|b| Ok(serde_json::from_slice(&b)?))
+                {
+                    Ok(p) => p,
+                    Err(e) => {
+                        log::warn!("store: {}: {e}", path.display());
+                        continue;
+                    }
+                };
+                let owner = if p.owner.is_empty() {
+                    Self::owner_of(&p.key)
+                } else {
+                    p.owner
+                };
+                owned.entry(owner).or_default().insert(p.key);
+            }
+        }
+        Ok(Store {
+            namespaces: HashMap::new(),
+            owned,
+            dir: limits.storage_dir.clone(),
+            max_bytes: limits.storage_max_bytes,
+            max_namespaces: limits.storage_max_namespaces,
+            ttl_secs: limits.storage_ttl_secs,
+            last_evict: 0,
+        })
+    }
+
+    // for files without the owner field
+    fn owner_of(key: &str) -> String {
+        match key.strip_prefix("user/").and_then(|k| k.split_once('/')) {
+            Some((user, _)) => format!("user/{user}"),
+            None => "global".to_string(),
+        }
+    }
+
+    /// Returns the owner ("user/<user>" or "global") and the key of the namespace.
+    fn namespace_key(user: &str, scope: &StorageScope) -> Result<(String, String)> {
+        let (prefix, namespace) = match scope {
+            StorageScope::Request => return Err(anyhow!("request scope is not handled by store")),
+            StorageScope::User { namespace } => (format!("user/{}", escape_part(user)), namespace),
+            StorageScope::Global { namespace } => ("global".to_string(), namespace),
+        };
+        if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+            return Err(anyhow!(
+                "namespace has to be between 1 and {MAX_NAMESPACE_LEN} bytes long"
+            ));
+        }
+        let key = format!("{prefix}/{}", escape_part(namespace));
+        Ok((prefix, key))
+    }
+
+    /// Drop idle namespaces from memory; they are reloaded from `dir` when needed.
+    /// Without `dir` memory is the only copy, so it's bounded by the limits instead.
+    fn evict_idle(&mut self) {
+        if self.dir.is_none() {
+            return;
+        }
+        let now = now_secs();
+        if self.namespaces.len() <= MAX_LOADED_NAMESPACES && now < self.last_evict + 60 {
+            return;
+        }
+        self.last_evict = now;
+        let deadline = now.saturating_sub(IDLE_SECS);
+        self.namespaces.retain(|_, ns| ns.last_used >= deadline);
+        if self.namespaces.len() > MAX_LOADED_NAMESPACES {
+            // keep the most recently used half
+            let mut used = self
+                .namespaces
+                .values()
+                .map(|ns| ns.last_used)
+                .collect::<Vec<_>>();
+            used.sort();
+            let cutoff = used[used.len() - MAX_LOADED_NAMESPACES / 2];
+            self.namespaces.retain(|_, ns| ns.last_used >= cutoff);
+        }
+    }
+
+    fn file_path(dir: &Path, key: &str) -> PathBuf {
+        let hash = hex::encode(Sha256::digest(key.as_bytes()));
+        dir.join(format!("{}.json", &hash[0..32]))
+    }
+
+    fn load(&self, key: &str) -> Result<Namespace> {
+        let path = match &self.dir {
+            Some(dir) => Self::file_path(dir, key),
+            None => return Ok(Namespace::default()),
+        };
+        if !path.exists() {
+            return Ok(Namespace::default());
+        }
+        let p: PersistedNamespace = serde_json::from_slice(&std::fs::read(&path)?)?;
+        if p.key != key {
+            return Err(anyhow!("{}: hash collision ({})", path.display(), p.key));
+        }
+        Ok(Namespace::from_persisted(p))
+    }
+
+    fn save(&self, owner: &str, key: &str) -> Result<()> {
+        let path = match &self.dir {
+            Some(dir) => Self::file_path(dir, key),
+            None => return Ok(()),
+        };
+        let ns = self.namespaces.get(key).unwrap();
+        if ns.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        // write to a temp file first, so that a crash never leaves a partial file
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&ns.to_persisted(owner, key))?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn process_cmd(&mut self, user: &str, cmd: StorageCmd) -> Result<StorageResp> {
+        let (owner, key) = Self::namespace_key(user, cmd.scope())?;
+        self.evict_idle();
+
+        let is_new = !self.owned.get(&owner).map_or(false, |s| s.contains(&key));
+        if is_new && cmd.is_write() && self.max_namespaces > 0 {
+            let num_owned = self.owned.get(&owner).map_or(0, |s| s.len());
+            if num_owned >= self.max_namespaces {
+                return Ok(StorageResp::Error {
+                    message: format!(
+                        "limit of {} {} namespaces exceeded",
+                        self.max_namespaces,
+                        if owner == "global" { "global" } else { "user" }
+                    ),
+                });
+            }
+        }
+
+        if !self.namespaces.contains_key(&key) {
+            let ns = self.load(&key)?;
+            self.namespaces.insert(key.clone(), ns);
+        }
+
+        let ns = self.namespaces.get_mut(&key).unwrap();
+        ns.last_used = now_secs();
+        let mut modified = ns.expire(self.ttl_secs);
+
+        let name = cmd.name().to_string();
+        let is_write = cmd.is_write();
+        let prev = ns.variables.variables.get(&name).cloned();
+        let mut resp = ns.variables.process_cmd(cmd);
+
+        if is_write {
+            if let StorageResp::DeleteVar {} = resp {
+                ns.written.remove(&name);
+                modified = true;
+            }
+            if matches!(
+                resp,
+                StorageResp::WriteVar { .. } | StorageResp::IncrementVar { .. }
+            ) {
+                if self.max_bytes > 0 && ns.size() > self.max_bytes {
+                    match prev {
+                        Some(v) => ns.variables.variables.insert(name, v),
+                        None => ns.variables.variables.remove(&name),
+                    };
+                    resp = StorageResp::Error {
+                        message: format!(
+                            "namespace size limit of {} bytes exceeded",
+                            self.max_bytes
+                        ),
+                    };
+                } else {
+                    ns.written.insert(name, now_secs());
+                    modified = true;
+                }
+            }
+        }
+
+        let is_empty = ns.is_empty();
+        if modified {
+            self.save(&owner, &key)?;
+        }
+
+        // empty namespaces don't count towards the limit, and aren't kept in memory
+        if is_empty {
+            self.namespaces.remove(&key);
+            if let Some(keys) = self.owned.get_mut(&owner) {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    self.owned.remove(&owner);
+                }
+            }
+        } else if is_new {
+            self.owned.entry(owner).or_default().insert(key);
+        }
+
+        Ok(resp)
+    }
+
+    fn handle_req(&mut self, user: &str, cmd: StorageCmd) -> StorageResp {
+        match self.process_cmd(user, cmd) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("store: {e}");
+                StorageResp::Error {
+                    message: e.to_string(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(user: &str, namespace: &str) -> String {
+        let scope = StorageScope::User {
+            namespace: namespace.to_string(),
+        };
+        let (owner, key) = Store::namespace_key(user, &scope).unwrap();
+        assert_eq!(Store::owner_of(&key), owner);
+        key
+    }
+
+    #[test]
+    fn namespace_keys() {
+        assert_eq!(key("a", "ns"), "user/a/ns");
+        assert_ne!(key("a/b", "ns"), key("a", "b/ns"));
+        assert_ne!(key("a%2Fb", "ns"), key("a/b", "ns"));
+        assert_ne!(key("a", "b%2Fns"), key("a", "b/ns"));
+
+        let scope = StorageScope::Global {
+            namespace: "x/y".to_string(),
+        };
+        let (owner, key) = Store::namespace_key("a", &scope).unwrap();
+        assert_eq!((owner.as_str(), key.as_str()), ("global", "global/x%2Fy"));
+        assert_eq!(Store::owner_of(&key), "global");
+    }
+}
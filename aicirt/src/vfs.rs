@@ -0,0 +1,313 @@
+// Read-only virtual filesystem behind the WASI calls of controllers.
+// The data files uploaded with the module (see MkModuleReq::data) are preopened as /data;
+// other than that there is only stdout/stderr.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    os::unix::fs::FileExt,
+    path::{Component, Path, PathBuf},
+};
+
+pub type Errno = i32;
+
+// see https://github.com/WebAssembly/WASI/blob/main/legacy/preview1/docs.md
+pub const ERRNO_BADF: Errno = 8;
+pub const ERRNO_FAULT: Errno = 21;
+pub const ERRNO_INVAL: Errno = 28;
+pub const ERRNO_IO: Errno = 29;
+pub const ERRNO_ISDIR: Errno = 31;
+pub const ERRNO_MFILE: Errno = 33;
+pub const ERRNO_NOENT: Errno = 44;
+pub const ERRNO_NOTDIR: Errno = 54;
+pub const ERRNO_ROFS: Errno = 69;
+pub const ERRNO_NOTCAPABLE: Errno = 76;
+
+pub const FILETYPE_DIRECTORY: u8 = 3;
+pub const FILETYPE_REGULAR_FILE: u8 = 4;
+
+const OFLAGS_CREAT: u32 = 1;
+const OFLAGS_DIRECTORY: u32 = 2;
+const OFLAGS_EXCL: u32 = 4;
+const OFLAGS_TRUNC: u32 = 8;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+
+pub const DATA_DIR_FD: u32 = 3;
+pub const DATA_DIR_NAME: &str = "/data";
+const MAX_OPEN_FILES: usize = 128;
+
+/// Limits on MkModuleReq::data.
+pub const MAX_DATA_FILES: usize = 1000;
+pub const MAX_DATA_BYTES: usize = 256 << 20;
+
+/// Where the data files of a module live, next to its compiled code.
+pub fn data_dir_for(module_path: &Path) -> PathBuf {
+    module_path.with_extension("data")
+}
+
+/// Check a path of a data file, as given in MkModuleReq::data.
+pub fn valid_data_path(path: &str) -> bool {
+    path.len() > 0
+        && path.len() <= 255
+        && !path.contains('\0')
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+pub struct FileStat {
+    pub filetype: u8,
+    pub ino: u64,
+    pub size: u64,
+}
+
+impl FileStat {
+    /// The WASI filestat struct; timestamps are always 0.
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut r = [0u8; 64];
+        r[8..16].copy_from_slice(&self.ino.to_le_bytes());
+        r[16] = self.filetype;
+        r[24..32].copy_from_slice(&1u64.to_le_bytes()); // nlink
+        r[32..40].copy_from_slice(&self.size.to_le_bytes());
+        r
+    }
+}
+
+enum OpenFile {
+    // data files are never modified, so the size doesn't change
+    File {
+        rel: PathBuf,
+        file: fs::File,
+        size: u64,
+        pos: u64,
+    },
+    Dir {
+        rel: PathBuf,
+    },
+}
+
+pub struct Vfs {
+    root: Option<PathBuf>,
+    // index is fd - DATA_DIR_FD - 1
+    files: Vec<Option<OpenFile>>,
+}
+
+impl Vfs {
+    pub fn new(root: Option<PathBuf>) -> Self {
+        Vfs {
+            root: root.filter(|p| p.is_dir()),
+            files: Vec::new(),
+        }
+    }
+
+    fn ino(rel: &Path) -> u64 {
+        let mut h = DefaultHasher::new();
+        rel.hash(&mut h);
+        h.finish() | 1
+    }
+
+    fn get(&self, fd: u32) -> Result<&OpenFile, Errno> {
+        let idx = fd.checked_sub(DATA_DIR_FD + 1).ok_or(ERRNO_BADF)? as usize;
+        self.files
+            .get(idx)
+            .and_then(|f| f.as_ref())
+            .ok_or(ERRNO_BADF)
+    }
+
+    fn get_mut(&mut self, fd: u32) -> Result<&mut OpenFile, Errno> {
+        let idx = fd.checked_sub(DATA_DIR_FD + 1).ok_or(ERRNO_BADF)? as usize;
+        self.files
+            .get_mut(idx)
+            .and_then(|f| f.as_mut())
+            .ok_or(ERRNO_BADF)
+    }
+
+    fn dir_rel(&self, fd: u32) -> Result<PathBuf, Errno> {
+        if fd == DATA_DIR_FD {
+            return Ok(PathBuf::new());
+        }
+        match self.get(fd)? {
+            OpenFile::Dir { rel } => Ok(rel.clone()),
+            OpenFile::File { .. } => Err(ERRNO_NOTDIR),
+        }
+    }
+
+    // resolve path relative to directory fd; the result is relative to the root
+    fn resolve(&self, dirfd: u32, path: &[u8]) -> Result<PathBuf, Errno> {
+        let path = std::str::from_utf8(path).map_err(|_| ERRNO_INVAL)?;
+        let mut rel = self.dir_rel(dirfd)?;
+        for c in Path::new(path).components() {
+            match c {
+                Component::Normal(n) => rel.push(n),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !rel.pop() {
+                        return Err(ERRNO_NOTCAPABLE);
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(ERRNO_NOTCAPABLE),
+            }
+        }
+        Ok(rel)
+    }
+
+    fn stat_rel(&self, rel: &Path) -> Result<FileStat, Errno> {
+        let root = self.root.as_ref().ok_or(ERRNO_NOENT)?;
+        let meta = fs::metadata(root.join(rel)).map_err(|_| ERRNO_NOENT)?;
+        Ok(FileStat {
+            filetype: if meta.is_dir() {
+                FILETYPE_DIRECTORY
+            } else {
+                FILETYPE_REGULAR_FILE
+            },
+            ino: Self::ino(rel),
+            size: meta.len(),
+        })
+    }
+
+    pub fn filetype(&self, fd: u32) -> Result<u8, Errno> {
+        if fd == DATA_DIR_FD {
+            return Ok(FILETYPE_DIRECTORY);
+        }
+        match self.get(fd)? {
+            OpenFile::Dir { .. } => Ok(FILETYPE_DIRECTORY),
+            OpenFile::File { .. } => Ok(FILETYPE_REGULAR_FILE),
+        }
+    }
+
+    pub fn stat_fd(&self, fd: u32) -> Result<FileStat, Errno> {
+        if fd == DATA_DIR_FD {
+            return Ok(FileStat {
+                filetype: FILETYPE_DIRECTORY,
+                ino: Self::ino(Path::new("")),
+                size: 0,
+            });
+        }
+        match self.get(fd)? {
+            OpenFile::Dir { rel } => self.stat_rel(rel),
+            OpenFile::File { rel, size, .. } => Ok(FileStat {
+                filetype: FILETYPE_REGULAR_FILE,
+                ino: Self::ino(rel),
+                size: *size,
+            }),
+        }
+    }
+
+    pub fn stat_path(&self, dirfd: u32, path: &[u8]) -> Result<FileStat, Errno> {
+        let rel = self.resolve(dirfd, path)?;
+        self.stat_rel(&rel)
+    }
+
+    pub fn open(
+        &mut self,
+        dirfd: u32,
+        path: &[u8],
+        oflags: u32,
+        rights_base: u64,
+    ) -> Result<u32, Errno> {
+        let rel = self.resolve(dirfd, path)?;
+        if oflags & (OFLAGS_CREAT | OFLAGS_EXCL | OFLAGS_TRUNC) != 0
+            || rights_base & RIGHTS_FD_WRITE != 0
+        {
+            return Err(ERRNO_ROFS);
+        }
+        let st = self.stat_rel(&rel)?;
+        let file = if st.filetype == FILETYPE_DIRECTORY {
+            OpenFile::Dir { rel }
+        } else if oflags & OFLAGS_DIRECTORY != 0 {
+            return Err(ERRNO_NOTDIR);
+        } else {
+            let file =
+                fs::File::open(self.root.as_ref().unwrap().join(&rel)).map_err(|_| ERRNO_NOENT)?;
+            OpenFile::File {
+                rel,
+                file,
+                size: st.size,
+                pos: 0,
+            }
+        };
+        let idx = match self.files.iter().position(|f| f.is_none()) {
+            Some(idx) => idx,
+            None if self.files.len() < MAX_OPEN_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(ERRNO_MFILE),
+        };
+        self.files[idx] = Some(file);
+        Ok(DATA_DIR_FD + 1 + idx as u32)
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<(), Errno> {
+        if fd == DATA_DIR_FD {
+            return Err(ERRNO_NOTCAPABLE);
+        }
+        self.get(fd)?;
+        self.files[(fd - DATA_DIR_FD - 1) as usize] = None;
+        Ok(())
+    }
+
+    /// Read up to `len` bytes at the current position and advance it.
+    pub fn read(&mut self, fd: u32, len: usize) -> Result<Vec<u8>, Errno> {
+        match self.get_mut(fd)? {
+            OpenFile::File {
+                file, size, pos, ..
+            } => {
+                let start = std::cmp::min(*pos, *size);
+                let end = std::cmp::min(start.saturating_add(len as u64), *size);
+                let mut buf = vec![0u8; (end - start) as usize];
+                file.read_exact_at(&mut buf, start).map_err(|_| ERRNO_IO)?;
+                *pos = end;
+                Ok(buf)
+            }
+            OpenFile::Dir { .. } => Err(ERRNO_ISDIR),
+        }
+    }
+
+    pub fn seek(&mut self, fd: u32, offset: i64, whence: u8) -> Result<u64, Errno> {
+        match self.get_mut(fd)? {
+            OpenFile::File { size, pos, .. } => {
+                let base = match whence {
+                    0 => 0,
+                    1 => *pos as i64,
+                    2 => *size as i64,
+                    _ => return Err(ERRNO_INVAL),
+                };
+                let new_pos = base.checked_add(offset).ok_or(ERRNO_INVAL)?;
+                if new_pos < 0 {
+                    return Err(ERRNO_INVAL);
+                }
+                *pos = new_pos as u64;
+                Ok(*pos)
+            }
+            OpenFile::Dir { .. } => Err(ERRNO_ISDIR),
+        }
+    }
+
+    /// WASI dirent records for entries starting at `cookie`; the caller truncates them
+    /// to the buffer size.
+    pub fn readdir(&self, fd: u32, cookie: u64) -> Result<Vec<u8>, Errno> {
+        let rel = self.dir_rel(fd)?;
+        let mut names = match &self.root {
+            Some(root) => fs::read_dir(root.join(&rel))
+                .map_err(|_| ERRNO_NOENT)?
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().into_string().ok())
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        names.sort();
+        let mut res = Vec::new();
+        for (idx, name) in names.iter().enumerate().skip(cookie as usize) {
+            let entry = rel.join(name);
+            let st = self.stat_rel(&entry)?;
+            res.extend_from_slice(&(idx as u64 + 1).to_le_bytes()); // d_next
+            res.extend_from_slice(&st.ino.to_le_bytes());
+            res.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            res.extend_from_slice(&[st.filetype, 0, 0, 0]);
+            res.extend_from_slice(name.as_bytes());
+        }
+        Ok(res)
+    }
+}
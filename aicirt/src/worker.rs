@@ -1,13 +1,15 @@
 use crate::{
-    api::ModuleInstId,
+    api::{AuthInfo, ModuleInstId, ModuleLimits, ModuleMeta},
     hostimpl::AiciLimits,
-    moduleinstance::{ModuleInstance, WasmContext},
+    moduleinstance::{MemorySnapshot, ModuleInstance, WasmContext},
     setup_bg_worker_pool,
     shm::Shm,
-    InstantiateReq, UserError,
+    store::{store_channel, Store, StoreClient, StoreClientHandle},
+    vfs, InstantiateReq, UserError,
 };
 use aici_abi::{
-    InitPromptResult, MidProcessArg, ProcessResultOffset, StorageCmd, StorageResp, TokenId,
+    InitPromptResult, MidProcessArg, ProcessResultOffset, StorageCmd, StorageResp, StorageScope,
+    TokenId,
 };
 use aicirt::{
     api::SequenceResult,
@@ -20,17 +22,29 @@ use aicirt::{
 use anyhow::{anyhow, Result};
 use libc::pid_t;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
 const QUICK_OP_MS: u64 = 3;
 const QUICK_OP_RETRY_MS: u64 = 100;
 
+// limits for the template cache in the forker; the forker is subject to MAX_MALLOC
+const MAX_TEMPLATE_MODULES: usize = 64;
+const MAX_TEMPLATE_BYTES: usize = 256 * 1024 * 1024;
+
+// how many module/snapshot paths to remember in each PathSet
+const MAX_PATHS: usize = 4096;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GroupCmd {
     StorageCmd { cmd: StorageCmd },
@@ -128,6 +142,121 @@ pub type GroupHandle = ProcessHandle<GroupCmd, GroupResp>;
 struct ForkerCmd {
     id: String,
     for_compile: bool,
+    user: String,
+    template: Option<TemplateReq>,
+    /// Channel to the store, for the comms process.
+    store: Option<StoreClientHandle>,
+}
+
+/// Module, and the memory snapshot after aici_preinit() if any, to be passed by the forker
+/// to the seq worker.
+/// The forker keeps them, so later seq workers for the same module and argument
+/// inherit them, and don't have to read them from disk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct TemplateReq {
+    module_path: PathBuf,
+    snapshot_path: Option<PathBuf>,
+}
+
+type Template = (wasmtime::Module, Option<Rc<MemorySnapshot>>);
+
+#[derive(Default)]
+struct TemplateCache {
+    modules: HashMap<PathBuf, wasmtime::Module>,
+    // least recently used first
+    module_order: VecDeque<PathBuf>,
+    snapshots: HashMap<PathBuf, Rc<MemorySnapshot>>,
+    // least recently used first
+    snapshot_order: VecDeque<PathBuf>,
+    snapshot_bytes: usize,
+    // templates that were asked for but not in memory; see load_pending()
+    pending: VecDeque<TemplateReq>,
+}
+
+impl TemplateCache {
+    /// Returns the template if it's in memory.
+    /// Otherwise, it's queued for loading, and the seq worker reads it from disk itself,
+    /// so that the forker doesn't stall.
+    fn get(&mut self, req: &TemplateReq) -> Option<Template> {
+        let module = self.modules.get(&req.module_path).cloned();
+        let snapshot = match &req.snapshot_path {
+            Some(p) => self.snapshots.get(p).map(|s| Some(s.clone())),
+            None => Some(None),
+        };
+        match (module, snapshot) {
+            (Some(module), Some(snapshot)) => {
+                self.module_order.retain(|p| p != &req.module_path);
+                self.module_order.push_back(req.module_path.clone());
+                if let Some(p) = &req.snapshot_path {
+                    self.snapshot_order.retain(|q| q != p);
+                    self.snapshot_order.push_back(p.clone());
+                }
+                Some((module, snapshot))
+            }
+            _ => {
+                if self.pending.len() < MAX_TEMPLATE_MODULES && !self.pending.contains(req) {
+                    self.pending.push_back(req.clone());
+                }
+                None
+            }
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        self.pending.len() > 0
+    }
+
+    /// Load one of the queued templates; called when the forker has nothing else to do.
+    fn load_pending(&mut self, wasm_ctx: &WasmContext) {
+        if let Some(req) = self.pending.pop_front() {
+            if let Err(e) = self.load(wasm_ctx, &req) {
+                log::warn!("template load failed: {e}");
+            }
+        }
+    }
+
+    fn load(&mut self, wasm_ctx: &WasmContext, req: &TemplateReq) -> Result<()> {
+        if !self.modules.contains_key(&req.module_path) {
+            let m = wasm_ctx.deserialize_module(req.module_path.clone())?;
+            while self.modules.len() >= MAX_TEMPLATE_MODULES {
+                match self.module_order.pop_front() {
+                    Some(old) => {
+                        self.modules.remove(&old);
+                    }
+                    None => break,
+                }
+            }
+            self.modules.insert(req.module_path.clone(), m);
+            self.module_order.push_back(req.module_path.clone());
+        }
+        if let Some(p) = &req.snapshot_path {
+            if !self.snapshots.contains_key(p) {
+                self.load_snapshot(p)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_snapshot(&mut self, path: &Path) -> Result<()> {
+        let s = Rc::new(read_snapshot(path)?);
+        let size = s.num_bytes();
+        if size > MAX_TEMPLATE_BYTES {
+            return Ok(());
+        }
+        while self.snapshot_bytes + size > MAX_TEMPLATE_BYTES {
+            match self.snapshot_order.pop_front() {
+                Some(old) => {
+                    let old = self.snapshots.remove(&old).unwrap();
+                    self.snapshot_bytes -= old.num_bytes();
+                }
+                None => break,
+            }
+        }
+        self.snapshot_bytes += size;
+        self.snapshots.insert(path.to_path_buf(), s);
+        self.snapshot_order.push_back(path.to_path_buf());
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -145,8 +274,15 @@ enum SeqCmd {
         module_path: PathBuf,
         module_id: String,
         module_arg: String,
+        snapshot_path: Option<PathBuf>,
         prompt_str: Option<String>,
         prompt_toks: Option<Vec<TokenId>>,
+        module_limits: ModuleLimits,
+    },
+    Preinit {
+        module_path: PathBuf,
+        module_arg: String,
+        snapshot_path: PathBuf,
     },
     Fork {
         inst_id: ModuleInstId,
@@ -161,6 +297,9 @@ enum SeqCmd {
     Compile {
         wasm: Vec<u8>,
     },
+    Storage {
+        cmd: StorageCmd,
+    },
 }
 
 impl SeqCmd {
@@ -168,11 +307,13 @@ impl SeqCmd {
         match self {
             SeqCmd::GetCommsPid {} => "get_comms_pid",
             SeqCmd::Instantiate { .. } => "instantiate",
+            SeqCmd::Preinit { .. } => "preinit",
             SeqCmd::Fork { .. } => "fork",
             SeqCmd::SetId { .. } => "set_id",
             SeqCmd::MidProcess { .. } => "process",
             SeqCmd::RunMain {} => "run_main",
             SeqCmd::Compile { .. } => "compile",
+            SeqCmd::Storage { .. } => "storage",
         }
     }
 }
@@ -186,6 +327,8 @@ enum SeqResp {
     PostPreProcess { post_json: String, pre_json: String },
     MidProcess { json: String },
     Compile { binary: Vec<u8> },
+    Preinit { has_preinit: bool, first_use: bool },
+    Storage { resp: StorageResp },
     Error { msg: String, is_user_error: bool },
 }
 
@@ -353,15 +496,70 @@ impl SeqCtx {
                     }
                 }
             }
+            SeqCmd::Preinit {
+                module_path,
+                module_arg,
+                snapshot_path,
+            } => {
+                let start_time = Instant::now();
+                let data_dir = vfs::data_dir_for(&module_path);
+                let module = self.wasm_ctx.deserialize_module(module_path)?;
+                let ch = std::mem::take(&mut self.query);
+                // the snapshot is shared by all modules, so it only uses host limits
+                let module_limits = self
+                    .wasm_ctx
+                    .limits
+                    .module_limits(&ModuleMeta::default(), &[]);
+                let mut inst = ModuleInstance::new(
+                    424242,
+                    self.wasm_ctx.clone(),
+                    module,
+                    module_arg,
+                    ch.unwrap(),
+                    self.shm.clone(),
+                    None,
+                    module_limits,
+                    Some(data_dir),
+                )?;
+                let first_use = inst.preinit_on_first_use();
+                let has_preinit = match inst.preinit()? {
+                    Some(snapshot) => {
+                        write_snapshot(&snapshot_path, &snapshot)?;
+                        log::info!(
+                            "preinit snapshot {} written; {:?}",
+                            snapshot_path.display(),
+                            Instant::now() - start_time
+                        );
+                        true
+                    }
+                    None => false,
+                };
+                Ok(SeqResp::Preinit {
+                    has_preinit,
+                    first_use,
+                })
+            }
             SeqCmd::Instantiate {
                 module_path,
                 module_id,
                 module_arg,
+                snapshot_path,
                 prompt_str,
                 prompt_toks,
+                module_limits,
             } => {
-                let module = self.wasm_ctx.deserialize_module(module_path).unwrap();
                 let _ = module_id;
+                let data_dir = vfs::data_dir_for(&module_path);
+                let (module, snapshot) = match self.template.take() {
+                    Some(t) => t,
+                    None => (
+                        self.wasm_ctx.deserialize_module(module_path)?,
+                        match snapshot_path {
+                            Some(p) => Some(Rc::new(read_snapshot(&p)?)),
+                            None => None,
+                        },
+                    ),
+                };
                 let ch = std::mem::take(&mut self.query);
                 let mut inst = ModuleInstance::new(
                     424242,
@@ -370,6 +568,9 @@ impl SeqCtx {
                     module_arg,
                     ch.unwrap(),
                     self.shm.clone(),
+                    snapshot.as_deref(),
+                    module_limits,
+                    Some(data_dir),
                 )?;
                 let prompt_toks = if let Some(t) = prompt_toks {
                     t
@@ -404,6 +605,12 @@ impl SeqCtx {
                 self.mutinst().run_main()?;
                 ok()
             }
+            SeqCmd::Storage { cmd } => match self.group_cmd(GroupCmd::StorageCmd { cmd }) {
+                GroupResp::StorageResp {
+                    resp: StorageResp::Error { message },
+                } => Err(anyhow!("storage: {message}")),
+                GroupResp::StorageResp { resp } => Ok(SeqResp::Storage { resp }),
+            },
         }
     }
 
@@ -411,8 +618,7 @@ impl SeqCtx {
         self.modinst.as_mut().unwrap()
     }
 
-    // we may want to do this in future, but for now only group cmd is storage
-    #[allow(dead_code)]
+    // storage commands issued by the runtime itself (not the module), see SeqCmd::Storage
     fn group_cmd(&self, query: GroupCmd) -> GroupResp {
         if let Some(q) = &self.query {
             q.send_cmd(query).unwrap()
@@ -448,6 +654,8 @@ struct GroupCtx {
     variables: Variables,
     server: TypedServer<GroupCmd, GroupResp>,
     limits: AiciLimits,
+    user: String,
+    store: Option<StoreClient>,
 }
 
 struct SeqCtx {
@@ -459,10 +667,13 @@ struct SeqCtx {
     inst_id: ModuleInstId,
     modinst: Option<ModuleInstance>,
     shm: Rc<ShmAllocator>,
+    // inherited from the forker
+    template: Option<Template>,
 }
 
 struct CommsPid {
     pid: pid_t,
+    _store: StoreClosed,
 }
 
 impl Drop for CommsPid {
@@ -471,8 +682,21 @@ impl Drop for CommsPid {
     }
 }
 
+/// Stops the thread serving the store channel of the comms process when dropped.
+struct StoreClosed(Arc<AtomicBool>);
+
+impl Drop for StoreClosed {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
 pub struct SeqWorkerHandle {
     pub req_id: String,
+    /// User that started the request; used for sub-generations.
+    pub user: String,
+    /// Effective limits of the module running in this worker.
+    pub limits: ModuleLimits,
     handle: SeqHandle,
     comms_pid: Option<Arc<CommsPid>>,
 }
@@ -502,6 +726,8 @@ impl SeqWorkerHandle {
             SeqResp::Fork { handle } => {
                 let res = SeqWorkerHandle {
                     req_id: self.req_id.clone(),
+                    user: self.user.clone(),
+                    limits: self.limits,
                     handle: handle.to_client(),
                     comms_pid: self.comms_pid.clone(),
                 };
@@ -514,6 +740,17 @@ impl SeqWorkerHandle {
         }
     }
 
+    /// Run storage command in the group (request) of this sequence.
+    pub fn storage_cmd(&self, cmd: StorageCmd) -> Result<StorageResp> {
+        match self
+            .handle
+            .send_cmd_with_timeout(SeqCmd::Storage { cmd }, Timeout::Quick)?
+        {
+            SeqResp::Storage { resp } => Ok(resp),
+            r => Err(anyhow!("unexpected response (storage) {r:?}")),
+        }
+    }
+
     pub fn start_process(&self, data: RtMidProcessArg) -> Result<()> {
         self.handle.just_send(SeqCmd::MidProcess { data })?;
         Ok(())
@@ -533,7 +770,15 @@ impl SeqWorkerHandle {
 
 impl GroupCtx {
     fn dispatch_storage_cmd(&mut self, cmd: StorageCmd) -> StorageResp {
-        self.variables.process_cmd(cmd)
+        match cmd.scope() {
+            StorageScope::Request => self.variables.process_cmd(cmd),
+            _ => match &mut self.store {
+                Some(store) => store.send(&self.user, cmd),
+                None => StorageResp::Error {
+                    message: "storage is not available here".to_string(),
+                },
+            },
+        }
     }
 
     fn dispatch_cmd(&mut self, cmd: GroupCmd) -> GroupResp {
@@ -556,6 +801,135 @@ impl GroupCtx {
 pub struct WorkerForker {
     limits: AiciLimits,
     fork_worker: ForkerHandle,
+    trie_hash: String,
+    // module paths without aici_preinit(), and snapshot paths where it failed
+    no_preinit: PathSet,
+    // snapshot paths of module arguments seen once, but not snapshotted
+    preinit_seen: PathSet,
+    // for modules where aici_preinit() ran: whether to snapshot on first use of an argument
+    preinit_first_use: HashMap<PathBuf, bool>,
+    snapshot_files: SnapshotFiles,
+    // user- and global-scoped variables; each comms process gets a channel to it
+    store: Arc<Mutex<Store>>,
+}
+
+/// Set of paths, forgetting the oldest ones when it has more than MAX_PATHS.
+#[derive(Default)]
+struct PathSet {
+    set: HashSet<PathBuf>,
+    // oldest first
+    order: VecDeque<PathBuf>,
+}
+
+impl PathSet {
+    fn contains(&self, path: &Path) -> bool {
+        self.set.contains(path)
+    }
+
+    /// Returns true if `path` wasn't there yet.
+    fn insert(&mut self, path: PathBuf) -> bool {
+        if self.set.contains(&path) {
+            return false;
+        }
+        if self.set.len() >= MAX_PATHS {
+            if let Some(old) = self.order.pop_front() {
+                self.set.remove(&old);
+            }
+        }
+        self.set.insert(path.clone());
+        self.order.push_back(path);
+        true
+    }
+}
+
+/// Snapshot files on disk, evicted least recently used first
+/// when they take more than `AiciLimits::snapshot_cache_bytes`.
+#[derive(Default)]
+struct SnapshotFiles {
+    // files left over from previous runs are only found on first use
+    scanned: bool,
+    // least recently used first
+    order: VecDeque<PathBuf>,
+    sizes: HashMap<PathBuf, u64>,
+    total_bytes: u64,
+}
+
+impl SnapshotFiles {
+    fn scan(&mut self, dir: &Path) {
+        if self.scanned {
+            return;
+        }
+        self.scanned = true;
+        let mut found = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for ent in entries.flatten() {
+                let path = ent.path();
+                if path.extension().map_or(false, |e| e == "snap") {
+                    if let Ok(meta) = ent.metadata() {
+                        let mtime = meta.modified().unwrap_or(std::time::UNIX_EPOCH);
+                        found.push((mtime, path, meta.len()));
+                    }
+                }
+            }
+        }
+        found.sort();
+        for (_, path, size) in found {
+            self.add(&path, size);
+        }
+    }
+
+    fn add(&mut self, path: &Path, size: u64) {
+        if let Some(old) = self.sizes.insert(path.to_path_buf(), size) {
+            self.total_bytes -= old;
+            self.order.retain(|p| p != path);
+        }
+        self.total_bytes += size;
+        self.order.push_back(path.to_path_buf());
+    }
+
+    fn touch(&mut self, path: &Path) {
+        if self.sizes.contains_key(path) {
+            self.order.retain(|p| p != path);
+            self.order.push_back(path.to_path_buf());
+        } else {
+            let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            self.add(path, size);
+        }
+    }
+
+    /// Remove files until under `max_bytes`; the most recently used one is always kept.
+    fn evict(&mut self, max_bytes: u64) {
+        while self.total_bytes > max_bytes && self.order.len() > 1 {
+            let path = self.order.pop_front().unwrap();
+            self.total_bytes -= self.sizes.remove(&path).unwrap();
+            // the forker may still have it in TemplateCache, which is fine
+            match std::fs::remove_file(&path) {
+                Ok(()) => log::debug!("evicted snapshot {}", path.display()),
+                Err(e) => log::warn!("can't remove snapshot {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+fn write_snapshot(path: &Path, snapshot: &MemorySnapshot) -> Result<()> {
+    // write to a temp file first, so that concurrent readers never see a partial snapshot
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    let file = std::fs::File::create(&tmp_path)?;
+    bincode::serialize_into(std::io::BufWriter::new(file), snapshot)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn read_snapshot(path: &Path) -> Result<MemorySnapshot> {
+    let start_time = Instant::now();
+    let file = std::fs::File::open(path)?;
+    let snapshot = bincode::deserialize_from(std::io::BufReader::new(file))?;
+    log::debug!(
+        "snapshot {} read; {:?}",
+        path.display(),
+        start_time.elapsed()
+    );
+    Ok(snapshot)
 }
 
 fn forker_dispatcher(
@@ -564,6 +938,7 @@ fn forker_dispatcher(
     shm: Rc<ShmAllocator>,
 ) -> ! {
     set_process_name("aicirt-forker");
+    let mut templates = TemplateCache::default();
     loop {
         // wait for any children that might have exited to prevent zombies
         loop {
@@ -582,9 +957,22 @@ fn forker_dispatcher(
             }
         }
 
-        let cmd = server.recv_req(wasm_ctx.limits.busy_wait_duration);
+        // load templates missed by earlier requests, while no command is waiting
+        let cmd = loop {
+            if !templates.has_pending() {
+                break server.recv_req(wasm_ctx.limits.busy_wait_duration);
+            }
+            match server.recv_req_timeout(Duration::ZERO, Duration::ZERO) {
+                Some(cmd) => break cmd,
+                None => templates.load_pending(&wasm_ctx),
+            }
+        };
         let cmd_id = cmd.id;
         let for_compile = cmd.for_compile;
+        let user = cmd.user;
+        let store = cmd.store;
+        // the seq worker inherits it by forking
+        let template = cmd.template.and_then(|t| templates.get(&t));
 
         // fork the seq worker first
         match fork_child(&wasm_ctx.limits).unwrap() {
@@ -601,6 +989,7 @@ fn forker_dispatcher(
                     query: None,
                     inst_id: 424242,
                     modinst: None,
+                    template,
                 };
 
                 if for_compile {
@@ -621,10 +1010,13 @@ fn forker_dispatcher(
                     ForkResult::Child { server } => {
                         set_process_name("aicirt-comms");
                         set_max_priority();
+                        let busy_wait = w_ctx.wasm_ctx.limits.busy_wait_duration;
                         let mut grp_ctx = GroupCtx {
                             variables: Variables::default(),
                             server,
                             limits: w_ctx.wasm_ctx.limits,
+                            user,
+                            store: store.map(|h| StoreClient::new(h, busy_wait)),
                         };
                         grp_ctx.dispatch_loop()
                     }
@@ -678,23 +1070,157 @@ impl WorkerForker {
         };
 
         let limits = wasm_ctx.limits.clone();
+        // snapshots contain the token trie, so they are only valid for the same tokenizer;
+        // aici_preinit() can also look at get_config()
+        let mut hasher = Sha256::new();
+        hasher.update(wasm_ctx.globals.trie_bytes.as_slice());
+        hasher.update(serde_json::to_vec(&wasm_ctx.globals.inference_caps).unwrap());
+        let trie_hash = hex::encode(hasher.finalize());
 
         match fork_child(&limits).unwrap() {
             ForkResult::Parent { handle } => {
                 unsafe { libc::signal(libc::SIGUSR1, clean_exit as usize) };
+                let store = Store::new(&limits).unwrap();
                 WorkerForker {
                     fork_worker: handle.to_client(),
                     limits,
+                    trie_hash,
+                    no_preinit: PathSet::default(),
+                    preinit_seen: PathSet::default(),
+                    preinit_first_use: HashMap::new(),
+                    snapshot_files: SnapshotFiles::default(),
+                    store: Arc::new(Mutex::new(store)),
                 }
             }
             ForkResult::Child { server } => forker_dispatcher(server, wasm_ctx, shm),
         }
     }
 
-    pub fn instantiate(
+    fn snapshot_path(&self, module_path: &Path, module_arg: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(self.trie_hash.as_bytes());
+        hasher.update(module_arg.as_bytes());
+        let hash = hex::encode(hasher.finalize());
+        module_path.with_extension(format!("{}.snap", &hash[0..32]))
+    }
+
+    fn snapshot_used(&mut self, snapshot_path: &Path) {
+        if let Some(dir) = snapshot_path.parent() {
+            self.snapshot_files.scan(dir);
+        }
+        self.snapshot_files.touch(snapshot_path);
+        self.snapshot_files
+            .evict(self.limits.snapshot_cache_bytes as u64);
+    }
+
+    /// Run `aici_preinit()` in a separate worker and save the memory snapshot.
+    /// This has the init limits (time, or fuel with fuel metering) of the host,
+    /// separately from the initialization of the request.
+    /// Unless the module asks for it on first use (see `aici_expose_preinit!`),
+    /// this only happens when an argument is used the second time, so that one-off
+    /// arguments (e.g., grammars generated for a single request) don't fill the cache.
+    fn ensure_snapshot(
+        &mut self,
+        req_id: &str,
+        module_path: &Path,
+        module_arg: &str,
+    ) -> Result<Option<PathBuf>> {
+        let snapshot_path = self.snapshot_path(module_path, module_arg);
+        if self.no_preinit.contains(module_path) || self.no_preinit.contains(&snapshot_path) {
+            return Ok(None);
+        }
+        if snapshot_path.exists() {
+            self.snapshot_used(&snapshot_path);
+            return Ok(Some(snapshot_path));
+        }
+        // until aici_preinit() runs once, it's not known what the module asks for
+        if self.preinit_first_use.get(module_path) == Some(&false)
+            && self.preinit_seen.insert(snapshot_path.clone())
+        {
+            return Ok(None);
+        }
+        let module_limits = self.limits.module_limits(&ModuleMeta::default(), &[]);
+        let res = self.new_seq_worker(&format!("{req_id}-preinit"), "", module_limits, None)?;
+        match res.handle.send_cmd_with_timeout(
+            SeqCmd::Preinit {
+                module_path: module_path.to_path_buf(),
+                module_arg: module_arg.to_string(),
+                snapshot_path: snapshot_path.clone(),
+            },
+            Timeout::from_millis(
+                self.limits
+                    .wall_clock_ms(module_limits.init_fuel, module_limits.max_init_ms),
+            ),
+        ) {
+            Ok(SeqResp::Preinit {
+                has_preinit: true,
+                first_use,
+            }) => {
+                self.preinit_first_use
+                    .insert(module_path.to_path_buf(), first_use);
+                self.snapshot_used(&snapshot_path);
+                Ok(Some(snapshot_path))
+            }
+            Ok(SeqResp::Preinit {
+                has_preinit: false, ..
+            }) => {
+                self.no_preinit.insert(module_path.to_path_buf());
+                Ok(None)
+            }
+            r => {
+                // don't retry for every request
+                self.no_preinit.insert(snapshot_path);
+                match r {
+                    Ok(r) => Err(anyhow!("unexpected response (preinit) {r:?}")),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    fn new_seq_worker(
         &self,
+        req_id: &str,
+        user: &str,
+        limits: ModuleLimits,
+        template: Option<TemplateReq>,
+    ) -> Result<SeqWorkerHandle> {
+        let store_closed = StoreClosed(Arc::new(AtomicBool::new(false)));
+        let store = store_channel(&self.store, &self.limits, store_closed.0.clone())?;
+        let resp = self.fork_worker.send_cmd(ForkerCmd {
+            id: req_id.to_string(),
+            for_compile: false,
+            user: user.to_string(),
+            template,
+            store: Some(store),
+        })?;
+        let mut res = SeqWorkerHandle {
+            req_id: req_id.to_string(),
+            user: user.to_string(),
+            limits,
+            handle: resp.0.to_client(),
+            comms_pid: None,
+        };
+        let comms_pid = match res
+            .handle
+            .send_cmd_with_timeout(SeqCmd::GetCommsPid {}, Timeout::Quick)?
+        {
+            SeqResp::CommsPid { pid } => pid,
+            r => return Err(anyhow!("unexpected response (get comms pid) {r:?}")),
+        };
+        res.comms_pid = Some(Arc::new(CommsPid {
+            pid: comms_pid,
+            _store: store_closed,
+        }));
+        Ok(res)
+    }
+
+    pub fn instantiate(
+        &mut self,
         req: InstantiateReq,
         module_path: PathBuf,
+        module_limits: ModuleLimits,
+        auth: &AuthInfo,
     ) -> Result<(SeqWorkerHandle, SequenceResult<InitPromptResult>)> {
         let module_arg = match req.module_arg.as_str() {
             Some(a) => a.to_string(),
@@ -722,32 +1248,34 @@ impl WorkerForker {
             )
         };
 
-        let resp = self.fork_worker.send_cmd(ForkerCmd {
-            id: req.req_id.clone(),
-            for_compile: false,
-        })?;
-        let mut res = SeqWorkerHandle {
-            req_id: req.req_id.clone(),
-            handle: resp.0.to_client(),
-            comms_pid: None,
+        let snapshot_path = match self.ensure_snapshot(&req.req_id, &module_path, &module_arg) {
+            Ok(p) => p,
+            Err(e) => {
+                // errors in aici_preinit() are reported when the request is run normally
+                log::warn!("preinit failed: {e}");
+                None
+            }
         };
-        let comms_pid = match res
-            .handle
-            .send_cmd_with_timeout(SeqCmd::GetCommsPid {}, Timeout::Quick)?
-        {
-            SeqResp::CommsPid { pid } => pid,
-            r => return Err(anyhow!("unexpected response (get comms pid) {r:?}")),
+
+        let template = TemplateReq {
+            module_path: module_path.clone(),
+            snapshot_path: snapshot_path.clone(),
         };
-        res.comms_pid = Some(Arc::new(CommsPid { pid: comms_pid }));
+        let res = self.new_seq_worker(&req.req_id, &auth.user, module_limits, Some(template))?;
         match res.handle.send_cmd_with_timeout(
             SeqCmd::Instantiate {
                 module_path,
                 module_id: req.module_id.clone(),
                 module_arg,
+                snapshot_path,
                 prompt_str,
                 prompt_toks,
+                module_limits,
             },
-            Timeout::from_millis(self.limits.max_init_ms),
+            Timeout::from_millis(
+                self.limits
+                    .wall_clock_ms(module_limits.init_fuel, module_limits.max_init_ms),
+            ),
         )? {
             SeqResp::InitPrompt { json } => {
                 let r: SequenceResult<InitPromptResult> = serde_json::from_str(&json)?;
@@ -762,11 +1290,16 @@ impl WorkerForker {
         let resp = self.fork_worker.send_cmd(ForkerCmd {
             id: id.clone(),
             for_compile: true,
+            user: String::new(),
+            template: None,
+            store: None,
         })?;
 
         // res.drop() kills handle
         let res = SeqWorkerHandle {
             req_id: id.clone(),
+            user: String::new(),
+            limits: self.limits.module_limits(&ModuleMeta::default(), &[]),
             handle: resp.0.to_client(),
             comms_pid: None,
         };
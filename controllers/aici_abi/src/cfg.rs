@@ -1,17 +1,18 @@
 use crate::host::host_trie;
 use crate::lex::{Lexer, LexerState, StateID, VobIdx, VobSet};
 use crate::{
-    toktrie::{Recognizer, SpecialToken},
+    toktrie::{Recognizer, SpecialToken, TokTrie},
     SimpleVob,
 };
 use anyhow::Result;
 use cfgrammar::{
-    yacc::{YaccGrammar, YaccKind},
-    Span, Spanned, Symbol, TIdx,
+    yacc::{ast::ASTWithValidityInfo, YaccGrammar, YaccKind},
+    PIdx, RIdx, Span, Spanned, Symbol, TIdx,
 };
 use lrtable::{from_yacc, Action, Minimiser, StIdx, StateTable};
 use rustc_hash::FxHashMap;
-use std::{cell::RefCell, vec};
+use serde::Serialize;
+use std::{cell::RefCell, fmt::Display, vec};
 use vob::{vob, Vob};
 
 type StorageT = u32;
@@ -31,8 +32,93 @@ struct CfgStats {
     states_pushed: usize,
 }
 
+/// A rule, terminal or production, with its position in the yacc source.
+#[derive(Debug, Clone, Serialize)]
+pub struct GrammarSymbol {
+    pub name: String,
+    /// "(line,column)" or empty if not known
+    pub pos: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GrammarConflict {
+    /// "shift/reduce" or "reduce/reduce"
+    pub kind: String,
+    pub state: usize,
+    pub lookahead: GrammarSymbol,
+    /// Productions involved in the conflict; positions point to the production
+    /// (or the rule name if it couldn't be found).
+    pub productions: Vec<GrammarSymbol>,
+}
+
+/// Static diagnostics for a grammar, see `CfgParser::analyze()`.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct GrammarReport {
+    pub conflicts: Vec<GrammarConflict>,
+    /// Rules that cannot be derived from the start rule.
+    pub unreachable_rules: Vec<GrammarSymbol>,
+    /// Terminals that do not occur in any reachable rule (and are not SKIP).
+    pub unused_terminals: Vec<GrammarSymbol>,
+    /// Terminals that cannot match any prefix of any token in the tokenizer.
+    /// Only computed when a `TokTrie` is passed to `analyze()`.
+    pub unproductive_terminals: Vec<GrammarSymbol>,
+    pub num_patterns: usize,
+    pub parser_states: usize,
+    pub lexer_states: usize,
+    pub lexer_dfa_bytes: usize,
+}
+
+impl GrammarReport {
+    /// Conflicts and unproductive terminals almost always indicate a bug in the grammar.
+    pub fn has_errors(&self) -> bool {
+        !self.conflicts.is_empty() || !self.unproductive_terminals.is_empty()
+    }
+}
+
+impl Display for GrammarSymbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.pos.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.pos, self.name)
+        }
+    }
+}
+
+impl Display for GrammarReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "parser: {} states; lexer: {} patterns, {} DFA states, {} bytes",
+            self.parser_states, self.num_patterns, self.lexer_states, self.lexer_dfa_bytes
+        )?;
+        for c in &self.conflicts {
+            writeln!(
+                f,
+                "{} conflict in state {} on {}:",
+                c.kind, c.state, c.lookahead
+            )?;
+            for p in &c.productions {
+                writeln!(f, "  {}", p)?;
+            }
+        }
+        let lists = [
+            ("unreachable rule", &self.unreachable_rules),
+            ("unused terminal", &self.unused_terminals),
+            ("unproductive terminal", &self.unproductive_terminals),
+        ];
+        for (lbl, lst) in lists {
+            for s in lst {
+                writeln!(f, "{}: {}", lbl, s)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct CfgParser {
     grm: YaccGrammar<StorageT>,
+    prod_spans: Vec<Span>,
     stable: StateTable<StorageT>,
     lexer: Lexer,
     byte_states: Vec<ByteState>,
@@ -91,9 +177,11 @@ fn span_to_str(s: &Span, src: &str) -> String {
     format!("({},{})", line, column)
 }
 
-pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
+/// Returns the grammar, and the spans of its productions (indexed by `PIdx`).
+pub(crate) fn parse_yacc(yacc: &str) -> Result<(YaccGrammar<StorageT>, Vec<Span>)> {
     let grmkind = YaccKind::Original(cfgrammar::yacc::YaccOriginalActionKind::NoAction);
-    let grm = match YaccGrammar::new(grmkind, yacc) {
+    let ast = ASTWithValidityInfo::new(grmkind, yacc);
+    let grm = match YaccGrammar::new_from_ast_with_validity_info(&ast) {
         Ok(grm) => grm,
         Err(e) => {
             let err_str = e
@@ -112,12 +200,20 @@ pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
             anyhow::bail!("yacc grammar errors:\n{}", err_str);
         }
     };
-    Ok(grm)
+    // productions from the source keep their indices; the added start production comes last
+    let prod_spans = ast.ast().prods.iter().map(|p| p.prod_span).collect();
+    Ok((grm, prod_spans))
+}
+
+/// Build the parser for `yacc` and run `CfgParser::analyze()` on it.
+pub fn analyze_yacc(yacc: &str, trie: Option<&TokTrie>) -> Result<GrammarReport> {
+    let cfg = CfgParser::from_yacc(yacc)?;
+    Ok(cfg.analyze(yacc, trie))
 }
 
 impl CfgParser {
     pub fn from_yacc(yacc: &str) -> Result<Self> {
-        let grm = parse_yacc(yacc)?;
+        let (grm, prod_spans) = parse_yacc(yacc)?;
         // TIME: all these annotation are for native release x86 build for C grammar
         // TIME: 27ms
         let (sgraph, stable) = match from_yacc(&grm, Minimiser::Pager) {
@@ -232,6 +328,7 @@ impl CfgParser {
 
         let mut cfg = CfgParser {
             grm,
+            prod_spans,
             stable,
             lexer: dfa,
             byte_states: vec![byte_state],
@@ -419,6 +516,118 @@ impl CfgParser {
         r
     }
 
+    /// Report conflicts, unreachable rules and unused terminals in the grammar.
+    /// `yacc` has to be the source the parser was created from; it's only used for positions.
+    /// If `trie` is given, also report terminals that can't start with any token.
+    pub fn analyze(&self, yacc: &str, trie: Option<&TokTrie>) -> GrammarReport {
+        let grm = &self.grm;
+        let rule_sym = |ridx: RIdx<StorageT>| GrammarSymbol {
+            name: grm.rule_name_str(ridx).to_string(),
+            pos: span_to_str(&grm.rule_name_span(ridx), yacc),
+        };
+        let token_sym = |tidx: TIdx<StorageT>| GrammarSymbol {
+            name: grm.token_name(tidx).unwrap_or("<EOF>").to_string(),
+            pos: grm
+                .token_span(tidx)
+                .map(|s| span_to_str(&s, yacc))
+                .unwrap_or_default(),
+        };
+        let prod_sym = |pidx: PIdx<StorageT>| {
+            let span = self
+                .prod_spans
+                .get(pidx.as_storaget() as usize)
+                .copied()
+                .unwrap_or_else(|| grm.rule_name_span(grm.prod_to_rule(pidx)));
+            GrammarSymbol {
+                name: grm.pp_prod(pidx),
+                pos: span_to_str(&span, yacc),
+            }
+        };
+
+        let mut report = GrammarReport {
+            num_patterns: self.friendly_pattern_names.len(),
+            parser_states: self.viable_vobidx_by_state.len(),
+            lexer_states: self.lexer.num_states(),
+            lexer_dfa_bytes: self.lexer.dfa_memory_usage(),
+            ..Default::default()
+        };
+
+        if let Some(conflicts) = self.stable.conflicts() {
+            for (tidx, pidx, stidx) in conflicts.sr_conflicts() {
+                report.conflicts.push(GrammarConflict {
+                    kind: "shift/reduce".to_string(),
+                    state: stidx.as_storaget() as usize,
+                    lookahead: token_sym(*tidx),
+                    productions: vec![prod_sym(*pidx)],
+                });
+            }
+            for (tidx, pidx, pidx2, stidx) in conflicts.rr_conflicts() {
+                report.conflicts.push(GrammarConflict {
+                    kind: "reduce/reduce".to_string(),
+                    state: stidx.as_storaget() as usize,
+                    lookahead: token_sym(*tidx),
+                    productions: vec![prod_sym(*pidx), prod_sym(*pidx2)],
+                });
+            }
+        }
+
+        // rules reachable from the start rule; SKIP is used by the lexer directly
+        let mut reachable = vob![false; grm.rules_len().as_storaget() as usize];
+        let mut used_tokens = vob![false; grm.tokens_len().as_storaget() as usize];
+        let mut todo = vec![grm.start_rule_idx()];
+        todo.extend(grm.iter_rules().filter(|r| grm.rule_name_str(*r) == "SKIP"));
+        for ridx in &todo {
+            reachable.set(ridx.as_storaget() as usize, true);
+        }
+        while let Some(ridx) = todo.pop() {
+            for pidx in grm.rule_to_prods(ridx) {
+                for sym in grm.prod(*pidx) {
+                    match sym {
+                        Symbol::Rule(r) => {
+                            if !reachable[r.as_storaget() as usize] {
+                                reachable.set(r.as_storaget() as usize, true);
+                                todo.push(*r);
+                            }
+                        }
+                        Symbol::Token(t) => {
+                            used_tokens.set(t.as_storaget() as usize, true);
+                        }
+                    }
+                }
+            }
+        }
+
+        for ridx in grm.iter_rules() {
+            if Some(ridx) == grm.implicit_rule() {
+                continue;
+            }
+            if !reachable[ridx.as_storaget() as usize] {
+                report.unreachable_rules.push(rule_sym(ridx));
+            }
+        }
+
+        for (pat_idx, tidx) in self.pat_idx_to_tidx.iter().enumerate() {
+            if !used_tokens[tidx.as_storaget() as usize] && !self.skip_patterns[pat_idx] {
+                report.unused_terminals.push(token_sym(*tidx));
+            }
+        }
+
+        if let Some(trie) = trie {
+            let mut productive = vob![false; self.pat_idx_to_tidx.len()];
+            for tok in 0..trie.vocab_size() as u32 {
+                self.lexer
+                    .prefix_patterns(&self.vobset, trie.token(tok), &mut productive);
+            }
+            for (pat_idx, tidx) in self.pat_idx_to_tidx.iter().enumerate() {
+                if !productive[pat_idx] {
+                    report.unproductive_terminals.push(token_sym(*tidx));
+                }
+            }
+        }
+
+        report
+    }
+
     fn mk_byte_state(
         &self,
         ls: LexerState,
@@ -595,3 +804,84 @@ pub fn cfg_test() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPR_Y: &str = r#"%start expr
+%%
+SKIP: "/[ \t\n]+/" ;
+expr
+    : expr "+" expr
+    | "a"
+    ;
+unused: "b" ;
+"#;
+
+    #[test]
+    fn analyze_clean() {
+        let yacc = r#"%start list
+%%
+SKIP: "/[ \t\n]+/" ;
+list: item | list "," item ;
+item: "/[a-z]+/" ;
+"#;
+        let report = analyze_yacc(yacc, None).unwrap();
+        assert!(!report.has_errors());
+        assert!(report.conflicts.is_empty());
+        // SKIP is used by the lexer
+        assert!(report.unreachable_rules.is_empty());
+        assert!(report.unused_terminals.is_empty());
+        assert_eq!(report.num_patterns, 3);
+    }
+
+    #[test]
+    fn analyze_shift_reduce() {
+        let report = analyze_yacc(EXPR_Y, None).unwrap();
+        assert!(report.has_errors());
+        assert_eq!(report.conflicts.len(), 1);
+        let c = &report.conflicts[0];
+        assert_eq!(c.kind, "shift/reduce");
+        assert_eq!(c.lookahead.name, "+");
+        assert_eq!(c.productions.len(), 1);
+        // points at the alternative, not the rule name
+        assert_eq!(c.productions[0].pos, "(5,7)");
+    }
+
+    #[test]
+    fn analyze_reduce_reduce() {
+        let yacc = r#"%start s
+%%
+s: a | b ;
+a: "x" ;
+b: "x" ;
+"#;
+        let report = analyze_yacc(yacc, None).unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+        let c = &report.conflicts[0];
+        assert_eq!(c.kind, "reduce/reduce");
+        assert_eq!(c.lookahead.to_string(), "<EOF>");
+        let mut pos = c
+            .productions
+            .iter()
+            .map(|p| p.pos.as_str())
+            .collect::<Vec<_>>();
+        pos.sort();
+        assert_eq!(pos, vec!["(4,4)", "(5,4)"]);
+    }
+
+    #[test]
+    fn analyze_unused() {
+        let report = analyze_yacc(EXPR_Y, None).unwrap();
+        let names = |syms: &[GrammarSymbol]| syms.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(names(&report.unreachable_rules), vec!["(8,1) unused"]);
+        assert_eq!(names(&report.unused_terminals), vec!["(8,10) b"]);
+        assert!(report.unproductive_terminals.is_empty());
+    }
+
+    #[test]
+    fn analyze_errors() {
+        assert!(analyze_yacc("%start s\n%%\ns: t ;\n", None).is_err());
+    }
+}
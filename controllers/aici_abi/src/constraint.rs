@@ -0,0 +1,1038 @@
+use crate::{
+    healing::HealingRecognizer,
+    recognizer::{AnythingGoes, FunctionalRecognizer, StackRecognizer},
+    substring::{SubStrMatch, SubStrMatcher, SubStrOptions},
+    toktrie::{Recognizer, SpecialToken, TokTrie},
+    SimpleVob, TokenId,
+};
+use anyhow::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "cfg")]
+use crate::cfg::CfgParser;
+#[cfg(feature = "rx")]
+use crate::rx::RecRx;
+#[cfg(feature = "cfg")]
+use std::cell::RefCell;
+
+/// Token-level view of a recognizer, as used by the script controllers.
+pub trait Constraint {
+    /// Check if the generation can stop at the current point.
+    fn eos_allowed(&mut self) -> bool;
+    /// Check if the generation has to stop at the current point.
+    fn eos_forced(&mut self) -> bool;
+    fn token_allowed(&mut self, trie: &TokTrie, t: TokenId) -> bool;
+    /// Fails if the token is not allowed.
+    fn append_token(&mut self, trie: &TokTrie, t: TokenId) -> Result<()>;
+    fn allow_tokens(&mut self, trie: &TokTrie, logits: &mut SimpleVob);
+}
+
+impl<T: Recognizer> Constraint for T {
+    fn eos_allowed(&mut self) -> bool {
+        self.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    fn eos_forced(&mut self) -> bool {
+        self.special_allowed(SpecialToken::EndOfSentence)
+            && (0..=255).all(|b| !self.byte_allowed(b))
+    }
+
+    fn token_allowed(&mut self, trie: &TokTrie, t: TokenId) -> bool {
+        trie.token_allowed(self, t)
+    }
+
+    fn append_token(&mut self, trie: &TokTrie, t: TokenId) -> Result<()> {
+        trie.append_token(self, t)
+    }
+
+    fn allow_tokens(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        trie.compute_bias(self, logits)
+    }
+}
+
+/// Constraint over a recognizer that is also modified from elsewhere
+/// (e.g., a `DynamicLexerRec` where the script adds identifiers).
+pub struct SharedConstraint<T: Recognizer>(pub Arc<Mutex<T>>);
+
+impl<T: Recognizer> Constraint for SharedConstraint<T> {
+    fn eos_allowed(&mut self) -> bool {
+        self.0.lock().unwrap().eos_allowed()
+    }
+
+    fn eos_forced(&mut self) -> bool {
+        self.0.lock().unwrap().eos_forced()
+    }
+
+    fn token_allowed(&mut self, trie: &TokTrie, t: TokenId) -> bool {
+        self.0.lock().unwrap().token_allowed(trie, t)
+    }
+
+    fn append_token(&mut self, trie: &TokTrie, t: TokenId) -> Result<()> {
+        self.0.lock().unwrap().append_token(trie, t)
+    }
+
+    fn allow_tokens(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        self.0.lock().unwrap().allow_tokens(trie, logits)
+    }
+}
+
+/// Description of a constraint.
+/// Combinators are built from descriptions rather than from live recognizers,
+/// so that every use of a sub-constraint starts in its initial state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ConstraintSpec {
+    /// Any text.
+    Anything {},
+    /// Text matching the regex; implicitly anchored at the start and end.
+    Regex { rx: String },
+    /// Text matching the yacc-like grammar, see `CfgParser`.
+    Cfg { yacc: String },
+    /// Substring of one of the `sources`, possibly followed by `end_str`, see `SubStrMatcher`.
+    SubStr {
+        sources: Vec<String>,
+        end_str: String,
+        #[serde(default)]
+        options: SubStrOptions,
+    },
+    /// Any text up to and including the first occurrence of `stop`.
+    UpTo { stop: String },
+    /// Text matching each of the items in turn.
+    Seq { items: Vec<ConstraintSpec> },
+    /// Text matching any of the items (at most 64).
+    Or { items: Vec<ConstraintSpec> },
+    /// Text matching all of the items.
+    And { items: Vec<ConstraintSpec> },
+    /// Text matching `item` between `min` and `max` times (inclusive).
+    Repeat {
+        item: Box<ConstraintSpec>,
+        min: usize,
+        max: Option<usize>,
+    },
+    /// Bytes removed from the prompt by token healing, followed by text matching `item`.
+    Healed {
+        prefix: Vec<u8>,
+        item: Box<ConstraintSpec>,
+    },
+}
+
+impl ConstraintSpec {
+    /// Check the spec and build the parts that all its recognizers can share
+    /// (regex automata, grammars, substring indexes).
+    pub fn compile(&self) -> Result<Rc<CompiledConstraint>> {
+        let c = match self {
+            ConstraintSpec::Anything {} => CompiledConstraint::Anything,
+            #[cfg(feature = "rx")]
+            ConstraintSpec::Regex { rx } => {
+                CompiledConstraint::Regex(Rc::new(RecRx::from_rx(rx, None)?))
+            }
+            #[cfg(feature = "cfg")]
+            ConstraintSpec::Cfg { yacc } => CompiledConstraint::Cfg {
+                yacc: yacc.clone(),
+                first: RefCell::new(Some(CfgParser::from_yacc(yacc)?)),
+            },
+            ConstraintSpec::SubStr {
+                sources,
+                end_str,
+                options,
+            } => {
+                let sources = sources.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                CompiledConstraint::SubStr(Rc::new(SubStrMatcher::new_multi(
+                    &sources, end_str, *options,
+                )))
+            }
+            ConstraintSpec::UpTo { stop } => CompiledConstraint::UpTo(Rc::new(UpTo::new(stop))),
+            ConstraintSpec::Seq { items } => {
+                ensure!(!items.is_empty(), "Seq needs at least one item");
+                CompiledConstraint::Seq(compile_all(items)?)
+            }
+            ConstraintSpec::Or { items } => {
+                ensure!(!items.is_empty(), "Or needs at least one item");
+                ensure!(items.len() <= 64, "Or supports at most 64 items");
+                CompiledConstraint::Or(compile_all(items)?)
+            }
+            ConstraintSpec::And { items } => {
+                ensure!(!items.is_empty(), "And needs at least one item");
+                CompiledConstraint::And(compile_all(items)?)
+            }
+            ConstraintSpec::Repeat { item, min, max } => {
+                if let Some(max) = max {
+                    ensure!(*max > 0, "Repeat max must be positive");
+                    ensure!(min <= max, "Repeat min ({}) is above max ({})", min, max);
+                }
+                CompiledConstraint::Repeat {
+                    item: item.compile()?,
+                    min: *min,
+                    max: *max,
+                }
+            }
+            ConstraintSpec::Healed { prefix, item } => CompiledConstraint::Healed {
+                prefix: prefix.clone(),
+                item: item.compile()?,
+            },
+            #[allow(unreachable_patterns)]
+            _ => bail!("constraint not supported in this build: {:?}", self),
+        };
+        Ok(Rc::new(c))
+    }
+
+    pub fn build(&self) -> Result<BoxedRecognizer> {
+        Ok(self.compile()?.fresh())
+    }
+}
+
+fn compile_all(items: &[ConstraintSpec]) -> Result<Vec<Rc<CompiledConstraint>>> {
+    items.iter().map(|s| s.compile()).collect()
+}
+
+/// A checked `ConstraintSpec`; `fresh()` creates recognizers in their initial state.
+pub enum CompiledConstraint {
+    Anything,
+    #[cfg(feature = "rx")]
+    Regex(Rc<RecRx>),
+    #[cfg(feature = "cfg")]
+    Cfg {
+        yacc: String,
+        // the parser built when checking the spec, until the first fresh()
+        first: RefCell<Option<CfgParser>>,
+    },
+    SubStr(Rc<SubStrMatcher>),
+    UpTo(Rc<UpTo>),
+    Seq(Vec<Rc<CompiledConstraint>>),
+    Or(Vec<Rc<CompiledConstraint>>),
+    And(Vec<Rc<CompiledConstraint>>),
+    Repeat {
+        item: Rc<CompiledConstraint>,
+        min: usize,
+        max: Option<usize>,
+    },
+    Healed {
+        prefix: Vec<u8>,
+        item: Rc<CompiledConstraint>,
+    },
+}
+
+impl CompiledConstraint {
+    /// For `SubStr` constraints, find which source (and where in it) the generated `text` comes from.
+    pub fn substr_locate(&self, text: &[u8]) -> Result<Option<SubStrMatch>> {
+        match self {
+            CompiledConstraint::SubStr(m) => Ok(m.locate(text)),
+            _ => bail!("not a substring constraint"),
+        }
+    }
+
+    pub fn fresh(&self) -> BoxedRecognizer {
+        match self {
+            CompiledConstraint::Anything => {
+                BoxedRecognizer::new(StackRecognizer::from(AnythingGoes {}))
+            }
+            #[cfg(feature = "rx")]
+            CompiledConstraint::Regex(rx) => {
+                BoxedRecognizer::new(StackRecognizer::from(Shared(rx.clone())))
+            }
+            #[cfg(feature = "cfg")]
+            CompiledConstraint::Cfg { yacc, first } => match first.borrow_mut().take() {
+                Some(cfg) => BoxedRecognizer::new(cfg),
+                // the parser keeps its parse stacks, so it can't be shared;
+                // SeqRecognizer and RepeatRecognizer keep spare ones to limit this
+                None => match CfgParser::from_yacc(yacc) {
+                    Ok(cfg) => BoxedRecognizer::new(cfg),
+                    // it was built from the same source in compile(), so this doesn't happen
+                    Err(_) => BoxedRecognizer::new(Nothing {}),
+                },
+            },
+            CompiledConstraint::SubStr(m) => {
+                BoxedRecognizer::new(StackRecognizer::from(Shared(m.clone())))
+            }
+            CompiledConstraint::UpTo(u) => {
+                BoxedRecognizer::new(StackRecognizer::from(Shared(u.clone())))
+            }
+            CompiledConstraint::Seq(items) => {
+                BoxedRecognizer::new(SeqRecognizer::new(items.clone()))
+            }
+            CompiledConstraint::Or(items) => {
+                BoxedRecognizer::new(OrRecognizer::new(items.iter().map(|i| i.fresh()).collect()))
+            }
+            CompiledConstraint::And(items) => BoxedRecognizer::new(AndRecognizer::new(
+                items.iter().map(|i| i.fresh()).collect(),
+            )),
+            CompiledConstraint::Repeat { item, min, max } => {
+                BoxedRecognizer::new(RepeatRecognizer::new(item.clone(), *min, *max))
+            }
+            CompiledConstraint::Healed { prefix, item } => {
+                BoxedRecognizer::new(HealingRecognizer::new(prefix.clone(), item.fresh()))
+            }
+        }
+    }
+}
+
+// Functional recognizers are immutable, so fresh recognizers can share them.
+struct Shared<R>(Rc<R>);
+
+impl<S: Copy, R: FunctionalRecognizer<S>> FunctionalRecognizer<S> for Shared<R> {
+    fn initial(&self) -> S {
+        self.0.initial()
+    }
+
+    #[inline(always)]
+    fn try_append(&self, state: S, byte: u8) -> Option<S> {
+        self.0.try_append(state, byte)
+    }
+
+    #[inline(always)]
+    fn special_allowed(&self, state: S, tok: SpecialToken) -> bool {
+        self.0.special_allowed(state, tok)
+    }
+}
+
+#[cfg(feature = "cfg")]
+struct Nothing {}
+
+#[cfg(feature = "cfg")]
+impl Recognizer for Nothing {
+    fn pop_bytes(&mut self, _num: usize) {}
+    fn collapse(&mut self) {}
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        false
+    }
+    fn trie_finished(&mut self) {}
+    fn try_push_byte(&mut self, _byte: u8) -> bool {
+        false
+    }
+}
+
+/// Type-erased recognizer, so that combinators can be nested.
+pub struct BoxedRecognizer(Box<dyn Recognizer>);
+
+impl BoxedRecognizer {
+    pub fn new(rec: impl Recognizer + 'static) -> Self {
+        BoxedRecognizer(Box::new(rec))
+    }
+}
+
+impl Recognizer for BoxedRecognizer {
+    #[inline(always)]
+    fn pop_bytes(&mut self, num: usize) {
+        self.0.pop_bytes(num)
+    }
+
+    #[inline(always)]
+    fn collapse(&mut self) {
+        self.0.collapse()
+    }
+
+    #[inline(always)]
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.special_allowed(tok)
+    }
+
+    #[inline(always)]
+    fn trie_finished(&mut self) {
+        self.0.trie_finished()
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.0.try_push_byte(byte)
+    }
+}
+
+pub type UpToStackRecognizer = StackRecognizer<usize, UpTo>;
+
+/// Matches any text that doesn't contain `stop`, followed by `stop`.
+/// State is the length of the longest prefix of `stop` that the text ends with.
+pub struct UpTo {
+    stop: Vec<u8>,
+    // fail[i] is the length of the longest proper prefix of stop[..=i] that is also its suffix
+    fail: Vec<usize>,
+}
+
+impl UpTo {
+    pub fn new(stop: &str) -> Self {
+        let stop = stop.as_bytes().to_vec();
+        let mut fail = vec![0; stop.len()];
+        let mut k = 0;
+        for i in 1..stop.len() {
+            while k > 0 && stop[i] != stop[k] {
+                k = fail[k - 1];
+            }
+            if stop[i] == stop[k] {
+                k += 1;
+            }
+            fail[i] = k;
+        }
+        UpTo { stop, fail }
+    }
+
+    pub fn to_stack_recognizer(self) -> UpToStackRecognizer {
+        StackRecognizer::from(self)
+    }
+}
+
+impl FunctionalRecognizer<usize> for UpTo {
+    fn initial(&self) -> usize {
+        0
+    }
+
+    fn try_append(&self, state: usize, byte: u8) -> Option<usize> {
+        if self.stop.is_empty() {
+            return Some(0);
+        }
+        if state == self.stop.len() {
+            return None;
+        }
+        let mut k = state;
+        loop {
+            if self.stop[k] == byte {
+                return Some(k + 1);
+            }
+            if k == 0 {
+                return Some(0);
+            }
+            k = self.fail[k - 1];
+        }
+    }
+
+    fn special_allowed(&self, state: usize, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => state == self.stop.len(),
+            _ => false,
+        }
+    }
+}
+
+/// Positions of a `ChainRecognizer`; the text at each position is matched by one of its items.
+pub trait Chain {
+    /// Index of the item matching at `pos`.
+    fn item_at(&self, pos: usize) -> usize;
+    /// Whether there can be another position after `pos`.
+    fn has_next(&self, pos: usize) -> bool;
+    /// Whether the text can end when the item at `pos` ends.
+    fn can_end_after(&self, pos: usize) -> bool;
+    /// Whether the empty text matches.
+    fn empty_ok(&self) -> bool;
+}
+
+// at most this many positions (with their recognizers) are tracked at once;
+// more only happen when items can match the same text in many ways
+const MAX_THREADS: usize = 32;
+
+struct Thread {
+    pos: usize,
+    rec: BoxedRecognizer,
+}
+
+struct Frame {
+    // threads that took the byte
+    alive: Vec<usize>,
+    // threads started for the byte, and to be returned to spares when it's popped
+    spawned: Vec<usize>,
+}
+
+/// Matches a sequence of items, like an NFA: whenever the item at some position
+/// can end, the next position is tried too, each with its own recognizer.
+pub struct ChainRecognizer<C: Chain> {
+    chain: C,
+    items: Vec<Rc<CompiledConstraint>>,
+    // recognizers in initial state, for each item
+    spare: Vec<Vec<BoxedRecognizer>>,
+    // indexed by thread id; None if free
+    threads: Vec<Option<Thread>>,
+    free: Vec<usize>,
+    // threads alive before the first byte on the stack
+    base: Vec<usize>,
+    stack: Vec<Frame>,
+    // set once any byte was collapsed
+    started: bool,
+}
+
+// which items match the empty text; the recognizers are kept as spares
+fn check_empty(items: &[Rc<CompiledConstraint>]) -> (Vec<bool>, Vec<Vec<BoxedRecognizer>>) {
+    items
+        .iter()
+        .map(|item| {
+            let mut rec = item.fresh();
+            (rec.special_allowed(SpecialToken::EndOfSentence), vec![rec])
+        })
+        .unzip()
+}
+
+impl<C: Chain> ChainRecognizer<C> {
+    fn with_chain(
+        chain: C,
+        items: Vec<Rc<CompiledConstraint>>,
+        spare: Vec<Vec<BoxedRecognizer>>,
+    ) -> Self {
+        let mut r = ChainRecognizer {
+            chain,
+            items,
+            spare,
+            threads: Vec::new(),
+            free: Vec::new(),
+            base: Vec::new(),
+            stack: Vec::new(),
+            started: false,
+        };
+        let first = r.spawn(0);
+        r.base.push(first);
+        r
+    }
+
+    fn top(&self) -> &[usize] {
+        match self.stack.last() {
+            Some(f) => &f.alive,
+            None => &self.base,
+        }
+    }
+
+    fn thread(&mut self, id: usize) -> &mut Thread {
+        self.threads[id].as_mut().unwrap()
+    }
+
+    fn spawn(&mut self, pos: usize) -> usize {
+        let item = self.chain.item_at(pos);
+        let rec = match self.spare[item].pop() {
+            Some(rec) => rec,
+            None => self.items[item].fresh(),
+        };
+        let th = Some(Thread { pos, rec });
+        match self.free.pop() {
+            Some(id) => {
+                self.threads[id] = th;
+                id
+            }
+            None => {
+                self.threads.push(th);
+                self.threads.len() - 1
+            }
+        }
+    }
+
+    // the recognizer has to be in its initial state
+    fn release(&mut self, id: usize) {
+        let th = self.threads[id].take().unwrap();
+        self.spare[self.chain.item_at(th.pos)].push(th.rec);
+        self.free.push(id);
+    }
+
+    fn can_end(&mut self, id: usize) -> bool {
+        let pos = self.thread(id).pos;
+        self.chain.can_end_after(pos)
+            && self
+                .thread(id)
+                .rec
+                .special_allowed(SpecialToken::EndOfSentence)
+    }
+}
+
+impl<C: Chain> Recognizer for ChainRecognizer<C> {
+    fn pop_bytes(&mut self, num: usize) {
+        for _ in 0..num {
+            let frame = self.stack.pop().unwrap();
+            for &id in &frame.alive {
+                self.thread(id).rec.pop_bytes(1);
+            }
+            for id in frame.spawned {
+                self.release(id);
+            }
+        }
+    }
+
+    fn collapse(&mut self) {
+        let frame = match self.stack.pop() {
+            Some(f) => f,
+            None => return,
+        };
+        for &id in &frame.alive {
+            self.thread(id).rec.collapse();
+        }
+        // all the other threads are dead now
+        for (id, th) in self.threads.iter_mut().enumerate() {
+            if th.is_some() && !frame.alive.contains(&id) {
+                *th = None;
+                self.free.push(id);
+            }
+        }
+        self.base = frame.alive;
+        self.stack.clear();
+        self.started = true;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => {
+                if !self.started && self.stack.is_empty() && self.chain.empty_ok() {
+                    return true;
+                }
+                let ids = self.top().to_vec();
+                ids.into_iter().any(|id| self.can_end(id))
+            }
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.stack.len());
+        for id in self.base.clone() {
+            self.thread(id).rec.trie_finished();
+        }
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        // threads that may take the byte: current ones, and the following positions
+        // of the ones whose item can end here
+        let mut cand = self.top().to_vec();
+        let mut spawned: Vec<usize> = Vec::new();
+        let mut idx = 0;
+        while idx < cand.len() {
+            let id = cand[idx];
+            idx += 1;
+            let pos = self.thread(id).pos;
+            if !self.chain.has_next(pos) {
+                continue;
+            }
+            // a fresh thread for the same item would be the same as this one
+            let is_fresh = spawned.contains(&id);
+            if is_fresh && self.chain.item_at(pos + 1) == self.chain.item_at(pos) {
+                continue;
+            }
+            let dup = spawned
+                .iter()
+                .any(|&s| self.threads[s].as_ref().unwrap().pos == pos + 1);
+            if !dup
+                && self
+                    .thread(id)
+                    .rec
+                    .special_allowed(SpecialToken::EndOfSentence)
+            {
+                let next = self.spawn(pos + 1);
+                spawned.push(next);
+                cand.push(next);
+            }
+        }
+
+        let mut alive = Vec::new();
+        for &id in &cand {
+            if alive.len() < MAX_THREADS && self.thread(id).rec.try_push_byte(byte) {
+                alive.push(id);
+            }
+        }
+        let (spawned, unused): (Vec<usize>, Vec<usize>) =
+            spawned.into_iter().partition(|id| alive.contains(id));
+        for id in unused {
+            self.release(id);
+        }
+        if alive.is_empty() {
+            return false;
+        }
+        self.stack.push(Frame { alive, spawned });
+        true
+    }
+}
+
+pub struct SeqChain {
+    // rest_empty[i] is set when items i.. all match the empty text
+    rest_empty: Vec<bool>,
+}
+
+impl Chain for SeqChain {
+    fn item_at(&self, pos: usize) -> usize {
+        pos
+    }
+
+    fn has_next(&self, pos: usize) -> bool {
+        pos + 2 < self.rest_empty.len()
+    }
+
+    fn can_end_after(&self, pos: usize) -> bool {
+        self.rest_empty[pos + 1]
+    }
+
+    fn empty_ok(&self) -> bool {
+        self.rest_empty[0]
+    }
+}
+
+/// Matches the items one after another.
+pub type SeqRecognizer = ChainRecognizer<SeqChain>;
+
+impl SeqRecognizer {
+    pub fn new(items: Vec<Rc<CompiledConstraint>>) -> Self {
+        assert!(!items.is_empty());
+        let (empty, spare) = check_empty(&items);
+        let mut rest_empty = vec![true; items.len() + 1];
+        for i in (0..items.len()).rev() {
+            rest_empty[i] = empty[i] && rest_empty[i + 1];
+        }
+        ChainRecognizer::with_chain(SeqChain { rest_empty }, items, spare)
+    }
+}
+
+/// Matches text matched by any of the items.
+pub struct OrRecognizer {
+    items: Vec<BoxedRecognizer>,
+    // bitmask of items still alive after given byte
+    stack: Vec<u64>,
+    base: u64,
+}
+
+impl OrRecognizer {
+    pub fn new(items: Vec<BoxedRecognizer>) -> Self {
+        assert!(items.len() <= 64);
+        let base = if items.len() == 64 {
+            u64::MAX
+        } else {
+            (1u64 << items.len()) - 1
+        };
+        OrRecognizer {
+            items,
+            stack: Vec::new(),
+            base,
+        }
+    }
+
+    fn top(&self) -> u64 {
+        *self.stack.last().unwrap_or(&self.base)
+    }
+
+    fn alive(&self, mask: u64) -> impl Iterator<Item = usize> {
+        (0..self.items.len()).filter(move |i| mask & (1 << i) != 0)
+    }
+}
+
+impl Recognizer for OrRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        for _ in 0..num {
+            let mask = self.stack.pop().unwrap();
+            for i in self.alive(mask).collect::<Vec<_>>() {
+                self.items[i].pop_bytes(1);
+            }
+        }
+    }
+
+    fn collapse(&mut self) {
+        if self.stack.is_empty() {
+            return;
+        }
+        let mask = self.top();
+        for i in self.alive(mask).collect::<Vec<_>>() {
+            self.items[i].collapse();
+        }
+        self.base = mask;
+        self.stack.clear();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let mask = self.top();
+        for i in self.alive(mask).collect::<Vec<_>>() {
+            if self.items[i].special_allowed(tok) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.stack.len());
+        for i in self.alive(self.base).collect::<Vec<_>>() {
+            self.items[i].trie_finished();
+        }
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let mask = self.top();
+        let mut next = 0;
+        for i in self.alive(mask).collect::<Vec<_>>() {
+            if self.items[i].try_push_byte(byte) {
+                next |= 1 << i;
+            }
+        }
+        if next == 0 {
+            false
+        } else {
+            self.stack.push(next);
+            true
+        }
+    }
+}
+
+/// Matches text matched by all of the items.
+pub struct AndRecognizer {
+    items: Vec<BoxedRecognizer>,
+}
+
+impl AndRecognizer {
+    pub fn new(items: Vec<BoxedRecognizer>) -> Self {
+        AndRecognizer { items }
+    }
+}
+
+impl Recognizer for AndRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        for r in &mut self.items {
+            r.pop_bytes(num);
+        }
+    }
+
+    fn collapse(&mut self) {
+        for r in &mut self.items {
+            r.collapse();
+        }
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.items.iter_mut().all(|r| r.special_allowed(tok))
+    }
+
+    fn trie_finished(&mut self) {
+        for r in &mut self.items {
+            r.trie_finished();
+        }
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        for i in 0..self.items.len() {
+            if !self.items[i].try_push_byte(byte) {
+                for r in &mut self.items[0..i] {
+                    r.pop_bytes(1);
+                }
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct RepeatChain {
+    min: usize,
+    max: Option<usize>,
+    item_empty: bool,
+}
+
+impl Chain for RepeatChain {
+    fn item_at(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn has_next(&self, pos: usize) -> bool {
+        self.max.map_or(true, |m| pos + 1 < m)
+    }
+
+    fn can_end_after(&self, pos: usize) -> bool {
+        // repetitions up to min can be empty, if the item allows it
+        pos + 1 >= self.min || self.item_empty
+    }
+
+    fn empty_ok(&self) -> bool {
+        self.min == 0 || self.item_empty
+    }
+}
+
+/// Matches text matched by `item` repeated between `min` and `max` times.
+pub type RepeatRecognizer = ChainRecognizer<RepeatChain>;
+
+impl RepeatRecognizer {
+    pub fn new(item: Rc<CompiledConstraint>, min: usize, max: Option<usize>) -> Self {
+        let items = vec![item];
+        let (empty, spare) = check_empty(&items);
+        let chain = RepeatChain {
+            min,
+            max,
+            item_empty: empty[0],
+        };
+        ChainRecognizer::with_chain(chain, items, spare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "rx")]
+    fn rx(rx: &str) -> ConstraintSpec {
+        ConstraintSpec::Regex { rx: rx.to_string() }
+    }
+
+    fn up_to(stop: &str) -> ConstraintSpec {
+        ConstraintSpec::UpTo {
+            stop: stop.to_string(),
+        }
+    }
+
+    fn repeat(item: ConstraintSpec, min: usize, max: Option<usize>) -> ConstraintSpec {
+        ConstraintSpec::Repeat {
+            item: Box::new(item),
+            min,
+            max,
+        }
+    }
+
+    // push the bytes one by one, collapsing after each, as when appending tokens
+    fn matches(spec: &ConstraintSpec, text: &str) -> bool {
+        let mut rec = spec.build().unwrap();
+        for &b in text.as_bytes() {
+            if !rec.try_push_byte(b) {
+                return false;
+            }
+            rec.collapse();
+        }
+        rec.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    fn check(spec: &ConstraintSpec, ok: &[&str], bad: &[&str]) {
+        for text in ok {
+            assert!(matches(spec, text), "{:?} should match {:?}", spec, text);
+        }
+        for text in bad {
+            assert!(
+                !matches(spec, text),
+                "{:?} shouldn't match {:?}",
+                spec,
+                text
+            );
+        }
+    }
+
+    #[cfg(feature = "rx")]
+    #[test]
+    fn seq() {
+        let spec = ConstraintSpec::Seq {
+            items: vec![rx("a*"), rx("ab")],
+        };
+        check(
+            &spec,
+            &["ab", "aab", "aaaab"],
+            &["", "a", "aa", "b", "aabb"],
+        );
+
+        let spec = ConstraintSpec::Seq {
+            items: vec![rx("a*"), rx("b*"), rx("c")],
+        };
+        check(&spec, &["c", "ac", "bc", "aabbc"], &["", "ab", "bac", "cc"]);
+
+        let spec = ConstraintSpec::Seq {
+            items: vec![rx("a*"), rx("b*")],
+        };
+        check(&spec, &["", "a", "b", "aabb"], &["ba", "c"]);
+    }
+
+    #[cfg(feature = "rx")]
+    #[test]
+    fn repeat_counts() {
+        let spec = repeat(rx("ab"), 1, Some(2));
+        check(&spec, &["ab", "abab"], &["", "a", "aba", "ababab"]);
+
+        // needs to try both splits of "aaa"
+        let spec = repeat(rx("a|aa"), 2, Some(2));
+        check(&spec, &["aa", "aaa", "aaaa"], &["a", "aaaaa"]);
+
+        let spec = repeat(rx("x"), 0, None);
+        check(&spec, &["", "x", "xxxxxxxx"], &["y", "xxy"]);
+    }
+
+    #[cfg(feature = "rx")]
+    #[test]
+    fn repeat_empty_item() {
+        let spec = repeat(rx("a*"), 3, None);
+        check(&spec, &["", "a", "aaaa"], &["b"]);
+
+        let spec = repeat(rx("a?b?"), 2, Some(2));
+        check(&spec, &["", "ab", "abab", "ba", "aa"], &["ababa", "bb_"]);
+    }
+
+    #[test]
+    fn or_and() {
+        let spec = ConstraintSpec::Or {
+            items: vec![up_to("x"), up_to("yz")],
+        };
+        check(
+            &spec,
+            &["x", "abx", "yz", "ayz", "xyz"],
+            &["", "ab", "xa", "yza"],
+        );
+
+        let spec = ConstraintSpec::And {
+            items: vec![up_to("x"), up_to("ax")],
+        };
+        check(&spec, &["ax", "bax"], &["", "bx", "axax"]);
+
+        let spec = repeat(up_to("a"), 2, Some(2));
+        check(&spec, &["aa", "baba"], &["", "a", "aaa", "bab"]);
+    }
+
+    #[test]
+    fn substr() {
+        let spec = ConstraintSpec::SubStr {
+            sources: vec!["hello big world".to_string()],
+            end_str: String::new(),
+            options: SubStrOptions::default(),
+        };
+        check(&spec, &["hello", "big world"], &["goodbye", "big big"]);
+    }
+
+    #[test]
+    fn substr_locate() {
+        let spec = ConstraintSpec::SubStr {
+            sources: vec!["one two".to_string(), "three four".to_string()],
+            end_str: String::new(),
+            options: SubStrOptions::default(),
+        };
+        let compiled = spec.compile().unwrap();
+        let m = compiled.substr_locate(b"four").unwrap();
+        assert_eq!(
+            m,
+            Some(SubStrMatch {
+                source_idx: 1,
+                start: 6,
+                end: 10
+            })
+        );
+        assert_eq!(compiled.substr_locate(b"five").unwrap(), None);
+        assert!(up_to("x").compile().unwrap().substr_locate(b"x").is_err());
+    }
+
+    #[cfg(feature = "rx")]
+    #[test]
+    fn push_pop() {
+        let spec = ConstraintSpec::Seq {
+            items: vec![rx("a*"), rx("ab")],
+        };
+        let mut rec = spec.build().unwrap();
+        assert!(rec.try_push_byte(b'a'));
+        assert!(rec.try_push_byte(b'a'));
+        assert!(!rec.special_allowed(SpecialToken::EndOfSentence));
+        rec.pop_bytes(1);
+        assert!(rec.try_push_byte(b'b'));
+        assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+        assert!(!rec.try_push_byte(b'b'));
+        rec.trie_finished();
+
+        // a "token" was appended
+        assert!(rec.try_push_byte(b'a'));
+        rec.collapse();
+        assert!(rec.try_push_byte(b'a'));
+        assert!(rec.try_push_byte(b'b'));
+        rec.pop_bytes(2);
+        assert!(rec.try_push_byte(b'b'));
+        assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+        rec.collapse();
+        assert!(!rec.try_push_byte(b'a'));
+        assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+    }
+
+    #[test]
+    fn errors() {
+        let spec = repeat(up_to("x"), 3, Some(2));
+        assert!(spec.build().is_err());
+        let spec = ConstraintSpec::Seq { items: vec![] };
+        assert!(spec.build().is_err());
+        #[cfg(feature = "rx")]
+        {
+            let spec = repeat(rx("("), 0, None);
+            assert!(spec.compile().is_err());
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     recognizer::{FunctionalRecognizer, StackRecognizer},
-    toktrie::SpecialToken,
+    toktrie::{Recognizer, SpecialToken},
     SimpleVob,
 };
 
@@ -14,7 +14,14 @@ impl NodeId {
 
 #[derive(Debug, Default, Clone)]
 pub struct NodeData {
-    pub is_terminal: bool,
+    /// How many times the word ending at this node was added (and not removed).
+    pub num_words: u32,
+}
+
+impl NodeData {
+    pub fn is_terminal(&self) -> bool {
+        self.num_words > 0
+    }
 }
 
 enum TrieNode {
@@ -62,6 +69,10 @@ impl TrieNode {
     }
 }
 
+fn child_or_null(trie: &Trie, node_id: NodeId, byte: u8) -> NodeId {
+    trie.child_at(node_id, byte).unwrap_or(NodeId::NULL)
+}
+
 pub struct Trie {
     nodes: Vec<TrieNode>,
 }
@@ -133,7 +144,9 @@ impl Trie {
         Some(node_id)
     }
 
-    pub fn add(&mut self, word: &[u8]) {
+    /// Add the word, and return the node where it ends.
+    /// Words are counted, so adding a word twice requires removing it twice.
+    pub fn add(&mut self, word: &[u8]) -> NodeId {
         let mut node_id = NodeId::ROOT;
         for &byte in word {
             let new_node_id = NodeId(self.nodes.len() as u32);
@@ -166,29 +179,54 @@ impl Trie {
             }
         }
 
-        self.node_mut(node_id).data_mut().is_terminal = true;
+        self.node_mut(node_id).data_mut().num_words += 1;
+        node_id
+    }
+
+    /// Undo `add()` that returned given node.
+    pub fn remove(&mut self, node_id: NodeId) {
+        let data = self.node_mut(node_id).data_mut();
+        assert!(data.num_words > 0);
+        data.num_words -= 1;
     }
 }
 
+/// Only allows identifiers from a set that is extended as the generation goes.
+/// Identifiers are added to the current scope; when a scope is popped,
+/// identifiers added in it are no longer allowed (unless also present in an outer scope).
+/// Declaration rules (like `"let "`) make the identifier that follows them declared
+/// in the current scope, see `DynamicLexerRec`.
 pub struct DynamicLexer {
     trie: Trie,
     id_start: SimpleVob,
     id_body: SimpleVob,
+    // words added in each scope; the first one is the global scope
+    scopes: Vec<Vec<NodeId>>,
+    // prefixes after which the next identifier is being declared
+    rules: Trie,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct DState {
+    // position in `trie`; when capturing, NULL if the identifier is not a known word
     node_id: NodeId,
+    // position in `rules`; NULL when no rule can match (inside of a word)
+    rule_node: NodeId,
+    // inside of an identifier being declared
+    capturing: bool,
+    // the previous bytes were an identifier being declared
+    declared: bool,
 }
 
 impl DState {
     const ROOT: DState = DState {
         node_id: NodeId::ROOT,
+        rule_node: NodeId::ROOT,
+        capturing: false,
+        declared: false,
     };
 }
 
-pub type DynamicLexerRec = StackRecognizer<DState, DynamicLexer>;
-
 impl DynamicLexer {
     pub fn new(additional_id_chars: &Vec<char>) -> Self {
         let mut id_start = SimpleVob::alloc(0x100);
@@ -213,54 +251,320 @@ impl DynamicLexer {
             trie: Trie::new(),
             id_start,
             id_body,
+            scopes: vec![vec![]],
+            rules: Trie::new(),
         }
     }
 
+    /// The resulting recognizer doesn't track declarations; use `to_recognizer()` for that.
     pub fn to_stack_recognizer(self) -> StackRecognizer<DState, DynamicLexer> {
         StackRecognizer::from(self)
     }
 
+    pub fn to_recognizer(self) -> DynamicLexerRec {
+        DynamicLexerRec::new(self)
+    }
+
+    /// Allow the word in the current scope.
     pub fn add(&mut self, word: &[u8]) {
-        self.trie.add(word);
+        let node_id = self.trie.add(word);
+        self.scopes.last_mut().unwrap().push(node_id);
     }
-}
 
-impl FunctionalRecognizer<DState> for DynamicLexer {
-    fn initial(&self) -> DState {
-        DState::ROOT
+    pub fn push_scope(&mut self) {
+        self.scopes.push(vec![]);
     }
 
-    fn try_append(&self, state: DState, byte: u8) -> Option<DState> {
-        if state.node_id == NodeId::ROOT {
+    /// Remove words added in the innermost scope.
+    /// Returns false (and does nothing) when only the global scope is left.
+    pub fn pop_scope(&mut self) -> bool {
+        if self.scopes.len() <= 1 {
+            return false;
+        }
+        for node_id in self.scopes.pop().unwrap() {
+            self.trie.remove(node_id);
+        }
+        true
+    }
+
+    /// Number of scopes, including the global one.
+    pub fn num_scopes(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// After `prefix` is generated (starting at a word boundary), the following identifier
+    /// is declared in the current scope.
+    /// For example, with `"let "` and `"let mut "` the lexer allows `let foo = 1; foo + 1`.
+    /// Keywords in the prefix still need to be allowed with `add()`.
+    /// Any number of additional whitespace characters is allowed between the prefix and the identifier.
+    pub fn add_declaration_rule(&mut self, prefix: &[u8]) {
+        self.rules.add(prefix);
+    }
+
+    fn next_rule_node(&self, rule_node: NodeId, byte: u8) -> NodeId {
+        match self.rules.child_at(rule_node, byte) {
+            Some(n) => n,
+            None if self.id_body.is_allowed(byte as u32) => NodeId::NULL,
+            None => NodeId::ROOT,
+        }
+    }
+
+    fn try_append_word(&self, state: DState, byte: u8) -> Option<DState> {
+        let rule_node = self.next_rule_node(state.rule_node, byte);
+        let node_id = if state.node_id == NodeId::ROOT {
             if self.id_start.is_allowed(byte as u32) {
-                match self.trie.child_at(state.node_id, byte) {
-                    Some(node_id) => Some(DState { node_id }),
-                    None => None,
-                }
+                self.trie.child_at(state.node_id, byte)?
             } else {
-                Some(state)
+                state.node_id
             }
         } else {
             if self.id_body.is_allowed(byte as u32) {
-                match self.trie.child_at(state.node_id, byte) {
-                    Some(node_id) => Some(DState { node_id }),
-                    None => None,
-                }
+                self.trie.child_at(state.node_id, byte)?
             } else {
-                if self.trie.node_data(state.node_id).is_terminal {
-                    Some(DState::ROOT)
+                if self.trie.node_data(state.node_id).is_terminal() {
+                    NodeId::ROOT
                 } else {
-                    None
+                    return None;
+                }
+            }
+        };
+        Some(DState {
+            node_id,
+            rule_node,
+            capturing: false,
+            declared: false,
+        })
+    }
+}
+
+impl FunctionalRecognizer<DState> for DynamicLexer {
+    fn initial(&self) -> DState {
+        DState::ROOT
+    }
+
+    fn try_append(&self, state: DState, byte: u8) -> Option<DState> {
+        if state.capturing {
+            if self.id_body.is_allowed(byte as u32) {
+                return Some(DState {
+                    node_id: child_or_null(&self.trie, state.node_id, byte),
+                    rule_node: child_or_null(&self.rules, state.rule_node, byte),
+                    ..state
+                });
+            }
+            // the identifier was a keyword of a longer rule (like "mut" in "let mut ")
+            if let Some(rule_node) = self.rules.child_at(state.rule_node, byte) {
+                if self.trie.node_data(state.node_id).is_terminal() {
+                    return Some(DState {
+                        rule_node,
+                        ..DState::ROOT
+                    });
                 }
             }
+            // the declared identifier ends here
+            let mut next = self.try_append_word(DState::ROOT, byte)?;
+            next.declared = true;
+            return Some(next);
+        }
+
+        let rule_matched = self.rules.node_data(state.rule_node).is_terminal();
+        if rule_matched && state.node_id == NodeId::ROOT && self.id_start.is_allowed(byte as u32) {
+            Some(DState {
+                node_id: child_or_null(&self.trie, NodeId::ROOT, byte),
+                rule_node: child_or_null(&self.rules, state.rule_node, byte),
+                capturing: true,
+                declared: false,
+            })
+        } else if rule_matched
+            && state.node_id == NodeId::ROOT
+            && self.rules.child_at(state.rule_node, byte).is_none()
+            && (byte as char).is_ascii_whitespace()
+        {
+            Some(state)
+        } else {
+            self.try_append_word(state, byte)
         }
     }
 
     fn special_allowed(&self, state: DState, tok: SpecialToken) -> bool {
         if tok == SpecialToken::EndOfSentence {
-            self.trie.node_data(state.node_id).is_terminal
+            state.capturing || self.trie.node_data(state.node_id).is_terminal()
         } else {
             false
         }
     }
 }
+
+/// Recognizer for `DynamicLexer` that declares identifiers according to the declaration rules.
+/// Identifiers are declared once the tokens containing them are committed (`collapse()`),
+/// so they can be used starting from the next token.
+pub struct DynamicLexerRec {
+    lexer: DynamicLexer,
+    // state after each byte since the last collapse() together with the byte;
+    // the first entry is the state at the last collapse()
+    stack: Vec<(DState, u8)>,
+    // committed bytes of the identifier being declared
+    pending_decl: Vec<u8>,
+}
+
+impl DynamicLexerRec {
+    pub fn new(lexer: DynamicLexer) -> Self {
+        DynamicLexerRec {
+            lexer,
+            stack: vec![(DState::ROOT, 0)],
+            pending_decl: vec![],
+        }
+    }
+
+    pub fn lexer(&self) -> &DynamicLexer {
+        &self.lexer
+    }
+
+    pub fn lexer_mut(&mut self) -> &mut DynamicLexer {
+        &mut self.lexer
+    }
+
+    fn top(&self) -> DState {
+        self.stack.last().unwrap().0
+    }
+}
+
+impl Recognizer for DynamicLexerRec {
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.truncate(self.stack.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        for i in 1..self.stack.len() {
+            let (prev, _) = self.stack[i - 1];
+            let (state, byte) = self.stack[i];
+            if state.capturing {
+                self.pending_decl.push(byte);
+            } else if prev.capturing {
+                let word = std::mem::take(&mut self.pending_decl);
+                if state.declared {
+                    self.lexer.add(&word);
+                }
+            }
+        }
+        let top = self.top();
+        self.stack.clear();
+        self.stack.push((top, 0));
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.lexer.special_allowed(self.top(), tok)
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.stack.len() == 1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        match self.lexer.try_append(self.top(), byte) {
+            Some(state) => {
+                self.stack.push((state, byte));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_lexer(words: &[&str], rules: &[&str]) -> DynamicLexer {
+        let mut lexer = DynamicLexer::new(&vec![]);
+        for w in words {
+            lexer.add(w.as_bytes());
+        }
+        for r in rules {
+            lexer.add_declaration_rule(r.as_bytes());
+        }
+        lexer
+    }
+
+    fn accepts(lexer: &DynamicLexer, text: &str) -> bool {
+        let mut state = lexer.initial();
+        for &b in text.as_bytes() {
+            match lexer.try_append(state, b) {
+                Some(s) => state = s,
+                None => return false,
+            }
+        }
+        lexer.special_allowed(state, SpecialToken::EndOfSentence)
+    }
+
+    // push the bytes one by one, collapsing after each, as when appending tokens
+    fn push_str(rec: &mut DynamicLexerRec, text: &str) -> bool {
+        for &b in text.as_bytes() {
+            if !rec.try_push_byte(b) {
+                return false;
+            }
+            rec.collapse();
+        }
+        true
+    }
+
+    #[test]
+    fn scopes() {
+        let mut lexer = mk_lexer(&["foo"], &[]);
+        assert_eq!(lexer.num_scopes(), 1);
+        assert!(!lexer.pop_scope());
+        lexer.push_scope();
+        lexer.add(b"bar");
+        assert_eq!(lexer.num_scopes(), 2);
+        assert!(accepts(&lexer, "foo bar"));
+        assert!(lexer.pop_scope());
+        assert_eq!(lexer.num_scopes(), 1);
+        assert!(accepts(&lexer, "foo"));
+        assert!(!accepts(&lexer, "bar"));
+        assert!(!accepts(&lexer, "foo bar"));
+    }
+
+    #[test]
+    fn nested_scopes() {
+        let mut lexer = mk_lexer(&["x"], &[]);
+        lexer.push_scope();
+        lexer.add(b"x");
+        lexer.add(b"y");
+        lexer.push_scope();
+        lexer.add(b"z");
+        assert!(accepts(&lexer, "x y z"));
+        assert!(lexer.pop_scope());
+        assert!(!accepts(&lexer, "z"));
+        assert!(accepts(&lexer, "x y"));
+        assert!(lexer.pop_scope());
+        // still there from the global scope
+        assert!(accepts(&lexer, "x"));
+        assert!(!accepts(&lexer, "y"));
+    }
+
+    #[test]
+    fn declarations() {
+        let mut rec = mk_lexer(&["let"], &["let "]).to_recognizer();
+        assert!(push_str(&mut rec, "let foo foo"));
+        assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+        assert!(!push_str(&mut rec, " bar"));
+
+        // only declared once committed
+        let mut rec = mk_lexer(&["let"], &["let "]).to_recognizer();
+        assert!("let foo ".bytes().all(|b| rec.try_push_byte(b)));
+        assert!(!rec.try_push_byte(b'f'));
+    }
+
+    #[test]
+    fn declarations_in_scope() {
+        let mut rec = mk_lexer(&["let"], &["let "]).to_recognizer();
+        rec.lexer_mut().push_scope();
+        assert!(push_str(&mut rec, "let foo foo "));
+        assert!(rec.lexer_mut().pop_scope());
+        // foo was declared in the popped scope
+        assert!(push_str(&mut rec, "foo"));
+        assert!(!rec.special_allowed(SpecialToken::EndOfSentence));
+        assert!(!push_str(&mut rec, " "));
+        assert!(accepts(rec.lexer(), "let"));
+        assert!(!accepts(rec.lexer(), "foo"));
+    }
+}
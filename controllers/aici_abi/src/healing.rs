@@ -0,0 +1,129 @@
+// Token healing.
+//
+// When the prompt ends in the middle of what would normally be a single token
+// (e.g., `"url": "http` instead of `"url": "https`), the model is forced to continue
+// from an unnatural token boundary.
+// Token healing removes the last token(s) of the prompt, and then requires the
+// generated text to start with the removed bytes, letting the model pick a better token.
+
+use crate::{
+    toktrie::{Recognizer, SpecialToken, TokTrie},
+    InitPromptArg, InitPromptResult, TokenId,
+};
+
+/// Maximum number of prompt tokens removed by `heal_prompt()`.
+pub const MAX_HEALING_TOKENS: usize = 3;
+
+/// Remove up to `max_tokens` tokens from the end of `prompt`, as long as
+/// the removed bytes are a proper prefix of some token.
+/// Returns the removed bytes, which the generation should start with.
+pub fn heal_prompt(trie: &TokTrie, prompt: &mut Vec<TokenId>, max_tokens: usize) -> Vec<u8> {
+    let eos = trie.special_token(SpecialToken::EndOfSentence);
+    let mut prefix = Vec::new();
+    for _ in 0..max_tokens {
+        let t = match prompt.last() {
+            Some(&t) if t != eos => t,
+            _ => break,
+        };
+        let tbytes = trie.token(t);
+        if tbytes.is_empty() {
+            break;
+        }
+        let mut candidate = tbytes.to_vec();
+        candidate.extend_from_slice(&prefix);
+        let has_extensions = match trie.child_at_bytes(trie.root(), &candidate) {
+            Some(n) => n.subtree_size() > 1,
+            None => false,
+        };
+        if !has_extensions {
+            break;
+        }
+        prompt.pop();
+        prefix = candidate;
+    }
+    prefix
+}
+
+impl InitPromptResult {
+    /// Like `from_arg()`, but with the prompt healed; see `heal_prompt()`.
+    /// Returns the bytes that were removed from the prompt.
+    pub fn healed(arg: InitPromptArg, trie: &TokTrie) -> (Self, Vec<u8>) {
+        let mut prompt = arg.prompt;
+        let prefix = heal_prompt(trie, &mut prompt, MAX_HEALING_TOKENS);
+        (InitPromptResult { prompt }, prefix)
+    }
+}
+
+/// Forces the bytes removed by token healing, and then continues with `inner`.
+/// The inner recognizer doesn't see the healed bytes.
+pub struct HealingRecognizer<R: Recognizer> {
+    prefix: Vec<u8>,
+    // number of prefix bytes matched after each byte; the first entry is for no bytes
+    stack: Vec<usize>,
+    inner: R,
+}
+
+impl<R: Recognizer> HealingRecognizer<R> {
+    pub fn new(prefix: Vec<u8>, inner: R) -> Self {
+        HealingRecognizer {
+            prefix,
+            stack: vec![0],
+            inner,
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    fn top(&self) -> usize {
+        *self.stack.last().unwrap()
+    }
+}
+
+impl<R: Recognizer> Recognizer for HealingRecognizer<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        for _ in 0..num {
+            self.stack.pop();
+            // the byte was passed to inner, if the prefix was already matched before it
+            if self.top() == self.prefix.len() {
+                self.inner.pop_bytes(1);
+            }
+        }
+    }
+
+    fn collapse(&mut self) {
+        let pos = self.top();
+        if pos == self.prefix.len() {
+            self.inner.collapse();
+        }
+        self.stack.clear();
+        self.stack.push(pos);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.top() == self.prefix.len() && self.inner.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.stack.len() - 1);
+        self.inner.trie_finished();
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let pos = self.top();
+        if pos < self.prefix.len() {
+            if self.prefix[pos] == byte {
+                self.stack.push(pos + 1);
+                true
+            } else {
+                false
+            }
+        } else if self.inner.try_push_byte(byte) {
+            self.stack.push(pos);
+            true
+        } else {
+            false
+        }
+    }
+}
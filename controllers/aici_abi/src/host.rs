@@ -43,6 +43,21 @@ extern "C" {
     // Stop the program - any error info is assumed to have been printed already.
     // Backtraces will be limited.
     fn aici_host_stop();
+
+    // Report details of an exception in script controllers, before calling aici_host_stop().
+    // The argument is JSON serialization of ScriptError.
+    fn aici_host_script_error(err: *const u8, err_size: u32);
+
+    // Emit a structured event; these are returned to the client separately from the logs.
+    // The data is JSON.
+    fn aici_host_emit(kind: *const u8, kind_size: u32, data: *const u8, data_size: u32);
+
+    // Stop given sequences of the current request (typically from fork_group) after this step.
+    fn aici_host_stop_seqs(ids: *const u32, num_ids: u32);
+
+    // Start an independent generation after this step. The argument is JSON serialization
+    // of SubGeneration.
+    fn aici_host_spawn_generation(gen: *const u8, gen_size: u32);
 }
 
 // TODO: add <T>
@@ -114,6 +129,10 @@ pub trait HostInterface {
     fn self_seq_id(&self) -> SeqId;
     fn eos_token(&self) -> TokenId;
     fn get_config(&self, name: &str) -> i32;
+    fn script_error(&self, err: &ScriptError);
+    fn emit(&self, kind: &str, data: &[u8]);
+    fn stop_seqs(&self, ids: &[SeqId]);
+    fn spawn_generation(&self, gen: &SubGeneration);
     fn stop(&self) -> !;
 }
 
@@ -151,6 +170,32 @@ impl HostInterface for WasmHost {
         serde_json::from_slice(&resp_bytes).unwrap()
     }
 
+    fn script_error(&self, err: &ScriptError) {
+        let err_bytes = serde_json::to_vec(err).unwrap();
+        unsafe { aici_host_script_error(err_bytes.as_ptr(), err_bytes.len() as u32) };
+    }
+
+    fn emit(&self, kind: &str, data: &[u8]) {
+        unsafe {
+            aici_host_emit(
+                kind.as_ptr(),
+                kind.len() as u32,
+                data.as_ptr(),
+                data.len() as u32,
+            )
+        };
+    }
+
+    fn stop_seqs(&self, ids: &[SeqId]) {
+        // SeqId is repr(transparent)
+        unsafe { aici_host_stop_seqs(ids.as_ptr() as *const u32, ids.len() as u32) };
+    }
+
+    fn spawn_generation(&self, gen: &SubGeneration) {
+        let gen_bytes = serde_json::to_vec(gen).unwrap();
+        unsafe { aici_host_spawn_generation(gen_bytes.as_ptr(), gen_bytes.len() as u32) };
+    }
+
     fn stop(&self) -> ! {
         unsafe { aici_host_stop() };
         panic!("didn't stop")
@@ -229,6 +274,60 @@ pub fn get_config(name: &str) -> i32 {
     get_host().get_config(name)
 }
 
+/// Exception thrown by the script in a script controller (like pyctrl or jsctrl).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScriptError {
+    /// Exception type, like "TypeError".
+    pub kind: String,
+    pub message: String,
+    /// Location of the innermost frame (1-based line and column), if known.
+    /// For jsctrl, this is after applying the source map, if any.
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    /// Full stack trace, as printed to the logs.
+    pub stack: String,
+}
+
+/// Structured output of the controller, see emit().
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    /// Controller-specific, like "progress" or "capture".
+    pub kind: String,
+    pub data: serde_json::Value,
+}
+
+/// Auxiliary generation, independent of the current sequence, see spawn_generation().
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubGeneration {
+    /// Prompt of the child generation; it doesn't see the current sequence.
+    pub prompt: String,
+    /// Module ID or tag of the child's controller; defaults to the one of the parent.
+    #[serde(default)]
+    pub controller: Option<String>,
+    #[serde(default)]
+    pub controller_arg: String,
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub temperature: f32,
+    /// Request-scoped variable, set to the generated text once the child finishes.
+    pub result_var: String,
+    /// Request-scoped variable, set just before `result_var` to the error message
+    /// if the child failed or couldn't be started, and to an empty string otherwise.
+    /// Defaults to `result_var` followed by `.error`.
+    #[serde(default)]
+    pub error_var: Option<String>,
+}
+
+impl SubGeneration {
+    /// Name of the variable holding the error, see `error_var`.
+    pub fn error_var_name(&self) -> String {
+        self.error_var
+            .clone()
+            .unwrap_or_else(|| format!("{}.error", self.result_var))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageOp {
     Set,
@@ -266,10 +365,46 @@ pub mod hex_string {
     }
 }
 
+/// Where variables are stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum StorageScope {
+    /// Shared between forks of the current request; discarded when the request ends.
+    #[default]
+    Request,
+    /// Persistent across requests, private to the user who made the request.
+    User { namespace: String },
+    /// Persistent across requests, shared by all users.
+    Global { namespace: String },
+}
+
+impl std::str::FromStr for StorageScope {
+    type Err = String;
+
+    /// Parse "request", "user:<namespace>", or "global:<namespace>".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "request" => Ok(StorageScope::Request),
+            Some(("user", ns)) => Ok(StorageScope::User {
+                namespace: ns.to_string(),
+            }),
+            Some(("global", ns)) => Ok(StorageScope::Global {
+                namespace: ns.to_string(),
+            }),
+            _ => Err(format!(
+                "invalid storage scope {s:?}; expecting request, user:<namespace>, or global:<namespace>"
+            )),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageCmd {
     /// Read variable. Returns StorageResp::ReadVar or StorageResp::VariableMissing.
-    ReadVar { name: String },
+    ReadVar {
+        name: String,
+        #[serde(default)]
+        scope: StorageScope,
+    },
 
     /// Write variable.
     /// If `when_version_is == None`, always writes the variable and returns StorageResp::WriteVar.
@@ -283,9 +418,84 @@ pub enum StorageCmd {
         value: Vec<u8>,
         op: StorageOp,
         when_version_is: Option<u64>,
+        #[serde(default)]
+        scope: StorageScope,
+    },
+
+    /// Delete variable. Returns StorageResp::DeleteVar or StorageResp::VariableMissing.
+    DeleteVar {
+        name: String,
+        #[serde(default)]
+        scope: StorageScope,
+    },
+
+    /// List names of variables starting with `prefix`. Returns StorageResp::ListVars.
+    ListVars {
+        prefix: String,
+        #[serde(default)]
+        scope: StorageScope,
+    },
+
+    /// Atomically add `delta` to the variable, which holds a decimal integer (unset is 0).
+    /// Returns StorageResp::IncrementVar, or StorageResp::Error if the value is not an integer.
+    IncrementVar {
+        name: String,
+        delta: i64,
+        #[serde(default)]
+        scope: StorageScope,
+    },
+
+    /// Watch variable for changes.
+    /// If the variable is still at `version` (`None` meaning unset), returns StorageResp::Watching,
+    /// and if mid_process() then returns a noop, the sequence is suspended
+    /// (not scheduled and not passed to mid_process()) until the variable changes.
+    /// Otherwise, returns either StorageResp::ReadVar or StorageResp::VariableMissing
+    /// just like ReadVar would.
+    WatchVar {
+        name: String,
+        version: Option<u64>,
+        #[serde(default)]
+        scope: StorageScope,
     },
 }
 
+impl StorageCmd {
+    /// Variable name; the prefix for ListVars.
+    pub fn name(&self) -> &str {
+        match self {
+            StorageCmd::ReadVar { name, .. } => name,
+            StorageCmd::WriteVar { name, .. } => name,
+            StorageCmd::DeleteVar { name, .. } => name,
+            StorageCmd::ListVars { prefix, .. } => prefix,
+            StorageCmd::IncrementVar { name, .. } => name,
+            StorageCmd::WatchVar { name, .. } => name,
+        }
+    }
+
+    pub fn scope(&self) -> &StorageScope {
+        match self {
+            StorageCmd::ReadVar { scope, .. } => scope,
+            StorageCmd::WriteVar { scope, .. } => scope,
+            StorageCmd::DeleteVar { scope, .. } => scope,
+            StorageCmd::ListVars { scope, .. } => scope,
+            StorageCmd::IncrementVar { scope, .. } => scope,
+            StorageCmd::WatchVar { scope, .. } => scope,
+        }
+    }
+
+    /// Whether the command can modify variables.
+    pub fn is_write(&self) -> bool {
+        match self {
+            StorageCmd::WriteVar { .. }
+            | StorageCmd::DeleteVar { .. }
+            | StorageCmd::IncrementVar { .. } => true,
+            StorageCmd::ReadVar { .. }
+            | StorageCmd::ListVars { .. }
+            | StorageCmd::WatchVar { .. } => false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum StorageResp {
     /// Upon handling the request the variable had the specified value and version number.
@@ -298,6 +508,16 @@ pub enum StorageResp {
     VariableMissing {},
     /// The variable has been written, and the new version is returned.
     WriteVar { version: u64 },
+    /// The variable has been deleted.
+    DeleteVar {},
+    /// Names of matching variables, sorted.
+    ListVars { names: Vec<String> },
+    /// The variable has been incremented; new version and value are returned.
+    IncrementVar { version: u64, value: i64 },
+    /// The variable hasn't changed; the sequence is suspended until it does.
+    Watching {},
+    /// The command failed, eg. because of a size limit or invalid namespace.
+    Error { message: String },
 }
 
 pub fn storage_cmd(cmd: StorageCmd) -> StorageResp {
@@ -310,13 +530,18 @@ pub fn storage_cmd(cmd: StorageCmd) -> StorageResp {
 // Public APIs
 
 pub struct VariableStorage {
-    // no fields (yet?)
+    scope: StorageScope,
 }
 
 impl VariableStorage {
-    /// Create a new instance of VariableStorage. It currently has no fields.
+    /// Create a new instance of VariableStorage, for variables of the current request.
     pub fn new() -> Self {
-        VariableStorage {}
+        Self::with_scope(StorageScope::Request)
+    }
+
+    /// Create a new instance of VariableStorage, for variables in the given scope.
+    pub fn with_scope(scope: StorageScope) -> Self {
+        VariableStorage { scope }
     }
 
     /// Read variable. Returns None if the variable is unset.
@@ -340,19 +565,78 @@ impl VariableStorage {
             value,
             op,
             when_version_is: None,
+            scope: self.scope.clone(),
         }) {
             StorageResp::WriteVar { version } => version,
+            StorageResp::Error { message } => panic!("write var {name}: {message}"),
             _ => panic!("unexpected response to write var"),
         }
     }
 
-    fn get_with_version(&self, name: &str) -> Option<(u64, Vec<u8>)> {
+    /// Delete variable. Returns false if it was already unset.
+    pub fn delete(&self, name: &str) -> bool {
+        match storage_cmd(StorageCmd::DeleteVar {
+            name: name.to_string(),
+            scope: self.scope.clone(),
+        }) {
+            StorageResp::DeleteVar {} => true,
+            StorageResp::VariableMissing {} => false,
+            StorageResp::Error { message } => panic!("delete var {name}: {message}"),
+            _ => panic!("unexpected response to delete var"),
+        }
+    }
+
+    /// List names of variables starting with `prefix` (use "" for all variables).
+    pub fn list(&self, prefix: &str) -> Vec<String> {
+        match storage_cmd(StorageCmd::ListVars {
+            prefix: prefix.to_string(),
+            scope: self.scope.clone(),
+        }) {
+            StorageResp::ListVars { names } => names,
+            StorageResp::Error { message } => panic!("list vars {prefix}: {message}"),
+            _ => panic!("unexpected response to list vars"),
+        }
+    }
+
+    /// Atomically add `delta` to an integer variable, and return the new value.
+    pub fn increment(&self, name: &str, delta: i64) -> i64 {
+        match storage_cmd(StorageCmd::IncrementVar {
+            name: name.to_string(),
+            delta,
+            scope: self.scope.clone(),
+        }) {
+            StorageResp::IncrementVar { value, .. } => value,
+            StorageResp::Error { message } => panic!("increment var {name}: {message}"),
+            _ => panic!("unexpected response to increment var"),
+        }
+    }
+
+    /// Ask the runtime not to call mid_process() until the variable changes from `version`
+    /// (`None` meaning unset); mid_process() should then return MidProcessResult::noop().
+    /// Returns false if the variable has already changed, in which case nothing is suspended.
+    pub fn watch(&self, name: &str, version: Option<u64>) -> bool {
+        match storage_cmd(StorageCmd::WatchVar {
+            name: name.to_string(),
+            version,
+            scope: self.scope.clone(),
+        }) {
+            StorageResp::Watching {} => true,
+            StorageResp::ReadVar { .. } | StorageResp::VariableMissing {} => false,
+            StorageResp::Error { message } => panic!("watch var {name}: {message}"),
+            _ => panic!("unexpected response to watch var"),
+        }
+    }
+
+    /// Read variable together with its version number.
+    pub fn get_with_version(&self, name: &str) -> Option<(u64, Vec<u8>)> {
         match storage_cmd(StorageCmd::ReadVar {
             name: name.to_string(),
+            scope: self.scope.clone(),
         }) {
             StorageResp::ReadVar { version, value } => Some((version, value)),
             StorageResp::VariableMissing {} => None,
-            StorageResp::WriteVar { .. } => panic!("unexpected response to read var"),
+            StorageResp::Error { message } => panic!("read var {name}: {message}"),
+            _ => panic!("unexpected response to read var"),
         }
     }
 }
@@ -372,11 +656,44 @@ pub fn self_seq_id() -> SeqId {
     get_host().self_seq_id()
 }
 
+/// Stop given sequences after the current step, for example the losing branches from
+/// `MidProcessArg::fork_group`. Only sequences of the current request can be stopped.
+/// Siblings can't be paused; a sequence can instead suspend itself by waiting for
+/// a variable with `VariableStorage::watch()`, until another one writes it.
+pub fn stop_seqs(ids: &[SeqId]) {
+    get_host().stop_seqs(ids)
+}
+
+/// Start a child generation with its own prompt and controller after the current step.
+/// Its tokens count towards the fuel of the current request, and fuel for its prompt
+/// and `gen.max_tokens` is set aside when it starts. Once it finishes,
+/// the generated text is written to the request-scoped `gen.result_var`;
+/// use `VariableStorage::watch()` to wait for it. Before that, `gen.error_var_name()`
+/// is set to the error message if the child failed or couldn't be started
+/// (e.g., due to lack of fuel or too many children running), and to "" otherwise;
+/// the text is then whatever was generated, possibly nothing.
+pub fn spawn_generation(gen: &SubGeneration) {
+    get_host().spawn_generation(gen)
+}
+
 /// Return the ID of the EOS token.
 pub fn eos_token() -> TokenId {
     get_host().eos_token()
 }
 
+/// Report structured information about script exception; typically followed by aici_stop().
+pub fn script_error(err: &ScriptError) {
+    get_host().script_error(err)
+}
+
+/// Emit a structured event, returned to the client in `events` (and not in the logs).
+/// Fails (without emitting anything) if `data` can't be serialized to JSON.
+pub fn emit<T: Serialize>(kind: &str, data: &T) -> serde_json::Result<()> {
+    let data = serde_json::to_vec(data)?;
+    get_host().emit(kind, &data);
+    Ok(())
+}
+
 /// Stop the program - any error info is assumed to have been printed already.
 pub fn aici_stop() -> ! {
     get_host().stop();
@@ -118,6 +118,7 @@ pub struct Lexer {
     dfa: dense::DFA<Vec<u32>>,
     initial: LexerState,
     vobidx_by_state_off: Vec<VobIdx>,
+    num_states: usize,
 }
 
 impl Lexer {
@@ -223,6 +224,7 @@ impl Lexer {
             dfa,
             vobidx_by_state_off,
             initial: LexerState::fake(),
+            num_states: states.len(),
         };
 
         lex.initial = lex.mk_state(initial);
@@ -240,6 +242,36 @@ impl Lexer {
         lex
     }
 
+    pub fn num_states(&self) -> usize {
+        self.num_states
+    }
+
+    pub fn dfa_memory_usage(&self) -> usize {
+        self.dfa.memory_usage()
+    }
+
+    /// Set in `acc` all patterns that match a non-empty prefix of `bytes`,
+    /// or that can still match after reading all of `bytes`.
+    /// Lexeme boundaries are not considered, i.e., `bytes` is treated as a single lexeme.
+    pub fn prefix_patterns(&self, vobset: &VobSet, bytes: &[u8], acc: &mut Vob) {
+        let mut state = self.initial.state;
+        for &b in bytes {
+            state = self.dfa.next_state(state, b);
+            let s2 = self.dfa.next_eoi_state(state);
+            if self.dfa.is_match_state(s2) {
+                for idx in 0..self.dfa.match_len(s2) {
+                    acc.set(self.dfa.match_pattern(s2, idx).as_usize(), true);
+                }
+            }
+            if self.is_dead(state) {
+                return;
+            }
+        }
+        if bytes.len() > 0 {
+            *acc |= vobset.resolve(self.reachable_tokens(state));
+        }
+    }
+
     pub fn file_start_state(&self) -> StateID {
         self.initial.state
         // pretend we've just seen a newline at the beginning of the file
@@ -18,11 +18,16 @@ pub mod dlex;
 
 pub mod substring;
 
+pub mod constraint;
+
+pub mod healing;
+
 pub type TokenId = toktrie::TokenId;
 
 pub use host::{
-    aici_stop, arg_bytes, arg_string, get_config, host_trie, self_seq_id, tokenize, tokenize_bytes,
-    StorageCmd, StorageOp, StorageResp, VariableStorage, WasmTokenizerEnv,
+    aici_stop, arg_bytes, arg_string, emit, get_config, host_trie, script_error, self_seq_id,
+    spawn_generation, stop_seqs, tokenize, tokenize_bytes, Event, ScriptError, StorageCmd,
+    StorageOp, StorageResp, StorageScope, SubGeneration, VariableStorage, WasmTokenizerEnv,
 };
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -88,6 +93,16 @@ pub struct MidProcessResult {
     /// If multiple branches are returned, they are executed in parallel.
     /// If no branches are returned, the request is terminated.
     pub branches: Vec<Branch<SimpleVob>>,
+
+    /// Attention masks, either empty or one per branch.
+    /// If empty, the masks set previously stay in effect (forked branches inherit them).
+    /// Element `i` of a mask applies to token `i` of the sequence (including the prompt):
+    /// 1.0 means the token is attended to, and 0.0 means it's hidden from the model.
+    /// Tokens past the end of the mask, as well as the last token, are always attended to.
+    /// An empty mask means no masking.
+    /// The mask is applied by the engine starting with the next forward pass;
+    /// engines that don't support masking fail the sequence if any token is hidden.
+    pub attention_masks: Vec<Vec<f32>>,
 }
 
 impl MidProcessResult {
@@ -97,12 +112,16 @@ impl MidProcessResult {
         } else {
             MidProcessResult {
                 branches: vec![branch],
+                attention_masks: vec![],
             }
         }
     }
 
     pub fn stop() -> Self {
-        MidProcessResult { branches: vec![] }
+        MidProcessResult {
+            branches: vec![],
+            attention_masks: vec![],
+        }
     }
 
     pub fn sample(set: SimpleVob) -> Self {
@@ -124,12 +143,21 @@ impl MidProcessResult {
     pub fn is_stop(&self) -> bool {
         self.branches.is_empty()
     }
+
+    /// Use the same attention mask for all branches.
+    pub fn with_attention_mask(mut self, mask: Vec<f32>) -> Self {
+        self.attention_masks = self.branches.iter().map(|_| mask.clone()).collect();
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ProcessResultOffset {
     /// Branches use byte offsets into the bias tensor.
     pub branches: Vec<Branch<usize>>,
+    /// See MidProcessResult::attention_masks.
+    #[serde(default)]
+    pub attention_masks: Vec<Vec<f32>>,
 }
 
 pub trait AiciCtrl {
@@ -169,6 +197,7 @@ pub trait AiciCtrl {
                     })
                 })
                 .collect(),
+            attention_masks: res.attention_masks,
         };
         let res_bytes = serde_json::to_vec(&res).expect("aici_mid_process: failed to serialize");
         host::return_process_result(&res_bytes);
@@ -218,6 +247,33 @@ macro_rules! aici_expose_all {
     }
 }
 
+/// Expose `aici_preinit()`, usage:
+///     aici_expose_preinit!(my_preinit);
+///     aici_expose_preinit!(my_preinit, first_use);
+/// The runtime calls it once, after `aici_init()`, and snapshots the Wasm memory afterwards;
+/// later requests with the same module argument start from the snapshot
+/// (and `aici_create()` is called directly).
+/// This happens the second time a module argument is used, or already the first time
+/// with `first_use` (for modules where the setup doesn't fit in the init time limit).
+/// Thus, `my_preinit()` can only depend on the module argument, the tokenizer and `get_config()`,
+/// and it should not call the host otherwise (no variables, no logging).
+#[macro_export]
+macro_rules! aici_expose_preinit {
+    ($preinit:path) => {
+        #[no_mangle]
+        pub extern "C" fn aici_preinit() {
+            $preinit()
+        }
+    };
+    ($preinit:path, first_use) => {
+        $crate::aici_expose_preinit!($preinit);
+
+        // only checked for presence by the runtime
+        #[no_mangle]
+        pub extern "C" fn aici_preinit_first_use() {}
+    };
+}
+
 #[macro_export]
 macro_rules! include_bytes_aligned {
     ($align_ty:ty, $path:literal) => {{
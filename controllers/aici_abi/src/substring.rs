@@ -5,6 +5,7 @@ use crate::{
     recognizer::{FunctionalRecognizer, StackRecognizer},
     toktrie::SpecialToken,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 enum Node {
@@ -12,10 +13,45 @@ enum Node {
     Leaf { source_offset: usize },
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct SubStrOptions {
+    /// Only allow substrings that start and end at word (space) boundaries.
+    pub word_boundaries: bool,
+    /// Match ASCII letters case-insensitively, and treat any run of whitespace as a single space.
+    pub normalize: bool,
+}
+
+impl Default for SubStrOptions {
+    fn default() -> Self {
+        SubStrOptions {
+            word_boundaries: true,
+            normalize: false,
+        }
+    }
+}
+
+/// Location of generated text within the sources, see `SubStrMatcher::locate()`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubStrMatch {
+    pub source_idx: usize,
+    /// Byte offsets within the (original, not normalized) source.
+    pub start: usize,
+    pub end: usize,
+}
+
 pub struct SubStrMatcher {
     end_str: String,
+    options: SubStrOptions,
+    // all sources (normalized if needed), each followed by a space
     source: Vec<u8>,
+    // offset in `source` where each source starts
+    source_starts: Vec<usize>,
+    // for each byte of `source`, its offset within the original source
+    orig_offsets: Vec<usize>,
     nodes: Vec<Node>,
+    // the byte on the edge leading to given node
+    node_bytes: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -28,12 +64,25 @@ pub enum SubStrState {
 
 pub type SubStrStackRecognizer = StackRecognizer<SubStrState, SubStrMatcher>;
 
-fn add_node(nodes: &mut Vec<Node>, n: Node) -> usize {
+fn add_node(nodes: &mut Vec<Node>, node_bytes: &mut Vec<u8>, n: Node, byte: u8) -> usize {
     let idx = nodes.len();
     nodes.push(n);
+    node_bytes.push(byte);
     idx
 }
 
+fn is_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\n' || b == b'\t' || b == b'\r'
+}
+
+fn normalize_byte(b: u8) -> u8 {
+    if is_whitespace(b) {
+        b' '
+    } else {
+        b.to_ascii_lowercase()
+    }
+}
+
 impl Display for SubStrMatcher {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.pp(f, 0, 0)
@@ -85,15 +134,31 @@ impl SubStrMatcher {
     }
 
     pub fn new(source: &str, end_str: &str) -> Self {
+        Self::new_multi(&[source], end_str, SubStrOptions::default())
+    }
+
+    /// Matches substrings of any of the `sources`; a match never spans two sources.
+    pub fn new_multi(sources: &[&str], end_str: &str, options: SubStrOptions) -> Self {
         let mut tmp = Self {
-            source: (source.to_string() + " ").as_bytes().to_vec(),
+            source: Vec::new(),
+            source_starts: Vec::new(),
+            orig_offsets: Vec::new(),
             end_str: end_str.to_string(),
+            options,
             nodes: vec![Node::Inner { children: vec![] }],
+            node_bytes: vec![0],
         };
-        tmp.add(0);
-        for i in 0..tmp.source.len() {
-            if tmp.source[i] == b' ' {
-                tmp.add(i + 1);
+        for src in sources {
+            tmp.push_source(src.as_bytes());
+        }
+        for idx in 0..tmp.source_starts.len() {
+            let start = tmp.source_starts[idx];
+            let end = tmp.source_end(start);
+            tmp.add(start);
+            for i in start..end {
+                if !options.word_boundaries || tmp.source[i] == b' ' {
+                    tmp.add(i + 1);
+                }
             }
         }
         // println!("{}", tmp);
@@ -101,6 +166,89 @@ impl SubStrMatcher {
         tmp
     }
 
+    fn push_source(&mut self, src: &[u8]) {
+        self.source_starts.push(self.source.len());
+        let mut prev_ws = true;
+        for (i, &b) in src.iter().enumerate() {
+            let b = if self.options.normalize {
+                let b = normalize_byte(b);
+                if b == b' ' && prev_ws {
+                    continue;
+                }
+                prev_ws = b == b' ';
+                b
+            } else {
+                b
+            };
+            self.source.push(b);
+            self.orig_offsets.push(i);
+        }
+        // when normalizing, a trailing whitespace (if any) is already there
+        if !(self.options.normalize && prev_ws) {
+            self.source.push(b' ');
+            self.orig_offsets.push(src.len());
+        }
+    }
+
+    /// End (exclusive) of the source containing byte at `off`.
+    fn source_end(&self, off: usize) -> usize {
+        let idx = self.source_starts.partition_point(|&s| s <= off);
+        if idx < self.source_starts.len() {
+            self.source_starts[idx]
+        } else {
+            self.source.len()
+        }
+    }
+
+    /// Find which source the `text` (generated under this matcher) comes from.
+    /// If the text occurs in several sources, one of them is returned.
+    pub fn locate(&self, text: &[u8]) -> Option<SubStrMatch> {
+        let mut state = self.initial();
+        let mut num_matched = 0;
+        for &b in text {
+            match self.do_append(state, b) {
+                SubStrState::Dead => return None,
+                SubStrState::EndStrOffset(_) => break,
+                next => {
+                    // whitespace collapsed by normalization doesn't advance the state
+                    if next != state {
+                        num_matched += 1;
+                    }
+                    state = next;
+                }
+            }
+        }
+        let end = self.match_end(state)?;
+        let start = end - num_matched;
+        let source_idx = self.source_starts.partition_point(|&s| s <= start) - 1;
+        Some(SubStrMatch {
+            source_idx,
+            start: self.orig_offsets[start],
+            end: self.orig_offsets[end - 1] + 1,
+        })
+    }
+
+    fn match_end(&self, state: SubStrState) -> Option<usize> {
+        match state {
+            SubStrState::SourceOffset(off) => Some(off),
+            SubStrState::Node(0) => None,
+            SubStrState::Node(mut idx) => {
+                // any leaf below the node will do
+                let mut depth = 0;
+                loop {
+                    match &self.nodes[idx] {
+                        Node::Leaf { source_offset } => return Some(source_offset - depth),
+                        Node::Inner { children } => {
+                            idx = children[0].1;
+                            depth += 1;
+                        }
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
     fn find(&self, s: &[u8]) -> (usize, usize) {
         let mut node_idx = 0;
         for (i, b) in s.iter().enumerate() {
@@ -126,13 +274,14 @@ impl SubStrMatcher {
     }
 
     fn add(&mut self, source_offset1: usize) {
-        let s1 = &self.source[source_offset1..];
+        let end1 = self.source_end(source_offset1);
+        let s1 = &self.source[source_offset1..end1];
         let (mut node_idx, offset) = self.find(s1);
         if offset >= s1.len() {
             return;
         }
         let source_offset1 = source_offset1 + offset;
-        let s1 = &self.source[source_offset1..];
+        let s1 = &self.source[source_offset1..end1];
 
         let num_nodes = self.nodes.len();
         match &mut self.nodes[node_idx] {
@@ -140,15 +289,19 @@ impl SubStrMatcher {
                 children.push((s1[0], num_nodes));
                 let n = add_node(
                     &mut self.nodes,
+                    &mut self.node_bytes,
                     Node::Leaf {
                         source_offset: source_offset1 + 1,
                     },
+                    s1[0],
                 );
                 assert!(n == num_nodes);
             }
             Node::Leaf { source_offset } => {
                 let source_offset2 = *source_offset;
-                let s2 = &self.source[source_offset2..];
+                // the leaf continues the source of the byte before it
+                let end2 = self.source_end(source_offset2 - 1);
+                let s2 = &self.source[source_offset2..end2];
                 if s2.starts_with(s1) {
                     return;
                 }
@@ -165,22 +318,31 @@ impl SubStrMatcher {
                     if b1 != b2 {
                         let n1 = add_node(
                             &mut self.nodes,
+                            &mut self.node_bytes,
                             Node::Leaf {
                                 source_offset: source_offset1 + i + 1,
                             },
+                            b1,
                         );
                         let n2 = add_node(
                             &mut self.nodes,
+                            &mut self.node_bytes,
                             Node::Leaf {
                                 source_offset: source_offset2 + i + 1,
                             },
+                            b2,
                         );
                         self.nodes[node_idx] = Node::Inner {
                             children: vec![(b1, n1), (b2, n2)],
                         };
                         return;
                     } else {
-                        let n1 = add_node(&mut self.nodes, Node::Inner { children: vec![] });
+                        let n1 = add_node(
+                            &mut self.nodes,
+                            &mut self.node_bytes,
+                            Node::Inner { children: vec![] },
+                            b1,
+                        );
                         self.nodes[node_idx] = Node::Inner {
                             children: vec![(b1, n1)],
                         };
@@ -196,7 +358,7 @@ impl SubStrMatcher {
     }
 
     fn append_to_src_off(&self, off: usize, byte: u8) -> SubStrState {
-        if off < self.source.len() && self.source[off] == byte {
+        if off < self.source_end(off - 1) && self.source[off] == byte {
             SubStrState::SourceOffset(off + 1)
         } else {
             SubStrState::Dead
@@ -231,19 +393,45 @@ impl SubStrMatcher {
         }
     }
 
+    fn last_byte(&self, state: SubStrState) -> Option<u8> {
+        match state {
+            SubStrState::Node(0) => None,
+            SubStrState::Node(idx) => Some(self.node_bytes[idx]),
+            SubStrState::SourceOffset(off) => Some(self.source[off - 1]),
+            _ => None,
+        }
+    }
+
+    fn can_end(&self, state: SubStrState) -> bool {
+        if self.options.word_boundaries {
+            self.append_inner(state, b' ') != SubStrState::Dead
+        } else {
+            state != SubStrState::Node(0)
+        }
+    }
+
     #[inline(always)]
     fn do_append(&self, state: SubStrState, byte: u8) -> SubStrState {
         let state = match state {
             SubStrState::Node(_) | SubStrState::SourceOffset(_)
-                if self.end_str.as_bytes().first() == Some(&byte)
-                    && self.append_inner(state, b' ') != SubStrState::Dead =>
+                if self.end_str.as_bytes().first() == Some(&byte) && self.can_end(state) =>
             {
                 SubStrState::EndStrOffset(0)
             }
             _ => state,
         };
 
-        self.append_inner(state, byte)
+        match state {
+            SubStrState::Node(_) | SubStrState::SourceOffset(_) if self.options.normalize => {
+                let byte = normalize_byte(byte);
+                if byte == b' ' && self.last_byte(state) == Some(b' ') {
+                    state
+                } else {
+                    self.append_inner(state, byte)
+                }
+            }
+            _ => self.append_inner(state, byte),
+        }
     }
 }
 
@@ -266,7 +454,7 @@ impl FunctionalRecognizer<SubStrState> for SubStrMatcher {
             SpecialToken::EndOfSentence => {
                 let l = self.end_str.len();
                 if l == 0 {
-                    self.append_inner(state, b' ') != SubStrState::Dead
+                    self.can_end(state)
                 } else {
                     state == SubStrState::EndStrOffset(l)
                 }
@@ -275,3 +463,61 @@ impl FunctionalRecognizer<SubStrState> for SubStrMatcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(source_idx: usize, start: usize, end: usize) -> Option<SubStrMatch> {
+        Some(SubStrMatch {
+            source_idx,
+            start,
+            end,
+        })
+    }
+
+    #[test]
+    fn locate_single() {
+        let matcher = SubStrMatcher::new("hello big world", "");
+        assert_eq!(matcher.locate(b"hello"), m(0, 0, 5));
+        assert_eq!(matcher.locate(b"big"), m(0, 6, 9));
+        assert_eq!(matcher.locate(b"big world"), m(0, 6, 15));
+        assert_eq!(matcher.locate(b"big big"), None);
+        // only whole words
+        assert_eq!(matcher.locate(b"ig"), None);
+        assert_eq!(matcher.locate(b""), None);
+    }
+
+    #[test]
+    fn locate_multi() {
+        let options = SubStrOptions {
+            word_boundaries: false,
+            normalize: false,
+        };
+        let matcher = SubStrMatcher::new_multi(&["the cat sat", "a dog ran"], "", options);
+        assert_eq!(matcher.locate(b"dog"), m(1, 2, 5));
+        assert_eq!(matcher.locate(b"at s"), m(0, 5, 9));
+        assert_eq!(matcher.locate(b"og r"), m(1, 3, 7));
+        // doesn't span sources
+        assert_eq!(matcher.locate(b"sat a"), None);
+    }
+
+    #[test]
+    fn locate_end_str() {
+        let matcher = SubStrMatcher::new("hello big world", "\"");
+        assert_eq!(matcher.locate(b"big world\""), m(0, 6, 15));
+        assert_eq!(matcher.locate(b"big\"  and more"), m(0, 6, 9));
+    }
+
+    #[test]
+    fn locate_normalized() {
+        let options = SubStrOptions {
+            word_boundaries: true,
+            normalize: true,
+        };
+        let matcher = SubStrMatcher::new_multi(&["Hello   Big\nWorld"], "", options);
+        // offsets are within the original source
+        assert_eq!(matcher.locate(b"big world"), m(0, 8, 17));
+        assert_eq!(matcher.locate(b"HELLO  big"), m(0, 0, 11));
+    }
+}
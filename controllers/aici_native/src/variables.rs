@@ -7,17 +7,73 @@ pub struct Variables {
 }
 
 impl Variables {
+    fn read_var(&self, name: &str) -> StorageResp {
+        match self.variables.get(name).map(|x| x.clone()) {
+            None => StorageResp::VariableMissing {},
+            Some((version, value)) => StorageResp::ReadVar { value, version },
+        }
+    }
+
     pub fn process_cmd(&mut self, cmd: StorageCmd) -> StorageResp {
         match cmd {
-            StorageCmd::ReadVar { name } => match self.variables.get(&name).map(|x| x.clone()) {
+            StorageCmd::ReadVar { name, .. } => self.read_var(&name),
+            StorageCmd::DeleteVar { name, .. } => match self.variables.remove(&name) {
+                Some(_) => StorageResp::DeleteVar {},
                 None => StorageResp::VariableMissing {},
-                Some((version, value)) => StorageResp::ReadVar { value, version },
             },
+            StorageCmd::ListVars { prefix, .. } => {
+                let mut names = self
+                    .variables
+                    .keys()
+                    .filter(|k| k.starts_with(&prefix))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                names.sort();
+                StorageResp::ListVars { names }
+            }
+            StorageCmd::IncrementVar { name, delta, .. } => {
+                let (prev_version, prev) = match self.variables.get(&name) {
+                    Some((version, value)) => {
+                        match std::str::from_utf8(value)
+                            .ok()
+                            .and_then(|s| s.trim().parse::<i64>().ok())
+                        {
+                            Some(v) => (*version, v),
+                            None => {
+                                return StorageResp::Error {
+                                    message: format!("variable {name} is not an integer"),
+                                }
+                            }
+                        }
+                    }
+                    None => (0, 0),
+                };
+                let value = match prev.checked_add(delta) {
+                    Some(v) => v,
+                    None => {
+                        return StorageResp::Error {
+                            message: format!("variable {name} overflow"),
+                        }
+                    }
+                };
+                let version = prev_version + 1;
+                self.variables
+                    .insert(name, (version, value.to_string().into_bytes()));
+                StorageResp::IncrementVar { version, value }
+            }
+            StorageCmd::WatchVar { name, version, .. } => {
+                if self.variables.get(&name).map(|x| x.0) == version {
+                    StorageResp::Watching {}
+                } else {
+                    self.read_var(&name)
+                }
+            }
             StorageCmd::WriteVar {
                 name,
                 value,
                 when_version_is,
                 op,
+                ..
             } => {
                 let curr = self.variables.get(&name).map(|x| x.clone());
                 match curr {
@@ -54,3 +110,112 @@ impl Variables {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aici_abi::StorageScope;
+
+    fn watch(vars: &mut Variables, name: &str, version: Option<u64>) -> StorageResp {
+        vars.process_cmd(StorageCmd::WatchVar {
+            name: name.to_string(),
+            version,
+            scope: StorageScope::Request,
+        })
+    }
+
+    fn write(vars: &mut Variables, name: &str, value: &str) -> u64 {
+        match vars.process_cmd(StorageCmd::WriteVar {
+            name: name.to_string(),
+            value: value.as_bytes().to_vec(),
+            op: StorageOp::Set,
+            when_version_is: None,
+            scope: StorageScope::Request,
+        }) {
+            StorageResp::WriteVar { version } => version,
+            r => panic!("unexpected {r:?}"),
+        }
+    }
+
+    #[test]
+    fn watch_suspends_until_written() {
+        let mut vars = Variables::default();
+        assert!(matches!(
+            watch(&mut vars, "x", None),
+            StorageResp::Watching {}
+        ));
+        // writing other variables doesn't wake it up
+        write(&mut vars, "y", "foo");
+        assert!(matches!(
+            watch(&mut vars, "x", None),
+            StorageResp::Watching {}
+        ));
+
+        let version = write(&mut vars, "x", "bar");
+        match watch(&mut vars, "x", None) {
+            StorageResp::ReadVar { version: v, value } => {
+                assert_eq!(v, version);
+                assert_eq!(value, b"bar");
+            }
+            r => panic!("unexpected {r:?}"),
+        }
+
+        // watching the current version suspends again, until the next change
+        assert!(matches!(
+            watch(&mut vars, "x", Some(version)),
+            StorageResp::Watching {}
+        ));
+        write(&mut vars, "x", "baz");
+        assert!(matches!(
+            watch(&mut vars, "x", Some(version)),
+            StorageResp::ReadVar { version: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn watch_already_changed() {
+        let mut vars = Variables::default();
+        write(&mut vars, "x", "a");
+        write(&mut vars, "x", "b");
+        // stale version; no suspension
+        assert!(matches!(
+            watch(&mut vars, "x", Some(1)),
+            StorageResp::ReadVar { version: 2, .. }
+        ));
+        // the variable is set, and the watcher waits for it to be unset
+        assert!(matches!(
+            watch(&mut vars, "x", None),
+            StorageResp::ReadVar { version: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn watch_delete_and_increment() {
+        let mut vars = Variables::default();
+        write(&mut vars, "x", "a");
+        vars.process_cmd(StorageCmd::DeleteVar {
+            name: "x".to_string(),
+            scope: StorageScope::Request,
+        });
+        assert!(matches!(
+            watch(&mut vars, "x", Some(1)),
+            StorageResp::VariableMissing {}
+        ));
+
+        assert!(matches!(
+            watch(&mut vars, "n", None),
+            StorageResp::Watching {}
+        ));
+        vars.process_cmd(StorageCmd::IncrementVar {
+            name: "n".to_string(),
+            delta: 3,
+            scope: StorageScope::Request,
+        });
+        match watch(&mut vars, "n", None) {
+            StorageResp::ReadVar { version, value } => {
+                assert_eq!(version, 1);
+                assert_eq!(value, b"3");
+            }
+            r => panic!("unexpected {r:?}"),
+        }
+    }
+}
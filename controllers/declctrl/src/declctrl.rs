@@ -10,14 +10,25 @@
 */
 
 use aici_abi::{
-    aici_expose_all, bytes::limit_str, cfg::CfgParser, host_trie, rx::{RecRx, RxStackRecognizer}, SimpleVob, tokenize_bytes, toktrie::{Recognizer, SpecialToken, TokTrie}, AiciCtrl, Branch, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, TokenId, VariableStorage
+    aici_expose_all, bytes::limit_str, cfg::CfgParser, constraint::BoxedRecognizer, healing::{heal_prompt, HealingRecognizer, MAX_HEALING_TOKENS}, host_trie, rx::{RecRx, RxStackRecognizer}, SimpleVob, tokenize_bytes, toktrie::{Recognizer, SpecialToken, TokTrie}, AiciCtrl, Branch, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, TokenId, VariableStorage
 };
 use core::panic;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use serde_json::json;
+use std::{fmt::Debug, sync::Mutex};
+
+mod dsl;
+mod typed;
 
 const LOG_ADVANCE: bool = false;
 
+// progress of the program goes to the events, so that the logs are left for the errors
+fn trace(kind: &str, data: serde_json::Value) {
+    if let Err(e) = aici_abi::emit(kind, &data) {
+        println!("warning: {kind}: {e}");
+    }
+}
+
 //
 // The JSON AST
 //
@@ -51,6 +62,7 @@ pub enum Expr {
     /// Literal string
     String { str: String },
     /// The current value of this variable, or empty string if not set.
+    /// `prompt`, unless set, is the prompt.
     Var { var: VarName },
     /// The result of the current step (typically Gen, but can be anything).
     Current {},
@@ -178,6 +190,25 @@ pub struct InnerConstraint {
     pub options: Expr,
 }
 
+/// Typed values for Gen; these are compiled to exact regexes, see typed.rs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum GenType {
+    /// Integer, optionally in range [min, max].
+    Int { min: Option<i64>, max: Option<i64> },
+    /// Number with exactly `decimals` digits after the dot, optionally in range [min, max].
+    Float {
+        min: Option<f64>,
+        max: Option<f64>,
+        decimals: usize,
+    },
+    /// `true` or `false`
+    Bool {},
+    /// Date and/or time; `format` uses %Y, %m, %d, %H, %M, %S. Defaults to "%Y-%m-%d".
+    Date { format: Option<String> },
+    /// One of the strings.
+    Enum { options: Vec<String> },
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Step {
     // Generate exactly the provided string
@@ -212,6 +243,10 @@ pub enum Step {
         /// Generate string that matches the yacc grammar.
         yacc: Option<String>,
 
+        /// Generate a typed value. Cannot be used with rx, yacc or inner.
+        /// `Current` evaluates to the value in normalized form.
+        r#type: Option<GenType>,
+
         /// Constraints to apply in the middle of the generation.
         #[serde(default)]
         inner: Vec<InnerConstraint>,
@@ -247,6 +282,24 @@ pub enum Step {
 
     /// Stop the sequence (makes most sense in a Fork).
     Stop {},
+
+    /// Execute `body` repeatedly.
+    /// The loop ends after `max_iter` iterations, or when `until` evaluates to
+    /// a non-empty string; `until` is checked after each iteration.
+    Repeat {
+        body: Vec<Step>,
+        max_iter: Option<usize>,
+        until: Option<Expr>,
+    },
+
+    /// Execute `then` if `cond` evaluates to a non-empty string, and `else` otherwise.
+    /// The condition is evaluated once all the preceding steps are finished.
+    If {
+        cond: Expr,
+        then: Vec<Step>,
+        #[serde(default)]
+        r#else: Vec<Step>,
+    },
 }
 
 impl Debug for StepAttributes {
@@ -275,6 +328,8 @@ impl std::fmt::Display for Step {
             Step::Fork { branches } => write!(f, "Fork({})", branches.len()),
             Step::Wait { vars } => write!(f, "Wait({})", vars.len()),
             Step::Stop {} => write!(f, "Stop"),
+            Step::Repeat { body, .. } => write!(f, "Repeat({})", body.len()),
+            Step::If { .. } => write!(f, "If()"),
             Step::Fixed { .. } => write!(f, "Fixed()"),
             Step::Choose { .. } => write!(f, "Choose()"),
             Step::Gen { .. } => write!(f, "Gen()"),
@@ -303,6 +358,37 @@ impl Debug for Step {
             }
             Step::Wait { vars } => write!(f, "Wait({:?})", vars),
             Step::Stop {} => write!(f, "Stop"),
+            Step::Repeat {
+                body,
+                max_iter,
+                until,
+            } => {
+                write!(f, "Repeat(")?;
+                if let Some(max_iter) = max_iter {
+                    write!(f, "max_iter:{}, ", max_iter)?;
+                }
+                if let Some(until) = until {
+                    write!(f, "until:{:?}, ", until)?;
+                }
+                write!(f, "{{\n")?;
+                for step in body {
+                    write!(f, "      {:?}\n", step)?;
+                }
+                write!(f, "}})")
+            }
+            Step::If { cond, then, r#else } => {
+                write!(f, "If({:?}) {{\n", cond)?;
+                for step in then {
+                    write!(f, "      {:?}\n", step)?;
+                }
+                if r#else.len() > 0 {
+                    write!(f, "}} else {{\n")?;
+                    for step in r#else {
+                        write!(f, "      {:?}\n", step)?;
+                    }
+                }
+                write!(f, "}}")
+            }
             Step::Fixed {
                 text,
                 attrs,
@@ -321,6 +407,7 @@ impl Debug for Step {
             Step::Gen {
                 rx,
                 yacc,
+                r#type,
                 inner,
                 stop_at,
                 max_tokens,
@@ -336,6 +423,9 @@ impl Debug for Step {
                 if let Some(yacc) = yacc {
                     write!(f, "yacc:{:?} ", limit_str(yacc, 200))?;
                 }
+                if let Some(tp) = r#type {
+                    write!(f, "type:{:?} ", tp)?;
+                }
                 if inner.len() > 0 {
                     write!(f, "inner:")?;
                     for ic in inner {
@@ -366,17 +456,27 @@ impl Debug for Step {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Program {
     pub steps: Vec<Step>,
+    /// Remove the last token(s) of the prompt, and force the first step to start
+    /// with the removed bytes; see aici_abi::healing.
+    #[serde(default)]
+    pub token_healing: bool,
 }
 
+// Repeat and If are replaced by other states when reached, see Runner::unroll().
+// For Repeat, `iter` is the number of iterations done so far,
+// and `start` the number of tokens at the start of the last iteration.
 enum StepSpecific {
     Options { tokens: Vec<Vec<TokenId>> },
     ExpandOptions { text: Expr, many: bool },
     Inner { constraints: Vec<InnerConstraint> },
     Rx { rx: RxStackRecognizer },
     Cfg { cfg: CfgParser },
+    Healed { rec: HealingRecognizer<BoxedRecognizer> },
     Fork { branches: Vec<Vec<StepState>> },
     Wait { vars: Vec<VarName> },
     Stop,
+    Repeat { iter: usize, start: usize },
+    If,
 }
 struct StepState {
     ast: Step,
@@ -392,6 +492,9 @@ struct StepState {
     // if true, this step was derived from the next step
     is_derived: bool,
 
+    // bytes removed from the prompt by token healing, that this step has to start with
+    heal_prefix: Vec<u8>,
+
     mask_tags: Vec<TagName>,
     attrs: StepAttributes,
 
@@ -412,6 +515,8 @@ struct RunnerCtx {
     vars: VariableStorage,
     tokens: Vec<TokenInfo>,
     bytes: Vec<u8>,
+    // the unhealed prompt, for $prompt
+    prompt: Vec<u8>,
 }
 
 impl RunnerCtx {
@@ -429,10 +534,19 @@ impl RunnerCtx {
             Expr::String { str } => str.as_bytes().to_vec(),
             Expr::Var { var } => match self.vars.get(&var.0) {
                 Some(r) => r,
+                None if var.0 == "prompt" => self.prompt.clone(),
                 None => Vec::new(),
             },
             Expr::Current {} => match curr_ctx {
-                Some(ctx) => self.bytes[self.bytes.len() - ctx.num_bytes..].to_vec(),
+                Some(ctx) => {
+                    let curr = &self.bytes[self.bytes.len() - ctx.num_bytes..];
+                    match &ctx.ast {
+                        Step::Gen {
+                            r#type: Some(tp), ..
+                        } => tp.normalize(curr),
+                        _ => curr.to_vec(),
+                    }
+                }
                 None => panic!("$current used outside of stmts:..."),
             },
             Expr::Concat { parts, list } => {
@@ -491,6 +605,7 @@ pub struct Runner {
     prev_state_idx: usize,
     state_idx: usize,
     states: Vec<StepState>,
+    token_healing: bool,
 }
 
 impl Debug for StepState {
@@ -541,6 +656,13 @@ impl StepSpecific {
             _ => false,
         }
     }
+
+    fn is_control(&self) -> bool {
+        match self {
+            StepSpecific::Repeat { .. } | StepSpecific::If => true,
+            _ => false,
+        }
+    }
 }
 
 impl StepState {
@@ -564,6 +686,7 @@ impl StepState {
             num_bytes: 0,
             following: None,
             is_derived: false,
+            heal_prefix: Vec::new(),
         }
     }
 
@@ -585,6 +708,11 @@ impl StepState {
 
             Step::Wait { vars } => Self::new(s, StepSpecific::Wait { vars: vars.clone() }),
 
+            // the body is checked to be non-empty by check_steps() or dsl::compile()
+            Step::Repeat { .. } => Self::new(s, StepSpecific::Repeat { iter: 0, start: 0 }),
+
+            Step::If { .. } => Self::new(s, StepSpecific::If),
+
             Step::Fork { branches } => {
                 assert!(branches.len() > 1, "more than one branch required in fork");
                 assert!(
@@ -628,6 +756,7 @@ impl StepState {
             Step::Gen {
                 rx,
                 yacc,
+                r#type,
                 stop_at,
                 inner,
                 max_tokens,
@@ -636,6 +765,15 @@ impl StepState {
                 mask_tags,
                 attrs,
             } => {
+                let typed_rx = r#type.as_ref().map(|tp| {
+                    assert!(
+                        rx.is_none() && yacc.is_none() && inner.len() == 0,
+                        "can't have type= and any of rx=, yacc=, inner="
+                    );
+                    // checked by check_steps() or dsl::compile()
+                    tp.to_regex().unwrap_or_else(|e| panic!("{}", e))
+                });
+                let rx = if typed_rx.is_some() { &typed_rx } else { rx };
                 let spec = match (yacc, rx) {
                     (None, None) if inner.len() > 0 => StepSpecific::Inner {
                         constraints: inner.clone(),
@@ -702,6 +840,7 @@ impl StepState {
             StepSpecific::Fork { .. } => false,
             StepSpecific::Wait { .. } => false,
             StepSpecific::Stop => false,
+            StepSpecific::Repeat { .. } | StepSpecific::If => false,
             StepSpecific::ExpandOptions { .. } => {
                 assert!(self.num_tokens == 0);
                 false
@@ -727,7 +866,33 @@ impl StepState {
                 rx.special_allowed(SpecialToken::EndOfSentence)
                     && (optional || (0..=255).all(|byte| !rx.byte_allowed(byte)))
             }
+            StepSpecific::Healed { rec } => {
+                rec.special_allowed(SpecialToken::EndOfSentence)
+                    && (optional || (0..=255).all(|byte| !rec.byte_allowed(byte)))
+            }
+        }
+    }
+
+    fn heal(&mut self, prefix: &[u8]) -> bool {
+        match std::mem::replace(&mut self.specific, StepSpecific::Stop) {
+            StepSpecific::ExpandOptions { text, many } => {
+                self.heal_prefix = prefix.to_vec();
+                self.specific = StepSpecific::ExpandOptions { text, many };
+            }
+            StepSpecific::Rx { rx } => {
+                let rec = HealingRecognizer::new(prefix.to_vec(), BoxedRecognizer::new(rx));
+                self.specific = StepSpecific::Healed { rec };
+            }
+            StepSpecific::Cfg { cfg } => {
+                let rec = HealingRecognizer::new(prefix.to_vec(), BoxedRecognizer::new(cfg));
+                self.specific = StepSpecific::Healed { rec };
+            }
+            other => {
+                self.specific = other;
+                return false;
+            }
         }
+        true
     }
 
     fn allows_eos(&mut self) -> bool {
@@ -738,23 +903,22 @@ impl StepState {
         self.check_eos(false)
     }
 
-    #[allow(dead_code)]
     fn attention_mask(&self, ctx: &RunnerCtx) -> Vec<f32> {
         if self.mask_tags.len() == 0 {
             vec![]
         } else {
             let mut mask = vec![1.0f32; ctx.tokens.len()];
+            let mut num_masked = 0;
             for (idx, tok) in ctx.tokens.iter().enumerate() {
                 if self.mask_tags.contains(&tok.tag) {
-                    println!(
-                        "masking t[{}] = {:?} tag={:?}",
-                        idx,
-                        ctx.trie.token_str(tok.id),
-                        tok.tag
-                    );
                     mask[idx] = 0.0;
+                    num_masked += 1;
                 }
             }
+            if num_masked > 0 {
+                let tags = self.mask_tags.iter().map(|t| &t.0).collect::<Vec<_>>();
+                trace("mask", json!({ "num_masked": num_masked, "tags": tags }));
+            }
             mask
         }
     }
@@ -782,12 +946,16 @@ impl StepState {
             StepSpecific::ExpandOptions { .. } => panic!("advance on ExpandOptions"),
             StepSpecific::Fork { .. } => panic!("advance on fork"),
             StepSpecific::Wait { .. } => panic!("advance on wait"),
+            StepSpecific::Repeat { .. } | StepSpecific::If => {
+                panic!("advance on repeat/if")
+            }
             StepSpecific::Stop => {}
             StepSpecific::Options { tokens } => {
                 tokens.retain(has_token_at(token, self.num_tokens - 1))
             }
             StepSpecific::Cfg { cfg } => runner.trie.append_token(cfg, token).unwrap(),
             StepSpecific::Rx { rx } => runner.trie.append_token(rx, token).unwrap(),
+            StepSpecific::Healed { rec } => runner.trie.append_token(rec, token).unwrap(),
             StepSpecific::Inner { constraints } => {
                 for c in constraints {
                     let pos = runner.string_position(sidx, &c.after);
@@ -838,24 +1006,35 @@ impl StepState {
             StepSpecific::Fork { .. } => false,
             StepSpecific::Wait { .. } => false,
             StepSpecific::Stop => false,
+            StepSpecific::Repeat { .. } | StepSpecific::If => false,
             StepSpecific::Inner { .. } => true,
             StepSpecific::Options { tokens } => {
                 tokens.iter().any(has_token_at(token, self.num_tokens))
             }
             StepSpecific::Cfg { cfg } => trie.token_allowed(cfg, token),
             StepSpecific::Rx { rx } => trie.token_allowed(rx, token),
+            StepSpecific::Healed { rec } => trie.token_allowed(rec, token),
         }
     }
 
     fn finish(&mut self, runner: &RunnerCtx) {
         let sidx = runner.bytes.len() - self.num_bytes;
         let my_bytes = runner.bytes[sidx..].to_vec();
-        println!("finish: {self:?} {:?}", String::from_utf8_lossy(&my_bytes));
+        trace(
+            "finish",
+            json!({
+                "step": format!("{self:?}"),
+                "text": String::from_utf8_lossy(&my_bytes),
+            }),
+        );
         for s in &self.attrs.stmts {
             match s {
                 Stmt::Set { var, expr } => {
                     let val = runner.expand_with_curr(&expr, self);
-                    println!("  set {:?} := {:?}", var, String::from_utf8_lossy(&val));
+                    trace(
+                        "set",
+                        json!({ "var": var.0, "value": String::from_utf8_lossy(&val) }),
+                    );
                     runner.vars.set(&var.0, val);
                 }
             }
@@ -873,7 +1052,7 @@ impl StepState {
                 };
                 let tokens = options
                     .iter()
-                    .map(|v| tokenize_bytes(v))
+                    .map(|v| tokenize_bytes(&[&self.heal_prefix[..], v].concat()))
                     .collect::<Vec<_>>();
                 self.specific = StepSpecific::Options { tokens }
             }
@@ -889,6 +1068,7 @@ impl StepState {
             StepSpecific::ExpandOptions { .. } => {}
             StepSpecific::Wait { .. } => {}
             StepSpecific::Fork { .. } => {}
+            StepSpecific::Repeat { .. } | StepSpecific::If => {}
             StepSpecific::Inner { .. } => {
                 // anything goes, until one of constraint strings is generated
                 toks.set_all(true);
@@ -907,6 +1087,9 @@ impl StepState {
             StepSpecific::Cfg { cfg } => {
                 trie.add_bias(cfg, toks, &[]);
             }
+            StepSpecific::Healed { rec } => {
+                trie.add_bias(rec, toks, &[]);
+            }
         }
     }
 }
@@ -920,25 +1103,32 @@ impl Runner {
             .collect::<Vec<_>>();
         states.push(StepState::from_ast(&Step::Stop {}));
 
-        for (idx, state) in states.iter_mut().enumerate() {
-            println!("[{}] {} {:?}", idx, state.pp(), state);
-        }
-
         Self {
             ctx: RunnerCtx {
                 trie: host_trie(),
                 tokens: Vec::new(),
                 bytes: Vec::new(),
                 vars: VariableStorage::new(),
+                prompt: Vec::new(),
             },
             state_idx: 0,
             prev_state_idx: 0,
             states,
+            token_healing: program.token_healing,
         }
     }
 
+    fn trace_states(&self) {
+        let states = self
+            .states
+            .iter()
+            .map(|state| format!("{} {:?}", state.pp(), state))
+            .collect::<Vec<_>>();
+        trace("states", json!(states));
+    }
+
     fn stop(&mut self, info: &str) {
-        println!("stop: {}", info);
+        trace("stop", json!({ "info": info }));
         self.finish_states();
         self.state_idx = self.states.len() - 1;
         // don't finish the states
@@ -971,8 +1161,8 @@ impl Runner {
 
         if token == self.ctx.trie.special_token(SpecialToken::EndOfSentence) {
             if self.state_idx < self.states.len() - 1 {
-                println!("EOS: advancing to next state");
                 self.state_idx += 1;
+                trace("eos", json!({ "state_idx": self.state_idx }));
             }
         }
 
@@ -1047,7 +1237,73 @@ impl Runner {
             .position(|t| t.labels.contains(label))
     }
 
+    /// Replace Repeat and If states at the current position with the states they stand for.
+    /// This happens only once all the states before are finished, so that the conditions
+    /// see the variables they set.
+    fn unroll(&mut self) {
+        loop {
+            self.finish_states();
+            let st = &self.states[self.state_idx];
+            let steps = match (&st.specific, &st.ast) {
+                (StepSpecific::If, Step::If { cond, then, r#else }) => {
+                    let val = self.ctx.expand(cond);
+                    trace(
+                        "if",
+                        json!({ "cond": String::from_utf8_lossy(&val), "taken": val.len() > 0 }),
+                    );
+                    if val.len() > 0 {
+                        then.iter().map(StepState::from_ast).collect()
+                    } else {
+                        r#else.iter().map(StepState::from_ast).collect()
+                    }
+                }
+                (
+                    StepSpecific::Repeat { iter, start },
+                    Step::Repeat {
+                        body,
+                        max_iter,
+                        until,
+                    },
+                ) => {
+                    let num_tokens = self.ctx.tokens.len();
+                    // no tokens generated in the last iteration; it would loop forever
+                    let stalled = *iter > 0 && num_tokens == *start;
+                    let done = if *iter >= max_iter.unwrap_or(usize::MAX) || stalled {
+                        true
+                    } else if *iter == 0 {
+                        false
+                    } else if let Some(until) = until {
+                        self.ctx.expand(until).len() > 0
+                    } else {
+                        false
+                    };
+                    trace(
+                        "repeat",
+                        json!({ "iter": iter, "done": done, "stalled": stalled }),
+                    );
+                    if done {
+                        vec![]
+                    } else {
+                        let body = rename_labels(body, &format!("#{}", iter));
+                        let mut states = body.iter().map(StepState::from_ast).collect::<Vec<_>>();
+                        let next = StepSpecific::Repeat {
+                            iter: *iter + 1,
+                            start: num_tokens,
+                        };
+                        states.push(StepState::new(&st.ast, next));
+                        states
+                    }
+                }
+                _ => break,
+            };
+            self.states
+                .splice(self.state_idx..self.state_idx + 1, steps);
+        }
+    }
+
     fn try_backtrack(&mut self) -> MidProcessResult {
+        self.unroll();
+
         for idx in self.state_idx..self.states.len() {
             self.states[idx].concretize(&self.ctx);
 
@@ -1064,10 +1320,13 @@ impl Runner {
                     let backtrack = (self.ctx.tokens.len() - lbl_idx.unwrap()) as u32;
 
                     let t0 = self.ctx.tokens.iter().map(|t| t.id).collect::<Vec<_>>();
-                    println!(
-                        "slice: {t0:?} -> {:?} + {:?}",
-                        &t0[..lbl_idx.unwrap()],
-                        tokens[0]
+                    trace(
+                        "backtrack",
+                        json!({
+                            "tokens": t0,
+                            "keep": lbl_idx.unwrap(),
+                            "splice": tokens[0],
+                        }),
                     );
 
                     return MidProcessResult::splice(backtrack, tokens[0].clone());
@@ -1113,21 +1372,30 @@ impl Runner {
 
         self.finish_states();
 
+        if self.curr_state().specific.is_control() {
+            // we skipped all states before Repeat or If
+            return self.try_backtrack();
+        }
+
         if let Some(ff_tokens) = ff_tokens {
             MidProcessResult::splice(0, ff_tokens)
         } else {
-            MidProcessResult::sample(allowed_tokens)
+            let mask = self.curr_state().attention_mask(&self.ctx);
+            MidProcessResult::sample(allowed_tokens).with_attention_mask(mask)
         }
     }
 
     fn maybe_wait(&mut self) -> bool {
         if let StepSpecific::Wait { vars } = &self.curr_state().specific {
-            if vars.iter().any(|name| self.ctx.vars.get(&name.0).is_none()) {
-                println!("wait {vars:?} suspend");
+            // watch() suspends us until the (first) missing variable is set
+            let names = vars.iter().map(|v| &v.0).collect::<Vec<_>>();
+            if vars.iter().any(|name| self.ctx.vars.watch(&name.0, None)) {
+                trace("wait", json!({ "vars": names, "suspended": true }));
                 true
             } else {
-                println!("wait {vars:?} done");
+                trace("wait", json!({ "vars": names, "suspended": false }));
                 self.state_idx += 1;
+                self.unroll();
                 false
             }
         } else {
@@ -1145,7 +1413,22 @@ impl Runner {
 
 impl AiciCtrl for Runner {
     fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
-        println!("prompt: {:?}", arg.prompt);
+        let mut arg = arg;
+        // conditions of leading If/Repeat steps can look at the prompt
+        self.ctx.prompt = self.ctx.trie.decode(&arg.prompt);
+        self.unroll();
+        if self.token_healing {
+            let mut prompt = arg.prompt.clone();
+            let prefix = heal_prompt(&self.ctx.trie, &mut prompt, MAX_HEALING_TOKENS);
+            if prefix.len() > 0 && self.states[self.state_idx].heal(&prefix) {
+                trace(
+                    "healing",
+                    json!({ "prefix": String::from_utf8_lossy(&prefix) }),
+                );
+                arg.prompt = prompt;
+            }
+        }
+        trace("prompt", json!({ "tokens": arg.prompt }));
         for t in &arg.prompt {
             self.ctx.tokens.push(TokenInfo {
                 id: *t,
@@ -1191,6 +1474,8 @@ impl AiciCtrl for Runner {
             self.finish_states();
         }
 
+        self.unroll();
+
         if let StepSpecific::Stop = &self.curr_state().specific {
             return MidProcessResult::stop();
         }
@@ -1219,10 +1504,13 @@ impl AiciCtrl for Runner {
         self.finish_states();
 
         if arg.fork_group.len() > 1 {
-            println!("fork group: {:?}", arg.fork_group);
-            if self.state_idx == 0 && !self.curr_state().specific.is_fork() {
-                println!("initial fork; nothing to see here");
-            } else {
+            // the initial fork (of the prompt) is not in the program
+            let initial = self.state_idx == 0 && !self.curr_state().specific.is_fork();
+            trace(
+                "fork",
+                json!({ "group": arg.fork_group, "initial": initial }),
+            );
+            if !initial {
                 let st = self.states.remove(self.state_idx);
                 if let StepSpecific::Fork { mut branches } = st.specific {
                     assert!(arg.fork_group.len() == branches.len());
@@ -1236,10 +1524,13 @@ impl AiciCtrl for Runner {
             }
         }
 
+        self.unroll();
+
         if let StepSpecific::Fork { branches } = &self.curr_state().specific {
             assert!(branches.len() > 1);
             return MidProcessResult {
                 branches: branches.iter().map(|_| Branch::noop()).collect(),
+                attention_masks: vec![],
             };
         }
 
@@ -1253,14 +1544,179 @@ impl AiciCtrl for Runner {
 }
 
 fn main() {
+    // natively, `aici_declctrl --compile prog.txt` prints JSON AST of a DSL program
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "--compile" {
+        let src = match std::fs::read_to_string(&args[2]) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}: {}", args[2], e);
+                std::process::exit(1)
+            }
+        };
+        match dsl::compile(&src) {
+            Ok(p) => println!("{}", serde_json::to_string_pretty(&p).unwrap()),
+            Err(e) => {
+                eprintln!("{}: {}", args[2], e);
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
     aici_abi::cfg::cfg_test().unwrap();
     //    let _run = sample_prog();
 }
 
+fn collect_labels(steps: &[Step], labels: &mut Vec<LabelName>) {
+    for step in steps {
+        match step {
+            Step::Fixed { attrs, .. } | Step::Choose { attrs, .. } | Step::Gen { attrs, .. } => {
+                labels.extend(attrs.label.iter().cloned())
+            }
+            Step::Fork { branches } => {
+                for branch in branches {
+                    collect_labels(branch, labels);
+                }
+            }
+            Step::Repeat { body, .. } => collect_labels(body, labels),
+            Step::If { then, r#else, .. } => {
+                collect_labels(then, labels);
+                collect_labels(r#else, labels);
+            }
+            Step::Wait { .. } | Step::Stop {} => {}
+        }
+    }
+}
+
+fn add_suffix(label: &mut Option<LabelName>, labels: &[LabelName], suffix: &str) {
+    if let Some(l) = label {
+        if labels.contains(l) {
+            l.0.push_str(suffix);
+        }
+    }
+}
+
+fn rename_in(steps: &mut [Step], labels: &[LabelName], suffix: &str) {
+    for step in steps {
+        match step {
+            Step::Fixed {
+                attrs, following, ..
+            } => {
+                add_suffix(&mut attrs.label, labels, suffix);
+                add_suffix(following, labels, suffix);
+            }
+            Step::Choose { attrs, .. } | Step::Gen { attrs, .. } => {
+                add_suffix(&mut attrs.label, labels, suffix)
+            }
+            Step::Fork { branches } => {
+                for branch in branches {
+                    rename_in(branch, labels, suffix);
+                }
+            }
+            Step::Repeat { body, .. } => rename_in(body, labels, suffix),
+            Step::If { then, r#else, .. } => {
+                rename_in(then, labels, suffix);
+                rename_in(r#else, labels, suffix);
+            }
+            Step::Wait { .. } | Step::Stop {} => {}
+        }
+    }
+}
+
+/// Append `suffix` to the labels set in `steps` (an iteration of a Repeat body),
+/// and to the `following=` references to them, so that each iteration backtracks
+/// to its own labels rather than to the ones of the first iteration.
+fn rename_labels(steps: &[Step], suffix: &str) -> Vec<Step> {
+    let mut labels = Vec::new();
+    collect_labels(steps, &mut labels);
+    let mut steps = steps.to_vec();
+    if !labels.is_empty() {
+        rename_in(&mut steps, &labels, suffix);
+    }
+    steps
+}
+
+/// Report invalid typed Gen steps and empty Repeat bodies before running the program.
+fn check_steps(steps: &[Step]) -> Result<(), String> {
+    for step in steps {
+        match step {
+            Step::Gen {
+                r#type: Some(tp), ..
+            } => {
+                tp.to_regex()?;
+            }
+            Step::Fork { branches } => {
+                for branch in branches {
+                    check_steps(branch)?;
+                }
+            }
+            Step::Repeat { body, .. } => {
+                if body.is_empty() {
+                    return Err("repeat body cannot be empty".to_string());
+                }
+                check_steps(body)?
+            }
+            Step::If { then, r#else, .. } => {
+                check_steps(then)?;
+                check_steps(r#else)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Runner built by aici_preinit(); regexes and grammars are compiled there.
+struct Preinit(Runner);
+
+// Wasm is single-threaded
+unsafe impl Send for Preinit {}
+
+static PREINIT: Mutex<Option<Preinit>> = Mutex::new(None);
+
+fn runner_preinit() {
+    // errors are reported by program_from_env(), when the request is run
+    let a = aici_abi::arg_bytes();
+    let program = if a.starts_with(dsl::MARKER.as_bytes()) {
+        dsl::compile(&String::from_utf8_lossy(&a)).ok()
+    } else {
+        serde_json::from_slice::<Program>(&a)
+            .ok()
+            .filter(|p| check_steps(&p.steps).is_ok())
+    };
+    if let Some(p) = program {
+        *PREINIT.lock().unwrap() = Some(Preinit(Runner::new(p)));
+    }
+}
+
 fn runner_from_env() -> Runner {
+    let runner = match PREINIT.lock().unwrap().take() {
+        Some(Preinit(runner)) => runner,
+        None => Runner::new(program_from_env()),
+    };
+    runner.trace_states();
+    runner
+}
+
+fn program_from_env() -> Program {
     let a = aici_abi::arg_bytes();
-    match serde_json::from_slice(&a) {
-        Ok(p) => Runner::new(p),
+    if a.starts_with(dsl::MARKER.as_bytes()) {
+        match dsl::compile(&String::from_utf8_lossy(&a)) {
+            Ok(p) => return p,
+            Err(e) => {
+                println!("DSL parsing {}", e);
+                panic!()
+            }
+        }
+    }
+    match serde_json::from_slice::<Program>(&a) {
+        Ok(p) => {
+            if let Err(e) = check_steps(&p.steps) {
+                println!("JSON AST error: {}", e);
+                panic!()
+            }
+            p
+        }
         Err(e) => {
             let mut col = e.column().saturating_sub(1);
             let mut line = e.line().saturating_sub(1);
@@ -1287,3 +1743,43 @@ fn runner_from_env() -> Runner {
 }
 
 aici_expose_all!(Runner, runner_from_env());
+aici_abi::aici_expose_preinit!(runner_preinit);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn steps(v: Value) -> Vec<Step> {
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn repeat_labels() {
+        let body = steps(json!([
+            {"Fixed": {"text": {"String": {"str": "a"}}, "label": "x"}},
+            {"Fixed": {"text": {"String": {"str": "b"}}, "following": "x"}},
+            {"Fixed": {"text": {"String": {"str": "c"}}, "following": "outer"}},
+            {"Repeat": {"body": [
+                {"Gen": {"label": "y"}},
+                {"Fixed": {"text": {"String": {"str": "d"}}, "following": "x"}},
+            ]}},
+        ]));
+        let renamed = serde_json::to_value(rename_labels(&body, "#1")).unwrap();
+        assert_eq!(renamed[0]["Fixed"]["label"], json!("x#1"));
+        assert_eq!(renamed[1]["Fixed"]["following"], json!("x#1"));
+        assert_eq!(renamed[2]["Fixed"]["following"], json!("outer"));
+        let inner = &renamed[3]["Repeat"]["body"];
+        assert_eq!(inner[0]["Gen"]["label"], json!("y#1"));
+        assert_eq!(inner[1]["Fixed"]["following"], json!("x#1"));
+    }
+
+    #[test]
+    fn empty_repeat() {
+        let s = steps(json!([{"If": {
+            "cond": {"String": {"str": "1"}},
+            "then": [{"Repeat": {"body": []}}],
+        }}]));
+        assert_eq!(check_steps(&s), Err("repeat body cannot be empty".to_string()));
+    }
+}
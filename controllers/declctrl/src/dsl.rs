@@ -0,0 +1,927 @@
+// A Handlebars-like syntax for declctrl programs, for example:
+//
+//     #dsl
+//     Name: {{gen name max_tokens=10 stop="\n"}}
+//     Favorite color: {{select color options=["red", "green", "blue"]}}
+//     {{#if color == "red"}}Why red? {{gen why stop="\n"}}{{/if}}
+//
+// The first line has to be MARKER, optionally followed by options (currently only
+// `token_healing`, which sets Program.token_healing). The rest is compiled into the JSON AST (Program).
+// Text outside of {{...}} is generated as is, {{var}} expands to the value of variable.
+// Commands:
+//   {{gen [var] [regex="..."] [yacc="..."] [stop="..."] [max_tokens=N] [max_words=N]
+//        [max_bytes=N] [mask_tags=[...]] [append=var] [tag=...] [label=...]}}
+//   {{gen [var] type=int|float|bool|date|enum [min=N] [max=N] [decimals=N] [format="..."]
+//        [options=[...]] ...}}
+//   {{select [var] options=["a", "b"] | options=var [tag=...] [label=...]}}
+//   {{text "..." [following=label] [tag=...] [label=...]}}
+//   {{wait var...}}  {{stop}}  {{! comment }}
+//   {{#if cond}}...{{else}}...{{/if}}
+//   {{#repeat [max_iter=N] [until=cond]}}...{{/repeat}}
+//   {{#fork}}...{{branch}}...{{/fork}}
+// Conditions are `x`, `x == y` or `x != y`, where x and y are variables or strings.
+
+use crate::{Expr, GenType, LabelName, Program, Step, StepAttributes, Stmt, TagName, VarName};
+use std::fmt::Display;
+
+pub const MARKER: &str = "#dsl";
+
+#[derive(Debug, Clone)]
+pub struct DslError {
+    /// 1-based
+    pub line: usize,
+    /// 1-based, in characters
+    pub column: usize,
+    pub message: String,
+    /// The source line where the error occurred.
+    pub source_line: String,
+}
+
+impl Display for DslError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "error at line {}, column {}: {}",
+            self.line, self.column, self.message
+        )?;
+        writeln!(f, "  {}", self.source_line)?;
+        write!(f, "  {}^", " ".repeat(self.column - 1))
+    }
+}
+
+impl std::error::Error for DslError {}
+
+pub type Result<T> = std::result::Result<T, DslError>;
+
+/// Compile a program starting with MARKER line (which may list options).
+pub fn compile(src: &str) -> Result<Program> {
+    let mut p = Parser { src, pos: 0 };
+    if !src.starts_with(MARKER) {
+        return Err(p.error_at(0, format!("program has to start with {:?}", MARKER)));
+    }
+    let line_end = src.find('\n').map(|p| p + 1).unwrap_or(src.len());
+    let mut token_healing = false;
+    let mut pos = MARKER.len();
+    for word in src[MARKER.len()..line_end].split_inclusive(char::is_whitespace) {
+        match word.trim() {
+            "" => {}
+            "token_healing" => token_healing = true,
+            w => return Err(p.error_at(pos, format!("unknown option {:?}", w))),
+        }
+        pos += word.len();
+    }
+    p.pos = line_end;
+    let (steps, _) = p.parse_steps(&[])?;
+    Ok(Program {
+        steps,
+        token_healing,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(String),
+    Assign,
+    EqEq,
+    NotEq,
+    LBracket,
+    RBracket,
+    Comma,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Ident(String),
+    Str(String),
+    Num(String),
+    List(Vec<Value>),
+}
+
+// a {{...}} tag
+struct Tag {
+    // offset of "{{"
+    start: usize,
+    // '#', '/' or ' '
+    kind: char,
+    name: String,
+    // tokens after the name, with their offsets
+    tokens: Vec<(Token, usize)>,
+}
+
+struct Args {
+    positional: Vec<(Value, usize)>,
+    named: Vec<(String, Value, usize)>,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+const COMMANDS: &[&str] = &["gen", "select", "text", "wait", "stop", "else", "branch"];
+
+impl<'a> Parser<'a> {
+    fn error_at(&self, pos: usize, message: String) -> DslError {
+        let before = &self.src[..pos];
+        let line_start = before.rfind('\n').map(|p| p + 1).unwrap_or(0);
+        let line_end = self.src[pos..]
+            .find('\n')
+            .map(|p| pos + p)
+            .unwrap_or(self.src.len());
+        DslError {
+            line: before.matches('\n').count() + 1,
+            column: self.src[line_start..pos].chars().count() + 1,
+            message,
+            source_line: self.src[line_start..line_end].to_string(),
+        }
+    }
+
+    fn error(&self, message: &str) -> DslError {
+        self.error_at(self.pos, message.to_string())
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.rest().chars().next() {
+            if c == ' ' || c == '\t' || c == '\r' || c == '\n' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Parse steps until one of the `terminators` tags (like "/if") or end of input.
+    /// Returns the terminating tag, if any.
+    fn parse_steps(&mut self, terminators: &[&str]) -> Result<(Vec<Step>, Option<Tag>)> {
+        let mut steps = Vec::new();
+        let mut text: Vec<Expr> = Vec::new();
+
+        fn flush(text: &mut Vec<Expr>, steps: &mut Vec<Step>) {
+            if text.len() > 0 {
+                let parts = std::mem::take(text);
+                let text = if parts.len() == 1 {
+                    parts.into_iter().next().unwrap()
+                } else {
+                    Expr::Concat { parts, list: false }
+                };
+                steps.push(Step::Fixed {
+                    text,
+                    following: None,
+                    attrs: StepAttributes::default(),
+                });
+            }
+        }
+
+        loop {
+            let next_tag = self.rest().find("{{").map(|p| self.pos + p);
+            let text_end = next_tag.unwrap_or(self.src.len());
+            if text_end > self.pos {
+                text.push(Expr::String {
+                    str: self.src[self.pos..text_end].to_string(),
+                });
+                self.pos = text_end;
+            }
+            if next_tag.is_none() {
+                flush(&mut text, &mut steps);
+                if terminators.len() > 0 {
+                    return Err(self.error(&format!(
+                        "expecting {{{{{}}}}}",
+                        terminators.last().unwrap()
+                    )));
+                }
+                return Ok((steps, None));
+            }
+
+            let tag = self.parse_tag()?;
+            let full_name = format!("{}{}", tag.kind, tag.name);
+            let full_name = full_name.trim();
+            if terminators.contains(&full_name) {
+                flush(&mut text, &mut steps);
+                return Ok((steps, Some(tag)));
+            }
+
+            match tag.kind {
+                '!' => {}
+                ' ' if !COMMANDS.contains(&tag.name.as_str()) => {
+                    if tag.tokens.len() > 0 {
+                        return Err(self
+                            .error_at(tag.tokens[0].1, format!("unknown command {:?}", tag.name)));
+                    }
+                    text.push(Expr::Var {
+                        var: VarName(tag.name.clone()),
+                    });
+                }
+                '/' => {
+                    return Err(
+                        self.error_at(tag.start, format!("unexpected {{{{{}}}}}", full_name))
+                    )
+                }
+                _ => {
+                    flush(&mut text, &mut steps);
+                    let step = self.command(tag)?;
+                    steps.push(step);
+                }
+            }
+        }
+    }
+
+    fn parse_tag(&mut self) -> Result<Tag> {
+        let start = self.pos;
+        self.pos += 2;
+        if self.rest().starts_with('!') {
+            match self.rest().find("}}") {
+                Some(p) => self.pos += p + 2,
+                None => return Err(self.error_at(start, "unterminated comment".to_string())),
+            }
+            return Ok(Tag {
+                start,
+                kind: '!',
+                name: String::new(),
+                tokens: vec![],
+            });
+        }
+        self.skip_spaces();
+        let kind = match self.rest().chars().next() {
+            Some(c @ ('#' | '/')) => {
+                self.pos += 1;
+                c
+            }
+            _ => ' ',
+        };
+        let name = match self.next_token()? {
+            Some((Token::Ident(name), _)) => name,
+            _ => return Err(self.error_at(start, "expecting command or variable name".to_string())),
+        };
+        let mut tokens = Vec::new();
+        while let Some(t) = self.next_token()? {
+            tokens.push(t);
+        }
+        Ok(Tag {
+            start,
+            kind,
+            name,
+            tokens,
+        })
+    }
+
+    // returns None on "}}"
+    fn next_token(&mut self) -> Result<Option<(Token, usize)>> {
+        self.skip_spaces();
+        let start = self.pos;
+        let mut chars = self.rest().chars();
+        let c = match chars.next() {
+            Some(c) => c,
+            None => return Err(self.error("expecting }}")),
+        };
+        let tok = match c {
+            '}' if self.rest().starts_with("}}") => {
+                self.pos += 2;
+                return Ok(None);
+            }
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            '=' if self.rest().starts_with("==") => {
+                self.pos += 1;
+                Token::EqEq
+            }
+            '=' => Token::Assign,
+            '!' if self.rest().starts_with("!=") => {
+                self.pos += 1;
+                Token::NotEq
+            }
+            '"' => {
+                self.pos += 1;
+                return Ok(Some((Token::Str(self.string_literal(start)?), start)));
+            }
+            '0'..='9' | '-' => {
+                let len = 1 + self.rest()[1..]
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                    .unwrap_or(self.rest().len() - 1);
+                let num = &self.rest()[..len];
+                if num.parse::<f64>().is_err() {
+                    return Err(self.error("invalid number"));
+                }
+                self.pos += len;
+                return Ok(Some((Token::Num(num.to_string()), start)));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let len = self
+                    .rest()
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                    .unwrap_or(self.rest().len());
+                let id = self.rest()[..len].to_string();
+                self.pos += len;
+                return Ok(Some((Token::Ident(id), start)));
+            }
+            _ => return Err(self.error(&format!("unexpected character {:?}", c))),
+        };
+        self.pos += 1;
+        Ok(Some((tok, start)))
+    }
+
+    fn string_literal(&mut self, start: usize) -> Result<String> {
+        let mut res = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += idx + 1;
+                    return Ok(res);
+                }
+                '\\' => {
+                    let esc = match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        _ => {
+                            return Err(self.error_at(self.pos + idx, "invalid escape".to_string()))
+                        }
+                    };
+                    res.push(esc);
+                }
+                _ => res.push(c),
+            }
+        }
+        Err(self.error_at(start, "unterminated string".to_string()))
+    }
+
+    fn command(&mut self, tag: Tag) -> Result<Step> {
+        match (tag.kind, tag.name.as_str()) {
+            (' ', "gen") => self.gen(tag),
+            (' ', "select") => {
+                let args = self.args(&tag)?;
+                let (var, attrs) = self.var_and_attrs(&args, &["options"])?;
+                let options = match self.named(&args, "options") {
+                    Some((Value::List(items), pos)) => {
+                        let mut parts = Vec::new();
+                        for item in items {
+                            parts.push(Expr::String {
+                                str: self.string_value(item, *pos)?,
+                            });
+                        }
+                        Expr::Concat { parts, list: true }
+                    }
+                    Some((Value::Ident(var), _)) => Expr::Var {
+                        var: VarName(var.clone()),
+                    },
+                    Some((_, pos)) => {
+                        return Err(self.error_at(*pos, "expecting list or variable".to_string()))
+                    }
+                    None => return Err(self.error_at(tag.start, "missing options=".to_string())),
+                };
+                Ok(Step::Choose {
+                    options,
+                    attrs: with_set_var(attrs, var),
+                })
+            }
+            (' ', "text") => {
+                let args = self.args(&tag)?;
+                let text = match args.positional.as_slice() {
+                    [(Value::Str(s), _)] => s.clone(),
+                    _ => return Err(self.error_at(tag.start, "expecting one string".to_string())),
+                };
+                let following = match self.named(&args, "following") {
+                    Some((v, pos)) => Some(LabelName(self.string_value(v, *pos)?)),
+                    None => None,
+                };
+                let attrs = self.attrs(&args, &["following"])?;
+                Ok(Step::Fixed {
+                    text: Expr::String { str: text },
+                    following,
+                    attrs,
+                })
+            }
+            (' ', "wait") => {
+                let args = self.args(&tag)?;
+                if args.named.len() > 0 || args.positional.len() == 0 {
+                    return Err(self.error_at(tag.start, "expecting variable names".to_string()));
+                }
+                let mut vars = Vec::new();
+                for (v, pos) in &args.positional {
+                    vars.push(VarName(self.ident_value(v, *pos)?));
+                }
+                Ok(Step::Wait { vars })
+            }
+            (' ', "stop") => {
+                self.args(&tag)?;
+                Ok(Step::Stop {})
+            }
+            ('#', "if") => {
+                let cond = self.condition(&tag.tokens, tag.start)?;
+                let (then, end) = self.parse_steps(&["else", "/if"])?;
+                let r#else = if end.unwrap().name == "else" {
+                    self.parse_steps(&["/if"])?.0
+                } else {
+                    vec![]
+                };
+                Ok(Step::If { cond, then, r#else })
+            }
+            ('#', "repeat") => {
+                let mut max_iter = None;
+                let mut until = None;
+                let mut i = 0;
+                while i < tag.tokens.len() {
+                    let (tok, pos) = &tag.tokens[i];
+                    match tok {
+                        Token::Ident(k) if k == "max_iter" || k == "until" => {
+                            if tag.tokens.get(i + 1).map(|t| &t.0) != Some(&Token::Assign) {
+                                return Err(self.error_at(*pos, "expecting =".to_string()));
+                            }
+                            i += 2;
+                            if k == "max_iter" {
+                                match tag.tokens.get(i) {
+                                    Some((Token::Num(n), _)) if n.parse::<usize>().is_ok() => {
+                                        max_iter = n.parse().ok()
+                                    }
+                                    _ => {
+                                        return Err(
+                                            self.error_at(*pos, "expecting number".to_string())
+                                        )
+                                    }
+                                }
+                                i += 1;
+                            } else {
+                                // the condition takes the rest of the tag
+                                until = Some(self.condition(&tag.tokens[i..], *pos)?);
+                                i = tag.tokens.len();
+                            }
+                        }
+                        _ => {
+                            return Err(
+                                self.error_at(*pos, "expecting max_iter= or until=".to_string())
+                            )
+                        }
+                    }
+                }
+                let (body, _) = self.parse_steps(&["/repeat"])?;
+                if body.len() == 0 {
+                    return Err(self.error_at(tag.start, "empty repeat".to_string()));
+                }
+                Ok(Step::Repeat {
+                    body,
+                    max_iter,
+                    until,
+                })
+            }
+            ('#', "fork") => {
+                self.args(&tag)?;
+                let mut branches = Vec::new();
+                loop {
+                    let (steps, end) = self.parse_steps(&["branch", "/fork"])?;
+                    if steps.len() == 0 {
+                        return Err(self.error_at(tag.start, "empty fork branch".to_string()));
+                    }
+                    branches.push(steps);
+                    if end.unwrap().name == "fork" {
+                        break;
+                    }
+                }
+                if branches.len() < 2 {
+                    return Err(
+                        self.error_at(tag.start, "fork needs at least two branches".to_string())
+                    );
+                }
+                Ok(Step::Fork { branches })
+            }
+            _ => Err(self.error_at(
+                tag.start,
+                format!("unexpected {}{}", tag.kind, tag.name).replace(' ', ""),
+            )),
+        }
+    }
+
+    fn gen(&mut self, tag: Tag) -> Result<Step> {
+        let args = self.args(&tag)?;
+        let keys = [
+            "regex",
+            "yacc",
+            "stop",
+            "max_tokens",
+            "max_words",
+            "max_bytes",
+            "mask_tags",
+            "append",
+            "type",
+            "min",
+            "max",
+            "decimals",
+            "format",
+            "options",
+        ];
+        let (var, mut attrs) = self.var_and_attrs(&args, &keys)?;
+        let str_arg = |name| match self.named(&args, name) {
+            Some((v, pos)) => self.string_value(v, *pos).map(Some),
+            None => Ok(None),
+        };
+        let mask_tags = match self.named(&args, "mask_tags") {
+            Some((Value::List(items), pos)) => {
+                let mut tags = Vec::new();
+                for item in items {
+                    tags.push(TagName(self.string_value(item, *pos)?));
+                }
+                Some(tags)
+            }
+            Some((_, pos)) => return Err(self.error_at(*pos, "expecting list".to_string())),
+            None => None,
+        };
+        if let Some(append) = str_arg("append")? {
+            let var = VarName(append);
+            attrs.stmts.push(Stmt::Set {
+                var: var.clone(),
+                expr: Expr::Concat {
+                    parts: vec![Expr::Var { var }, Expr::Current {}],
+                    list: false,
+                },
+            });
+        }
+        let rx = str_arg("regex")?;
+        let yacc = str_arg("yacc")?;
+        if rx.is_some() && yacc.is_some() {
+            return Err(self.error_at(tag.start, "can't have both regex= and yacc=".to_string()));
+        }
+        let r#type = match str_arg("type")?.as_deref() {
+            None => None,
+            Some("int") => Some(GenType::Int {
+                min: self.num_arg(&args, "min")?,
+                max: self.num_arg(&args, "max")?,
+            }),
+            Some("float") => Some(GenType::Float {
+                min: self.num_arg(&args, "min")?,
+                max: self.num_arg(&args, "max")?,
+                decimals: self.num_arg(&args, "decimals")?.unwrap_or(2),
+            }),
+            Some("bool") => Some(GenType::Bool {}),
+            Some("date") => Some(GenType::Date {
+                format: str_arg("format")?,
+            }),
+            Some("enum") => {
+                let mut options = Vec::new();
+                match self.named(&args, "options") {
+                    Some((Value::List(items), pos)) => {
+                        for item in items {
+                            options.push(self.string_value(item, *pos)?);
+                        }
+                    }
+                    _ => {
+                        return Err(self.error_at(tag.start, "expecting options=[...]".to_string()))
+                    }
+                }
+                Some(GenType::Enum { options })
+            }
+            Some(t) => return Err(self.error_at(tag.start, format!("unknown type {:?}", t))),
+        };
+        if r#type.is_some() && (rx.is_some() || yacc.is_some()) {
+            return Err(self.error_at(
+                tag.start,
+                "can't have type= with regex= or yacc=".to_string(),
+            ));
+        }
+        if let Some(tp) = &r#type {
+            if let Err(e) = tp.to_regex() {
+                let pos = match (tp, self.named(&args, "format")) {
+                    (GenType::Date { .. }, Some((_, pos))) => *pos,
+                    _ => *self.named(&args, "type").unwrap().1,
+                };
+                return Err(self.error_at(pos, e));
+            }
+        }
+        Ok(Step::Gen {
+            rx,
+            yacc,
+            r#type,
+            inner: vec![],
+            stop_at: str_arg("stop")?,
+            max_tokens: self.num_arg(&args, "max_tokens")?,
+            max_words: self.num_arg(&args, "max_words")?,
+            max_bytes: self.num_arg(&args, "max_bytes")?,
+            mask_tags,
+            attrs: with_set_var(attrs, var),
+        })
+    }
+
+    fn args(&self, tag: &Tag) -> Result<Args> {
+        let mut args = Args {
+            positional: vec![],
+            named: vec![],
+        };
+        let mut i = 0;
+        let toks = &tag.tokens;
+        while i < toks.len() {
+            let pos = toks[i].1;
+            if let (Token::Ident(k), Some((Token::Assign, _))) = (&toks[i].0, toks.get(i + 1)) {
+                if args.named.iter().any(|(n, _, _)| n == k) {
+                    return Err(self.error_at(pos, format!("duplicate argument {:?}", k)));
+                }
+                i += 2;
+                let v = self.value(toks, &mut i, pos)?;
+                args.named.push((k.clone(), v, pos));
+            } else {
+                if args.named.len() > 0 {
+                    return Err(self.error_at(pos, "positional argument after named".to_string()));
+                }
+                let v = self.value(toks, &mut i, pos)?;
+                args.positional.push((v, pos));
+            }
+        }
+        Ok(args)
+    }
+
+    fn value(&self, toks: &[(Token, usize)], i: &mut usize, pos: usize) -> Result<Value> {
+        let (tok, pos) = match toks.get(*i) {
+            Some((t, p)) => (t, *p),
+            None => return Err(self.error_at(pos, "expecting value".to_string())),
+        };
+        *i += 1;
+        match tok {
+            Token::Ident(s) => Ok(Value::Ident(s.clone())),
+            Token::Str(s) => Ok(Value::Str(s.clone())),
+            Token::Num(n) => Ok(Value::Num(n.clone())),
+            Token::LBracket => {
+                let mut items = Vec::new();
+                loop {
+                    if let Some((Token::RBracket, _)) = toks.get(*i) {
+                        *i += 1;
+                        return Ok(Value::List(items));
+                    }
+                    if items.len() > 0 {
+                        match toks.get(*i) {
+                            Some((Token::Comma, _)) => *i += 1,
+                            _ => return Err(self.error_at(pos, "expecting , or ]".to_string())),
+                        }
+                    }
+                    items.push(self.value(toks, i, pos)?);
+                }
+            }
+            _ => Err(self.error_at(pos, "expecting value".to_string())),
+        }
+    }
+
+    fn condition(&self, toks: &[(Token, usize)], pos: usize) -> Result<Expr> {
+        let operand = |t: Option<&(Token, usize)>| match t {
+            Some((Token::Ident(v), _)) => Ok(Expr::Var {
+                var: VarName(v.clone()),
+            }),
+            Some((Token::Str(s), _)) => Ok(Expr::String { str: s.clone() }),
+            Some((_, p)) => Err(self.error_at(*p, "expecting variable or string".to_string())),
+            None => Err(self.error_at(pos, "expecting condition".to_string())),
+        };
+        let a = operand(toks.get(0))?;
+        if toks.len() == 1 {
+            return Ok(a);
+        }
+        let eq = match toks.get(1) {
+            Some((Token::EqEq, _)) => true,
+            Some((Token::NotEq, _)) => false,
+            Some((_, p)) => return Err(self.error_at(*p, "expecting == or !=".to_string())),
+            None => unreachable!(),
+        };
+        let b = operand(toks.get(2))?;
+        if let Some((_, p)) = toks.get(3) {
+            return Err(self.error_at(*p, "unexpected token after condition".to_string()));
+        }
+        let yes = Box::new(Expr::String {
+            str: "1".to_string(),
+        });
+        let no = Box::new(Expr::String { str: String::new() });
+        let (eq, neq) = if eq { (yes, no) } else { (no, yes) };
+        Ok(Expr::IfEq {
+            a: Box::new(a),
+            b: Box::new(b),
+            eq,
+            neq,
+        })
+    }
+
+    fn named<'b>(&self, args: &'b Args, name: &str) -> Option<(&'b Value, &'b usize)> {
+        args.named
+            .iter()
+            .find(|(n, _, _)| n == name)
+            .map(|(_, v, p)| (v, p))
+    }
+
+    fn num_arg<T: std::str::FromStr>(&self, args: &Args, name: &str) -> Result<Option<T>> {
+        match self.named(args, name) {
+            Some((Value::Num(n), pos)) => n
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error_at(*pos, format!("invalid number {:?}", n))),
+            Some((_, pos)) => Err(self.error_at(*pos, "expecting number".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn string_value(&self, v: &Value, pos: usize) -> Result<String> {
+        match v {
+            Value::Str(s) | Value::Ident(s) => Ok(s.clone()),
+            _ => Err(self.error_at(pos, "expecting string".to_string())),
+        }
+    }
+
+    fn ident_value(&self, v: &Value, pos: usize) -> Result<String> {
+        match v {
+            Value::Ident(s) => Ok(s.clone()),
+            _ => Err(self.error_at(pos, "expecting variable name".to_string())),
+        }
+    }
+
+    // optional variable name to store the result, and tag= and label=
+    fn var_and_attrs(
+        &self,
+        args: &Args,
+        keys: &[&str],
+    ) -> Result<(Option<VarName>, StepAttributes)> {
+        let var = match args.positional.as_slice() {
+            [] => None,
+            [(v, pos)] => Some(VarName(self.ident_value(v, *pos)?)),
+            [_, (_, pos), ..] => {
+                return Err(self.error_at(*pos, "too many positional arguments".to_string()))
+            }
+        };
+        Ok((var, self.attrs(args, keys)?))
+    }
+
+    fn attrs(&self, args: &Args, keys: &[&str]) -> Result<StepAttributes> {
+        let mut attrs = StepAttributes::default();
+        for (name, v, pos) in &args.named {
+            match name.as_str() {
+                "tag" => attrs.tag = Some(TagName(self.string_value(v, *pos)?)),
+                "label" => attrs.label = Some(LabelName(self.string_value(v, *pos)?)),
+                _ if keys.contains(&name.as_str()) => {}
+                _ => return Err(self.error_at(*pos, format!("unknown argument {:?}", name))),
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+fn with_set_var(mut attrs: StepAttributes, var: Option<VarName>) -> StepAttributes {
+    if let Some(var) = var {
+        attrs.stmts.insert(
+            0,
+            Stmt::Set {
+                var,
+                expr: Expr::Current {},
+            },
+        );
+    }
+    attrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn ok(src: &str) -> Value {
+        match compile(src) {
+            Ok(p) => serde_json::to_value(&p).unwrap(),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn error(src: &str, line: usize, column: usize, msg: &str) {
+        match compile(src) {
+            Ok(p) => panic!("expected error, got {:?}", serde_json::to_string(&p)),
+            Err(e) => {
+                assert_eq!((e.line, e.column), (line, column), "{e}");
+                assert!(e.message.contains(msg), "{e}");
+            }
+        }
+    }
+
+    #[test]
+    fn gen() {
+        let p = ok("#dsl\nName: {{gen name max_tokens=10 stop=\"\\n\"}}");
+        assert_eq!(p["token_healing"], json!(false));
+        assert_eq!(p["steps"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            p["steps"][0]["Fixed"]["text"],
+            json!({"String": {"str": "Name: "}})
+        );
+        let gen = &p["steps"][1]["Gen"];
+        assert_eq!(gen["max_tokens"], json!(10));
+        assert_eq!(gen["stop_at"], json!("\n"));
+        assert_eq!(
+            gen["stmts"],
+            json!([{"Set": {"var": "name", "expr": {"Current": {}}}}])
+        );
+    }
+
+    #[test]
+    fn text_and_vars() {
+        let p = ok("#dsl\nHi {{name}}!{{! comment }}");
+        assert_eq!(
+            p["steps"][0]["Fixed"]["text"]["Concat"]["parts"],
+            json!([
+                {"String": {"str": "Hi "}},
+                {"Var": {"var": "name"}},
+                {"String": {"str": "!"}},
+            ])
+        );
+    }
+
+    #[test]
+    fn select_append() {
+        let p = ok("#dsl\n{{select c options=[\"a\", \"b\"]}}{{gen regex=\"[0-9]\" append=n}}");
+        let opts = &p["steps"][0]["Choose"]["options"]["Concat"];
+        assert_eq!(opts["list"], json!(true));
+        assert_eq!(
+            opts["parts"],
+            json!([{"String": {"str": "a"}}, {"String": {"str": "b"}}])
+        );
+        let gen = &p["steps"][1]["Gen"];
+        assert_eq!(gen["rx"], json!("[0-9]"));
+        assert_eq!(gen["stmts"][0]["Set"]["var"], json!("n"));
+        assert_eq!(
+            gen["stmts"][0]["Set"]["expr"]["Concat"]["parts"],
+            json!([{"Var": {"var": "n"}}, {"Current": {}}])
+        );
+    }
+
+    #[test]
+    fn if_repeat() {
+        let p = ok(concat!(
+            "#dsl\n{{#repeat max_iter=3 until=n == \"done\"}}x{{/repeat}}",
+            "{{#if c != \"red\"}}A{{else}}B{{/if}}"
+        ));
+        let rep = &p["steps"][0]["Repeat"];
+        assert_eq!(rep["max_iter"], json!(3));
+        assert_eq!(rep["until"]["IfEq"]["a"], json!({"Var": {"var": "n"}}));
+        assert_eq!(
+            rep["until"]["IfEq"]["b"],
+            json!({"String": {"str": "done"}})
+        );
+        assert_eq!(rep["until"]["IfEq"]["eq"], json!({"String": {"str": "1"}}));
+        assert_eq!(rep["body"].as_array().unwrap().len(), 1);
+        let cond = &p["steps"][1]["If"];
+        // != swaps the branches of IfEq
+        assert_eq!(cond["cond"]["IfEq"]["eq"], json!({"String": {"str": ""}}));
+        assert_eq!(
+            cond["then"][0]["Fixed"]["text"],
+            json!({"String": {"str": "A"}})
+        );
+        assert_eq!(
+            cond["else"][0]["Fixed"]["text"],
+            json!({"String": {"str": "B"}})
+        );
+    }
+
+    #[test]
+    fn token_healing() {
+        assert_eq!(
+            ok("#dsl token_healing\nHello")["token_healing"],
+            json!(true)
+        );
+        error(
+            "#dsl tokn_healing\nHello",
+            1,
+            6,
+            "unknown option \"tokn_healing\"",
+        );
+    }
+
+    #[test]
+    fn errors() {
+        error("Hello", 1, 1, "program has to start with \"#dsl\"");
+        error("#dsl\nHi {{foo bar}}", 2, 10, "unknown command \"foo\"");
+        error("#dsl\nA\n{{#if x}}yes", 3, 13, "expecting {{/if}}");
+        error("#dsl\n{{gen x max_tokens=\"a\"}}", 2, 9, "expecting number");
+        error("#dsl\nX {{text \"abc}}", 2, 10, "unterminated string");
+        error("#dsl\n{{/repeat}}", 2, 1, "unexpected {{/repeat}}");
+        error(
+            "#dsl\n{{#fork}}a{{/fork}}",
+            2,
+            1,
+            "fork needs at least two branches",
+        );
+    }
+
+    #[test]
+    fn type_errors() {
+        error(
+            "#dsl\n{{gen d type=date format=\"%q\"}}",
+            2,
+            19,
+            "unsupported date format: %q",
+        );
+        error("#dsl\n{{gen n type=int min=3 max=1}}", 2, 9, "empty range");
+    }
+
+    #[test]
+    fn error_display() {
+        let e = compile("#dsl\nHi {{foo bar}}").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "error at line 2, column 10: unknown command \"foo\"\n  Hi {{foo bar}}\n           ^"
+        );
+    }
+}
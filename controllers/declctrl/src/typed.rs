@@ -0,0 +1,333 @@
+// Compilation of typed Gen steps into regexes.
+//
+// Numeric ranges are encoded directly in the regex (and thus in the DFA),
+// for example [7, 123] becomes ([7-9]|[1-9][0-9]|1[0-1][0-9]|12[0-3]).
+// Floats are handled as integers scaled by 10^decimals, with the dot inserted
+// before the last `decimals` digits.
+
+use crate::GenType;
+
+impl GenType {
+    /// Returns an error message for invalid types (empty range, unsupported date format etc.).
+    pub fn to_regex(&self) -> Result<String, String> {
+        match self {
+            GenType::Int { min, max } => {
+                number_regex(min.map(|v| v as i128), max.map(|v| v as i128), 0)
+            }
+            GenType::Float { min, max, decimals } => {
+                let scale = 10f64.powi(*decimals as i32);
+                let min = match min {
+                    Some(v) => Some(scaled(*v, scale, true)?),
+                    None => None,
+                };
+                let max = match max {
+                    Some(v) => Some(scaled(*v, scale, false)?),
+                    None => None,
+                };
+                number_regex(min, max, *decimals)
+            }
+            GenType::Bool {} => Ok("(true|false)".to_string()),
+            GenType::Date { format } => date_regex(format.as_deref().unwrap_or("%Y-%m-%d")),
+            GenType::Enum { options } => {
+                if options.len() == 0 {
+                    return Err("enum needs at least one option".to_string());
+                }
+                let opts = options.iter().map(|o| escape(o)).collect::<Vec<_>>();
+                Ok(format!("({})", opts.join("|")))
+            }
+        }
+    }
+
+    /// Normalize generated value, for storing in variables.
+    pub fn normalize(&self, text: &[u8]) -> Vec<u8> {
+        let s = String::from_utf8_lossy(text);
+        let s = s.trim();
+        let r = match self {
+            GenType::Int { .. } => s.parse::<i128>().map(|v| v.to_string()).ok(),
+            GenType::Float { decimals, .. } => {
+                s.parse::<f64>().map(|v| format!("{:.*}", decimals, v)).ok()
+            }
+            GenType::Bool {} => Some(s.to_lowercase()),
+            GenType::Date { .. } | GenType::Enum { .. } => None,
+        };
+        r.unwrap_or_else(|| s.to_string()).into_bytes()
+    }
+}
+
+fn scaled(v: f64, scale: f64, is_min: bool) -> Result<i128, String> {
+    let x = v * scale;
+    if !(x.abs() < 1e30) {
+        return Err(format!("float bound out of range: {}", v));
+    }
+    // avoid rounding errors like 0.29 * 100 = 28.999999999999996
+    Ok(if (x - x.round()).abs() < 1e-6 {
+        x.round() as i128
+    } else if is_min {
+        x.ceil() as i128
+    } else {
+        x.floor() as i128
+    })
+}
+
+// Regex for integers in [lo, hi] (None means unbounded), when `decimals` > 0
+// the dot is placed before last `decimals` digits (and there is at least one digit before it).
+fn number_regex(lo: Option<i128>, hi: Option<i128>, decimals: usize) -> Result<String, String> {
+    let mut alts = Vec::new();
+    if hi.map_or(true, |h| h >= 0) {
+        let from = lo.map_or(0, |l| l.max(0));
+        non_negative(from, hi, decimals, "", &mut alts);
+    }
+    if lo.map_or(true, |l| l < 0) {
+        // -0 and -0.00 are not allowed
+        let from = hi.map_or(1, |h| (-h).max(1));
+        non_negative(from, lo.map(|l| -l), decimals, "-", &mut alts);
+    }
+    if alts.len() == 0 {
+        return Err(format!("empty range: {:?} .. {:?}", lo, hi));
+    }
+    Ok(format!("({})", alts.join("|")))
+}
+
+fn num_digits(n: i128, decimals: usize) -> usize {
+    std::cmp::max(n.to_string().len(), decimals + 1)
+}
+
+fn non_negative(
+    from: i128,
+    to: Option<i128>,
+    decimals: usize,
+    prefix: &str,
+    alts: &mut Vec<String>,
+) {
+    if to.map_or(false, |t| t < from) {
+        return;
+    }
+    let k_from = num_digits(from, decimals);
+    let k_to = to.map_or(k_from, |t| num_digits(t, decimals));
+    for k in k_from..=k_to {
+        // only the shortest numbers (like 0.05) can start with 0
+        let lo = if k == decimals + 1 {
+            from
+        } else {
+            std::cmp::max(from, 10i128.pow(k as u32 - 1))
+        };
+        let hi = std::cmp::min(to.unwrap_or(i128::MAX), 10i128.pow(k as u32) - 1);
+        if lo > hi {
+            continue;
+        }
+        let mut patterns = Vec::new();
+        fixed_width(
+            &format!("{:0k$}", lo).into_bytes(),
+            &format!("{:0k$}", hi).into_bytes(),
+            &mut vec![],
+            &mut patterns,
+        );
+        for p in patterns {
+            alts.push(format!("{}{}", prefix, render(&p, decimals)));
+        }
+    }
+    if to.is_none() {
+        // all numbers longer than k_from
+        let mut r = format!("{}[1-9][0-9]{{{},}}", prefix, k_from - decimals);
+        if decimals > 0 {
+            r.push_str(&format!("\\.[0-9]{{{}}}", decimals));
+        }
+        alts.push(r);
+    }
+}
+
+// Split [lo, hi] (of the same length, possibly with leading zeros) into
+// patterns of digit ranges.
+fn fixed_width(lo: &[u8], hi: &[u8], prefix: &mut Vec<(u8, u8)>, out: &mut Vec<Vec<(u8, u8)>>) {
+    if lo.len() == 0 {
+        out.push(prefix.clone());
+        return;
+    }
+    let (a, b) = (lo[0], hi[0]);
+    let (lo_rest, hi_rest) = (&lo[1..], &hi[1..]);
+    let n = prefix.len();
+    if a == b {
+        prefix.push((a, a));
+        fixed_width(lo_rest, hi_rest, prefix, out);
+        prefix.truncate(n);
+        return;
+    }
+    let all_lo = lo_rest.iter().all(|&c| c == b'0');
+    let all_hi = hi_rest.iter().all(|&c| c == b'9');
+    if !all_lo {
+        prefix.push((a, a));
+        fixed_width(lo_rest, &vec![b'9'; lo_rest.len()], prefix, out);
+        prefix.truncate(n);
+    }
+    let start = if all_lo { a } else { a + 1 };
+    let end = if all_hi { b } else { b - 1 };
+    if start <= end {
+        let mut p = prefix.clone();
+        p.push((start, end));
+        p.extend(std::iter::repeat((b'0', b'9')).take(lo_rest.len()));
+        out.push(p);
+    }
+    if !all_hi {
+        prefix.push((b, b));
+        fixed_width(&vec![b'0'; hi_rest.len()], hi_rest, prefix, out);
+        prefix.truncate(n);
+    }
+}
+
+fn render(pattern: &[(u8, u8)], decimals: usize) -> String {
+    let mut r = String::new();
+    for (idx, &(a, b)) in pattern.iter().enumerate() {
+        if decimals > 0 && idx == pattern.len() - decimals {
+            r.push_str("\\.");
+        }
+        if a == b {
+            r.push(a as char);
+        } else {
+            r.push_str(&format!("[{}-{}]", a as char, b as char));
+        }
+    }
+    r
+}
+
+fn date_regex(format: &str) -> Result<String, String> {
+    let mut r = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            r.push_str(&escape(&c.to_string()));
+            continue;
+        }
+        let part = match chars.next() {
+            Some('Y') => "[0-9]{4}",
+            Some('m') => "(0[1-9]|1[0-2])",
+            Some('d') => "(0[1-9]|[12][0-9]|3[01])",
+            Some('H') => "([01][0-9]|2[0-3])",
+            Some('M') | Some('S') => "[0-5][0-9]",
+            Some('%') => "%",
+            Some(c) => return Err(format!("unsupported date format: %{}", c)),
+            None => return Err("date format ends with %".to_string()),
+        };
+        r.push_str(part);
+    }
+    Ok(r)
+}
+
+fn escape(s: &str) -> String {
+    let mut r = String::new();
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            r.push('\\');
+        }
+        r.push(c);
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex_automata::meta::Regex;
+
+    fn compile(tp: GenType) -> Regex {
+        let rx = tp.to_regex().unwrap();
+        Regex::new(&format!("^(?:{rx})$")).unwrap()
+    }
+
+    fn check_ints(tp: GenType, lo: Option<i64>, hi: Option<i64>, candidates: &[i64]) {
+        let rx = compile(tp);
+        for &v in candidates {
+            let ok = lo.map_or(true, |lo| v >= lo) && hi.map_or(true, |hi| v <= hi);
+            assert_eq!(rx.is_match(&v.to_string()), ok, "{v} in {lo:?}..{hi:?}");
+        }
+    }
+
+    #[test]
+    fn int_ranges() {
+        for (lo, hi) in [
+            (7, 123),
+            (0, 0),
+            (1, 9),
+            (10, 99),
+            (99, 100),
+            (95, 1005),
+            (-15, -3),
+            (-12, 7),
+            (-1000, -999),
+            (-100, 0),
+        ] {
+            let tp = || GenType::Int {
+                min: Some(lo),
+                max: Some(hi),
+            };
+            let candidates: Vec<i64> = (lo - 120..hi + 120).collect();
+            check_ints(tp(), Some(lo), Some(hi), &candidates);
+            // no leading zeros or negative zero
+            let rx = compile(tp());
+            for s in ["007", "-0", "00", "+5"] {
+                assert!(!rx.is_match(s), "{s} in {lo}..{hi}");
+            }
+        }
+    }
+
+    #[test]
+    fn int_unbounded() {
+        let mut candidates: Vec<i64> = (-300..300).collect();
+        candidates.extend([-123456789, 123456789]);
+        let int = |min, max| GenType::Int { min, max };
+        check_ints(int(Some(5), None), Some(5), None, &candidates);
+        check_ints(int(None, Some(-5)), None, Some(-5), &candidates);
+        check_ints(int(None, None), None, None, &candidates);
+    }
+
+    #[test]
+    fn float_ranges() {
+        for (lo, hi) in [(-150, 225), (5, 99), (99, 1001), (-1, 1)] {
+            let rx = compile(GenType::Float {
+                min: Some(lo as f64 / 100.0),
+                max: Some(hi as f64 / 100.0),
+                decimals: 2,
+            });
+            for i in lo - 200..hi + 200 {
+                let s = format!("{:.2}", i as f64 / 100.0);
+                let ok = lo <= i && i <= hi;
+                assert_eq!(rx.is_match(&s), ok, "{s} in {lo}..{hi}");
+            }
+            for s in ["-0.00", "00.50", "1.5", ".50"] {
+                assert!(!rx.is_match(s), "{s} in {lo}..{hi}");
+            }
+        }
+    }
+
+    #[test]
+    fn other_types() {
+        let date = compile(GenType::Date {
+            format: Some("%Y-%m-%d %H:%M".to_string()),
+        });
+        assert!(date.is_match("2024-02-29 23:59"));
+        assert!(!date.is_match("2024-13-01 10:00"));
+        let e = compile(GenType::Enum {
+            options: vec!["a.b".to_string(), "c|d".to_string()],
+        });
+        assert!(e.is_match("a.b") && e.is_match("c|d"));
+        assert!(!e.is_match("axb") && !e.is_match("c"));
+    }
+
+    #[test]
+    fn type_errors() {
+        let err = |tp: GenType| tp.to_regex().unwrap_err();
+        assert!(err(GenType::Int {
+            min: Some(10),
+            max: Some(5)
+        })
+        .contains("empty range"));
+        let date = |f: &str| GenType::Date {
+            format: Some(f.to_string()),
+        };
+        assert_eq!(err(date("%Y-%q")), "unsupported date format: %q");
+        assert_eq!(err(date("%Y%")), "date format ends with %");
+        assert_eq!(
+            err(GenType::Enum { options: vec![] }),
+            "enum needs at least one option"
+        );
+    }
+}
@@ -0,0 +1,14 @@
+/**
+ * Generate a bulleted list of up to `n` items.
+ * @param {string} topic
+ * @param {number} n
+ */
+export async function genList(topic, n) {
+    await $`Here is a list of ${topic}:\n`
+    const items = []
+    for (let i = 0; i < n; ++i) {
+        await $`- `
+        items.push(await gen({ stopAt: "\n", maxTokens: 20 }))
+    }
+    return items
+}
@@ -0,0 +1,8 @@
+import { genList } from "./lib/list.js"
+
+async function main() {
+    const items = await genList("fruits", 3)
+    console.log(items)
+}
+
+start(main)
@@ -0,0 +1,227 @@
+// Multi-file programs: the controller argument can be a JSON bundle of ES modules, like
+// { "entry": "main.js", "modules": { "main.js": "...", "lib/util.js": "..." } }
+// Imports between them are resolved via the rquickjs module loader.
+// Source maps for the modules can be included as "<module>.map" (eg. "main.js.map").
+
+use std::collections::HashMap;
+
+use rquickjs::{
+    loader::{Loader, Resolver},
+    Ctx, Error, Module, Result,
+};
+use serde::Deserialize;
+
+use crate::sourcemap::{SourceMap, SourceMaps};
+
+/// Modules provided by jsctrl itself, and not by the bundle.
+const BUILTIN_MODULES: &[&str] = &["aici", "_aici"];
+
+#[derive(Deserialize)]
+pub struct Bundle {
+    #[serde(default = "default_entry")]
+    pub entry: String,
+    pub modules: HashMap<String, String>,
+    #[serde(skip)]
+    pub source_maps: HashMap<String, String>,
+}
+
+fn default_entry() -> String {
+    "main.js".to_string()
+}
+
+impl Bundle {
+    /// Returns None if the argument is a plain script and not a bundle.
+    /// Arguments starting with '{' are always bundles, so that a malformed one
+    /// is reported instead of being run as a script.
+    pub fn from_arg(source: &str) -> Option<std::result::Result<Self, String>> {
+        if !source.trim_start().starts_with('{') {
+            return None;
+        }
+        Some(Self::parse(source).map_err(|e| format!("invalid bundle: {e}")))
+    }
+
+    fn parse(source: &str) -> std::result::Result<Self, String> {
+        let b: Bundle = serde_json::from_str(source).map_err(|e| e.to_string())?;
+        let mut modules = HashMap::new();
+        let mut source_maps = HashMap::new();
+        for (k, v) in b.modules {
+            let k = normalize_path(&k)?;
+            match k.strip_suffix(".map") {
+                Some(file) => source_maps.insert(file.to_string(), v),
+                None => modules.insert(k, v),
+            };
+        }
+        let entry = normalize_path(&b.entry)?;
+        if !modules.contains_key(&entry) {
+            return Err(format!("entry module {} not found", b.entry));
+        }
+        Ok(Bundle {
+            entry,
+            modules,
+            source_maps,
+        })
+    }
+
+    /// Parse the source maps; invalid ones are skipped with a warning.
+    pub fn take_source_maps(&mut self) -> SourceMaps {
+        std::mem::take(&mut self.source_maps)
+            .into_iter()
+            .filter_map(|(file, json)| match SourceMap::parse(&file, &json) {
+                Ok(m) => Some((file, m)),
+                Err(e) => {
+                    println!("warning: {file}.map: {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Take the entry module source, and return the resolver and loader for the rest.
+    pub fn into_loader(mut self) -> (String, String, BundleResolver, BundleLoader) {
+        let source = self.modules.remove(&self.entry).unwrap();
+        let mut names = self.modules.keys().cloned().collect::<Vec<_>>();
+        names.push(self.entry.clone());
+        (
+            self.entry,
+            source,
+            BundleResolver { names },
+            BundleLoader {
+                modules: self.modules,
+            },
+        )
+    }
+}
+
+// Remove ".", "..", and empty path segments; fails if ".." goes above the root.
+pub fn normalize_path(path: &str) -> std::result::Result<String, String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for p in path.split('/') {
+        match p {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(format!("path {path:?} is outside of the bundle"));
+                }
+            }
+            _ => parts.push(p),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+pub struct BundleResolver {
+    names: Vec<String>,
+}
+
+impl BundleResolver {
+    fn resolve_name(&self, base: &str, name: &str) -> Option<String> {
+        if BUILTIN_MODULES.contains(&name) {
+            return Some(name.to_string());
+        }
+        let path = if name.starts_with("./") || name.starts_with("../") {
+            let dir = match base.rfind('/') {
+                Some(idx) => &base[..idx],
+                None => "",
+            };
+            normalize_path(&format!("{dir}/{name}")).ok()?
+        } else {
+            normalize_path(name).ok()?
+        };
+        self.find(&path)
+    }
+
+    fn find(&self, path: &str) -> Option<String> {
+        [
+            path.to_string(),
+            format!("{path}.js"),
+            format!("{path}.mjs"),
+            format!("{path}/index.js"),
+        ]
+        .into_iter()
+        .find(|p| self.names.contains(p))
+    }
+}
+
+impl Resolver for BundleResolver {
+    fn resolve<'js>(&mut self, _ctx: &Ctx<'js>, base: &str, name: &str) -> Result<String> {
+        self.resolve_name(base, name)
+            .ok_or_else(|| Error::new_resolving(base, name))
+    }
+}
+
+pub struct BundleLoader {
+    modules: HashMap<String, String>,
+}
+
+impl Loader for BundleLoader {
+    fn load<'js>(&mut self, ctx: &Ctx<'js>, name: &str) -> Result<Module<'js>> {
+        // module names are the file names, so they show up in stack traces
+        match self.modules.get(name) {
+            Some(source) => Module::declare(ctx.clone(), name, source.as_str()),
+            None => Err(Error::new_loading(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(json: &str) -> std::result::Result<Bundle, String> {
+        Bundle::from_arg(json).expect("not a bundle")
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(normalize_path("a/./b//c.js").unwrap(), "a/b/c.js");
+        assert_eq!(normalize_path("/a/b/../c.js").unwrap(), "a/c.js");
+        assert_eq!(normalize_path("a/..").unwrap(), "");
+        assert!(normalize_path("../a.js").is_err());
+        assert!(normalize_path("a/../../b.js").is_err());
+    }
+
+    #[test]
+    fn from_arg() {
+        assert!(Bundle::from_arg("console.log(1)").is_none());
+        let b = bundle(r#"{"modules": {"./main.js": "", "lib/a.js": "", "lib/a.js.map": "{}"}}"#)
+            .unwrap();
+        assert_eq!(b.entry, "main.js");
+        let mut names = b.modules.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["lib/a.js", "main.js"]);
+        assert_eq!(b.source_maps.keys().collect::<Vec<_>>(), vec!["lib/a.js"]);
+
+        let b = bundle(r#"{"entry": "./x/m.js", "modules": {"x/m.js": ""}}"#).unwrap();
+        assert_eq!(b.entry, "x/m.js");
+    }
+
+    #[test]
+    fn from_arg_errors() {
+        // not valid JSON; not run as a script either
+        assert!(bundle(r#"{"modules": {"main.js": ""}"#).is_err());
+        assert!(bundle(r#"{"entry": "main.js"}"#).is_err());
+        assert!(bundle(r#"{"modules": {"a.js": ""}}"#).is_err());
+        assert!(bundle(r#"{"modules": {"main.js": "", "../a.js": ""}}"#).is_err());
+        assert!(bundle(r#"{"entry": "../main.js", "modules": {"main.js": ""}}"#).is_err());
+    }
+
+    #[test]
+    fn resolve() {
+        let r = BundleResolver {
+            names: ["main.js", "lib/a.js", "lib/b.mjs", "lib/c/index.js"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+        let res = |base, name| r.resolve_name(base, name);
+        assert_eq!(res("main.js", "./lib/a.js").unwrap(), "lib/a.js");
+        assert_eq!(res("main.js", "./lib/a").unwrap(), "lib/a.js");
+        assert_eq!(res("main.js", "lib/b").unwrap(), "lib/b.mjs");
+        assert_eq!(res("lib/a.js", "./c").unwrap(), "lib/c/index.js");
+        assert_eq!(res("lib/a.js", "../main").unwrap(), "main.js");
+        assert_eq!(res("lib/a.js", "aici").unwrap(), "aici");
+        assert!(res("lib/a.js", "./main").is_none());
+        assert!(res("main.js", "../main.js").is_none());
+        assert!(res("lib/a.js", "../../lib/a.js").is_none());
+    }
+}
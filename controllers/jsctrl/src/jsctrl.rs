@@ -1,22 +1,37 @@
-use std::sync::Mutex;
+use std::{
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+mod bundle;
+mod sourcemap;
 
 use aici_abi::{
-    aici_stop, host_trie,
-    recognizer::{AnythingGoes, StackRecognizer},
-    SimpleVob,
-    toktrie::{Recognizer, SpecialToken, TokTrie},
-    AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, TokenId,
-    VariableStorage,
+    aici_stop,
+    constraint::{self, CompiledConstraint, ConstraintSpec, SharedConstraint},
+    dlex::{self, DynamicLexerRec},
+    host_trie,
+    toktrie::TokTrie,
+    AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, ScriptError,
+    SimpleVob, TokenId, VariableStorage,
 };
 use rquickjs::{
-    class::Trace, function::IntoArgs, ArrayBuffer, Context, Ctx, FromJs, Function, IntoAtom,
-    IntoJs, Module, Object, Result, Runtime, TypedArray, Value,
+    class::Trace,
+    function::{IntoArgs, Opt},
+    ArrayBuffer, Context, Ctx, Exception, FromJs, Function, IntoAtom, IntoJs, Module, Object,
+    Result, Runtime, TypedArray, Value,
+};
+
+use crate::{
+    bundle::Bundle,
+    sourcemap::{map_location, map_stack, parse_frame, Location, SourceMaps},
 };
 
 struct ModuleState {
     trie: TokTrie,
     vars: VariableStorage,
     mid_process_result: Option<MidProcessResult>,
+    token_healing: bool,
 }
 
 unsafe impl Send for ModuleState {}
@@ -26,12 +41,16 @@ lazy_static::lazy_static! {
         trie: host_trie(),
         vars: VariableStorage::new(),
         mid_process_result: None,
+        token_healing: false,
     });
+    // separate from GLOBAL_STATE, since errors can be reported while it's locked
+    static ref SOURCE_MAPS: Mutex<SourceMaps> = Mutex::new(SourceMaps::new());
 }
 
 trait CtxExt<'js> {
+    fn script_error(&self, v: &Value<'js>) -> ScriptError;
     fn error_value_to_string(&self, v: Value<'js>) -> String;
-    fn error_to_string(&self, e: rquickjs::Error) -> String;
+    fn report_error(&self, e: rquickjs::Error) -> !;
     fn unwrap_js<T>(&self, result: Result<T>) -> T;
     fn eval2<V: FromJs<'js>, S: Into<Vec<u8>>>(&self, source: S) -> V;
 }
@@ -65,43 +84,72 @@ impl<'js> ObjectExt<'js> for Object<'js> {
 }
 
 impl<'js> CtxExt<'js> for Ctx<'js> {
+    fn script_error(&self, v: &Value<'js>) -> ScriptError {
+        let exn = match v.as_exception() {
+            Some(e) => e,
+            None => {
+                return ScriptError {
+                    kind: "Error".to_string(),
+                    message: format!("{v:?}"),
+                    ..Default::default()
+                }
+            }
+        };
+        let get_str = |k: &str| exn.get::<_, Option<String>>(k).ok().flatten();
+        let maps = SOURCE_MAPS.lock().unwrap();
+        let stack = map_stack(&maps, &get_str("stack").unwrap_or_default());
+        // the innermost frame comes first; stack of a SyntaxError may only have fileName/lineNumber
+        let loc = stack
+            .lines()
+            .find_map(|frame| parse_frame(frame).map(|(_, loc)| loc))
+            .or_else(|| {
+                Some(map_location(
+                    &maps,
+                    Location {
+                        file: get_str("fileName")?,
+                        line: exn.get::<_, Option<u32>>("lineNumber").ok().flatten()?,
+                        column: None,
+                    },
+                ))
+            });
+        ScriptError {
+            kind: get_str("name").unwrap_or_else(|| "Error".to_string()),
+            message: exn.message().unwrap_or_default(),
+            file: loc.as_ref().map(|l| l.file.clone()),
+            line: loc.as_ref().map(|l| l.line),
+            column: loc.and_then(|l| l.column),
+            stack,
+        }
+    }
+
     fn error_value_to_string(&self, v: Value<'js>) -> String {
         match v.as_exception() {
-            Some(e) if e.message().is_some() => format!(
-                "Exception: {}\n{}",
-                e.message().unwrap(),
-                e.stack().unwrap_or(String::new())
-            ),
+            Some(e) if e.message().is_some() => {
+                let err = self.script_error(&v);
+                format!("Exception: {}\n{}", err.message, err.stack)
+            }
             _ => format!("{v:?}"),
         }
     }
 
-    fn error_to_string(&self, e: rquickjs::Error) -> String {
-        match e {
-            rquickjs::Error::Exception => self.error_value_to_string(self.catch()),
-            _ => {
-                // let stack = match self.eval("new Error().stack") {
-                //     Ok(v) => v,
-                //     Err(e) => {
-                //         format!("Error getting stack: {e}")
-                //     }
-                // };
-                // let exn = rquickjs::Exception::from_message(self.clone(), &format!("{e}")).unwrap();
-                // let _ = exn.throw();
-                // self.error_to_string(rquickjs::Error::Exception)
-                // format!("{e}\n{}", exn.stack().unwrap_or("No stack".to_string()))
-                format!("{e}")
-            }
-        }
+    fn report_error(&self, e: rquickjs::Error) -> ! {
+        let err = match e {
+            rquickjs::Error::Exception => self.script_error(&self.catch()),
+            _ => ScriptError {
+                kind: "InternalError".to_string(),
+                message: format!("{e}"),
+                ..Default::default()
+            },
+        };
+        println!("Exception: {}\n{}", err.message, err.stack);
+        aici_abi::script_error(&err);
+        aici_stop();
     }
 
     fn unwrap_js<T>(&self, result: Result<T>) -> T {
         match result {
             Ok(r) => r,
-            Err(e) => {
-                println!("{}", self.error_to_string(e));
-                aici_stop();
-            }
+            Err(e) => self.report_error(e),
         }
     }
 
@@ -177,7 +225,11 @@ impl Default for TokenSet {
 
 #[rquickjs::class]
 pub struct Constraint {
-    inner: Box<dyn PyConstraint>,
+    inner: Box<dyn constraint::Constraint>,
+    // None for constraints that can't be re-created, and thus combined
+    spec: Option<ConstraintSpec>,
+    // the compiled spec, for locate()
+    compiled: Option<Rc<CompiledConstraint>>,
 }
 
 impl Trace<'_> for Constraint {
@@ -187,8 +239,23 @@ impl Trace<'_> for Constraint {
 }
 
 impl Constraint {
-    fn new(inner: Box<dyn PyConstraint>) -> Self {
-        Self { inner }
+    fn from_spec(spec: ConstraintSpec) -> anyhow::Result<Self> {
+        let compiled = spec.compile()?;
+        Ok(Self {
+            inner: Box::new(compiled.fresh()),
+            spec: Some(spec),
+            compiled: Some(compiled),
+        })
+    }
+
+    fn get_spec<'js>(&self, ctx: &Ctx<'js>) -> Result<ConstraintSpec> {
+        match &self.spec {
+            Some(spec) => Ok(spec.clone()),
+            None => Err(Exception::throw_type(
+                ctx,
+                "this constraint can't be combined with other constraints",
+            )),
+        }
     }
 }
 
@@ -197,7 +264,7 @@ impl Constraint {
 impl Constraint {
     #[qjs(constructor)]
     pub fn ctor() -> Self {
-        Self::new(Box::new(StackRecognizer::from(AnythingGoes {})))
+        Self::from_spec(ConstraintSpec::Anything {}).unwrap()
     }
 
     pub fn eosAllowed(&mut self) -> bool {
@@ -209,15 +276,95 @@ impl Constraint {
     }
 
     pub fn tokenAllowed(&mut self, t: TokenId) -> bool {
-        self.inner.token_allowed(t)
+        let trie = &GLOBAL_STATE.lock().unwrap().trie;
+        self.inner.token_allowed(trie, t)
     }
 
-    pub fn appendToken(&mut self, t: TokenId) {
-        self.inner.append_token(t)
+    pub fn appendToken<'js>(&mut self, ctx: Ctx<'js>, t: TokenId) -> Result<()> {
+        let trie = &GLOBAL_STATE.lock().unwrap().trie;
+        self.inner
+            .append_token(trie, t)
+            .map_err(|e| Exception::throw_type(&ctx, &format!("{}", e)))
     }
 
     pub fn allowTokens(&mut self, ts: &mut TokenSet) {
-        self.inner.allow_tokens(&mut ts.inner);
+        let trie = &GLOBAL_STATE.lock().unwrap().trie;
+        self.inner.allow_tokens(trie, &mut ts.inner);
+    }
+
+    pub fn locate<'js>(&self, ctx: Ctx<'js>, text: Buffer) -> Result<Option<Vec<usize>>> {
+        let compiled = match &self.compiled {
+            Some(c) => c,
+            None => return Err(Exception::throw_type(&ctx, "not a substring constraint")),
+        };
+        let m = compiled
+            .substr_locate(&text.0)
+            .map_err(|e| Exception::throw_type(&ctx, &format!("{}", e)))?;
+        Ok(m.map(|m| vec![m.source_idx, m.start, m.end]))
+    }
+}
+
+#[rquickjs::class]
+pub struct DynamicLexer {
+    inner: Arc<Mutex<DynamicLexerRec>>,
+}
+
+impl Trace<'_> for DynamicLexer {
+    fn trace<'a>(&self, _tracer: rquickjs::class::Tracer<'a, '_>) {
+        // do nothing
+    }
+}
+
+#[rquickjs::methods]
+#[allow(non_snake_case)]
+impl DynamicLexer {
+    #[qjs(constructor)]
+    pub fn new(additional_id_chars: Opt<String>) -> Self {
+        let id_chars = match additional_id_chars.0 {
+            Some(id_chars) => id_chars.chars().collect(),
+            None => vec![],
+        };
+        let lexer = dlex::DynamicLexer::new(&id_chars).to_recognizer();
+        DynamicLexer {
+            inner: Arc::new(Mutex::new(lexer)),
+        }
+    }
+
+    pub fn add(&self, word: String) {
+        let mut lexer = self.inner.lock().unwrap();
+        lexer.lexer_mut().add(word.as_bytes());
+    }
+
+    pub fn pushScope(&self) {
+        let mut lexer = self.inner.lock().unwrap();
+        lexer.lexer_mut().push_scope();
+    }
+
+    pub fn popScope<'js>(&self, ctx: Ctx<'js>) -> Result<()> {
+        let mut lexer = self.inner.lock().unwrap();
+        if lexer.lexer_mut().pop_scope() {
+            Ok(())
+        } else {
+            Err(Exception::throw_range(&ctx, "can't pop the global scope"))
+        }
+    }
+
+    pub fn numScopes(&self) -> usize {
+        let lexer = self.inner.lock().unwrap();
+        lexer.lexer().num_scopes()
+    }
+
+    pub fn addDeclarationRule(&self, prefix: String) {
+        let mut lexer = self.inner.lock().unwrap();
+        lexer.lexer_mut().add_declaration_rule(prefix.as_bytes());
+    }
+
+    pub fn constraint(&self) -> Constraint {
+        Constraint {
+            inner: Box::new(SharedConstraint(self.inner.clone())),
+            spec: None,
+            compiled: None,
+        }
     }
 }
 
@@ -249,14 +396,19 @@ impl<'js> IntoJs<'js> for Buffer {
 mod aici_mod {
     use crate::{Buffer, CtxExt, ObjectExt};
 
-    pub use super::{Constraint, TokenSet};
+    pub use super::{Constraint, DynamicLexer, TokenSet};
 
     use super::GLOBAL_STATE;
     use aici_abi::{
-        aici_stop, cfg::CfgParser, get_config, rx::RecRx, substring::SubStrMatcher,
+        aici_stop, constraint::ConstraintSpec, get_config, substring::SubStrOptions,
         toktrie::SpecialToken, Branch, MidProcessResult, Splice, TokenId,
     };
-    use rquickjs::{Ctx, Exception, Object, Result, Value};
+    use rquickjs::{function::Opt, Class, Ctx, Exception, FromJs, Function, Object, Result, Value};
+
+    #[rquickjs::function]
+    pub fn setTokenHealing(enabled: bool) {
+        GLOBAL_STATE.lock().unwrap().token_healing = enabled;
+    }
 
     #[rquickjs::function]
     pub fn selfSeqId() -> u32 {
@@ -270,7 +422,8 @@ mod aici_mod {
 
     #[rquickjs::function]
     pub fn panic<'js>(ctx: Ctx<'js>, err: Value<'js>) {
-        println!("panic:\n{}", ctx.error_value_to_string(err));
+        println!("panic:\n{}", ctx.error_value_to_string(err.clone()));
+        aici_abi::script_error(&ctx.script_error(&err));
         aici_stop();
     }
 
@@ -317,6 +470,47 @@ mod aici_mod {
         vars.append(name, value.0);
     }
 
+    #[rquickjs::function]
+    pub fn deleteVar(name: String) -> bool {
+        GLOBAL_STATE.lock().unwrap().vars.delete(&name)
+    }
+
+    #[rquickjs::function]
+    pub fn listVars(prefix: Opt<String>) -> Vec<String> {
+        let prefix = prefix.0.unwrap_or_default();
+        GLOBAL_STATE.lock().unwrap().vars.list(&prefix)
+    }
+
+    #[rquickjs::function]
+    pub fn incrementVar(name: String, delta: Opt<i64>) -> i64 {
+        let delta = delta.0.unwrap_or(1);
+        GLOBAL_STATE.lock().unwrap().vars.increment(&name, delta)
+    }
+
+    #[rquickjs::function]
+    pub fn getVarVersion(name: String) -> Option<u64> {
+        let vars = &GLOBAL_STATE.lock().unwrap().vars;
+        vars.get_with_version(&name).map(|v| v.0)
+    }
+
+    #[rquickjs::function]
+    pub fn watchVar(name: String, version: Opt<Option<u64>>) -> bool {
+        let version = version.0.flatten();
+        GLOBAL_STATE.lock().unwrap().vars.watch(&name, version)
+    }
+
+    #[rquickjs::function]
+    pub fn emit<'js>(ctx: Ctx<'js>, kind: String, data: Value<'js>) -> Result<()> {
+        let json: Object = ctx.globals().get("JSON")?;
+        let stringify: Function = json.get("stringify")?;
+        // undefined and functions have no JSON representation; cycles throw in stringify()
+        let text: Option<String> = stringify.call((data,))?;
+        let text = text.ok_or_else(|| Exception::throw_type(&ctx, "emit: data is not JSON"))?;
+        let data: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|e| Exception::throw_type(&ctx, &format!("emit: {e}")))?;
+        aici_abi::emit(&kind, &data).map_err(|e| Exception::throw_type(&ctx, &format!("emit: {e}")))
+    }
+
     #[rquickjs::function]
     pub fn getConfig(name: String) -> i32 {
         let name = name.as_str();
@@ -346,6 +540,7 @@ mod aici_mod {
                     }
                 })
                 .collect(),
+            attention_masks: vec![],
         };
 
         let mut st = GLOBAL_STATE.lock().unwrap();
@@ -414,66 +609,119 @@ mod aici_mod {
         trie.special_token(SpecialToken::EndOfSentence)
     }
 
+    fn from_spec<'js>(ctx: &Ctx<'js>, spec: ConstraintSpec) -> Result<Constraint> {
+        Constraint::from_spec(spec).map_err(|e| Exception::throw_type(ctx, &format!("{}", e)))
+    }
+
+    fn item_specs<'js>(
+        ctx: &Ctx<'js>,
+        items: Vec<Class<'js, Constraint>>,
+    ) -> Result<Vec<ConstraintSpec>> {
+        items.iter().map(|c| c.borrow().get_spec(ctx)).collect()
+    }
+
     #[rquickjs::function]
     pub fn regexConstraint<'js>(ctx: Ctx<'js>, regex: String) -> Result<Constraint> {
-        println!("regex constraint: {:?}", regex);
-        let rx = RecRx::from_rx(regex.as_str(), None)
-            .map_err(|e| Exception::throw_type(&ctx, &format!("{}", e)))?
-            .to_stack_recognizer();
-        Ok(Constraint::new(Box::new(rx)))
+        from_spec(&ctx, ConstraintSpec::Regex { rx: regex })
     }
 
     #[rquickjs::function]
     pub fn cfgConstraint<'js>(ctx: Ctx<'js>, cfg: String) -> Result<Constraint> {
-        match CfgParser::from_yacc(cfg.as_str()) {
-            Ok(cfg) => Ok(Constraint::new(Box::new(cfg))),
-            Err(e) => Err(Exception::throw_type(&ctx, &format!("{}", e))),
-        }
+        from_spec(&ctx, ConstraintSpec::Cfg { yacc: cfg })
     }
 
     #[rquickjs::function]
-    pub fn substrConstraint(templ: String, end_str: String) -> Constraint {
-        let rx = SubStrMatcher::new(templ.as_str(), end_str.as_str()).to_stack_recognizer();
-        Constraint::new(Box::new(rx))
+    pub fn substrConstraint<'js>(
+        ctx: Ctx<'js>,
+        templ: Value<'js>,
+        end_str: String,
+        options: Opt<Object<'js>>,
+    ) -> Result<Constraint> {
+        let sources = if templ.is_string() {
+            vec![String::from_js(&ctx, templ)?]
+        } else {
+            Vec::<String>::from_js(&ctx, templ)?
+        };
+        let mut opts = SubStrOptions::default();
+        if let Some(options) = options.0 {
+            if let Some(v) = options.get::<_, Option<bool>>("wordBoundaries")? {
+                opts.word_boundaries = v;
+            }
+            if let Some(v) = options.get::<_, Option<bool>>("normalize")? {
+                opts.normalize = v;
+            }
+        }
+        let spec = ConstraintSpec::SubStr {
+            sources,
+            end_str,
+            options: opts,
+        };
+        from_spec(&ctx, spec)
     }
-}
-
-fn main() {}
 
-trait PyConstraint {
-    fn eos_allowed(&mut self) -> bool;
-    fn eos_forced(&mut self) -> bool;
-    fn token_allowed(&mut self, t: TokenId) -> bool;
-    fn append_token(&mut self, t: TokenId);
-    fn allow_tokens(&mut self, logits: &mut SimpleVob);
-}
+    #[rquickjs::function]
+    pub fn upToConstraint<'js>(ctx: Ctx<'js>, stop: String) -> Result<Constraint> {
+        from_spec(&ctx, ConstraintSpec::UpTo { stop })
+    }
 
-impl<T: Recognizer> PyConstraint for T {
-    fn eos_allowed(&mut self) -> bool {
-        self.special_allowed(SpecialToken::EndOfSentence)
+    #[rquickjs::function]
+    pub fn healedConstraint<'js>(
+        ctx: Ctx<'js>,
+        prefix: Buffer,
+        item: Class<'js, Constraint>,
+    ) -> Result<Constraint> {
+        let spec = ConstraintSpec::Healed {
+            prefix: prefix.0,
+            item: Box::new(item.borrow().get_spec(&ctx)?),
+        };
+        from_spec(&ctx, spec)
     }
 
-    fn eos_forced(&mut self) -> bool {
-        self.special_allowed(SpecialToken::EndOfSentence)
-            && (0..=255).all(|b| !self.byte_allowed(b))
+    #[rquickjs::function]
+    pub fn seqConstraint<'js>(
+        ctx: Ctx<'js>,
+        items: Vec<Class<'js, Constraint>>,
+    ) -> Result<Constraint> {
+        let items = item_specs(&ctx, items)?;
+        from_spec(&ctx, ConstraintSpec::Seq { items })
     }
 
-    fn token_allowed(&mut self, t: TokenId) -> bool {
-        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
-        trie.token_allowed(self, t)
+    #[rquickjs::function]
+    pub fn orConstraint<'js>(
+        ctx: Ctx<'js>,
+        items: Vec<Class<'js, Constraint>>,
+    ) -> Result<Constraint> {
+        let items = item_specs(&ctx, items)?;
+        from_spec(&ctx, ConstraintSpec::Or { items })
     }
 
-    fn append_token(&mut self, t: TokenId) {
-        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
-        trie.append_token(self, t).unwrap()
+    #[rquickjs::function]
+    pub fn andConstraint<'js>(
+        ctx: Ctx<'js>,
+        items: Vec<Class<'js, Constraint>>,
+    ) -> Result<Constraint> {
+        let items = item_specs(&ctx, items)?;
+        from_spec(&ctx, ConstraintSpec::And { items })
     }
 
-    fn allow_tokens(&mut self, logits: &mut SimpleVob) {
-        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
-        trie.compute_bias(self, logits)
+    #[rquickjs::function]
+    pub fn repeatConstraint<'js>(
+        ctx: Ctx<'js>,
+        item: Class<'js, Constraint>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<Constraint> {
+        let spec = ConstraintSpec::Repeat {
+            item: Box::new(item.borrow().get_spec(&ctx)?),
+            min,
+            max,
+        };
+        from_spec(&ctx, spec)
     }
 }
 
+fn main() {}
+
 pub struct Runner {
     context: Context,
 }
@@ -487,6 +735,19 @@ impl Runner {
         let source = String::from_utf8(arg).unwrap();
 
         let rt = Runtime::new().unwrap();
+        let (main_name, source) = match Bundle::from_arg(&source) {
+            None => ("main".to_string(), source),
+            Some(Err(e)) => {
+                println!("{e}");
+                aici_stop();
+            }
+            Some(Ok(mut bundle)) => {
+                *SOURCE_MAPS.lock().unwrap() = bundle.take_source_maps();
+                let (entry, entry_source, resolver, loader) = bundle.into_loader();
+                rt.set_loader(resolver, loader);
+                (entry, entry_source)
+            }
+        };
         let s = Self {
             context: Context::full(&rt).unwrap(),
         };
@@ -503,7 +764,7 @@ impl Runner {
             Module::declare_def::<js_aici_mod, _>(ctx.clone(), "_aici").unwrap();
 
             let _ = ctx.unwrap_js(ctx.clone().compile("aici", aici_js));
-            let _ = ctx.unwrap_js(ctx.clone().compile("main", source));
+            let _ = ctx.unwrap_js(ctx.clone().compile(main_name, source));
         });
 
         s
@@ -522,11 +783,8 @@ impl Runner {
         loop {
             match self.context.runtime().execute_pending_job() {
                 Err(e) => e.0.with(|ctx| {
-                    println!(
-                        "exception in deferred job:\n{}",
-                        ctx.error_to_string(rquickjs::Error::Exception)
-                    );
-                    aici_stop();
+                    println!("exception in deferred job:");
+                    ctx.report_error(rquickjs::Error::Exception)
                 }),
                 Ok(false) => break,
                 Ok(true) => {
@@ -546,11 +804,19 @@ impl Runner {
 
 impl AiciCtrl for Runner {
     fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
+        let (res, prefix) = {
+            let st = GLOBAL_STATE.lock().unwrap();
+            if st.token_healing {
+                InitPromptResult::healed(arg, &st.trie)
+            } else {
+                (InitPromptResult::from_arg(arg), vec![])
+            }
+        };
         self.with_cb("init_prompt", |ctx| {
             let cb: Function = ctx.eval2("globalThis._aici_cb.init_prompt");
-            let _: Value = cb.call2((&arg.prompt,));
-            InitPromptResult::from_arg(arg)
-        })
+            let _: Value = cb.call2((&res.prompt, Buffer(prefix)));
+        });
+        res
     }
 
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
@@ -0,0 +1,319 @@
+// Source maps (as generated by tsc with "sourceMap": true), used to report
+// exception locations in terms of the original (TypeScript) sources.
+// Only the "mappings" and "sources" fields are used; see
+// https://sourcemaps.info/spec.html for the format.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::bundle::normalize_path;
+
+#[derive(Deserialize)]
+struct RawSourceMap {
+    version: u32,
+    #[serde(default, rename = "sourceRoot")]
+    source_root: String,
+    sources: Vec<String>,
+    mappings: String,
+}
+
+struct Segment {
+    gen_col: u32,
+    source: u32,
+    line: u32,
+    col: u32,
+}
+
+pub struct SourceMap {
+    sources: Vec<String>,
+    // indexed by 0-based generated line, sorted by gen_col
+    lines: Vec<Vec<Segment>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: String,
+    /// 1-based
+    pub line: u32,
+    /// 1-based, if known
+    pub column: Option<u32>,
+}
+
+fn base64_digit(c: u8) -> Option<i64> {
+    let v = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(v as i64)
+}
+
+fn decode_vlq(segment: &str) -> Result<Vec<i64>, String> {
+    let mut res = Vec::new();
+    let mut acc = 0i64;
+    let mut shift = 0;
+    for c in segment.bytes() {
+        let d = base64_digit(c).ok_or_else(|| format!("invalid VLQ character {:?}", c as char))?;
+        if shift > 60 {
+            return Err("VLQ value too large".to_string());
+        }
+        acc |= (d & 31) << shift;
+        if d & 32 != 0 {
+            shift += 5;
+        } else {
+            let v = acc >> 1;
+            res.push(if acc & 1 != 0 { -v } else { v });
+            acc = 0;
+            shift = 0;
+        }
+    }
+    if shift != 0 {
+        return Err("truncated VLQ value".to_string());
+    }
+    Ok(res)
+}
+
+impl SourceMap {
+    /// `file` is the bundle path of the generated file; sources are resolved relative to it.
+    pub fn parse(file: &str, json: &str) -> Result<Self, String> {
+        let raw: RawSourceMap =
+            serde_json::from_str(json).map_err(|e| format!("invalid source map: {e}"))?;
+        if raw.version != 3 {
+            return Err(format!("unsupported source map version {}", raw.version));
+        }
+
+        let dir = match file.rfind('/') {
+            Some(idx) => &file[..idx],
+            None => "",
+        };
+        let sources = raw
+            .sources
+            .iter()
+            .map(|s| {
+                // only used for display, so keep sources outside of the bundle as they are
+                normalize_path(&format!("{dir}/{}/{s}", raw.source_root))
+                    .unwrap_or_else(|_| s.clone())
+            })
+            .collect::<Vec<_>>();
+
+        // all fields except gen_col are relative to the previous segment in the file
+        let mut source = 0i64;
+        let mut line = 0i64;
+        let mut col = 0i64;
+        let mut lines = Vec::new();
+        for gen_line in raw.mappings.split(';') {
+            let mut gen_col = 0i64;
+            let mut segments = Vec::new();
+            for seg in gen_line.split(',').filter(|s| !s.is_empty()) {
+                let fields = decode_vlq(seg)?;
+                gen_col += fields[0];
+                if fields.len() < 4 {
+                    // no source location for this segment
+                    continue;
+                }
+                source += fields[1];
+                line += fields[2];
+                col += fields[3];
+                if gen_col < 0 || source < 0 || line < 0 || col < 0 {
+                    return Err("negative source map position".to_string());
+                }
+                if source as usize >= sources.len() {
+                    return Err(format!("source index {source} out of range"));
+                }
+                segments.push(Segment {
+                    gen_col: gen_col as u32,
+                    source: source as u32,
+                    line: line as u32,
+                    col: col as u32,
+                });
+            }
+            segments.sort_by_key(|s| s.gen_col);
+            lines.push(segments);
+        }
+
+        Ok(SourceMap { sources, lines })
+    }
+
+    /// Map a location in the generated file to the original source.
+    /// Without a column, the first mapping on the line is used.
+    pub fn lookup(&self, line: u32, column: Option<u32>) -> Option<Location> {
+        let segments = self.lines.get((line as usize).checked_sub(1)?)?;
+        let seg = match column {
+            Some(col) => {
+                let col = col.saturating_sub(1);
+                let idx = segments.partition_point(|s| s.gen_col <= col);
+                segments.get(idx.checked_sub(1)?)?
+            }
+            None => segments.first()?,
+        };
+        Some(Location {
+            file: self.sources[seg.source as usize].clone(),
+            line: seg.line + 1,
+            column: column.map(|_| seg.col + 1),
+        })
+    }
+}
+
+pub type SourceMaps = HashMap<String, SourceMap>;
+
+/// Parse a QuickJS stack frame, either "    at fn (file:line[:col])" or "    at file:line[:col]".
+/// Returns the byte range of the location part, and the location.
+pub fn parse_frame(frame: &str) -> Option<(std::ops::Range<usize>, Location)> {
+    let start = frame.find("at ")? + 3;
+    let (start, end) = if frame.ends_with(')') {
+        (frame.rfind('(')? + 1, frame.len() - 1)
+    } else {
+        (start, frame.len())
+    };
+    let (rest, last) = frame[start..end].rsplit_once(':')?;
+    let last = last.parse::<u32>().ok()?;
+    let location = match rest.rsplit_once(':') {
+        Some((file, line)) if line.parse::<u32>().is_ok() => Location {
+            file: file.to_string(),
+            line: line.parse().unwrap(),
+            column: Some(last),
+        },
+        _ => Location {
+            file: rest.to_string(),
+            line: last,
+            column: None,
+        },
+    };
+    Some((start..end, location))
+}
+
+pub fn map_location(maps: &SourceMaps, loc: Location) -> Location {
+    maps.get(&loc.file)
+        .and_then(|m| m.lookup(loc.line, loc.column))
+        .unwrap_or(loc)
+}
+
+fn format_location(loc: &Location) -> String {
+    match loc.column {
+        Some(col) => format!("{}:{}:{}", loc.file, loc.line, col),
+        None => format!("{}:{}", loc.file, loc.line),
+    }
+}
+
+/// Rewrite all frames of the stack trace that have a source map.
+pub fn map_stack(maps: &SourceMaps, stack: &str) -> String {
+    if maps.is_empty() {
+        return stack.to_string();
+    }
+    stack
+        .lines()
+        .map(|frame| match parse_frame(frame) {
+            Some((range, loc)) if maps.contains_key(&loc.file) => {
+                let loc = map_location(maps, loc);
+                format!(
+                    "{}{}{}",
+                    &frame[..range.start],
+                    format_location(&loc),
+                    &frame[range.end..]
+                )
+            }
+            _ => frame.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vlq() {
+        assert_eq!(decode_vlq("AAAA").unwrap(), vec![0, 0, 0, 0]);
+        assert_eq!(decode_vlq("CD").unwrap(), vec![1, -1]);
+        // multi-digit values, with the continuation bit
+        assert_eq!(decode_vlq("gB").unwrap(), vec![16]);
+        assert_eq!(decode_vlq("2H3H").unwrap(), vec![123, -123]);
+        assert_eq!(decode_vlq("").unwrap(), vec![]);
+        assert!(decode_vlq("g").is_err());
+        assert!(decode_vlq("A!").is_err());
+        assert!(decode_vlq("gggggggggggggggB").is_err());
+    }
+
+    fn sample_map() -> SourceMap {
+        // line 1: col 0 -> a.ts 1:1, col 4 -> a.ts 1:5
+        // line 2: col 2 -> a.ts 2:5 (fields are relative to the previous segment)
+        let json = r#"{
+            "version": 3,
+            "sources": ["../src/a.ts"],
+            "mappings": "AAAA,IAAI;EACA"
+        }"#;
+        SourceMap::parse("dist/a.js", json).unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        let m = sample_map();
+        assert_eq!(m.sources, vec!["src/a.ts"]);
+        let loc = |line, col| m.lookup(line, col).map(|l| (l.file, l.line, l.column));
+        let a = "src/a.ts".to_string();
+        assert_eq!(loc(1, Some(1)), Some((a.clone(), 1, Some(1))));
+        assert_eq!(loc(1, Some(4)), Some((a.clone(), 1, Some(1))));
+        assert_eq!(loc(1, Some(5)), Some((a.clone(), 1, Some(5))));
+        assert_eq!(loc(1, Some(100)), Some((a.clone(), 1, Some(5))));
+        assert_eq!(loc(1, None), Some((a.clone(), 1, None)));
+        // before the first segment on the line
+        assert_eq!(loc(2, Some(1)), None);
+        assert_eq!(loc(2, Some(3)), Some((a.clone(), 2, Some(5))));
+        assert_eq!(loc(2, None), Some((a.clone(), 2, None)));
+        assert_eq!(loc(0, None), None);
+        assert_eq!(loc(3, None), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let map = |mappings: &str| {
+            let json =
+                format!(r#"{{"version": 3, "sources": ["a.ts"], "mappings": "{mappings}"}}"#);
+            SourceMap::parse("a.js", &json).map(|_| ())
+        };
+        assert!(map("AAAA").is_ok());
+        assert!(map("ACAA").is_err()); // source index 1
+        assert!(map("AADA").is_err()); // negative line
+        assert!(
+            SourceMap::parse("a.js", r#"{"version": 2, "sources": [], "mappings": ""}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn frames() {
+        let frame = "    at foo (dist/a.js:3:7)";
+        let (range, loc) = parse_frame(frame).unwrap();
+        assert_eq!(&frame[range], "dist/a.js:3:7");
+        assert_eq!(
+            (loc.file.as_str(), loc.line, loc.column),
+            ("dist/a.js", 3, Some(7))
+        );
+
+        let frame = "    at dist/a.js:12";
+        let (range, loc) = parse_frame(frame).unwrap();
+        assert_eq!(&frame[range], "dist/a.js:12");
+        assert_eq!(
+            (loc.file.as_str(), loc.line, loc.column),
+            ("dist/a.js", 12, None)
+        );
+
+        assert!(parse_frame("    at <eval>").is_none());
+        assert!(parse_frame("Error: foo").is_none());
+    }
+
+    #[test]
+    fn stack() {
+        let mut maps = SourceMaps::new();
+        maps.insert("dist/a.js".to_string(), sample_map());
+        let stack = "    at foo (dist/a.js:1:6)\n    at bar (other.js:1:1)\n    at dist/a.js:2";
+        assert_eq!(
+            map_stack(&maps, stack),
+            "    at foo (src/a.ts:1:5)\n    at bar (other.js:1:1)\n    at src/a.ts:2"
+        );
+    }
+}
@@ -7,25 +7,46 @@ import {
   regexConstraint,
   cfgConstraint,
   substrConstraint,
+  upToConstraint,
+  healedConstraint,
   Constraint,
+  DynamicLexer,
   getVar,
   setVar,
   appendVar,
+  deleteVar,
+  listVars,
+  incrementVar,
+  getVarVersion,
+  watchVar,
   eosToken,
   panic,
   tokenRepr,
   tokensRepr,
   getConfig,
+  emit,
 } from "_aici";
 
 export {
   TokenSet,
+  Constraint,
+  DynamicLexer,
+  regexConstraint,
+  cfgConstraint,
+  substrConstraint,
+  upToConstraint,
   tokenize,
   detokenize,
   getVar,
   setVar,
   appendVar,
+  deleteVar,
+  listVars,
+  incrementVar,
+  getVarVersion,
+  watchVar,
   getConfig,
+  emit,
   eosToken,
   tokenRepr,
   tokensRepr,
@@ -281,6 +302,15 @@ export class NextToken {
   // Internal methods
   //
 
+  /**
+   * Make the tokens generated by this step start with the given bytes,
+   * that were removed from the prompt by token healing.
+   * Returns false if not supported.
+   */
+  _heal(prefix: Buffer): boolean {
+    return false;
+  }
+
   _mid_process(): MidProcessResult {
     this.reset();
     const spl = this.isFixed();
@@ -340,6 +370,12 @@ class FixedTokens extends NextToken {
     return true;
   }
 
+  override _heal(prefix: Buffer): boolean {
+    const text = concatBuffers(prefix, detokenize(this.fixedTokens));
+    this.fixedTokens = tokenize(text);
+    return true;
+  }
+
   override midProcess(): MidProcessResult {
     let backtrack = 0;
     if (this.following !== null) {
@@ -379,6 +415,17 @@ export class ConstrainedToken extends NextToken {
     super();
   }
 
+  override _heal(prefix: Buffer): boolean {
+    assert(this._constraint === null);
+    const mkConstraint = this.mkConstraint;
+    this.mkConstraint = () => {
+      const c = mkConstraint();
+      if (c instanceof ChooseConstraint) return c.withPrefix(prefix);
+      return healedConstraint(prefix, combinable([c])[0]);
+    };
+    return true;
+  }
+
   override midProcess(): MidProcessResult {
     const bias = new TokenSet();
     if (this._constraint === null) {
@@ -452,6 +499,8 @@ class WaitVars extends NextToken {
   }
 
   override midProcess(): MidProcessResult {
+    // watchVar() suspends us until the (first) missing variable is set
+    if (this.vars.some((v) => watchVar(v))) return MidProcessResult.noop();
     const values = this.vars.map((v) => getVar(v));
     if (values.includes(null)) return MidProcessResult.noop();
     this.values = values as Buffer[];
@@ -475,7 +524,7 @@ export async function waitVars(...vars: string[]): Promise<Buffer[]> {
  *  Low-level interface for AICI. Use aici.start() to wrap a coroutine.
  */
 export interface AiciCallbacks {
-  init_prompt(prompt: Token[]): void;
+  init_prompt(prompt: Token[], healPrefix: Buffer): void;
   mid_process(backtrack: number, tokens: Token[], fork_group: SeqId[]): void;
 }
 
@@ -510,6 +559,17 @@ export class AiciAsync implements AiciCallbacks {
   private _nextTokenCb?: () => void;
   private _token: CbType | undefined;
   private _getPrompt: GetPrompt | undefined;
+  private _healPrefix: Buffer | undefined;
+
+  private _applyHealing() {
+    const prefix = this._healPrefix;
+    if (prefix === undefined || !this._token) return;
+    this._healPrefix = undefined;
+    if (!this._token._heal(prefix))
+      throw new Error(
+        "token healing requires generation to start with gen() or fixed()"
+      );
+  }
 
   _setGetPrompt(g: GetPrompt) {
     assert(!this._getPrompt);
@@ -523,6 +583,7 @@ export class AiciAsync implements AiciCallbacks {
     assert(!this._getPrompt);
     assert(t instanceof NextToken);
     this._token = t;
+    this._applyHealing();
     const f = this._nextTokenCb;
     this._nextTokenCb = undefined;
     if (f) f();
@@ -566,7 +627,7 @@ export class AiciAsync implements AiciCallbacks {
     });
   }
 
-  init_prompt(prompt: Token[]): void {
+  init_prompt(prompt: Token[], healPrefix: Buffer): void {
     if (this._getPrompt) {
       assert(this._getPrompt instanceof GetPrompt);
       assert(!this._token);
@@ -578,11 +639,15 @@ export class AiciAsync implements AiciCallbacks {
     this._prompt_len = prompt.length;
     this._tokens.push(...prompt);
 
+    if (healPrefix.length > 0) this._healPrefix = healPrefix;
+
     if (this._getPrompt) {
+      // healing is applied once the next token is requested
       this._getPrompt._resolve!(prompt);
       this._getPrompt = undefined;
     } else {
       assert(this._token instanceof NextToken);
+      this._applyHealing();
     }
     this._went_ahead = true;
   }
@@ -650,8 +715,14 @@ export class AiciAsync implements AiciCallbacks {
 /**
  * Starts the AICI loop.
  * @param f async function
+ * @param options.tokenHealing remove the last token(s) of the prompt, and force the first
+ *   generation step to start with the removed bytes (which are included in its result)
  */
-export function start(f: () => Promise<void>): AiciAsync {
+export function start(
+  f: () => Promise<void>,
+  options: { tokenHealing?: boolean } = {}
+): AiciAsync {
+  _aici.setTokenHealing(!!options.tokenHealing);
   return new AiciAsync(f);
 }
 
@@ -703,12 +774,21 @@ export class ChooseConstraint extends Constraint {
   ptr: number;
   options: Token[][];
 
-  constructor(options: string[]) {
+  constructor(options: (string | Buffer)[]) {
     super();
     this.ptr = 0;
     this.options = options.map((o) => tokenize(o));
   }
 
+  /**
+   * Return a fresh constraint with all options prefixed with given bytes.
+   */
+  withPrefix(prefix: Buffer): ChooseConstraint {
+    return new ChooseConstraint(
+      this.options.map((o) => concatBuffers(prefix, detokenize(o)))
+    );
+  }
+
   eosAllowed(): boolean {
     return this.options.some((o) => o.length === this.ptr);
   }
@@ -739,6 +819,57 @@ export class ChooseConstraint extends Constraint {
   }
 }
 
+function concatBuffers(a: Buffer, b: Buffer): Buffer {
+  const r = new Uint8Array(a.length + b.length);
+  r.set(a);
+  r.set(b, a.length);
+  return r;
+}
+
+function combinable(items: Constraint[]): Constraint[] {
+  for (const c of items) {
+    if (Object.getPrototypeOf(c) !== Constraint.prototype) {
+      throw new TypeError(
+        "subclasses of Constraint can't be combined with other constraints"
+      );
+    }
+  }
+  return items;
+}
+
+/**
+ * A constraint that allows text matching each of the given constraints in turn.
+ * The constraints are re-created from their definitions; their current state is ignored.
+ */
+export function seqConstraint(...items: Constraint[]): Constraint {
+  return _aici.seqConstraint(combinable(items));
+}
+
+/**
+ * A constraint that allows text matching any of the given constraints (at most 64).
+ */
+export function orConstraint(...items: Constraint[]): Constraint {
+  return _aici.orConstraint(combinable(items));
+}
+
+/**
+ * A constraint that allows text matching all of the given constraints.
+ */
+export function andConstraint(...items: Constraint[]): Constraint {
+  return _aici.andConstraint(combinable(items));
+}
+
+/**
+ * A constraint that allows text matching `item` repeated between `min` and `max` times.
+ */
+export function repeatConstraint(
+  item: Constraint,
+  min = 0,
+  max?: number
+): Constraint {
+  return _aici.repeatConstraint(combinable([item])[0], min, max);
+}
+
 export async function genTokens(options: GenOptions): Promise<Token[]> {
   if (logLevel >= 2) console.log("GEN-OPT", options);
   const res: Token[] = [];
@@ -748,6 +879,7 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
     substring,
     substringEnd = '"',
     options: optionList,
+    constraint: mkConstraint,
     storeVar,
     stopAt,
     maxTokens = 20,
@@ -755,8 +887,9 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
 
   let constraint: Constraint;
   assert(
-    [regex, substring, yacc, optionList].filter((x) => x !== undefined)
-      .length <= 1
+    [regex, substring, yacc, optionList, mkConstraint].filter(
+      (x) => x !== undefined
+    ).length <= 1
   );
   if (regex !== undefined) {
     const rx = typeof regex === "string" ? regex : regex.source;
@@ -767,6 +900,8 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
     constraint = cfgConstraint(yacc);
   } else if (optionList !== undefined) {
     constraint = new ChooseConstraint(optionList);
+  } else if (mkConstraint !== undefined) {
+    constraint = mkConstraint();
   } else {
     constraint = new Constraint();
   }
@@ -35,8 +35,13 @@ declare function waitVars(...vars: string[]): Promise<Buffer[]>;
 /**
  * Starts the AICI loop. 
  * @param f async function
+ * @param options.tokenHealing remove the last token(s) of the prompt, and force the first
+ *   generation step to start with the removed bytes (which are included in its result)
  */
-declare function start(f: () => Promise<void>): void;
+declare function start(
+  f: () => Promise<void>,
+  options?: { tokenHealing?: boolean }
+): void;
 
 /**
  * Specifies options for gen() and genTokens().
@@ -55,14 +60,20 @@ interface GenOptions {
    */
   yacc?: string;
   /**
-   * Make sure the generated text is a substring of the given string.
+   * Make sure the generated text is a substring of the given string
+   * (or of one of the given strings).
    */
-  substring?: string;
+  substring?: string | string[];
   /**
    * Used together with `substring` - treat the substring as ending the substring
    * (typically '"' or similar).
    */
   substringEnd?: string;
+  /**
+   * Make sure the generated text matches the constraint returned by the function,
+   * e.g., `() => seqConstraint(regexConstraint("\\d+"), upToConstraint("\\n"))`.
+   */
+  constraint?: () => import("_aici").Constraint;
   /**
    * Store result of the generation (as bytes) into a shared variable.
    */
@@ -180,11 +191,45 @@ declare module "_aici" {
    */
   function appendVar(name: string, value: string | Buffer): void;
 
+  /**
+   * Delete a shared variable. Returns false if it was not set.
+   */
+  function deleteVar(name: string): boolean;
+
+  /**
+   * Return sorted names of shared variables starting with prefix.
+   */
+  function listVars(prefix?: string): string[];
+
+  /**
+   * Atomically add delta (default 1) to an integer variable (stored as decimal string; unset is 0).
+   * Returns the new value.
+   */
+  function incrementVar(name: string, delta?: number): number;
+
+  /**
+   * Get the version of a shared variable, which is incremented on every write.
+   */
+  function getVarVersion(name: string): number | null;
+
+  /**
+   * Suspend the sequence until the variable changes from the given version
+   * (null meaning it's not set); midProcess() should return MidProcessResult.noop() then.
+   * Returns false (and doesn't suspend) if the variable has already changed.
+   */
+  function watchVar(name: string, version?: number | null): boolean;
+
   /**
    * Get the value of a configuration parameter like "fork".
    */
   function getConfig(name: string): number;
 
+  /**
+   * Emit a structured event, returned to the client in `events` (and not in the logs).
+   * `data` is serialized with JSON.stringify(); throws if it can't be.
+   */
+  function emit(kind: string, data: any): void;
+
   /**
    * Index of the end of sequence token.
    */
@@ -263,6 +308,7 @@ declare module "_aici" {
 
     /**
      * Update the internal state of the constraint to reflect that token `t` was appended.
+     * Throws if `t` is not allowed.
      */
     appendToken(t: number): void;
 
@@ -270,6 +316,60 @@ declare module "_aici" {
      * Set ts[] to True at all tokens that are allowed by the constraint.
      */
     allowTokens(ts: TokenSet): void;
+
+    /**
+     * For substrConstraint(), return `[sourceIdx, start, end]` locating the generated `text`
+     * (excluding the stop string) in the sources, or undefined if it doesn't match.
+     * Offsets are in bytes.
+     */
+    locate(text: string | Buffer): [number, number, number] | undefined;
+  }
+
+  /**
+   * A lexer with a set of valid identifiers, that can be used as a Constraint.
+   * Identifiers are added to the current scope, and are removed when the scope is popped.
+   */
+  class DynamicLexer {
+    /**
+     * Normally, identifiers match `[a-zA-Z_][a-zA-Z0-9_]*`.
+     * If additionalIdChars is not empty, the chars are additionally allowed anywhere in the identifier.
+     * For example, use "$" for JavaScript, or "'" for ML-like languages.
+     */
+    constructor(additionalIdChars?: string);
+
+    /**
+     * Allow given identifier in the current scope.
+     */
+    add(identifier: string): void;
+
+    /**
+     * Start a new (nested) scope.
+     */
+    pushScope(): void;
+
+    /**
+     * Remove identifiers added in the innermost scope, including the ones declared by the model.
+     * Identifiers shadowed in that scope stay valid. Throws if only the global scope is left.
+     */
+    popScope(): void;
+
+    /**
+     * Number of scopes, including the global one.
+     */
+    numScopes(): number;
+
+    /**
+     * After the model generates `prefix` (e.g., "let " or "function "), the identifier
+     * that follows is declared in the current scope, and can be used in later tokens.
+     * Keywords in the prefix still need to be allowed with add().
+     */
+    addDeclarationRule(prefix: string): void;
+
+    /**
+     * This always returns a constraint sharing the state of the lexer.
+     * It can't be combined with other constraints.
+     */
+    constraint(): Constraint;
   }
 
   /**
@@ -283,8 +383,53 @@ declare module "_aici" {
    */
   function cfgConstraint(yacc_grammar: string): Constraint;
 
+  interface SubStrOptions {
+    /**
+     * Only allow substrings that start and end at word boundaries; defaults to true.
+     */
+    wordBoundaries?: boolean;
+    /**
+     * Match case-insensitively, and treat any run of whitespace as a single space; defaults to false.
+     */
+    normalize?: boolean;
+  }
+
+  /**
+   * A constraint that allows only word-substrings of given string,
+   * or of any of the given strings (a substring never spans two of them).
+   * Use `locate()` on the constraint to find out which string the generated text came from.
+   */
+  function substrConstraint(
+    template: string | string[],
+    stop_at: string,
+    options?: SubStrOptions
+  ): Constraint;
+
+  /**
+   * A constraint that allows any text up to and including the first occurrence of `stop`.
+   */
+  function upToConstraint(stop: string): Constraint;
+
+  /**
+   * A constraint that forces the bytes removed from the prompt by token healing,
+   * and then allows text matching `item`.
+   */
+  function healedConstraint(prefix: Buffer, item: Constraint): Constraint;
+
+  /**
+   * Enable token healing of the prompt; use start(f, { tokenHealing: true }) instead.
+   */
+  function setTokenHealing(enabled: boolean): void;
+
   /**
-   * A constraint that allows only word-substrings of given string.
+   * Native implementations of seqConstraint() and friends from 'aici' module.
    */
-  function substrConstraint(template: string, stop_at: string): Constraint;
+  function seqConstraint(items: Constraint[]): Constraint;
+  function orConstraint(items: Constraint[]): Constraint;
+  function andConstraint(items: Constraint[]): Constraint;
+  function repeatConstraint(
+    item: Constraint,
+    min: number,
+    max: number | undefined
+  ): Constraint;
 }
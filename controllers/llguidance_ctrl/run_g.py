@@ -226,6 +226,11 @@ def main():
     serialized["max_tokens"] = max_tokens
     serialized["test_trace"] = True
     llguidance_json = {"grammar": serialized}
+    # N=5 samples 5 completions in parallel; ALT=1 explores select() alternatives in parallel
+    if "N" in os.environ:
+        llguidance_json["n"] = int(os.environ["N"])
+    if "ALT" in os.environ:
+        llguidance_json["fork_alternatives"] = True
 
     llguidance_arg = json.dumps(llguidance_json, indent=1)
     # save llguidance_arg to file
@@ -266,17 +271,25 @@ def main():
 
     testcase_from_logs(res["logs"][0])
 
-    text = b""
-    captures = {}
-    for j in res["json_out"][0]:
-        if j["object"] == "text":
-            text += binascii.unhexlify(j["hex"])
-        elif j["object"] == "capture":
-            captures[j["name"]] = binascii.unhexlify(j["hex"]).decode(
-                "utf-8", errors="replace")
-    print("Captures:", json.dumps(captures, indent=2))
-    print("Final text:\n", text.decode("utf-8", errors="replace"))
-    print()
+    for idx, events in enumerate(res["events"]):
+        text = b""
+        captures = {}
+        branch = None
+        for ev in events:
+            if ev["kind"] != "progress":
+                continue
+            j = ev["data"]
+            branch = j.get("branch", branch)
+            if j["object"] == "text":
+                text += binascii.unhexlify(j["hex"])
+            elif j["object"] == "capture":
+                captures[j["name"]] = binascii.unhexlify(j["hex"]).decode(
+                    "utf-8", errors="replace")
+        if len(res["events"]) > 1:
+            print(f"Fork {idx}, branch {branch}:")
+        print("Captures:", json.dumps(captures, indent=2))
+        print("Final text:\n", text.decode("utf-8", errors="replace"))
+        print()
 
 
 def testcase_from_logs(logs: str):
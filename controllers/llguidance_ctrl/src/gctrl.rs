@@ -1,16 +1,24 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use aici_abi::{
     arg_bytes, get_config,
     toktrie::{InferenceCapabilities, StepArg},
-    AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult,
+    AiciCtrl, Branch, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, SimpleVob,
+    TokenId, TokenizerEnv, WasmTokenizerEnv,
 };
 use serde::{Deserialize, Serialize};
 
-use llguidance_parser::{api::TopLevelGrammar, output::Reporter, Logger, TokenParser};
+use llguidance_parser::{
+    api::{Node, TopLevelGrammar},
+    output::Reporter,
+    Logger, TokenParser,
+};
 
 const INFO: bool = true;
 
+/// Only look for a `select` when at most this many tokens are allowed.
+const MAX_SELECT_TOKENS: usize = 64;
+
 macro_rules! infoln {
     ($($arg:tt)*) => {
         if INFO {
@@ -22,42 +30,259 @@ macro_rules! infoln {
 pub struct Runner {
     tok_parser: TokenParser,
     reporter: Reporter,
+    tok_env: Arc<WasmTokenizerEnv>,
+    num_samples: usize,
+    fork_alternatives: bool,
+    /// `select`s of the grammar that can be forked on.
+    selects: Vec<Select>,
+    /// How many more sequences this one can fork into.
+    fork_budget: usize,
+    /// Set when the last mid_process() returned multiple branches.
+    pending_fork: Option<PendingFork>,
+    /// Index of the branch taken at each fork so far; reported with the progress output.
+    branch: Vec<usize>,
+    is_first: bool,
+}
+
+/// A `select` between literal strings, with their tokens.
+struct Select {
+    options: Vec<(Vec<u8>, Vec<TokenId>)>,
+}
+
+impl Select {
+    /// Check if the allowed tokens (with their bytes) are exactly the ones
+    /// that can start the options, i.e., the parser is at this select.
+    fn is_at(&self, allowed: &[(TokenId, &[u8])]) -> bool {
+        allowed.iter().all(|(_, bytes)| {
+            !bytes.is_empty() && self.options.iter().any(|(o, _)| o.starts_with(bytes))
+        }) && self.options.iter().all(|(_, toks)| match toks.first() {
+            Some(t0) => allowed.iter().any(|(t, _)| t == t0),
+            None => false,
+        })
+    }
+}
+
+// Selects where all options are distinct non-empty literals; with an empty option
+// the text after the select is allowed too, and it can't be told apart from the options.
+fn literal_selects(grammar: &TopLevelGrammar) -> Vec<Vec<Vec<u8>>> {
+    let mut res = vec![];
+    for g in &grammar.grammars {
+        for node in &g.nodes {
+            if let Node::Select { among, .. } = node {
+                let mut options: Vec<Vec<u8>> = vec![];
+                for id in among {
+                    match g.nodes.get(id.0) {
+                        Some(Node::String { literal, .. }) if !literal.is_empty() => {
+                            let lit = literal.as_bytes().to_vec();
+                            if !options.contains(&lit) {
+                                options.push(lit);
+                            }
+                        }
+                        _ => {
+                            options.clear();
+                            break;
+                        }
+                    }
+                }
+                if options.len() > 1 {
+                    res.push(options);
+                }
+            }
+        }
+    }
+    res
+}
+
+struct PendingFork {
+    num_branches: usize,
+    budget: usize,
 }
 
 #[derive(Serialize, Deserialize)]
 struct RunnerArg {
     grammar: TopLevelGrammar,
+    /// Number of independent completions to sample (self-consistency), each in its own fork.
+    #[serde(default = "default_n")]
+    n: usize,
+    /// Fork at each `select` between literal strings, and explore all the options in parallel.
+    #[serde(default)]
+    fork_alternatives: bool,
+    /// Maximum number of sequences (including the initial ones) the request can fork into.
+    #[serde(default = "default_max_forks")]
+    max_forks: usize,
+}
+
+fn default_n() -> usize {
+    1
+}
+
+fn default_max_forks() -> usize {
+    16
+}
+
+// Runner built by aici_preinit(), with the notes to log once a request uses it.
+struct Preinit(Runner, Vec<&'static str>);
+
+// Wasm is single-threaded
+unsafe impl Send for Preinit {}
+
+static PREINIT: Mutex<Option<Preinit>> = Mutex::new(None);
+
+fn runner_preinit() {
+    // errors are reported when the request is run
+    if let Ok((runner, notes)) = Runner::build(&arg_bytes()) {
+        *PREINIT.lock().unwrap() = Some(Preinit(runner, notes));
+    }
 }
 
 impl Runner {
     pub fn new() -> Self {
         infoln!("building runner...");
-        let arg: RunnerArg = serde_json::from_slice(&arg_bytes()).expect("invalid JSON arg");
+        let (runner, notes) = match PREINIT.lock().unwrap().take() {
+            Some(Preinit(runner, notes)) => (runner, notes),
+            None => Self::build(&arg_bytes()).unwrap_or_else(|e| panic!("{e}")),
+        };
+        for note in notes {
+            infoln!("{}", note);
+        }
+        runner
+    }
+
+    // the grammar is compiled here; no logging, so that it can run in aici_preinit()
+    fn build(arg: &[u8]) -> Result<(Self, Vec<&'static str>), String> {
+        let arg: RunnerArg =
+            serde_json::from_slice(arg).map_err(|e| format!("invalid JSON arg: {e:?}"))?;
+        let mut notes = vec![];
         let log_level = 2;
         let inf = InferenceCapabilities {
             backtrack: get_config("backtrack") != 0,
             ff_tokens: get_config("ff_tokens") != 0,
             conditional_ff_tokens: get_config("ff_tokens") != 0,
-            fork: false,
+            fork: get_config("fork") != 0,
+        };
+        let can_fork = inf.fork;
+        // alternatives are forced with splices
+        let fork_alternatives = arg.fork_alternatives && can_fork && inf.ff_tokens;
+        if (arg.n > 1 || arg.fork_alternatives) && !can_fork {
+            notes.push("forking not supported; ignoring n and fork_alternatives");
+        } else if arg.fork_alternatives && !inf.ff_tokens {
+            notes.push("fork_alternatives needs ff_tokens; ignoring it");
+        }
+        let tok_env = Arc::new(WasmTokenizerEnv::default());
+        let selects = if fork_alternatives {
+            literal_selects(&arg.grammar)
+                .into_iter()
+                .map(|options| Select {
+                    options: options
+                        .into_iter()
+                        .map(|o| {
+                            let toks = tok_env.tokenize_bytes(&o);
+                            (o, toks)
+                        })
+                        .collect(),
+                })
+                .collect()
+        } else {
+            vec![]
         };
         let tok_parser = TokenParser::from_llguidance_json(
-            Arc::new(aici_abi::WasmTokenizerEnv::default()),
+            tok_env.clone(),
             arg.grammar,
             Logger::new(0, log_level),
             inf,
         )
-        .expect("invalid guidance protobuf");
+        .map_err(|e| format!("invalid guidance protobuf: {e:?}"))?;
 
         let reporter = Reporter::new(&tok_parser);
-        Runner {
+        let runner = Runner {
             tok_parser,
             reporter,
+            tok_env,
+            num_samples: if can_fork { arg.n.max(1) } else { 1 },
+            fork_alternatives,
+            selects,
+            fork_budget: if can_fork {
+                arg.max_forks.saturating_sub(1)
+            } else {
+                0
+            },
+            pending_fork: None,
+            branch: vec![],
+            is_first: true,
+        };
+        Ok((runner, notes))
+    }
+
+    /// Called in each of the new sequences, right after forking.
+    fn enter_fork(&mut self, arg: &MidProcessArg, fork: PendingFork) {
+        let my_id = aici_abi::self_seq_id();
+        let n = fork.num_branches;
+        match arg.fork_group.iter().position(|id| *id == my_id) {
+            Some(idx) if arg.fork_group.len() == n => {
+                // split the remaining budget between the branches
+                self.fork_budget = fork.budget / n + if idx < fork.budget % n { 1 } else { 0 };
+                self.branch.push(idx);
+                infoln!("branch {:?}; fork budget {}", self.branch, self.fork_budget);
+            }
+            _ => {
+                // the budget can't be split without knowing the branch, so stop forking
+                infoln!(
+                    "seq {} not in fork group {:?} of {} branches; not forking further",
+                    my_id,
+                    arg.fork_group,
+                    n
+                );
+                self.fork_budget = 0;
+            }
         }
     }
-}
 
-fn json_out<T: Serialize>(obj: &T) {
-    println!("JSON-OUT: {}", serde_json::to_string(obj).unwrap());
+    fn fork(&mut self, branches: Vec<Branch<SimpleVob>>) -> MidProcessResult {
+        let num_branches = branches.len();
+        debug_assert!(num_branches > 1 && num_branches - 1 <= self.fork_budget);
+        self.pending_fork = Some(PendingFork {
+            num_branches,
+            budget: self.fork_budget.saturating_sub(num_branches - 1),
+        });
+        MidProcessResult {
+            branches,
+            attention_masks: vec![],
+        }
+    }
+
+    /// If the parser is at one of the `select`s, return the tokens of each of its options.
+    fn alternatives(&self, r: &Branch<SimpleVob>) -> Option<Vec<Vec<TokenId>>> {
+        let mask = match (&r.sample_mask, r.splices.is_empty()) {
+            (Some(mask), true) => mask,
+            _ => return None,
+        };
+        let num_set = mask.num_set();
+        if num_set < 2 || num_set > MAX_SELECT_TOKENS {
+            return None;
+        }
+        let trie = self.tok_env.tok_trie();
+        let allowed = (0..trie.vocab_size() as TokenId)
+            .filter(|t| mask.is_allowed(*t))
+            .map(|t| (t, trie.token(t)))
+            .collect::<Vec<_>>();
+        let sel = self.selects.iter().find(|s| s.is_at(&allowed))?;
+        if sel.options.len() - 1 > self.fork_budget {
+            return None;
+        }
+        Some(sel.options.iter().map(|(_, toks)| toks.clone()).collect())
+    }
+
+    fn emit_progress<T: Serialize>(&self, obj: &T) {
+        let mut v = serde_json::to_value(obj).unwrap();
+        if self.branch.len() > 0 {
+            if let Some(obj) = v.as_object_mut() {
+                obj.insert("branch".to_string(), serde_json::json!(self.branch));
+            }
+        }
+        if let Err(e) = aici_abi::emit("progress", &v) {
+            println!("warning: progress: {e}");
+        }
+    }
 }
 
 impl AiciCtrl for Runner {
@@ -67,14 +292,40 @@ impl AiciCtrl for Runner {
         }
     }
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        if let Some(fork) = self.pending_fork.take() {
+            self.enter_fork(&arg, fork);
+        }
+
+        // self-consistency: fork before the parser sees any tokens;
+        // each branch then samples its own completion
+        if self.is_first {
+            self.is_first = false;
+            let n = self.num_samples.min(self.fork_budget + 1);
+            if n > 1 {
+                return self.fork((0..n).map(|_| Branch::noop()).collect());
+            }
+        }
+
         let r = self.tok_parser.mid_process(StepArg {
             backtrack: arg.backtrack,
             tokens: arg.tokens,
             sampled: arg.sampled,
         });
         for v in self.reporter.get_progress(&mut self.tok_parser, &r) {
-            json_out(&v);
+            self.emit_progress(&v);
+        }
+
+        if self.fork_alternatives {
+            if let Some(alts) = self.alternatives(&r) {
+                // each branch forces one of the options
+                return self.fork(
+                    alts.into_iter()
+                        .map(|toks| Branch::splice(0, toks))
+                        .collect(),
+                );
+            }
         }
+
         MidProcessResult::from_branch(r)
     }
 }
@@ -82,3 +333,46 @@ impl AiciCtrl for Runner {
 fn main() {}
 
 aici_abi::aici_expose_all!(Runner, Runner::new());
+aici_abi::aici_expose_preinit!(runner_preinit);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(options: Vec<(&str, Vec<TokenId>)>) -> Select {
+        Select {
+            options: options
+                .into_iter()
+                .map(|(o, toks)| (o.as_bytes().to_vec(), toks))
+                .collect(),
+        }
+    }
+
+    fn allowed<'a>(toks: &[(TokenId, &'a str)]) -> Vec<(TokenId, &'a [u8])> {
+        toks.iter().map(|(t, s)| (*t, s.as_bytes())).collect()
+    }
+
+    #[test]
+    fn prefix_options() {
+        // select("a", "ab", "c"), where "ab" is a single token
+        let sel = select(vec![("a", vec![1]), ("ab", vec![2]), ("c", vec![3])]);
+        assert!(sel.is_at(&allowed(&[(1, "a"), (2, "ab"), (3, "c")])));
+        // "ab" has to be there
+        assert!(!sel.is_at(&allowed(&[(1, "a"), (3, "c")])));
+        // some other text is allowed
+        assert!(!sel.is_at(&allowed(&[(1, "a"), (2, "ab"), (3, "c"), (4, "x")])));
+        assert!(!sel.is_at(&allowed(&[(1, "a"), (2, "ab"), (3, "c"), (5, "abc")])));
+
+        // "ab" tokenized as "a" "b"
+        let sel = select(vec![("a", vec![1]), ("ab", vec![1, 6]), ("c", vec![3])]);
+        assert!(sel.is_at(&allowed(&[(1, "a"), (3, "c")])));
+    }
+
+    #[test]
+    fn prefix_tokens() {
+        // select("leather", "lead"); the tokens can be prefixes of the options
+        let sel = select(vec![("leather", vec![10, 11]), ("lead", vec![10, 12])]);
+        assert!(sel.is_at(&allowed(&[(7, "l"), (8, "le"), (10, "lea")])));
+        assert!(!sel.is_at(&allowed(&[(7, "l"), (8, "le")])));
+    }
+}
@@ -1,7 +1,12 @@
 fn main() {
-    for entry in glob::glob("Lib/**/*.py").expect("Lib/ exists?").flatten() {
-        let display = entry.display();
-        println!("cargo:rerun-if-changed={display}");
+    for dir in ["Lib", "pylib"] {
+        for entry in glob::glob(&format!("{dir}/**/*.py"))
+            .expect("exists?")
+            .flatten()
+        {
+            let display = entry.display();
+            println!("cargo:rerun-if-changed={display}");
+        }
     }
     for entry in glob::glob("../../py/pyaici/server*.py")
         .expect("exists?")
@@ -0,0 +1,321 @@
+"""A subset of the standard `dataclasses` module.
+
+Unlike Lib/, which is copied from RustPython (see Lib/copy.sh), this is pyctrl's own code:
+the standard implementation depends on `inspect` and generates code with exec(),
+which is too heavy for pyctrl.
+Supported are @dataclass (with init, repr, eq, order and frozen), field()
+(with default, default_factory, init, repr, compare and metadata), fields(),
+asdict(), astuple(), replace(), and is_dataclass().
+ClassVar, InitVar, KW_ONLY and slots are not supported.
+"""
+
+__all__ = [
+    "dataclass",
+    "field",
+    "Field",
+    "FrozenInstanceError",
+    "MISSING",
+    "fields",
+    "asdict",
+    "astuple",
+    "replace",
+    "is_dataclass",
+]
+
+
+class FrozenInstanceError(AttributeError):
+    pass
+
+
+class _MissingType:
+    def __repr__(self):
+        return "MISSING"
+
+
+MISSING = _MissingType()
+
+_FIELDS = "__dataclass_fields__"
+_PARAMS = "__dataclass_params__"
+
+
+class Field:
+    __slots__ = (
+        "name",
+        "type",
+        "default",
+        "default_factory",
+        "init",
+        "repr",
+        "compare",
+        "metadata",
+    )
+
+    def __init__(self, default, default_factory, init, repr, compare, metadata):
+        self.name = None
+        self.type = None
+        self.default = default
+        self.default_factory = default_factory
+        self.init = init
+        self.repr = repr
+        self.compare = compare
+        self.metadata = {} if metadata is None else metadata
+
+    def __repr__(self):
+        return (
+            f"Field(name={self.name!r},type={self.type!r},"
+            f"default={self.default!r},default_factory={self.default_factory!r},"
+            f"init={self.init!r},repr={self.repr!r},compare={self.compare!r},"
+            f"metadata={self.metadata!r})"
+        )
+
+
+def field(
+    *,
+    default=MISSING,
+    default_factory=MISSING,
+    init=True,
+    repr=True,
+    compare=True,
+    metadata=None,
+):
+    """Return an object to identify dataclass fields."""
+    if default is not MISSING and default_factory is not MISSING:
+        raise ValueError("cannot specify both default and default_factory")
+    return Field(default, default_factory, init, repr, compare, metadata)
+
+
+def _set_new_attribute(cls, name, value):
+    # never overwrite methods defined in the class itself
+    if name in cls.__dict__:
+        return
+    setattr(cls, name, value)
+
+
+def _has_default(f):
+    return f.default is not MISSING or f.default_factory is not MISSING
+
+
+def _make_init(all_fields):
+    init_fields = [f for f in all_fields if f.init]
+    init_names = set(f.name for f in init_fields)
+
+    def __init__(self, *args, **kwargs):
+        if len(args) > len(init_fields):
+            raise TypeError(
+                f"__init__() takes {len(init_fields) + 1} positional arguments "
+                f"but {len(args) + 1} were given"
+            )
+        values = dict(zip([f.name for f in init_fields], args))
+        for k, v in kwargs.items():
+            if k not in init_names:
+                raise TypeError(f"__init__() got an unexpected keyword argument {k!r}")
+            if k in values:
+                raise TypeError(f"__init__() got multiple values for argument {k!r}")
+            values[k] = v
+        for f in all_fields:
+            if f.name in values:
+                v = values[f.name]
+            elif f.default is not MISSING:
+                v = f.default
+            elif f.default_factory is not MISSING:
+                v = f.default_factory()
+            elif f.init:
+                raise TypeError(f"__init__() missing required argument: {f.name!r}")
+            else:
+                continue
+            # works for frozen classes too
+            object.__setattr__(self, f.name, v)
+        post_init = getattr(self, "__post_init__", None)
+        if post_init is not None:
+            post_init()
+
+    return __init__
+
+
+def _make_repr(all_fields):
+    repr_fields = [f for f in all_fields if f.repr]
+
+    def __repr__(self):
+        args = ", ".join(f"{f.name}={getattr(self, f.name)!r}" for f in repr_fields)
+        return f"{type(self).__qualname__}({args})"
+
+    return __repr__
+
+
+def _make_cmp(all_fields, op):
+    cmp_fields = [f for f in all_fields if f.compare]
+
+    def key(obj):
+        return tuple(getattr(obj, f.name) for f in cmp_fields)
+
+    def cmp(self, other):
+        if other.__class__ is not self.__class__:
+            return NotImplemented
+        return op(key(self), key(other))
+
+    return cmp, key
+
+
+def _frozen_setattr(self, name, value):
+    raise FrozenInstanceError(f"cannot assign to field {name!r}")
+
+
+def _frozen_delattr(self, name):
+    raise FrozenInstanceError(f"cannot delete field {name!r}")
+
+
+def _process_class(cls, init, repr, eq, order, frozen):
+    all_fields = {}
+    # fields of base dataclasses come first
+    for b in cls.__mro__[-1:0:-1]:
+        for f in getattr(b, _FIELDS, {}).values():
+            all_fields[f.name] = f
+
+    for name, tp in cls.__dict__.get("__annotations__", {}).items():
+        default = cls.__dict__.get(name, MISSING)
+        if isinstance(default, Field):
+            f = default
+            # the class attribute holds the plain default value, if any
+            if f.default is MISSING:
+                delattr(cls, name)
+            else:
+                setattr(cls, name, f.default)
+        else:
+            if isinstance(default, (list, dict, set)):
+                raise ValueError(
+                    f"mutable default {type(default)} for field {name} "
+                    "is not allowed: use default_factory"
+                )
+            f = field(default=default)
+        f.name = name
+        f.type = tp
+        all_fields[name] = f
+
+    setattr(cls, _FIELDS, all_fields)
+    setattr(
+        cls,
+        _PARAMS,
+        dict(init=init, repr=repr, eq=eq, order=order, frozen=frozen),
+    )
+    flds = list(all_fields.values())
+
+    if init:
+        seen_default = False
+        for f in flds:
+            if not f.init:
+                continue
+            if _has_default(f):
+                seen_default = True
+            elif seen_default:
+                raise TypeError(f"non-default argument {f.name!r} follows default argument")
+        _set_new_attribute(cls, "__init__", _make_init(flds))
+
+    if repr:
+        _set_new_attribute(cls, "__repr__", _make_repr(flds))
+
+    if eq:
+        eq_fn, key = _make_cmp(flds, lambda a, b: a == b)
+        _set_new_attribute(cls, "__eq__", eq_fn)
+        if "__hash__" not in cls.__dict__:
+            if frozen:
+                cls.__hash__ = lambda self: hash(key(self))
+            else:
+                cls.__hash__ = None
+
+    if order:
+        if not eq:
+            raise ValueError("eq must be true if order is true")
+        _set_new_attribute(cls, "__lt__", _make_cmp(flds, lambda a, b: a < b)[0])
+        _set_new_attribute(cls, "__le__", _make_cmp(flds, lambda a, b: a <= b)[0])
+        _set_new_attribute(cls, "__gt__", _make_cmp(flds, lambda a, b: a > b)[0])
+        _set_new_attribute(cls, "__ge__", _make_cmp(flds, lambda a, b: a >= b)[0])
+
+    if frozen:
+        cls.__setattr__ = _frozen_setattr
+        cls.__delattr__ = _frozen_delattr
+
+    _set_new_attribute(cls, "__match_args__", tuple(f.name for f in flds if f.init))
+
+    return cls
+
+
+def dataclass(cls=None, /, *, init=True, repr=True, eq=True, order=False, frozen=False):
+    """Add dunder methods based on the fields defined in the class.
+
+    Examines PEP 526 __annotations__ to determine fields.
+    """
+
+    def wrap(cls):
+        return _process_class(cls, init, repr, eq, order, frozen)
+
+    if cls is None:
+        return wrap
+    return wrap(cls)
+
+
+def fields(class_or_instance):
+    """Return a tuple describing the fields of this dataclass."""
+    try:
+        flds = getattr(class_or_instance, _FIELDS)
+    except AttributeError:
+        raise TypeError("must be called with a dataclass type or instance") from None
+    return tuple(flds.values())
+
+
+def is_dataclass(obj):
+    """Returns True if obj is a dataclass or an instance of a dataclass."""
+    cls = obj if isinstance(obj, type) else type(obj)
+    return hasattr(cls, _FIELDS)
+
+
+def _is_dataclass_instance(obj):
+    return hasattr(type(obj), _FIELDS)
+
+
+def asdict(obj, *, dict_factory=dict):
+    """Return the fields of a dataclass instance as a new dictionary,
+    recursing into dataclasses, lists, tuples and dicts.
+    """
+    if not _is_dataclass_instance(obj):
+        raise TypeError("asdict() should be called on dataclass instances")
+    return _convert(obj, dict_factory, lambda d: dict_factory(d))
+
+
+def astuple(obj, *, tuple_factory=tuple):
+    """Return the fields of a dataclass instance as a new tuple,
+    recursing into dataclasses, lists, tuples and dicts.
+    """
+    if not _is_dataclass_instance(obj):
+        raise TypeError("astuple() should be called on dataclass instances")
+    return _convert(obj, tuple_factory, lambda d: tuple_factory([v for _, v in d]))
+
+
+def _convert(obj, factory, make):
+    if _is_dataclass_instance(obj):
+        return make(
+            [(f.name, _convert(getattr(obj, f.name), factory, make)) for f in fields(obj)]
+        )
+    elif isinstance(obj, (list, tuple)):
+        return type(obj)(_convert(v, factory, make) for v in obj)
+    elif isinstance(obj, dict):
+        return type(obj)(
+            (_convert(k, factory, make), _convert(v, factory, make)) for k, v in obj.items()
+        )
+    else:
+        return obj
+
+
+def replace(obj, /, **changes):
+    """Return a new object replacing specified fields with new values."""
+    if not _is_dataclass_instance(obj):
+        raise TypeError("replace() should be called on dataclass instances")
+    for f in fields(obj):
+        if not f.init:
+            if f.name in changes:
+                raise ValueError(
+                    f"field {f.name} is declared with init=False, it cannot be specified with replace()"
+                )
+            continue
+        if f.name not in changes:
+            changes[f.name] = getattr(obj, f.name)
+    return obj.__class__(**changes)
@@ -0,0 +1,54 @@
+"""JSON encoder and decoder.
+
+This is a subset of the standard `json` module, implemented natively
+in pyctrl (see src/json.rs); like the rest of pylib/, it's not copied from RustPython.
+Non-ASCII characters are always output as is (as with ensure_ascii=False),
+and only integer `indent` is supported.
+"""
+
+import pyaici.server_native as _aici
+
+__all__ = ["dump", "dumps", "load", "loads", "JSONDecodeError"]
+
+
+class JSONDecodeError(ValueError):
+    pass
+
+
+def dumps(obj, *, indent=None, default=None, sort_keys=False):
+    """Serialize ``obj`` to a JSON formatted ``str``.
+
+    If ``indent`` is a non-negative integer, then JSON array elements and
+    object members will be pretty-printed with that indent level.
+    ``default(obj)`` is called for objects that can't otherwise be serialized;
+    it should return a serializable version of obj or raise TypeError.
+    """
+    if sort_keys:
+        obj = _sorted_keys(obj)
+    return _aici.json_dumps(obj, indent, default)
+
+
+def loads(s):
+    """Deserialize ``s`` (a ``str``, ``bytes`` or ``bytearray`` instance
+    containing a JSON document) to a Python object.
+    """
+    try:
+        return _aici.json_loads(s)
+    except ValueError as e:
+        raise JSONDecodeError(str(e)) from None
+
+
+def dump(obj, fp, **kw):
+    fp.write(dumps(obj, **kw))
+
+
+def load(fp):
+    return loads(fp.read())
+
+
+def _sorted_keys(obj):
+    if isinstance(obj, dict):
+        return {k: _sorted_keys(obj[k]) for k in sorted(obj)}
+    if isinstance(obj, (list, tuple)):
+        return [_sorted_keys(e) for e in obj]
+    return obj
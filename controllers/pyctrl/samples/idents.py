@@ -12,6 +12,10 @@ async def test_id():
     dyn_lex = aici.DynamicLexer("")
     for id in ["def", "fibo", "n", "return", "if"]:
         dyn_lex.add(id)
+    # allow local variables, like "a = ..." after "for a in ..."
+    for id in ["for", "in", "range"]:
+        dyn_lex.add(id)
+    dyn_lex.add_declaration_rule("for ")
     next_token = aici.ConstrainedToken(lambda: dyn_lex.constraint())
     res = []
     text = ""
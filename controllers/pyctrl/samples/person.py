@@ -0,0 +1,28 @@
+import pyaici.server as aici
+import json
+from dataclasses import dataclass, asdict
+from typing import List, Optional, Literal
+
+
+@dataclass
+class Pet:
+    name: str
+    species: Literal["cat", "dog", "fish"]
+
+
+@dataclass
+class Person:
+    name: str
+    age: int
+    pets: List[Pet]
+    nickname: Optional[str] = None
+
+
+async def main():
+    await aici.FixedTokens("A JSON description of Sherlock Holmes:\n")
+    person = await aici.gen_json(Person, max_tokens=100)
+    print(person)
+    print(json.dumps(asdict(person), indent=2))
+
+
+aici.start(main())
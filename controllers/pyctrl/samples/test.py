@@ -1,5 +1,9 @@
 import pyaici.server as aici
 import re
+import json
+import dataclasses
+from dataclasses import dataclass, field
+from typing import List, Literal, Optional
 
 # asserts for microsoft/Orca-2-13b
 
@@ -211,4 +215,105 @@ async def test_joke():
     await aici.gen_text(max_tokens=15)
 
 
+async def test_json():
+    v = {"a": [1, 2.5, True, None], "b": {"c": "d\u00e9\n\"x\""}, "e": -3}
+    s = json.dumps(v)
+    assert s == '{"a": [1, 2.5, true, null], "b": {"c": "d\u00e9\\n\\"x\\""}, "e": -3}', s
+    assert json.loads(s) == v
+    assert json.loads(s.encode()) == v
+    assert json.dumps({"b": 1, "a": [2]}, sort_keys=True) == '{"a": [2], "b": 1}'
+    assert json.dumps([1, {"a": 2}], indent=2) == '[\n  1,\n  {\n    "a": 2\n  }\n]'
+    assert json.dumps((1, 2)) == "[1, 2]"
+    assert json.dumps({1: "x"}) == '{"1": "x"}'
+    assert json.dumps({1}, default=list) == "[1]"
+    for bad in ['{"a": 1', "[1,]", "nope", ""]:
+        try:
+            json.loads(bad)
+            assert False, bad
+        except json.JSONDecodeError:
+            pass
+    try:
+        json.dumps(object())
+        assert False
+    except TypeError:
+        pass
+    l = []
+    l.append(l)
+    try:
+        json.dumps(l)
+        assert False
+    except ValueError:
+        pass
+
+
+@dataclass
+class Item:
+    name: str
+    qty: int = 1
+    tags: List[str] = field(default_factory=list)
+    note: str = field(default="", repr=False, compare=False)
+
+
+@dataclass(frozen=True, order=True)
+class Pos:
+    x: int
+    y: int
+
+
+async def test_dataclasses():
+    a = Item("apple")
+    assert a.qty == 1 and a.tags == [] and a.note == ""
+    assert repr(a) == "Item(name='apple', qty=1, tags=[])", repr(a)
+    b = Item("apple", 1, [], note="other")
+    assert a == b
+    assert a != Item("apple", 2)
+    assert Item("x").tags is not Item("x").tags
+    assert [f.name for f in dataclasses.fields(Item)] == ["name", "qty", "tags", "note"]
+    assert dataclasses.asdict(Item("p", 2, ["t"])) == {
+        "name": "p",
+        "qty": 2,
+        "tags": ["t"],
+        "note": "",
+    }
+    assert dataclasses.astuple(Pos(1, 2)) == (1, 2)
+    assert dataclasses.replace(a, qty=5) == Item("apple", 5)
+    assert dataclasses.is_dataclass(Item) and dataclasses.is_dataclass(a)
+    assert not dataclasses.is_dataclass(1)
+    assert Pos(1, 2) < Pos(1, 3) < Pos(2, 0)
+    assert len({Pos(1, 2), Pos(1, 2)}) == 1
+    try:
+        Pos(1, 2).x = 3
+        assert False
+    except dataclasses.FrozenInstanceError:
+        pass
+    try:
+        Item()
+        assert False
+    except TypeError:
+        pass
+
+
+@dataclass
+class Point:
+    x: int
+    y: int
+    color: Optional[Literal["red", "green", "blue"]] = None
+
+
+async def test_gen_json():
+    await aici.FixedTokens("A point in JSON: ")
+    p = await aici.gen_json(Point, store_var="point", max_tokens=30)
+    assert isinstance(p, Point), p
+    assert isinstance(p.x, int) and isinstance(p.y, int)
+    assert p.color in (None, "red", "green", "blue"), p
+    assert json.loads(aici.get_var("point").decode()) == dataclasses.asdict(p)
+    # the JSON doesn't fit in 3 tokens
+    await aici.FixedTokens("\nAnother one: ")
+    try:
+        await aici.gen_json(Point, max_tokens=3)
+        assert False
+    except ValueError as e:
+        assert "max_tokens=3" in str(e), e
+
+
 aici.test(test_noop())
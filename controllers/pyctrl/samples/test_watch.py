@@ -0,0 +1,51 @@
+import pyaici.server as aici
+
+# Suspending sequences on variables (aici.wait_vars() and aici.watch_var()).
+# Run with scripts/test-pyctrl.sh -k watch
+
+aici.log_level = 10
+
+
+async def test_watch_wake():
+    if not aici.fork_supported():
+        print("skipping fork test")
+        return
+    await aici.FixedTokens("2 + 2 =")
+    id = await aici.fork(2)
+    if id == 0:
+        # suspended (not scheduled) until branch 1 sets the variable
+        (answer,) = await aici.wait_vars("answer")
+        await aici.FixedTokens(f" The other branch says{answer.decode()}.")
+        aici.check_var("answer", " 4")
+    else:
+        await aici.gen_tokens(regex=r" \d+", store_var="answer", max_tokens=3)
+
+
+async def test_watch_changed():
+    aici.set_var("x", "a")
+    version = aici.get_var_version("x")
+    aici.set_var("x", "b")
+    # the variable has changed since; nothing is suspended
+    assert not aici.watch_var("x", version)
+    assert not aici.watch_var("x", None)
+    (x,) = await aici.wait_vars("x")
+    assert x == b"b"
+    aici.delete_var("x")
+    assert not aici.watch_var("x", version + 1)
+
+
+async def test_watch_deadlock():
+    if not aici.fork_supported():
+        print("skipping fork test")
+        return
+    await aici.FixedTokens("Waiting")
+    id = await aici.fork(2)
+    if id == 1:
+        # nobody is going to set it; once branch 0 is done, all sequences of
+        # the request are suspended, and rLLM finishes this one with
+        # finish_reason "deadlock" (otherwise the test would hang)
+        await aici.wait_vars("never_set")
+        raise RuntimeError("woken up without the variable set")
+
+
+aici.test(test_watch_wake())
@@ -0,0 +1,187 @@
+// Native implementation of json.loads() and json.dumps() for the bundled `json` module.
+
+use rustpython_vm::{
+    builtins::{PyDict, PyFloat, PyInt, PyList, PyStr, PyTuple},
+    AsObject, PyObjectRef, PyResult, VirtualMachine,
+};
+use serde_json::{ser::Formatter, Map, Number, Value};
+use std::io;
+
+// protects against cycles (which would overflow the Rust stack)
+const MAX_DEPTH: usize = 500;
+
+pub fn loads(vm: &VirtualMachine, data: &[u8]) -> PyResult {
+    let v: Value = serde_json::from_slice(data).map_err(|e| vm.new_value_error(e.to_string()))?;
+    Ok(from_json(vm, &v))
+}
+
+pub fn dumps(
+    vm: &VirtualMachine,
+    obj: PyObjectRef,
+    indent: Option<usize>,
+    default: Option<PyObjectRef>,
+) -> PyResult<String> {
+    let v = to_json(vm, obj, default.as_ref(), 0)?;
+    let mut writer = Vec::with_capacity(128);
+    let r = match indent {
+        Some(n) => {
+            let indent = vec![b' '; n];
+            let fmt = serde_json::ser::PrettyFormatter::with_indent(&indent);
+            let mut ser = serde_json::Serializer::with_formatter(&mut writer, fmt);
+            serde::Serialize::serialize(&v, &mut ser)
+        }
+        None => {
+            let mut ser = serde_json::Serializer::with_formatter(&mut writer, PyFormatter {});
+            serde::Serialize::serialize(&v, &mut ser)
+        }
+    };
+    r.map_err(|e| vm.new_value_error(e.to_string()))?;
+    Ok(String::from_utf8(writer).unwrap())
+}
+
+/// Like dumps(), but returns the JSON value.
+pub fn to_value(vm: &VirtualMachine, obj: PyObjectRef) -> PyResult<Value> {
+    to_json(vm, obj, None, 0)
+}
+
+fn from_json(vm: &VirtualMachine, v: &Value) -> PyObjectRef {
+    match v {
+        Value::Null => vm.ctx.none(),
+        Value::Bool(b) => vm.ctx.new_bool(*b).into(),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                vm.ctx.new_int(i).into()
+            } else if let Some(u) = n.as_u64() {
+                vm.ctx.new_int(u).into()
+            } else {
+                vm.ctx.new_float(n.as_f64().unwrap()).into()
+            }
+        }
+        Value::String(s) => vm.ctx.new_str(s.as_str()).into(),
+        Value::Array(arr) => {
+            let elts = arr.iter().map(|e| from_json(vm, e)).collect();
+            vm.ctx.new_list(elts).into()
+        }
+        Value::Object(map) => {
+            let dict = vm.ctx.new_dict();
+            for (k, e) in map {
+                // setting str keys never fails
+                dict.set_item(k.as_str(), from_json(vm, e), vm).unwrap();
+            }
+            dict.into()
+        }
+    }
+}
+
+fn to_json(
+    vm: &VirtualMachine,
+    obj: PyObjectRef,
+    default: Option<&PyObjectRef>,
+    depth: usize,
+) -> PyResult<Value> {
+    if depth > MAX_DEPTH {
+        return Err(vm.new_value_error("Circular reference detected".to_string()));
+    }
+    let rec = |obj: PyObjectRef| to_json(vm, obj, default, depth + 1);
+
+    if vm.is_none(&obj) {
+        Ok(Value::Null)
+    } else if obj.is(&vm.ctx.true_value) {
+        Ok(Value::Bool(true))
+    } else if obj.is(&vm.ctx.false_value) {
+        Ok(Value::Bool(false))
+    } else if let Some(s) = obj.payload_if_subclass::<PyStr>(vm) {
+        Ok(Value::String(s.as_str().to_string()))
+    } else if let Some(i) = obj.payload_if_subclass::<PyInt>(vm) {
+        let i = i.as_bigint();
+        let n = if let Ok(v) = i64::try_from(i) {
+            Number::from(v)
+        } else if let Ok(v) = u64::try_from(i) {
+            Number::from(v)
+        } else {
+            return Err(vm.new_overflow_error(format!("int too large for JSON: {i}")));
+        };
+        Ok(Value::Number(n))
+    } else if let Some(f) = obj.payload_if_subclass::<PyFloat>(vm) {
+        Number::from_f64(f.to_f64())
+            .map(Value::Number)
+            .ok_or_else(|| {
+                vm.new_value_error(format!(
+                    "Out of range float values are not JSON compliant: {}",
+                    f.to_f64()
+                ))
+            })
+    } else if let Some(lst) = obj.payload_if_subclass::<PyList>(vm) {
+        let elts = lst.borrow_vec().to_vec();
+        Ok(Value::Array(
+            elts.into_iter().map(|e| rec(e)).collect::<PyResult<_>>()?,
+        ))
+    } else if let Some(tup) = obj.payload_if_subclass::<PyTuple>(vm) {
+        Ok(Value::Array(
+            tup.as_slice()
+                .iter()
+                .map(|e| rec(e.clone()))
+                .collect::<PyResult<_>>()?,
+        ))
+    } else if let Some(dict) = obj.payload_if_subclass::<PyDict>(vm) {
+        let mut map = Map::new();
+        for (k, v) in dict {
+            let key = match k.payload_if_subclass::<PyStr>(vm) {
+                Some(s) => s.as_str().to_string(),
+                None => match rec(k.clone())? {
+                    Value::String(s) => s,
+                    Value::Object(_) | Value::Array(_) => {
+                        return Err(vm.new_type_error(format!(
+                            "keys must be str, int, float, bool or None, not {}",
+                            k.class().name()
+                        )))
+                    }
+                    other => other.to_string(),
+                },
+            };
+            map.insert(key, rec(v)?);
+        }
+        Ok(Value::Object(map))
+    } else if let Some(default) = default {
+        let replacement = default.call((obj,), vm)?;
+        rec(replacement)
+    } else {
+        Err(vm.new_type_error(format!(
+            "Object of type {} is not JSON serializable",
+            obj.class().name()
+        )))
+    }
+}
+
+// Same separators as json.dumps() in Python: ", " and ": "
+struct PyFormatter {}
+
+impl Formatter for PyFormatter {
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b": ")
+    }
+}
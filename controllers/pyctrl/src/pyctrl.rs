@@ -1,8 +1,6 @@
 use aici_abi::{
-    aici_stop, host_trie,
-    toktrie::{Recognizer, SpecialToken, TokTrie},
-    AiciCtrl, Branch, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, SimpleVob,
-    Splice, TokenId, VariableStorage,
+    aici_stop, host_trie, toktrie::TokTrie, AiciCtrl, Branch, InitPromptArg, InitPromptResult,
+    MidProcessArg, MidProcessResult, ScriptError, Splice, VariableStorage,
 };
 use anyhow::Result;
 use lazy_static::lazy_static;
@@ -11,16 +9,15 @@ use rustpython_vm::{
     builtins::*, compiler::parser::ast::bigint::BigInt, AsObject, PyObjectRef, PyRef, PyResult,
     VirtualMachine,
 };
-use std::{
-    ops::Deref,
-    sync::{Arc, Mutex},
-    vec,
-};
+use std::{ops::Deref, sync::Mutex, vec};
+
+mod json;
 
 struct ModuleState {
     cb_obj: Option<PyObjectRef>,
     trie: TokTrie,
     vars: VariableStorage,
+    token_healing: bool,
 }
 
 unsafe impl Send for ModuleState {}
@@ -30,6 +27,7 @@ lazy_static! {
         cb_obj: None,
         trie: host_trie(),
         vars: VariableStorage::new(),
+        token_healing: false,
         // tokens: vec![],
         // bytes: vec![],
     });
@@ -45,28 +43,27 @@ fn get_cb_obj() -> PyObjectRef {
 
 #[rustpython_derive::pymodule]
 mod _aici {
-    use crate::{ConstraintWrapper, PyConstraint, VmExt, GLOBAL_STATE};
+    use crate::{VmExt, GLOBAL_STATE};
     use aici_abi::{
-        cfg::CfgParser,
+        constraint::{self, CompiledConstraint, ConstraintSpec, SharedConstraint},
         dlex::{self, DynamicLexerRec},
-        recognizer::{AnythingGoes, StackRecognizer},
-        rx::RecRx,
-        substring::SubStrMatcher,
+        substring::SubStrOptions,
         toktrie::SpecialToken,
-        SimpleVob, TokenId,
+        SeqId, SimpleVob, StorageScope, TokenId, VariableStorage,
     };
     use once_cell::sync::Lazy;
     use rustpython_derive::pyclass;
     use rustpython_vm::{
         atomic_func,
-        builtins::{PyStrRef, PyTypeRef},
-        function::{ArgStrOrBytesLike, FuncArgs},
+        builtins::{PyList, PyStr, PyStrRef, PyTypeRef},
+        function::{ArgStrOrBytesLike, FuncArgs, OptionalArg, OptionalOption, PosArgs},
         protocol::PySequenceMethods,
         types::{AsSequence, Constructor, Representable},
-        Py, PyObjectRef, PyPayload, PyRef, PyResult, VirtualMachine,
+        AsObject, Py, PyObjectRef, PyPayload, PyRef, PyResult, VirtualMachine,
     };
     use std::{
         fmt::Debug,
+        rc::Rc,
         sync::{Arc, Mutex},
     };
 
@@ -76,11 +73,30 @@ mod _aici {
         Ok(())
     }
 
+    #[pyfunction]
+    fn set_token_healing(enabled: bool) {
+        GLOBAL_STATE.lock().unwrap().token_healing = enabled;
+    }
+
     #[pyfunction]
     fn self_seq_id() -> u32 {
         aici_abi::self_seq_id().0
     }
 
+    #[pyfunction]
+    fn stop_seqs(ids: PyObjectRef, vm: &VirtualMachine) {
+        let ids = vm.to_u32_list(ids);
+        aici_abi::stop_seqs(&ids.into_iter().map(SeqId).collect::<Vec<_>>());
+    }
+
+    #[pyfunction]
+    fn spawn_generation(gen: PyStrRef, vm: &VirtualMachine) -> PyResult<()> {
+        let gen: aici_abi::SubGeneration = serde_json::from_str(gen.as_str())
+            .map_err(|e| vm.new_value_error(format!("spawn_generation: {e}")))?;
+        aici_abi::spawn_generation(&gen);
+        Ok(())
+    }
+
     #[pyfunction]
     fn is_server_side() -> bool {
         true
@@ -116,25 +132,110 @@ mod _aici {
         trie.token_dbg(token)
     }
 
+    fn with_vars<T>(
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+        f: impl FnOnce(&VariableStorage) -> T,
+    ) -> PyResult<T> {
+        let scope = match scope {
+            OptionalArg::Present(s) => s
+                .as_str()
+                .parse::<StorageScope>()
+                .map_err(|e| vm.new_value_error(e))?,
+            OptionalArg::Missing => StorageScope::Request,
+        };
+        if scope == StorageScope::Request {
+            Ok(f(&GLOBAL_STATE.lock().unwrap().vars))
+        } else {
+            Ok(f(&VariableStorage::with_scope(scope)))
+        }
+    }
+
     #[pyfunction]
-    fn get_var(name: PyStrRef, _vm: &VirtualMachine) -> Option<Vec<u8>> {
-        let name = name.as_str();
-        let v = GLOBAL_STATE.lock().unwrap().vars.get(name);
-        v
+    fn get_var(
+        name: PyStrRef,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<Vec<u8>>> {
+        with_vars(scope, vm, |vars| vars.get(name.as_str()))
     }
 
     #[pyfunction]
-    fn set_var(name: PyStrRef, value: ArgStrOrBytesLike, _vm: &VirtualMachine) {
-        let name = name.as_str();
-        let vars = &GLOBAL_STATE.lock().unwrap().vars;
-        vars.set(name, (&value.borrow_bytes()).to_vec());
+    fn set_var(
+        name: PyStrRef,
+        value: ArgStrOrBytesLike,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<()> {
+        with_vars(scope, vm, |vars| {
+            vars.set(name.as_str(), (&value.borrow_bytes()).to_vec())
+        })
     }
 
     #[pyfunction]
-    fn append_var(name: PyStrRef, value: ArgStrOrBytesLike, _vm: &VirtualMachine) {
-        let name = name.as_str();
-        let vars = &GLOBAL_STATE.lock().unwrap().vars;
-        vars.append(name, (&value.borrow_bytes()).to_vec());
+    fn append_var(
+        name: PyStrRef,
+        value: ArgStrOrBytesLike,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<()> {
+        with_vars(scope, vm, |vars| {
+            vars.append(name.as_str(), (&value.borrow_bytes()).to_vec())
+        })
+    }
+
+    #[pyfunction]
+    fn delete_var(
+        name: PyStrRef,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<bool> {
+        with_vars(scope, vm, |vars| vars.delete(name.as_str()))
+    }
+
+    #[pyfunction]
+    fn list_vars(
+        prefix: OptionalArg<PyStrRef>,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<String>> {
+        let prefix = prefix.map_or(String::new(), |p| p.as_str().to_string());
+        with_vars(scope, vm, |vars| vars.list(&prefix))
+    }
+
+    #[pyfunction]
+    fn increment_var(
+        name: PyStrRef,
+        delta: OptionalArg<i64>,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<i64> {
+        with_vars(scope, vm, |vars| {
+            vars.increment(name.as_str(), delta.unwrap_or(1))
+        })
+    }
+
+    #[pyfunction]
+    fn get_var_version(
+        name: PyStrRef,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<u64>> {
+        with_vars(scope, vm, |vars| {
+            vars.get_with_version(name.as_str()).map(|v| v.0)
+        })
+    }
+
+    #[pyfunction]
+    fn watch_var(
+        name: PyStrRef,
+        version: OptionalOption<u64>,
+        scope: OptionalArg<PyStrRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<bool> {
+        with_vars(scope, vm, |vars| {
+            vars.watch(name.as_str(), version.flatten())
+        })
     }
 
     #[pyfunction]
@@ -150,10 +251,38 @@ mod _aici {
         Ok(v)
     }
 
+    #[pyfunction]
+    fn json_loads(s: ArgStrOrBytesLike, vm: &VirtualMachine) -> PyResult {
+        crate::json::loads(vm, &s.borrow_bytes())
+    }
+
+    #[pyfunction]
+    fn json_dumps(
+        obj: PyObjectRef,
+        indent: OptionalOption<usize>,
+        default: OptionalOption<PyObjectRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<String> {
+        crate::json::dumps(vm, obj, indent.flatten(), default.flatten())
+    }
+
+    #[pyfunction]
+    fn emit(kind: PyStrRef, data: PyObjectRef, vm: &VirtualMachine) -> PyResult<()> {
+        // raises ValueError for NaN and Infinity, which are not valid JSON
+        let data = crate::json::to_value(vm, data)?;
+        aici_abi::emit(kind.as_str(), &data).map_err(|e| vm.new_value_error(format!("emit: {e}")))
+    }
+
     #[pyattr]
     #[pyclass(name)]
     #[derive(PyPayload)]
-    pub struct Constraint(pub Mutex<Box<dyn PyConstraint>>);
+    pub struct Constraint {
+        inner: Mutex<Box<dyn constraint::Constraint>>,
+        // None for constraints that can't be re-created, and thus combined
+        spec: Option<ConstraintSpec>,
+        // the compiled spec, for locate()
+        compiled: Option<Rc<CompiledConstraint>>,
+    }
 
     impl Debug for Constraint {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -163,39 +292,81 @@ mod _aici {
 
     #[pyclass(flags(BASETYPE), with(Constructor))]
     impl Constraint {
-        fn new(obj: impl PyConstraint + 'static) -> Self {
-            Constraint(Mutex::new(Box::new(obj)))
+        fn new(obj: impl constraint::Constraint + 'static, spec: Option<ConstraintSpec>) -> Self {
+            Constraint {
+                inner: Mutex::new(Box::new(obj)),
+                spec,
+                compiled: None,
+            }
+        }
+
+        fn from_spec(spec: ConstraintSpec, vm: &VirtualMachine) -> PyResult<Self> {
+            let compiled = spec
+                .compile()
+                .map_err(|e| vm.new_runtime_error(format!("{}", e)))?;
+            let mut c = Constraint::new(compiled.fresh(), Some(spec));
+            c.compiled = Some(compiled);
+            Ok(c)
+        }
+
+        fn get_spec(&self, vm: &VirtualMachine) -> PyResult<ConstraintSpec> {
+            match &self.spec {
+                Some(spec) => Ok(spec.clone()),
+                None => Err(vm.new_type_error(
+                    "this constraint can't be combined with other constraints".to_string(),
+                )),
+            }
         }
 
         #[pymethod]
         fn eos_allowed(&self) -> bool {
-            let mut s = self.0.lock().unwrap();
+            let mut s = self.inner.lock().unwrap();
             s.eos_allowed()
         }
 
         #[pymethod]
         fn eos_forced(&self) -> bool {
-            let mut s = self.0.lock().unwrap();
+            let mut s = self.inner.lock().unwrap();
             s.eos_forced()
         }
 
         #[pymethod]
         fn token_allowed(&self, t: TokenId) -> bool {
-            let mut s = self.0.lock().unwrap();
-            s.token_allowed(t)
+            let mut s = self.inner.lock().unwrap();
+            let trie = &GLOBAL_STATE.lock().unwrap().trie;
+            s.token_allowed(trie, t)
         }
 
         #[pymethod]
-        fn append_token(&self, t: TokenId) {
-            let mut s = self.0.lock().unwrap();
-            s.append_token(t)
+        fn append_token(&self, t: TokenId, vm: &VirtualMachine) -> PyResult<()> {
+            let mut s = self.inner.lock().unwrap();
+            let trie = &GLOBAL_STATE.lock().unwrap().trie;
+            s.append_token(trie, t)
+                .map_err(|e| vm.new_runtime_error(format!("{}", e)))
         }
 
         #[pymethod]
         fn allow_tokens(&self, ts: PyRef<TokenSet>) {
-            let mut s = self.0.lock().unwrap();
+            let mut s = self.inner.lock().unwrap();
             let mut ts = ts.0.lock().unwrap();
-            s.allow_tokens(&mut *ts);
+            let trie = &GLOBAL_STATE.lock().unwrap().trie;
+            s.allow_tokens(trie, &mut *ts);
+        }
+
+        #[pymethod]
+        fn locate(
+            &self,
+            text: ArgStrOrBytesLike,
+            vm: &VirtualMachine,
+        ) -> PyResult<Option<(usize, usize, usize)>> {
+            let compiled = self
+                .compiled
+                .as_ref()
+                .ok_or_else(|| vm.new_type_error("not a substring constraint".to_string()))?;
+            let m = compiled
+                .substr_locate(&text.borrow_bytes())
+                .map_err(|e| vm.new_type_error(format!("{}", e)))?;
+            Ok(m.map(|m| (m.source_idx, m.start, m.end)))
         }
     }
 
@@ -215,12 +386,42 @@ mod _aici {
         #[pymethod]
         fn add(&self, word: PyStrRef) {
             let mut lexer = self.0.lock().unwrap();
-            lexer.recognizer_mut().add(word.as_str().as_bytes());
+            lexer.lexer_mut().add(word.as_str().as_bytes());
+        }
+
+        #[pymethod]
+        fn push_scope(&self) {
+            let mut lexer = self.0.lock().unwrap();
+            lexer.lexer_mut().push_scope();
+        }
+
+        #[pymethod]
+        fn pop_scope(&self, vm: &VirtualMachine) -> PyResult<()> {
+            let mut lexer = self.0.lock().unwrap();
+            if lexer.lexer_mut().pop_scope() {
+                Ok(())
+            } else {
+                Err(vm.new_value_error("can't pop the global scope".to_string()))
+            }
+        }
+
+        #[pymethod]
+        fn num_scopes(&self) -> usize {
+            let lexer = self.0.lock().unwrap();
+            lexer.lexer().num_scopes()
+        }
+
+        #[pymethod]
+        fn add_declaration_rule(&self, prefix: PyStrRef) {
+            let mut lexer = self.0.lock().unwrap();
+            lexer
+                .lexer_mut()
+                .add_declaration_rule(prefix.as_str().as_bytes());
         }
 
         #[pymethod]
         fn constraint(&self) -> PyResult<Constraint> {
-            Ok(Constraint::new(ConstraintWrapper(self.0.clone())))
+            Ok(Constraint::new(SharedConstraint(self.0.clone()), None))
         }
     }
 
@@ -231,7 +432,7 @@ mod _aici {
                 Some(id_chars) => id_chars.as_str().chars().collect(),
                 None => vec![],
             };
-            let lexer = dlex::DynamicLexer::new(&id_chars).to_stack_recognizer();
+            let lexer = dlex::DynamicLexer::new(&id_chars).to_recognizer();
             DynamicLexer(Arc::new(Mutex::new(lexer)))
                 .into_ref_with_type(vm, cls)
                 .map(Into::into)
@@ -240,31 +441,131 @@ mod _aici {
 
     #[pyfunction(name = "RegexConstraint")]
     fn regex_constraint(regex: PyStrRef, vm: &VirtualMachine) -> PyResult<Constraint> {
-        let rx = RecRx::from_rx(regex.as_str(), None)
-            .map_err(|e| vm.new_runtime_error(format!("{}", e)))?
-            .to_stack_recognizer();
-        Ok(Constraint::new(rx))
+        let rx = regex.as_str().to_string();
+        Constraint::from_spec(ConstraintSpec::Regex { rx }, vm)
     }
 
     #[pyfunction(name = "CfgConstraint")]
     fn cfg_constraint(cfg: PyStrRef, vm: &VirtualMachine) -> PyResult<Constraint> {
-        match CfgParser::from_yacc(cfg.as_str()) {
-            Ok(cfg) => Ok(Constraint::new(cfg)),
-            Err(e) => Err(vm.new_runtime_error(format!("{}", e))),
+        let yacc = cfg.as_str().to_string();
+        Constraint::from_spec(ConstraintSpec::Cfg { yacc }, vm)
+    }
+
+    fn str_or_str_list(obj: PyObjectRef, vm: &VirtualMachine) -> PyResult<Vec<String>> {
+        let type_err = || vm.new_type_error("expecting str or list of str".to_string());
+        if let Some(s) = obj.payload_if_exact::<PyStr>(vm) {
+            return Ok(vec![s.as_str().to_string()]);
         }
+        obj.payload_if_exact::<PyList>(vm)
+            .ok_or_else(type_err)?
+            .borrow_vec()
+            .iter()
+            .map(|x| {
+                x.payload_if_exact::<PyStr>(vm)
+                    .map(|s| s.as_str().to_string())
+                    .ok_or_else(type_err)
+            })
+            .collect()
     }
 
     #[pyfunction(name = "SubStrConstraint")]
-    fn substr_constraint(templ: PyStrRef, end_str: PyStrRef) -> PyResult<Constraint> {
-        let rx = SubStrMatcher::new(templ.as_str(), end_str.as_str()).to_stack_recognizer();
-        Ok(Constraint::new(rx))
+    fn substr_constraint(
+        templ: PyObjectRef,
+        end_str: PyStrRef,
+        word_boundaries: OptionalArg<bool>,
+        normalize: OptionalArg<bool>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let spec = ConstraintSpec::SubStr {
+            sources: str_or_str_list(templ, vm)?,
+            end_str: end_str.as_str().to_string(),
+            options: SubStrOptions {
+                word_boundaries: word_boundaries.unwrap_or(true),
+                normalize: normalize.unwrap_or(false),
+            },
+        };
+        Constraint::from_spec(spec, vm)
+    }
+
+    #[pyfunction(name = "UpToConstraint")]
+    fn up_to_constraint(stop: PyStrRef, vm: &VirtualMachine) -> PyResult<Constraint> {
+        let stop = stop.as_str().to_string();
+        Constraint::from_spec(ConstraintSpec::UpTo { stop }, vm)
+    }
+
+    #[pyfunction(name = "HealedConstraint")]
+    fn healed_constraint(
+        prefix: ArgStrOrBytesLike,
+        item: PyRef<Constraint>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let spec = ConstraintSpec::Healed {
+            prefix: prefix.borrow_bytes().to_vec(),
+            item: Box::new(item.get_spec(vm)?),
+        };
+        Constraint::from_spec(spec, vm)
+    }
+
+    fn item_specs(
+        items: PosArgs<PyRef<Constraint>>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<ConstraintSpec>> {
+        items.into_vec().iter().map(|c| c.get_spec(vm)).collect()
+    }
+
+    #[pyfunction(name = "SeqConstraint")]
+    fn seq_constraint(
+        items: PosArgs<PyRef<Constraint>>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let items = item_specs(items, vm)?;
+        Constraint::from_spec(ConstraintSpec::Seq { items }, vm)
+    }
+
+    #[pyfunction(name = "OrConstraint")]
+    fn or_constraint(
+        items: PosArgs<PyRef<Constraint>>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let items = item_specs(items, vm)?;
+        Constraint::from_spec(ConstraintSpec::Or { items }, vm)
+    }
+
+    #[pyfunction(name = "AndConstraint")]
+    fn and_constraint(
+        items: PosArgs<PyRef<Constraint>>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let items = item_specs(items, vm)?;
+        Constraint::from_spec(ConstraintSpec::And { items }, vm)
+    }
+
+    #[pyfunction(name = "RepeatConstraint")]
+    fn repeat_constraint(
+        item: PyRef<Constraint>,
+        min: OptionalArg<usize>,
+        max: OptionalOption<usize>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let spec = ConstraintSpec::Repeat {
+            item: Box::new(item.get_spec(vm)?),
+            min: min.unwrap_or(0),
+            max: max.flatten(),
+        };
+        Constraint::from_spec(spec, vm)
     }
 
     impl Constructor for Constraint {
         type Args = FuncArgs;
         fn py_new(cls: PyTypeRef, _arg: Self::Args, vm: &VirtualMachine) -> PyResult {
-            let anything = StackRecognizer::from(AnythingGoes {});
-            Constraint::new(anything)
+            // subclasses override the methods, so their behavior can't be re-created
+            let spec = if cls.is(Constraint::class(&vm.ctx)) {
+                Some(ConstraintSpec::Anything {})
+            } else {
+                None
+            };
+            let anything = ConstraintSpec::Anything {}.build().unwrap();
+            Constraint::new(anything, spec)
                 .into_ref_with_type(vm, cls)
                 .map(Into::into)
         }
@@ -385,8 +686,21 @@ pub struct Runner {
     interpreter: rustpython_vm::Interpreter,
 }
 
-impl Runner {
-    pub fn new(arg: Vec<u8>) -> Self {
+// Interpreter with the stdlib imported and the user script compiled (but not run).
+// This only depends on the module argument, so the runtime can snapshot it.
+struct Preinit {
+    interpreter: rustpython_vm::Interpreter,
+    code: PyResult<PyRef<PyCode>>,
+}
+
+unsafe impl Send for Preinit {}
+
+lazy_static! {
+    static ref PREINIT: Mutex<Option<Preinit>> = Mutex::new(None);
+}
+
+impl Preinit {
+    fn new(arg: Vec<u8>) -> Self {
         let source = String::from_utf8(arg).unwrap();
         let interpreter = rustpython_vm::Interpreter::with_init(Default::default(), |vm| {
             vm.add_native_module(
@@ -394,6 +708,8 @@ impl Runner {
                 Box::new(_aici::make_module),
             );
             vm.add_frozen(rustpython_vm::py_freeze!(dir = "Lib"));
+            // pyctrl's own replacements of standard modules
+            vm.add_frozen(rustpython_vm::py_freeze!(dir = "pylib"));
 
             let code = rustpython_vm::py_compile!(
                 file = "../../py/pyaici/server.py",
@@ -423,17 +739,48 @@ impl Runner {
             ];
             vm.add_frozen(frozen_vec.into_iter());
         });
-        interpreter.enter(|vm| {
-            let scope = vm.new_scope_with_builtins();
-
+        let code = interpreter.enter(|vm| {
+            // importing pyaici.server pulls in most of the bundled Lib/;
+            // it doesn't call the host, so it's safe to do here
+            let import = "import pyaici.server";
             let r = vm
                 .compile(
-                    &source,
+                    import,
                     rustpython_vm::compiler::Mode::Exec,
-                    "<arg>".to_owned(),
+                    "<preinit>".to_owned(),
                 )
-                .map_err(|err| vm.new_syntax_error(&err, Some(&source)))
-                .and_then(|code_obj| vm.run_code_obj(code_obj, scope));
+                .map_err(|err| vm.new_syntax_error(&err, Some(import)))
+                .and_then(|code_obj| vm.run_code_obj(code_obj, vm.new_scope_with_builtins()));
+            if let Err(e) = r {
+                vm.print_exception(e.clone());
+                panic!("Python Exception: {e:?}");
+            }
+
+            // errors are only reported when the script is run
+            vm.compile(
+                &source,
+                rustpython_vm::compiler::Mode::Exec,
+                "<arg>".to_owned(),
+            )
+            .map_err(|err| vm.new_syntax_error(&err, Some(&source)))
+        });
+        Preinit { interpreter, code }
+    }
+}
+
+fn runner_preinit() {
+    let preinit = Preinit::new(aici_abi::arg_bytes());
+    *PREINIT.lock().unwrap() = Some(preinit);
+}
+
+impl Runner {
+    pub fn new(arg: Vec<u8>) -> Self {
+        // use the (possibly snapshotted) interpreter from aici_preinit(), if any
+        let preinit = PREINIT.lock().unwrap().take();
+        let Preinit { interpreter, code } = preinit.unwrap_or_else(|| Preinit::new(arg));
+        interpreter.enter(|vm| {
+            let scope = vm.new_scope_with_builtins();
+            let r = code.and_then(|code_obj| vm.run_code_obj(code_obj, scope));
 
             match r {
                 Ok(_) => {
@@ -441,7 +788,7 @@ impl Runner {
                     let _ = get_cb_obj();
                 }
                 Err(e) => {
-                    vm.print_exception(e.clone());
+                    report_exception(vm, &e);
                     panic!("Python Exception: {e:?}");
                 }
             }
@@ -450,61 +797,75 @@ impl Runner {
     }
 }
 
-trait PyConstraint {
-    fn eos_allowed(&mut self) -> bool;
-    fn eos_forced(&mut self) -> bool;
-    fn token_allowed(&mut self, t: TokenId) -> bool;
-    fn append_token(&mut self, t: TokenId);
-    fn allow_tokens(&mut self, logits: &mut SimpleVob);
-}
-
-impl<T: Recognizer> PyConstraint for T {
-    fn eos_allowed(&mut self) -> bool {
-        self.special_allowed(SpecialToken::EndOfSentence)
-    }
-
-    fn eos_forced(&mut self) -> bool {
-        self.special_allowed(SpecialToken::EndOfSentence)
-            && (0..=255).all(|b| !self.byte_allowed(b))
-    }
-
-    fn token_allowed(&mut self, t: TokenId) -> bool {
-        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
-        trie.token_allowed(self, t)
-    }
-
-    fn append_token(&mut self, t: TokenId) {
-        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
-        trie.append_token(self, t).unwrap()
-    }
-
-    fn allow_tokens(&mut self, logits: &mut SimpleVob) {
-        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
-        trie.compute_bias(self, logits)
-    }
-}
-
-struct ConstraintWrapper<T: Recognizer>(Arc<Mutex<T>>);
-impl<T: Recognizer> PyConstraint for ConstraintWrapper<T> {
-    fn eos_allowed(&mut self) -> bool {
-        self.0.lock().unwrap().eos_allowed()
-    }
-
-    fn eos_forced(&mut self) -> bool {
-        self.0.lock().unwrap().eos_forced()
-    }
-
-    fn token_allowed(&mut self, t: TokenId) -> bool {
-        self.0.lock().unwrap().token_allowed(t)
-    }
+/// Print the exception and report it to the host as a ScriptError.
+fn report_exception(vm: &VirtualMachine, e: &PyBaseExceptionRef) {
+    vm.print_exception(e.clone());
+
+    let mut stack = String::new();
+    let _ = vm.write_exception(&mut stack, e);
+    let obj = e.as_object();
+    let kind = obj.class().name().to_string();
+    let message = obj
+        .str(vm)
+        .map(|s| s.as_str().to_string())
+        .unwrap_or_default();
+
+    let int_attr = |obj: &PyObjectRef, name: &'static str| {
+        obj.get_attr(name, vm)
+            .ok()
+            .and_then(|v| v.try_into_value::<u32>(vm).ok())
+    };
+    let str_attr = |obj: &PyObjectRef, name: &'static str| {
+        obj.get_attr(name, vm)
+            .ok()
+            .and_then(|v| v.downcast::<PyStr>().ok())
+            .map(|s| s.as_str().to_string())
+    };
 
-    fn append_token(&mut self, t: TokenId) {
-        self.0.lock().unwrap().append_token(t)
-    }
+    let obj = obj.to_owned();
+    let (file, line, column) = if obj.fast_isinstance(vm.ctx.exceptions.syntax_error) {
+        (
+            str_attr(&obj, "filename"),
+            int_attr(&obj, "lineno"),
+            int_attr(&obj, "offset"),
+        )
+    } else {
+        // the innermost frame is at the end of the traceback list
+        let mut tb = obj.get_attr("__traceback__", vm).ok();
+        let mut last = None;
+        while let Some(t) = tb.filter(|t| !vm.is_none(t)) {
+            tb = t.get_attr("tb_next", vm).ok();
+            last = Some(t);
+        }
+        match last {
+            Some(t) => {
+                let file = t
+                    .get_attr("tb_frame", vm)
+                    .and_then(|f| f.get_attr("f_code", vm))
+                    .ok()
+                    .and_then(|c| str_attr(&c, "co_filename"));
+                let line = int_attr(&t, "tb_lineno");
+                // the column is not exposed to Python; take it from the location
+                // of the failing instruction (lasti is one past it)
+                let column = t.downcast_ref::<PyTraceback>().and_then(|tb| {
+                    let idx = (tb.lasti as usize).checked_sub(1)?;
+                    let loc = tb.frame.code.locations.get(idx)?;
+                    (Some(loc.row.get() as u32) == line).then(|| loc.column.get() as u32)
+                });
+                (file, line, column)
+            }
+            None => (None, None, None),
+        }
+    };
 
-    fn allow_tokens(&mut self, logits: &mut SimpleVob) {
-        self.0.lock().unwrap().allow_tokens(logits)
-    }
+    aici_abi::script_error(&ScriptError {
+        kind,
+        message,
+        file,
+        line,
+        column,
+        stack,
+    });
 }
 
 trait VmExt {
@@ -515,7 +876,7 @@ trait VmExt {
             Ok(v) => v,
             Err(e) => {
                 let vm = self.get_vm();
-                vm.print_exception(e.clone());
+                report_exception(vm, &e);
                 aici_stop();
             }
         }
@@ -608,10 +969,24 @@ impl VmExt for VirtualMachine {
 impl AiciCtrl for Runner {
     fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
         let obj = get_cb_obj();
+        let (res, prefix) = {
+            let st = GLOBAL_STATE.lock().unwrap();
+            if st.token_healing {
+                InitPromptResult::healed(arg, &st.trie)
+            } else {
+                (InitPromptResult::from_arg(arg), vec![])
+            }
+        };
         self.interpreter.enter(|vm| {
-            let lst = vm.new_int_list(&arg.prompt);
-            vm.catch_exn(vm.call_method(obj.deref(), "init_prompt", vec![lst.into()]));
-            InitPromptResult::from_arg(arg)
+            let lst = vm.new_int_list(&res.prompt);
+            // only pass the prefix when healing happened, so that callbacks
+            // written before token healing (without heal_prefix argument) still work
+            let mut args: Vec<PyObjectRef> = vec![lst.into()];
+            if prefix.len() > 0 {
+                args.push(vm.ctx.new_bytes(prefix).into());
+            }
+            vm.catch_exn(vm.call_method(obj.deref(), "init_prompt", args));
+            res
         })
     }
 
@@ -656,7 +1031,10 @@ impl AiciCtrl for Runner {
                 }
             });
 
-            MidProcessResult { branches }
+            MidProcessResult {
+                branches,
+                attention_masks: vec![],
+            }
         })
     }
 }
@@ -666,3 +1044,4 @@ fn runner_from_env() -> Runner {
 }
 
 aici_abi::aici_expose_all!(Runner, runner_from_env());
+aici_abi::aici_expose_preinit!(runner_preinit, first_use);
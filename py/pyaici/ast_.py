@@ -68,6 +68,7 @@ def gen(
     *,
     rx: Optional[str] = None,
     yacc: Optional[str] = None,
+    type_: Optional[dict] = None,
     inner: Optional[dict] = None,
     stop_at: Optional[str] = None,
     max_tokens: Optional[int] = None,
@@ -82,6 +83,7 @@ def gen(
     """
     Generate output with given constraints.
     `rx` is a regular expression to match. If `yacc` is given, it is a yacc grammar to parse.
+    `type_` is a typed value, see `t_int()`, `t_float()` etc.; the value stored in variables is normalized.
     `stop_at` is a string to stop at.
     If `max_tokens` is given, stop after that many tokens; similarly for `max_words` and `max_bytes`.
     """
@@ -105,6 +107,7 @@ def gen(
         "Gen": {
             "rx": rx,
             "yacc": yacc,
+            "type": type_,
             "inner": inner,
             "stop_at": stop_at,
             "max_tokens": max_tokens,
@@ -116,6 +119,31 @@ def gen(
     }
 
 
+def t_int(min: Optional[int] = None, max: Optional[int] = None):
+    return {"Int": {"min": min, "max": max}}
+
+
+def t_float(min: Optional[float] = None,
+            max: Optional[float] = None,
+            decimals: int = 2):
+    return {"Float": {"min": min, "max": max, "decimals": decimals}}
+
+
+def t_bool():
+    return {"Bool": {}}
+
+
+def t_date(format: Optional[str] = None):
+    """
+    `format` uses %Y, %m, %d, %H, %M, %S; defaults to "%Y-%m-%d".
+    """
+    return {"Date": {"format": format}}
+
+
+def t_enum(options: List[str]):
+    return {"Enum": {"options": options}}
+
+
 def fork(*branches: List[dict]):
     return {
         "Fork": {
@@ -131,6 +159,24 @@ def wait_vars(*vars: str):
     return {"Wait": {"vars": list(vars)}}
 
 
+def repeat(body: List[dict],
+           max_iter: Optional[int] = None,
+           until: Optional[dict] = None):
+    """
+    Execute `body` steps repeatedly, at most `max_iter` times.
+    Stop once `until` expression evaluates to a non-empty string (checked after each iteration).
+    """
+    return {"Repeat": {"body": body, "max_iter": max_iter, "until": until}}
+
+
+def if_(cond: dict, then: List[dict], else_: Optional[List[dict]] = None):
+    """
+    Execute `then` steps if `cond` expression evaluates to a non-empty string,
+    and `else_` steps otherwise.
+    """
+    return {"If": {"cond": cond, "then": then, "else": else_ or []}}
+
+
 def compile_pattern(text: str):
     parts = []
     start = 0
@@ -202,7 +248,8 @@ def choose(options: Union[dict, List[str]]):
 
 def is_step(d: dict):
     return len(d) == 1 and ("Fixed" in d or "Gen" in d or "Choose" in d
-                            or "Fork" in d or "Wait" in d)
+                            or "Fork" in d or "Wait" in d or "Repeat" in d
+                            or "If" in d)
 
 
 # currently we fail for possibly empty rx, so put + not * at the end
@@ -15,7 +15,26 @@ def cli_error(msg: str):
     sys.exit(1)
 
 
-def build_rust(folder: str, features: List[str] = []):
+def js_bundle(folder: str) -> str:
+    """
+    Bundle all .js/.mjs files (and their .map source maps) in the folder
+    as a multi-file jsctrl program.
+    """
+    modules: Dict[str, str] = {}
+    for root, _, files in os.walk(folder):
+        for f in files:
+            if f.endswith((".js", ".mjs", ".js.map", ".mjs.map")):
+                path = os.path.join(root, f)
+                name = os.path.relpath(path, folder).replace(os.sep, "/")
+                modules[name] = open(path).read()
+    if "main.js" not in modules:
+        cli_error(f"{folder}/main.js not found")
+    return json.dumps({"entry": "main.js", "modules": modules})
+
+
+def build_rust(folder: str,
+               features: List[str] = [],
+               data_dir: Optional[str] = None):
     bin_file = ""
     spl = folder.split("::")
     if len(spl) > 1:
@@ -78,7 +97,7 @@ def build_rust(folder: str, features: List[str] = []):
     bb = open(trg_path, "rb").read()
     M = 1024 * 1024
     print(f"built: {trg_path}, {len(bb)/M:.3} MiB")
-    return rest.upload_module(trg_path)
+    return rest.upload_module(trg_path, data_dir=data_dir)
 
 
 def run_ctrl(
@@ -164,17 +183,17 @@ def main_rest(args):
 
     controller = ""
 
-    for k in ["build", "upload", "ctrl", "tag"]:
+    for k in ["build", "upload", "ctrl", "tag", "data"]:
         if k not in args:
             setattr(args, k, None)
 
     if args.build:
         assert not controller
-        controller = build_rust(args.build)
+        controller = build_rust(args.build, data_dir=args.data)
 
     if args.upload:
         assert not controller
-        controller = rest.upload_module(args.upload)
+        controller = rest.upload_module(args.upload, data_dir=args.data)
 
     if args.ctrl:
         assert not controller
@@ -190,6 +209,11 @@ def main_rest(args):
         fn: str = args.controller_arg
         if fn == "-":
             controller_arg = sys.stdin.read()
+        elif fn is not None and os.path.isdir(fn):
+            controller_arg = js_bundle(fn)
+            if not controller:
+                controller = "gh:microsoft/aici/jsctrl"
+                print(f"Running with tagged AICI Controller: {controller}")
         elif fn is not None:
             controller_arg = open(fn).read()
             if not controller:
@@ -250,6 +274,7 @@ def main_inner():
 
         If FILE ends with .py, --ctrl defaults to 'gh:microsoft/aici/pyctrl'.
         Similarly, it's 'gh:microsoft/aici/jsctrl' for .js and 'gh:microsoft/aici/declctrl' for .json.
+        If FILE is a folder, all .js files in it are bundled as a jsctrl program, starting at main.js.
         """,
     )
     run_cmd.add_argument("--prompt",
@@ -262,7 +287,7 @@ def main_inner():
         "controller_arg",
         metavar="FILE",
         nargs="?",
-        help="file to pass to the AICI Controller; use '-' for stdin",
+        help="file to pass to the AICI Controller; use '-' for stdin, or a folder with main.js",
     )
     infer_args(run_cmd)
     run_cmd.add_argument(
@@ -349,6 +374,14 @@ def main_inner():
             help=
             "tag the AICI Controller after uploading; can be used multiple times to set multiple tags",
         )
+        cmd.add_argument(
+            "--data",
+            "-D",
+            metavar="FOLDER",
+            type=str,
+            help=
+            "upload files in FOLDER with the AICI Controller; they are visible under /data",
+        )
 
     args = parser.parse_args()
 
@@ -309,18 +309,19 @@ class MidResult:
     logs: str
     micros: int
     branches: List[Branch]
+    # either empty or one per branch; empty mask means no masking
+    attention_masks: List[List[float]] = field(default_factory=list)
 
     @staticmethod
     def from_json(obj: dict) -> "MidResult":
+        res = obj.get("result", None) or {}
         return MidResult(
             error=obj["error"],
             storage=obj["storage"],
             logs=obj["logs"],
             micros=obj["micros"],
-            branches=[
-                Branch.from_json(q)
-                for q in (obj.get("result", None) or {}).get("branches", [])
-            ],
+            branches=[Branch.from_json(q) for q in res.get("branches", [])],
+            attention_masks=res.get("attention_masks", []),
         )
 
 
@@ -692,6 +693,8 @@ class AiciRunner:
             for line in lines.split("\n"):
                 if line:
                     print(f"[{seq_id}] {line}")
+        for ev in r.get("events", []):
+            print(f"[{seq_id}] EVENT {ev['kind']}: {json.dumps(ev['data'])}")
         lines: str = r["error"]
         if lines:
             for line in lines.split("\n"):
@@ -57,8 +57,13 @@ declare function waitVars(...vars: string[]): Promise<Buffer[]>;
 /**
  * Starts the AICI loop. 
  * @param f async function
+ * @param options.tokenHealing remove the last token(s) of the prompt, and force the first
+ *   generation step to start with the removed bytes (which are included in its result)
  */
-declare function start(f: () => Promise<void>): void;
+declare function start(
+  f: () => Promise<void>,
+  options?: { tokenHealing?: boolean }
+): void;
 
 /**
  * Specifies options for gen() and genTokens().
@@ -77,14 +82,20 @@ interface GenOptions {
    */
   yacc?: string;
   /**
-   * Make sure the generated text is a substring of the given string.
+   * Make sure the generated text is a substring of the given string
+   * (or of one of the given strings).
    */
-  substring?: string;
+  substring?: string | string[];
   /**
    * Used together with `substring` - treat the substring as ending the substring
    * (typically '"' or similar).
    */
   substringEnd?: string;
+  /**
+   * Make sure the generated text matches the constraint returned by the function,
+   * e.g., `() => seqConstraint(regexConstraint("\\d+"), upToConstraint("\\n"))`.
+   */
+  constraint?: () => import("_aici").Constraint;
   /**
    * Store result of the generation (as bytes) into a shared variable.
    */
@@ -202,6 +213,34 @@ declare module "_aici" {
    */
   function appendVar(name: string, value: string | Buffer): void;
 
+  /**
+   * Delete a shared variable. Returns false if it was not set.
+   */
+  function deleteVar(name: string): boolean;
+
+  /**
+   * Return sorted names of shared variables starting with prefix.
+   */
+  function listVars(prefix?: string): string[];
+
+  /**
+   * Atomically add delta (default 1) to an integer variable (stored as decimal string; unset is 0).
+   * Returns the new value.
+   */
+  function incrementVar(name: string, delta?: number): number;
+
+  /**
+   * Get the version of a shared variable, which is incremented on every write.
+   */
+  function getVarVersion(name: string): number | null;
+
+  /**
+   * Suspend the sequence until the variable changes from the given version
+   * (null meaning it's not set); midProcess() should return MidProcessResult.noop() then.
+   * Returns false (and doesn't suspend) if the variable has already changed.
+   */
+  function watchVar(name: string, version?: number | null): boolean;
+
   /**
    * Get the value of a configuration parameter like "fork".
    */
@@ -292,6 +331,60 @@ declare module "_aici" {
      * Set ts[] to True at all tokens that are allowed by the constraint.
      */
     allowTokens(ts: TokenSet): void;
+
+    /**
+     * For substrConstraint(), return `[sourceIdx, start, end]` locating the generated `text`
+     * (excluding the stop string) in the sources, or undefined if it doesn't match.
+     * Offsets are in bytes.
+     */
+    locate(text: string | Buffer): [number, number, number] | undefined;
+  }
+
+  /**
+   * A lexer with a set of valid identifiers, that can be used as a Constraint.
+   * Identifiers are added to the current scope, and are removed when the scope is popped.
+   */
+  class DynamicLexer {
+    /**
+     * Normally, identifiers match `[a-zA-Z_][a-zA-Z0-9_]*`.
+     * If additionalIdChars is not empty, the chars are additionally allowed anywhere in the identifier.
+     * For example, use "$" for JavaScript, or "'" for ML-like languages.
+     */
+    constructor(additionalIdChars?: string);
+
+    /**
+     * Allow given identifier in the current scope.
+     */
+    add(identifier: string): void;
+
+    /**
+     * Start a new (nested) scope.
+     */
+    pushScope(): void;
+
+    /**
+     * Remove identifiers added in the innermost scope, including the ones declared by the model.
+     * Identifiers shadowed in that scope stay valid. Throws if only the global scope is left.
+     */
+    popScope(): void;
+
+    /**
+     * Number of scopes, including the global one.
+     */
+    numScopes(): number;
+
+    /**
+     * After the model generates `prefix` (e.g., "let " or "function "), the identifier
+     * that follows is declared in the current scope, and can be used in later tokens.
+     * Keywords in the prefix still need to be allowed with add().
+     */
+    addDeclarationRule(prefix: string): void;
+
+    /**
+     * This always returns a constraint sharing the state of the lexer.
+     * It can't be combined with other constraints.
+     */
+    constraint(): Constraint;
   }
 
   /**
@@ -305,14 +398,59 @@ declare module "_aici" {
    */
   function cfgConstraint(yacc_grammar: string): Constraint;
 
+  interface SubStrOptions {
+    /**
+     * Only allow substrings that start and end at word boundaries; defaults to true.
+     */
+    wordBoundaries?: boolean;
+    /**
+     * Match case-insensitively, and treat any run of whitespace as a single space; defaults to false.
+     */
+    normalize?: boolean;
+  }
+
+  /**
+   * A constraint that allows only word-substrings of given string,
+   * or of any of the given strings (a substring never spans two of them).
+   * Use `locate()` on the constraint to find out which string the generated text came from.
+   */
+  function substrConstraint(
+    template: string | string[],
+    stop_at: string,
+    options?: SubStrOptions
+  ): Constraint;
+
+  /**
+   * A constraint that allows any text up to and including the first occurrence of `stop`.
+   */
+  function upToConstraint(stop: string): Constraint;
+
   /**
-   * A constraint that allows only word-substrings of given string.
+   * A constraint that forces the bytes removed from the prompt by token healing,
+   * and then allows text matching `item`.
    */
-  function substrConstraint(template: string, stop_at: string): Constraint;
+  function healedConstraint(prefix: Buffer, item: Constraint): Constraint;
+
+  /**
+   * Enable token healing of the prompt; use start(f, { tokenHealing: true }) instead.
+   */
+  function setTokenHealing(enabled: boolean): void;
+
+  /**
+   * Native implementations of seqConstraint() and friends from 'aici' module.
+   */
+  function seqConstraint(items: Constraint[]): Constraint;
+  function orConstraint(items: Constraint[]): Constraint;
+  function andConstraint(items: Constraint[]): Constraint;
+  function repeatConstraint(
+    item: Constraint,
+    min: number,
+    max: number | undefined
+  ): Constraint;
 }
 declare module 'aici' {
-import { TokenSet, tokenize, detokenize, regexConstraint, cfgConstraint, substrConstraint, Constraint, getVar, setVar, appendVar, eosToken, panic, tokenRepr, tokensRepr, getConfig } from "_aici";
-export { TokenSet, tokenize, detokenize, getVar, setVar, appendVar, getConfig, eosToken, tokenRepr, tokensRepr, };
+import { TokenSet, tokenize, detokenize, regexConstraint, cfgConstraint, substrConstraint, upToConstraint, healedConstraint, Constraint, DynamicLexer, getVar, setVar, appendVar, deleteVar, listVars, incrementVar, getVarVersion, watchVar, eosToken, panic, tokenRepr, tokensRepr, getConfig } from "_aici";
+export { TokenSet, Constraint, DynamicLexer, regexConstraint, cfgConstraint, substrConstraint, upToConstraint, tokenize, detokenize, getVar, setVar, appendVar, deleteVar, listVars, incrementVar, getVarVersion, watchVar, getConfig, eosToken, tokenRepr, tokensRepr, };
 export type SeqId = number;
 type int = number;
 export function setLogLevel(level: number): void;
@@ -407,6 +545,12 @@ export class NextToken {
      * If true, the postProcess() has to be empty and always self.midProcess().isSplice()
      */
     isFixed(): boolean;
+    /**
+     * Make the tokens generated by this step start with the given bytes,
+     * that were removed from the prompt by token healing.
+     * Returns false if not supported.
+     */
+    _heal(prefix: Buffer): boolean;
     _mid_process(): MidProcessResult;
     _post_process(backtrack: int, tokens: Token[]): void;
     private reset;
@@ -428,6 +572,7 @@ class FixedTokens extends NextToken {
     following: Label | null;
     constructor(text: string | Buffer, following?: Label | null);
     isFixed(): boolean;
+    _heal(prefix: Buffer): boolean;
     midProcess(): MidProcessResult;
 }
 /**
@@ -446,6 +591,7 @@ export class ConstrainedToken extends NextToken {
     mkConstraint: () => Constraint;
     _constraint: Constraint | null;
     constructor(mkConstraint: () => Constraint);
+    _heal(prefix: Buffer): boolean;
     midProcess(): MidProcessResult;
     postProcess(backtrack: number, tokens: Token[]): void;
 }
@@ -469,7 +615,7 @@ export function waitVars(...vars: string[]): Promise<Buffer[]>;
  *  Low-level interface for AICI. Use aici.start() to wrap a coroutine.
  */
 export interface AiciCallbacks {
-    init_prompt(prompt: Token[]): void;
+    init_prompt(prompt: Token[], healPrefix: Buffer): void;
     mid_process(backtrack: number, tokens: Token[], fork_group: SeqId[]): void;
 }
 /**
@@ -492,11 +638,13 @@ export class AiciAsync implements AiciCallbacks {
     private _nextTokenCb?;
     private _token;
     private _getPrompt;
+    private _healPrefix;
+    private _applyHealing;
     _setGetPrompt(g: GetPrompt): void;
     _nextToken(t: NextToken): void;
     constructor(f: () => Promise<void>);
     step(tokens: Token[]): Promise<void>;
-    init_prompt(prompt: Token[]): void;
+    init_prompt(prompt: Token[], healPrefix: Buffer): void;
     private applyTokens;
     private midProcessWithSkip;
     mid_process(backtrack: number, tokens: Token[], fork_group: SeqId[]): void;
@@ -505,8 +653,12 @@ export class AiciAsync implements AiciCallbacks {
 /**
  * Starts the AICI loop.
  * @param f async function
+ * @param options.tokenHealing remove the last token(s) of the prompt, and force the first
+ *   generation step to start with the removed bytes (which are included in its result)
  */
-export function start(f: () => Promise<void>): AiciAsync;
+export function start(f: () => Promise<void>, options?: {
+    tokenHealing?: boolean;
+}): AiciAsync;
 /**
  * Runs the loop as a test.
  */
@@ -534,13 +686,34 @@ export class Label {
 export class ChooseConstraint extends Constraint {
     ptr: number;
     options: Token[][];
-    constructor(options: string[]);
+    constructor(options: (string | Buffer)[]);
+    /**
+     * Return a fresh constraint with all options prefixed with given bytes.
+     */
+    withPrefix(prefix: Buffer): ChooseConstraint;
     eosAllowed(): boolean;
     eosForced(): boolean;
     tokenAllowed(t: Token): boolean;
     appendToken(t: Token): void;
     allowTokens(ts: TokenSet): void;
 }
+/**
+ * A constraint that allows text matching each of the given constraints in turn.
+ * The constraints are re-created from their definitions; their current state is ignored.
+ */
+export function seqConstraint(...items: Constraint[]): Constraint;
+/**
+ * A constraint that allows text matching any of the given constraints (at most 64).
+ */
+export function orConstraint(...items: Constraint[]): Constraint;
+/**
+ * A constraint that allows text matching all of the given constraints.
+ */
+export function andConstraint(...items: Constraint[]): Constraint;
+/**
+ * A constraint that allows text matching `item` repeated between `min` and `max` times.
+ */
+export function repeatConstraint(item: Constraint, min?: number, max?: number): Constraint;
 export function genTokens(options: GenOptions): Promise<Token[]>;
 export function gen(options: GenOptions): Promise<string>;
 export function checkVar(name: string, value: string): void;
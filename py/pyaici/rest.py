@@ -4,6 +4,7 @@ import os
 import urllib.parse
 import sys
 import time
+import base64
 import re
 from typing import Optional, List
 
@@ -63,6 +64,21 @@ def _mk_url(path: str, arg_base_url: Optional[str] = None) -> str:
     return pref + path
 
 
+def format_script_error(err: dict) -> str:
+    """
+    Format ScriptError as 'file:line:column: Kind: message'.
+    """
+    loc = ""
+    if err.get("file"):
+        loc = err["file"]
+        if err.get("line") is not None:
+            loc += f":{err['line']}"
+            if err.get("column") is not None:
+                loc += f":{err['column']}"
+        loc += ": "
+    return f"{loc}{err.get('kind', 'Error')}: {err.get('message', '')}"
+
+
 def response_error(kind: str, resp: requests.Response):
     text = resp.text
     try:
@@ -106,15 +122,39 @@ def detect_prefixes():
         return ["/"]
 
 
-def upload_module(file_path: str) -> str:
+def _read_data_dir(data_dir: str) -> dict:
+    data = {}
+    for root, _, files in os.walk(data_dir):
+        for fn in files:
+            full = os.path.join(root, fn)
+            rel = os.path.relpath(full, data_dir).replace(os.sep, "/")
+            with open(full, "rb") as f:
+                data[rel] = base64.b64encode(f.read()).decode()
+    return data
+
+
+def upload_module(file_path: str,
+                  meta: Optional[dict] = None,
+                  data_dir: Optional[str] = None) -> str:
     """
     Upload a WASM module to the server.
+    `meta` can set fuel budgets (`step_fuel`, `init_fuel`) when the module is first uploaded.
+    Files under `data_dir` are uploaded with the module; the controller sees them under /data.
     Returns the module ID.
     """
     if log_level > 0:
         print("upload module... ", end="")
     with open(file_path, "rb") as f:
-        resp = req("post", "controllers", data=f)
+        if data_dir:
+            resp = req("post",
+                       "controllers",
+                       json={
+                           "binary": base64.b64encode(f.read()).decode(),
+                           "meta": meta or {},
+                           "data": _read_data_dir(data_dir),
+                       })
+        else:
+            resp = req("post", "controllers", data=f, params=meta)
         if resp.status_code == 200:
             dd = resp.json()
             mod_id = dd["module_id"]
@@ -158,17 +198,49 @@ def tag_module(module_id: str, tags: List[str]):
     else:
         raise response_error("module tag", resp)
 
+
+def module_info(module_id: str) -> dict:
+    """
+    Get info about a module (by ID or tag name), including its requested `meta`
+    and effective `limits`.
+    """
+    resp = req("get", "controllers/" + urllib.parse.quote(module_id, safe=""))
+    if resp.status_code == 200:
+        return resp.json()
+    else:
+        raise response_error("module info", resp)
+
+
+def set_limits(limits: dict,
+               user: Optional[str] = None,
+               tag: Optional[str] = None):
+    """
+    Set upper bounds on limits (`step_fuel`, `init_fuel`, `memory_mb`, `step_ms`, `init_ms`, `forks`)
+    of modules uploaded by `user`, or run through `tag`. Admin only.
+    """
+    resp = req("post",
+               "controllers/limits",
+               json={
+                   "user": user,
+                   "tag": tag,
+                   "limits": limits
+               })
+    if resp.status_code != 200:
+        raise response_error("set limits", resp)
+
 def print_logs(logs: str, prefix=""):
     if logs and logs[-1] == "\n":
         logs = logs[:-1]
     for ln in logs.split("\n"):
-        if ln.startswith("JSON-OUT: "):
-            j = json.loads(ln[10:])
-            if "hex" in j:
-                j["hex"] = "..."
-            print(f"{prefix}JSON-OUT: ", json.dumps(j))
-        else:
-            print(f"{prefix}{ln}")
+        print(f"{prefix}{ln}")
+
+
+def print_events(events: List[dict], prefix=""):
+    for ev in events:
+        data = ev["data"]
+        if isinstance(data, dict) and "hex" in data:
+            data = {**data, "hex": "..."}
+        print(f"{prefix}EVENT {ev['kind']}: {json.dumps(data)}")
 
 def run_controller(
     *,
@@ -197,16 +269,17 @@ def run_controller(
     texts = [""]
     logs = [""]
     full_resp = []
-    json_out = [[]]
+    events = [[]]
     storage = {}
     res = {
         "request": data,
         "response": full_resp,
         "text": texts,
         "logs": logs,
-        "json_out": json_out,
+        "events": events,
         "raw_storage": storage,
         "error": None,
+        "script_error": None,
         "usage": {},
         "timing": {
             "http_response": time.time() - t0,
@@ -243,29 +316,35 @@ def run_controller(
                 while len(texts) <= idx:
                     texts.append("")
                     logs.append("")
-                    json_out.append([])
+                    events.append([])
                 for s in ch.get("storage", []):
                     w = s.get("WriteVar", None)
                     if w:
                         storage[w["name"]] = w["value"]
+                    dv = s.get("DeleteVar", None)
+                    if dv:
+                        storage.pop(dv["name"], None)
                 err = ch.get("error", "")
+                script_err = ch.get("script_error", None)
 
-                for ln in ch["logs"].split("\n"):
-                    ln: str
-                    if ln.startswith("JSON-OUT: "):
-                        j = json.loads(ln[10:])
-                        json_out[idx].append(j)
+                evs = ch.get("events", [])
+                events[idx].extend(evs)
 
                 if log_level > 2:
                     print_logs(ch["logs"], f"[{idx}]: ")
+                    print_events(evs, f"[{idx}]: ")
                 elif idx == 0:
                     if log_level > 1:
                         print_logs(ch["logs"])
+                        print_events(evs)
                     elif log_level > 0:
                         print(ch["text"], end="")
                         sys.stdout.flush()
                 if err:
                     res["error"] = err
+                    if script_err:
+                        res["script_error"] = script_err
+                        print(f"*** {format_script_error(script_err)}")
                     if log_level > 2 and err in ch["logs"]:
                         print(f"*** Error in [{idx}]")
                     else:
@@ -5,6 +5,10 @@
 #
 
 from typing import Any, Optional, Coroutine, Union, Callable, List, Dict
+import typing
+import enum
+import json
+import dataclasses
 
 # these are to provide re-exports
 from pyaici.server_native import (
@@ -14,12 +18,24 @@ from pyaici.server_native import (
     RegexConstraint,
     CfgConstraint,
     SubStrConstraint,
+    UpToConstraint,
+    SeqConstraint,
+    OrConstraint,
+    AndConstraint,
+    RepeatConstraint,
+    HealedConstraint,
     DynamicLexer,
     Constraint,
     get_config,
+    emit,
     get_var,
     set_var,
     append_var,
+    delete_var,
+    list_vars,
+    increment_var,
+    get_var_version,
+    watch_var,
     eos_token,
     token_repr,
     tokens_repr,
@@ -187,6 +203,14 @@ class NextToken:
         self.finished = eos_token() in tokens
         self.post_process(backtrack, tokens)
 
+    def _heal(self, prefix: bytes) -> bool:
+        """
+        Make the tokens generated by this step start with the given bytes,
+        that were removed from the prompt by token healing.
+        Returns False if not supported.
+        """
+        return False
+
     def __await__(self):
         if log_level >= 4:
             print(f"AWAIT-IN: {self}")
@@ -224,6 +248,10 @@ class FixedTokens(NextToken):
     def is_fixed(self) -> bool:
         return True
 
+    def _heal(self, prefix: bytes) -> bool:
+        self.fixed_tokens = tokenize(prefix + detokenize(self.fixed_tokens))
+        return True
+
     def mid_process(self) -> MidProcessResult:
         backtrack = 0
         if self.following is not None:
@@ -260,6 +288,19 @@ class ConstrainedToken(NextToken):
         self.mk_constraint = mk_constraint
         self._constraint: Optional[Constraint] = None
 
+    def _heal(self, prefix: bytes) -> bool:
+        assert self._constraint is None
+        mk_constraint = self.mk_constraint
+
+        def healed():
+            c = mk_constraint()
+            if isinstance(c, ChooseConstraint):
+                return c.with_prefix(prefix)
+            return HealedConstraint(prefix, c)
+
+        self.mk_constraint = healed
+        return True
+
     def mid_process(self) -> MidProcessResult:
         # we build the constraint lazily, in mid_process() which has reasonably long time limit
         # TODO remove this
@@ -322,6 +363,16 @@ async def fork(forks: Union[int, List[Branch]]):
     return fg.index(_aici.self_seq_id())
 
 
+def stop_forks(branches: List[int]):
+    """
+    Stop the given branches (as numbered by the last fork()) after the current step.
+    For example, the first branch to find an answer can stop all others.
+    """
+    assert AiciAsync.instance
+    fg = AiciAsync.instance.fork_group
+    _aici.stop_seqs([fg[b] for b in branches])
+
+
 class _WaitVars(NextToken):
 
     def __init__(self, vars: List[str]):
@@ -330,6 +381,9 @@ class _WaitVars(NextToken):
         self.values: List[bytes] = []
 
     def mid_process(self) -> MidProcessResult:
+        # watch_var() suspends us until the (first) missing variable is set
+        if any(watch_var(v) for v in self.vars):
+            return MidProcessResult.noop()
         values = [get_var(v) for v in self.vars]
         if None in values:
             return MidProcessResult.noop()
@@ -350,13 +404,53 @@ async def wait_vars(*vars: str) -> List[bytes]:
     return w.values
 
 
+async def sub_generate(
+    prompt: str,
+    max_tokens: int = 20,
+    controller: Optional[str] = None,
+    controller_arg: Any = "",
+    temperature: float = 0.0,
+) -> str:
+    """
+    Run a separate generation with its own prompt and return the generated text.
+    The child doesn't see the current sequence, and its tokens count towards the fuel
+    of the current request.
+    By default, the child runs the same controller, with `controller_arg`.
+    Raises RuntimeError if the child failed or couldn't be started.
+    """
+    if not isinstance(controller_arg, str):
+        controller_arg = json.dumps(controller_arg)
+    var = f"_sub_gen_{increment_var('_sub_gen')}"
+    _aici.spawn_generation(
+        json.dumps(
+            {
+                "prompt": prompt,
+                "controller": controller,
+                "controller_arg": controller_arg,
+                "max_tokens": max_tokens,
+                "temperature": temperature,
+                "result_var": var,
+            }
+        )
+    )
+    [text] = await wait_vars(var)
+    # set by the host just before the result
+    err = get_var(var + ".error")
+    if err:
+        raise RuntimeError(f"sub-generation failed: {err.decode(errors='replace')}")
+    return text.decode(errors="replace")
+
+
 class AiciCallbacks:
     """
     Low-level interface for AICI.
     Use pyaici.server.start() to wrap a coroutine.
     """
 
-    def init_prompt(self, prompt: List[Token]):
+    def init_prompt(self, prompt: List[Token], heal_prefix: bytes = b""):
+        """
+        `heal_prefix` is non-empty if token healing removed bytes from the end of the prompt.
+        """
         pass
 
     def mid_process(self, backtrack: int, tokens: List[Token],
@@ -423,7 +517,7 @@ class AiciAsync(AiciCallbacks):
             self._coro = _stop()
             return self._step_core()
 
-    def init_prompt(self, prompt: List[Token]):
+    def init_prompt(self, prompt: List[Token], heal_prefix: bytes = b""):
         assert not self._tokens
         self._prompt_len = len(prompt)
         self._tokens.extend(prompt)
@@ -431,6 +525,10 @@ class AiciAsync(AiciCallbacks):
             self._prompt_cb.prompt = prompt
             self._prompt_cb = None
             self.step()
+        if heal_prefix and not self._cb._heal(heal_prefix):
+            raise ValueError(
+                "token healing requires generation to start with gen_tokens() or FixedTokens()"
+            )
         self._went_ahead = True
         assert isinstance(self._cb, NextToken)
 
@@ -476,12 +574,16 @@ class AiciAsync(AiciCallbacks):
             self.step()
 
 
-def start(f: Coroutine[CbType, None, None]):
+def start(f: Coroutine[CbType, None, None], token_healing: bool = False):
     """
     Starts the AICI loop.
     The coroutine may first `await getPrompt()` and then can `await gen_*()` or
     `await FixedTokens()` multiple times.
+    If `token_healing` is set, the last token(s) of the prompt may be removed,
+    and the first generation step is then forced to start with the removed bytes
+    (which are included in its result).
     """
+    _aici.set_token_healing(token_healing)
     return AiciAsync(f)
 
 
@@ -521,11 +623,17 @@ class Label:
 
 class ChooseConstraint(Constraint):
 
-    def __init__(self, options: List[str]):
+    def __init__(self, options: List[Union[str, bytes]]):
         # super().__init__()
         self.ptr = 0
         self.options = [tokenize(o) for o in options]
 
+    def with_prefix(self, prefix: bytes) -> "ChooseConstraint":
+        """
+        Return a fresh constraint with all options prefixed with given bytes.
+        """
+        return ChooseConstraint([prefix + detokenize(o) for o in self.options])
+
     def eos_allowed(self) -> bool:
         return any(len(o) == self.ptr for o in self.options)
 
@@ -553,9 +661,10 @@ class ChooseConstraint(Constraint):
 async def gen_tokens(
     regex: Optional[str] = None,
     yacc: Optional[str] = None,
-    substring: Optional[str] = None,
+    substring: Optional[Union[str, List[str]]] = None,
     substring_end: str = '"',
     options: Optional[List[str]] = None,
+    constraint: Optional[Callable[[], Constraint]] = None,
     store_var: Optional[str] = None,
     stop_at: Optional[str] = None,
     max_tokens=20,
@@ -564,11 +673,15 @@ async def gen_tokens(
     Generates tokens with the given constraint.
     If `stop_at` is given, the generation stops when the given text is generated. The stop text is included in result.
     If `store_var` is given, the generated tokens are stored in the variable.
-    `regex` and `options` are mutually exclusive.
+    If `constraint` is given, it is called to create the constraint, e.g.,
+    `constraint=lambda: SeqConstraint(RegexConstraint(r"\\d+"), UpToConstraint("\\n"))`.
+    `regex`, `yacc`, `substring`, `options` and `constraint` are mutually exclusive.
     """
     res: List[Token] = []
-    assert len([x for x in [regex, options, yacc, substring] if x is not None
-                ]) <= 1
+    assert len([
+        x for x in [regex, options, yacc, substring, constraint]
+        if x is not None
+    ]) <= 1
     if regex is not None:
         next_token = ConstrainedToken(lambda: RegexConstraint(regex))
     elif substring is not None:
@@ -578,6 +691,8 @@ async def gen_tokens(
         next_token = ConstrainedToken(lambda: CfgConstraint(yacc))
     elif options is not None:
         next_token = ConstrainedToken(lambda: ChooseConstraint(options))
+    elif constraint is not None:
+        next_token = ConstrainedToken(constraint)
     else:
         next_token = ConstrainedToken(lambda: Constraint())
     for _ in range(max_tokens):
@@ -612,6 +727,118 @@ async def gen_text(**kwargs: Any) -> str:
     return detokenize(tokens).decode(errors="replace")
 
 
+_JSON_STRING = r'"(\\(["\\/bfnrt]|u[0-9a-fA-F]{4})|[^"\\\x00-\x1F\x7F])*"'
+_JSON_INT = r"-?(0|[1-9][0-9]*)"
+_JSON_FLOAT = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?"
+
+
+def _rx_escape(s: str) -> str:
+    return "".join("\\" + c if c in "\\.+*?()|[]{}^$#&-~" else c for c in s)
+
+
+def _rx_json_value(v: Any) -> str:
+    return _rx_escape(json.dumps(v))
+
+
+def json_regex(tp: Any) -> str:
+    """
+    Return a regex matching JSON serialization of values of type `tp`,
+    which can be a dataclass, `str`, `int`, `float`, `bool`, `None`, an `Enum`,
+    or `List`, `Dict[str, ...]`, `Optional`, `Union` and `Literal` of these.
+    The JSON is formatted as by `json.dumps()`, with object keys in field order.
+    """
+    if tp is str:
+        return _JSON_STRING
+    if tp is bool:
+        return "(true|false)"
+    if tp is int:
+        return _JSON_INT
+    if tp is float:
+        return _JSON_FLOAT
+    if tp is None or tp is type(None):
+        return "null"
+    if isinstance(tp, type) and issubclass(tp, enum.Enum):
+        return "(" + "|".join(_rx_json_value(e.value) for e in tp) + ")"
+    if dataclasses.is_dataclass(tp):
+        parts = [
+            _rx_json_value(f.name) + ": (" + json_regex(f.type) + ")"
+            for f in dataclasses.fields(tp)
+        ]
+        return "\\{" + ", ".join(parts) + "\\}"
+    origin = typing.get_origin(tp)
+    args = typing.get_args(tp)
+    if origin is list:
+        elt = "(" + json_regex(args[0] if args else Any) + ")"
+        return f"\\[({elt}(, {elt})*)?\\]"
+    if origin is dict:
+        if args and args[0] is not str:
+            raise TypeError(f"JSON object keys must be str, not {args[0]}")
+        entry = f"{_JSON_STRING}: ({json_regex(args[1] if args else Any)})"
+        return f"\\{{({entry}(, {entry})*)?\\}}"
+    if origin is Union:
+        return "(" + "|".join("(" + json_regex(a) + ")" for a in args) + ")"
+    if origin is typing.Literal:
+        return "(" + "|".join(_rx_json_value(a) for a in args) + ")"
+    if isinstance(tp, str):
+        raise TypeError(
+            f"unresolved type annotation {tp!r}; don't use 'from __future__ import annotations'"
+        )
+    raise TypeError(f"type not supported in JSON: {tp!r}")
+
+
+def _from_json(tp: Any, v: Any) -> Any:
+    if dataclasses.is_dataclass(tp):
+        return tp(**{f.name: _from_json(f.type, v[f.name]) for f in dataclasses.fields(tp)})
+    if isinstance(tp, type) and issubclass(tp, enum.Enum):
+        return tp(v)
+    if tp is float:
+        return float(v)
+    origin = typing.get_origin(tp)
+    args = typing.get_args(tp)
+    if origin is list and args:
+        return [_from_json(args[0], e) for e in v]
+    if origin is dict and args:
+        return {k: _from_json(args[1], e) for k, e in v.items()}
+    if origin is Union:
+        # the regex only allows values that match one of the alternatives
+        for a in args:
+            if a is type(None):
+                if v is None:
+                    return None
+            elif dataclasses.is_dataclass(a) or typing.get_origin(a) is dict:
+                if isinstance(v, dict):
+                    return _from_json(a, v)
+            elif typing.get_origin(a) is list:
+                if isinstance(v, list):
+                    return _from_json(a, v)
+            elif a is float and isinstance(v, (int, float)) and not isinstance(v, bool):
+                return float(v)
+            elif isinstance(a, type) and issubclass(a, enum.Enum):
+                if v in [e.value for e in a]:
+                    return a(v)
+            elif isinstance(a, type) and isinstance(v, a):
+                return v
+    return v
+
+
+async def gen_json(tp: Any, *, store_var: Optional[str] = None, max_tokens=200) -> Any:
+    """
+    Generates JSON matching the given type (typically a dataclass, see `json_regex()`),
+    and returns the parsed value, e.g., `person = await aici.gen_json(Person)`.
+    If `store_var` is given, the JSON text is stored in the variable.
+    Raises ValueError if `max_tokens` runs out before the JSON is complete.
+    """
+    text = await gen_text(regex=json_regex(tp), store_var=store_var, max_tokens=max_tokens)
+    try:
+        v = json.loads(text)
+    except ValueError:
+        # the regex only allows valid JSON, so it can only be incomplete
+        raise ValueError(
+            f"gen_json(): JSON cut off at max_tokens={max_tokens}: {text!r}"
+        ) from None
+    return _from_json(tp, v)
+
+
 def check_var(name: str, value: str):
     """
     Check if the variable has the given value.
@@ -1,7 +1,7 @@
 # Type stubs
 
 from __future__ import annotations
-from typing import Any, Sequence, List
+from typing import Any, Optional, Sequence, List, Tuple, Union
 import pyaici.server as aici
 
 
@@ -40,6 +40,13 @@ def tokens_repr(tokens: List[int]) -> str:
     ...
 
 
+def set_token_healing(enabled: bool):
+    """
+    Enable token healing of the prompt; use aici.start(..., token_healing=True) instead.
+    """
+    ...
+
+
 def self_seq_id() -> int:
     """
     Return identifier of the current sequence.
@@ -49,23 +56,84 @@ def self_seq_id() -> int:
     ...
 
 
-def get_var(name: str) -> None | bytes:
+def stop_seqs(ids: List[int]):
+    """
+    Stop given sequences (identifiers from fork_group) after the current step.
+    Only sequences of the current request can be stopped.
+    Best use aici.stop_forks() instead.
+    """
+    ...
+
+
+def spawn_generation(gen: str):
+    """
+    Start an independent generation after the current step.
+    The argument is JSON of aici_abi::SubGeneration; the generated text is written
+    to the request variable `result_var` once done, and just before that,
+    the error message (or "" on success) to `error_var` (default: `result_var` + ".error").
+    Best use aici.sub_generate() instead.
+    """
+    ...
+
+
+def get_var(name: str, scope: str = "request") -> None | bytes:
     """
     Get the value of a shared variable.
+    The scope is "request" (shared between forks of the current request),
+    "user:<namespace>" (persistent, private to the current user),
+    or "global:<namespace>" (persistent, shared by all users).
+    """
+    ...
+
+
+def set_var(name: str, value: bytes | str, scope: str = "request") -> None:
+    """
+    Set the value of a shared variable. See get_var() for scope.
+    """
+    ...
+
+
+def append_var(name: str, value: bytes | str, scope: str = "request") -> None:
+    """
+    Append to the value of a shared variable. See get_var() for scope.
+    """
+    ...
+
+
+def delete_var(name: str, scope: str = "request") -> bool:
+    """
+    Delete a shared variable. Returns False if it was not set.
+    """
+    ...
+
+
+def list_vars(prefix: str = "", scope: str = "request") -> List[str]:
+    """
+    Return sorted names of shared variables starting with prefix.
     """
     ...
 
 
-def set_var(name: str, value: bytes | str) -> None:
+def increment_var(name: str, delta: int = 1, scope: str = "request") -> int:
     """
-    Set the value of a shared variable.
+    Atomically add delta to an integer variable (stored as decimal string; unset is 0).
+    Returns the new value.
     """
     ...
 
 
-def append_var(name: str, value: bytes | str) -> None:
+def get_var_version(name: str, scope: str = "request") -> Optional[int]:
     """
-    Append to the value of a shared variable.
+    Get the version of a shared variable, which is incremented on every write.
+    """
+    ...
+
+
+def watch_var(name: str, version: Optional[int] = None, scope: str = "request") -> bool:
+    """
+    Suspend the sequence until the variable changes from the given version
+    (None meaning it's not set); mid_process() should return MidProcessResult.noop() then.
+    Returns False (and doesn't suspend) if the variable has already changed.
     """
     ...
 
@@ -84,6 +152,28 @@ def get_config(name: str) -> int:
     ...
 
 
+def json_loads(s: str | bytes) -> Any:
+    """
+    Parse JSON; use json.loads() instead.
+    """
+    ...
+
+
+def json_dumps(obj: Any, indent: Optional[int] = None, default: Any = None) -> str:
+    """
+    Serialize to JSON; use json.dumps() instead.
+    """
+    ...
+
+
+def emit(kind: str, data: Any):
+    """
+    Emit a structured event (data has to be JSON-serializable).
+    Events are returned to the client in `events`, separately from the logs.
+    """
+    ...
+
+
 class TokenSet(Sequence[bool]):
     """
     Represents a set of tokens.
@@ -150,6 +240,7 @@ class Constraint:
     def append_token(self, t: int):
         """
         Update the internal state of the constraint to reflect that token `t` was appended.
+        Raises RuntimeError if `t` is not allowed.
         """
         ...
 
@@ -159,6 +250,14 @@ class Constraint:
         """
         ...
 
+    def locate(self, text: Union[str, bytes]) -> Optional[Tuple[int, int, int]]:
+        """
+        For SubStrConstraint, return (source_index, start, end) locating the generated `text`
+        (excluding the stop string) in the sources, or None if it doesn't match.
+        Offsets are in bytes.
+        """
+        ...
+
 
 class RegexConstraint(Constraint):
     """
@@ -181,15 +280,81 @@ class CfgConstraint(Constraint):
 
 class SubStrConstraint(Constraint):
     """
-    A constraint that allows only word-substrings of given string.
+    A constraint that allows only word-substrings of given string,
+    or of any of the given strings (a substring never spans two of them).
+    With `word_boundaries=False` the substring can start and end anywhere.
+    With `normalize=True` the match is case-insensitive and any run of whitespace matches a single space.
+    Use `locate()` to find out which string the generated text came from.
+    """
+
+    def __init__(self,
+                 template: Union[str, List[str]],
+                 stop_at: str,
+                 word_boundaries: bool = True,
+                 normalize: bool = False):
+        ...
+
+
+class UpToConstraint(Constraint):
+    """
+    A constraint that allows any text up to and including the first occurrence of the stop string.
+    """
+
+    def __init__(self, stop: str):
+        ...
+
+
+class SeqConstraint(Constraint):
+    """
+    A constraint that allows text matching each of the given constraints in turn.
+    The constraints are re-created from their definitions; their current state is ignored.
+    Subclasses of Constraint and DynamicLexer constraints cannot be combined.
+    """
+
+    def __init__(self, *items: Constraint):
+        ...
+
+
+class OrConstraint(Constraint):
+    """
+    A constraint that allows text matching any of the given constraints (at most 64).
+    """
+
+    def __init__(self, *items: Constraint):
+        ...
+
+
+class AndConstraint(Constraint):
+    """
+    A constraint that allows text matching all of the given constraints.
+    """
+
+    def __init__(self, *items: Constraint):
+        ...
+
+
+class RepeatConstraint(Constraint):
+    """
+    A constraint that allows text matching the given constraint repeated between min and max times.
+    """
+
+    def __init__(self, item: Constraint, min: int = 0, max: Optional[int] = None):
+        ...
+
+class HealedConstraint(Constraint):
+    """
+    A constraint that forces the bytes removed from the prompt by token healing,
+    and then allows text matching the given constraint.
     """
 
-    def __init__(self, template: str, stop_at: str):
+    def __init__(self, prefix: bytes, item: Constraint):
         ...
 
+
 class DynamicLexer:
     """
     A lexer with a set of valid identifiers, that can be used as a Constraint.
+    Identifiers are added to the current scope, and are removed when the scope is popped.
     """
 
     def __init__(self, additional_id_chars: str):
@@ -203,10 +368,38 @@ class DynamicLexer:
 
     def add(self, identifier: str):
         """
-        Allow given identifier.
+        Allow given identifier in the current scope.
+        """
+        ...
+
+    def push_scope(self):
+        """
+        Start a new (nested) scope.
+        """
+        ...
+
+    def pop_scope(self):
+        """
+        Remove identifiers added in the innermost scope, including the ones declared by the model.
+        Identifiers shadowed in that scope stay valid.
+        Raises ValueError if only the global scope is left.
+        """
+        ...
+
+    def num_scopes(self) -> int:
+        """
+        Number of scopes, including the global one.
         """
         ...
-    
+
+    def add_declaration_rule(self, prefix: str):
+        """
+        After the model generates `prefix` (e.g., "let " or "def "), the identifier
+        that follows is declared in the current scope, and can be used in later tokens.
+        Keywords in the prefix still need to be allowed with add().
+        """
+        ...
+
     def constraint(self) -> Constraint:
         """
         This always returns the same constraint.
@@ -14,7 +14,7 @@ def wrap(text):
     return pyaici.util.orca_prompt(text)
 
 
-def greedy_query(prompt: str, steps: list):
+def greedy_query(prompt: str, steps: list, user_prompt: str = ""):
     ast_module = pyaici.rest.ast_module
     temperature = 0.0
     assert ast_module
@@ -23,6 +23,7 @@ def greedy_query(prompt: str, steps: list):
     res = pyaici.rest.run_controller(
         controller=ast_module,
         controller_arg={"steps": steps},  # type: ignore
+        prompt=user_prompt,
         temperature=temperature,
         max_tokens=200,
     )
@@ -31,10 +32,12 @@ def greedy_query(prompt: str, steps: list):
     return res["text"]
 
 
-def expect(expected: Union[list[str], str], prompt: str, steps: list):
+def expect(
+    expected: Union[list[str], str], prompt: str, steps: list, user_prompt: str = ""
+):
     if isinstance(expected, str):
         expected = [expected]
-    res = greedy_query(prompt, steps)
+    res = greedy_query(prompt, steps, user_prompt=user_prompt)
     if expected[-1] == "*":
         expected.pop()
         res = res[0 : len(expected)]
@@ -335,3 +338,70 @@ spanish: 'hola.'
             ),
         ],
     )
+
+
+def test_repeat_max_iter():
+    expect(
+        "Count: ab ab ab end",
+        "",
+        [
+            ast.fixed("Count:"),
+            ast.repeat([ast.fixed(" ab")], max_iter=3),
+            ast.fixed(" end"),
+        ],
+    )
+
+
+def test_repeat_until():
+    expect(
+        "Sevens: 7 7 7.",
+        "",
+        [
+            ast.fixed("Sevens:"),
+            ast.repeat(
+                [
+                    ast.fixed(" "),
+                    ast.gen(rx=r"7", max_tokens=2, append_to_var="n"),
+                ],
+                max_iter=10,
+                until=ast.e_ifeq(
+                    ast.e_var("n"), ast.e_str("777"), ast.e_str("1"), ast.e_str("")
+                ),
+            ),
+            ast.fixed("."),
+        ],
+    )
+
+
+def test_if_var():
+    for answer, branch in [("yes", " then"), ("no", " else")]:
+        expect(
+            f"Answer: {answer}{branch}",
+            "",
+            [
+                ast.fixed("Answer: "),
+                ast.gen(rx=answer, max_tokens=3, set_var="a"),
+                ast.if_(
+                    ast.e_ifeq(ast.e_var("a"), ast.e_str("yes"), ast.e_str("1"), ast.e_str("")),
+                    [ast.fixed(" then")],
+                    [ast.fixed(" else")],
+                ),
+            ],
+        )
+
+
+def test_if_prompt():
+    # the If is the very first step, so it's unrolled in init_prompt()
+    for user_prompt, branch in [("Hello", " world"), ("Goodbye", " moon")]:
+        res = greedy_query(
+            "",
+            [
+                ast.if_(
+                    ast.e_extract_one(r"(Hello)", ast.e_var("prompt")),
+                    [ast.fixed(" world")],
+                    [ast.fixed(" moon")],
+                ),
+            ],
+            user_prompt=user_prompt,
+        )
+        assert res[0].replace("░", "").rstrip(" ").endswith(branch)
@@ -275,10 +275,18 @@ impl SamplingParams {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AiciConfig {
     pub max_fuel: usize,
+    /// How many sub-generations a request can run at once.
+    pub max_sub_gens: usize,
+    /// How deep sub-generations can spawn further sub-generations.
+    pub max_sub_gen_depth: usize,
 }
 
 impl Default for AiciConfig {
     fn default() -> Self {
-        Self { max_fuel: 0 }
+        Self {
+            max_fuel: 0,
+            max_sub_gens: 8,
+            max_sub_gen_depth: 2,
+        }
     }
 }
@@ -2,8 +2,8 @@ use crate::{
     config::{ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig},
     iface::AiciRtIface,
     seq::{
-        FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence, SequenceGroup, Token,
-        TokenUsage,
+        FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence, SequenceGroup,
+        SubGenLink, Token, TokenUsage,
     },
     util::get_setting,
     AiciBias as _, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler, SchedulerOutputs,
@@ -11,7 +11,10 @@ use crate::{
 };
 use aici_abi::{toktrie::TokTrie, Splice};
 use aicirt::{
-    api::{AiciMidOp, AiciMidProcessReq, ModuleInstId, SequenceResult},
+    api::{
+        AiciMidOp, AiciMidProcessReq, InstantiateReq, ModuleInstId, SequenceResult, SpawnReq,
+        SubGenResult,
+    },
     with_timer, TimerRef, TimerSet,
 };
 use anyhow::{bail, Error as E, Result};
@@ -20,6 +23,7 @@ use hf_hub::{
     RepoType,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::Instant};
 use tokenizers::Tokenizer;
 
@@ -43,6 +47,16 @@ pub struct AddRequest {
     pub sampling_params: SamplingParams,
     pub expected: Option<ExpectedGeneration>,
     pub init_result: Option<SequenceResult>,
+    pub sub_gen: Option<SubGenLink>,
+}
+
+/// Sub-generation requested by a controller; the caller of step() instantiates
+/// the controller and then queues add_request (or calls sub_gen_failed()).
+pub struct SubGenRequest {
+    /// User of the parent request.
+    pub user: String,
+    pub instantiate: InstantiateReq,
+    pub add_request: AddRequest,
 }
 
 pub enum Repo {
@@ -148,6 +162,15 @@ pub struct RllmEngine<ME: ModelExec> {
 
     aicirt: Option<AiciRtIface>,
 
+    sub_gen_reqs: Vec<SubGenRequest>,
+    sub_gen_results: Vec<SubGenResult>,
+    // (used, reserved) fuel of finished sub-generations, to be settled with their parents
+    sub_gen_fuel: HashMap<String, (usize, usize)>,
+    // number of running sub-generations of each parent
+    sub_gen_running: HashMap<String, usize>,
+    // suspended sequences to resume once the step is finished
+    aici_resumed: Vec<usize>,
+
     scheduler: Scheduler<ME>,
     seq_mgr: Arc<ME::SequenceManager>,
 }
@@ -226,6 +249,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
             alt: args.alt,
             scheduler,
             aicirt: None,
+            sub_gen_reqs: Vec::new(),
+            sub_gen_results: Vec::new(),
+            sub_gen_fuel: HashMap::default(),
+            sub_gen_running: HashMap::default(),
+            aici_resumed: Vec::new(),
             tim_step: timers.new_timer("step"),
             tim_schedule: timers.new_timer("step.schedule"),
             tim_aici_mid: timers.new_timer("step.aici_mid"),
@@ -299,6 +327,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             logits_processor,
             max_index: 0,
             usage: TokenUsage::default(),
+            sub_gen: req.sub_gen,
         };
 
         self.scheduler.add_seq_group(sg);
@@ -321,6 +350,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             },
             expected: Some(exp_gen),
             init_result: None,
+            sub_gen: None,
         })
     }
 
@@ -337,9 +367,157 @@ impl<ME: ModelExec> RllmEngine<ME> {
             sampling_params,
             expected: None,
             init_result: None,
+            sub_gen: None,
         })
     }
 
+    /// Sub-generations requested in the last step.
+    pub fn take_sub_gen_reqs(&mut self) -> Vec<SubGenRequest> {
+        std::mem::take(&mut self.sub_gen_reqs)
+    }
+
+    /// Report to the parent that the sub-generation couldn't be started.
+    pub fn sub_gen_failed(&mut self, link: SubGenLink, error: String) {
+        self.sub_gen_finished(link, 0, String::new(), Some(error));
+    }
+
+    fn sub_gen_finished(
+        &mut self,
+        link: SubGenLink,
+        used_fuel: usize,
+        text: String,
+        error: Option<String>,
+    ) {
+        if let Some(n) = self.sub_gen_running.get_mut(&link.parent_req_id) {
+            *n -= 1;
+            if *n == 0 {
+                self.sub_gen_running.remove(&link.parent_req_id);
+            }
+        }
+        let f = self
+            .sub_gen_fuel
+            .entry(link.parent_req_id.clone())
+            .or_insert((0, 0));
+        f.0 += used_fuel;
+        f.1 += link.reserved_fuel;
+        self.sub_gen_results.push(SubGenResult {
+            parent_req_id: link.parent_req_id,
+            result_var: link.result_var,
+            error_var: link.error_var,
+            text,
+            error,
+        });
+    }
+
+    fn spawn_sub_gen(&mut self, parent: &mut SequenceGroup, spawn: &SpawnReq) {
+        let gen = &spawn.gen;
+        let mut link = SubGenLink {
+            parent_req_id: parent.request_id.clone(),
+            result_var: gen.result_var.clone(),
+            error_var: gen.error_var_name(),
+            depth: parent.sub_gen.as_ref().map_or(0, |l| l.depth) + 1,
+            reserved_fuel: 0,
+        };
+        // these fail without being counted as running
+        let fail = |this: &mut Self, link: SubGenLink, msg: String| {
+            log::warn!("sub-generation of {}: {msg}", link.parent_req_id);
+            this.sub_gen_results.push(SubGenResult {
+                parent_req_id: link.parent_req_id,
+                result_var: link.result_var,
+                error_var: link.error_var,
+                text: String::new(),
+                error: Some(msg),
+            });
+        };
+        let max_depth = self.config.aici.max_sub_gen_depth;
+        if link.depth > max_depth {
+            return fail(self, link, format!("nested too deep (max {max_depth})"));
+        }
+        let max_running = self.config.aici.max_sub_gens;
+        let running = self
+            .sub_gen_running
+            .get(&parent.request_id)
+            .copied()
+            .unwrap_or(0);
+        if running >= max_running {
+            return fail(self, link, format!("too many running (max {max_running})"));
+        }
+        let prompt = match self.tokenize(&gen.prompt, true) {
+            Ok(p) => p,
+            Err(e) => return fail(self, link, e.to_string()),
+        };
+
+        // the child can only use what's left of the parent's fuel
+        let max_fuel = std::cmp::min(
+            parent.sampling_params.aici_fuel.unwrap_or(usize::MAX),
+            self.config.aici.max_fuel,
+        );
+        let available = max_fuel.saturating_sub(parent.usage.fuel_tokens());
+        // worst case, see TokenUsage::fuel_tokens(); set aside now,
+        // so that the parent and its other children can't use it too
+        let reserved_fuel = prompt.len() + 2 * gen.max_tokens;
+        if reserved_fuel > available {
+            return fail(
+                self,
+                link,
+                format!("not enough fuel ({reserved_fuel} > {available})"),
+            );
+        }
+        let controller = gen
+            .controller
+            .clone()
+            .unwrap_or_else(|| parent.sampling_params.controller.clone().unwrap());
+        let sampling_params = SamplingParams {
+            controller: Some(controller.clone()),
+            controller_arg: gen.controller_arg.clone(),
+            aici_fuel: Some(reserved_fuel),
+            temperature: gen.temperature,
+            max_tokens: gen.max_tokens,
+            ignore_eos: true,
+            ..SamplingParams::default()
+        };
+        let max_len = self.config.meta.max_sequence_length;
+        if let Err(e) = sampling_params.verify_args() {
+            return fail(self, link, e.to_string());
+        }
+        if prompt.len() + gen.max_tokens > max_len {
+            let msg = format!(
+                "too long ({} + {} > {max_len})",
+                prompt.len(),
+                gen.max_tokens
+            );
+            return fail(self, link, msg);
+        }
+
+        // from now on, the child is settled with sub_gen_finished()
+        link.reserved_fuel = reserved_fuel;
+        parent.usage.sub_gen_reserved += reserved_fuel;
+        *self
+            .sub_gen_running
+            .entry(parent.request_id.clone())
+            .or_insert(0) += 1;
+
+        let request_id = format!("{}{}", parent.request_id, self.gen_req_id());
+        log::debug!("sub-generation {} -> {request_id}", parent.request_id);
+        self.sub_gen_reqs.push(SubGenRequest {
+            user: spawn.user.clone(),
+            instantiate: InstantiateReq {
+                req_id: request_id.clone(),
+                prompt: json!(prompt),
+                module_id: controller,
+                module_arg: json!(gen.controller_arg),
+            },
+            add_request: AddRequest {
+                request_id,
+                prompt,
+                sampling_params,
+                expected: None,
+                init_result: None,
+                sub_gen: Some(link),
+            },
+        });
+    }
+
     fn aici_bias(
         &mut self,
         sched_out: &mut SchedulerOutputs,
@@ -351,11 +529,26 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
 
         let mid_res = self.aicirt.as_mut().unwrap().finish_mid_process()?;
+        self.aici_resumed.extend_from_slice(&mid_res.resumed);
 
         for sg in sched_out.next_seq_groups.iter_mut() {
             if sg.sampling_params.controller.is_none() {
                 continue;
             }
+            // aicirt only allows stopping sequences from the same group
+            for seq in sg.seqs.iter_mut() {
+                if mid_res.stop_seqs.contains(&seq.seq_id.to_num()) {
+                    // keep logs of the last step, if any
+                    self.save_aici_log(seq, &mid_res.seqs);
+                    self.scheduler
+                        .finish_seq(seq, FinishReason::AiciStopSibling);
+                }
+            }
+            for spawn in mid_res.spawns.iter() {
+                if spawn.parent_req_id == sg.request_id {
+                    self.spawn_sub_gen(sg, spawn);
+                }
+            }
             let mut to_add = Vec::new();
             for seq in sg.seqs.iter_mut() {
                 if seq.sched_phase != SchedulingPhase::Running {
@@ -368,9 +561,23 @@ impl<ME: ModelExec> RllmEngine<ME> {
                             self.scheduler.finish_seq(seq, FinishReason::AiciStop);
                             continue;
                         }
+                        if !self.tmodel.supports_attention_mask()
+                            && resp
+                                .attention_masks
+                                .iter()
+                                .any(|m| m.iter().any(|v| *v < 0.5))
+                        {
+                            if let Some(log) = seq.aici_logs.last_mut() {
+                                log.error =
+                                    "attention masks are not supported by this backend".to_string();
+                            }
+                            self.scheduler.finish_seq(seq, FinishReason::Failed);
+                            continue;
+                        }
                         for (idx, b) in resp.branches.iter().enumerate() {
                             if idx == 0 {
                                 seq.aici_sampling = Some(b.clone());
+                                seq.set_attention_mask(&resp.attention_masks, idx);
                                 seq.mid_op = Some(seq.defl_mid_op());
                             } else {
                                 let new_id = self.seq_mgr.new_sequence();
@@ -380,6 +587,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
                                 seq_id_mapping.insert(copy.seq_id.to_num(), seq.seq_id.to_num());
                                 sg.max_index += 1;
                                 copy.aici_sampling = Some(b.clone());
+                                copy.set_attention_mask(&resp.attention_masks, idx);
                                 copy.mid_op = Some(AiciMidOp {
                                     clone_id: Some(seq.seq_id.to_num()),
                                     clone_idx: Some(idx),
@@ -388,6 +596,10 @@ impl<ME: ModelExec> RllmEngine<ME> {
                                 to_add.push(copy);
                             }
                         }
+                        if mid_res.suspended.contains(&seq.seq_id.to_num()) {
+                            // not sampled, and not scheduled until resumed
+                            seq.sched_phase = SchedulingPhase::Suspended;
+                        }
                     }
                     _ => {
                         assert!(seq.sched_phase != SchedulingPhase::Running);
@@ -398,15 +610,26 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
 
         let shm = &self.aicirt.as_mut().unwrap().bin_shm;
-        let slice = shm.slice_at_byte_offset::<f32>(
-            mid_res.first_mask_byte_offset,
-            mid_res.mask_num_elts * mid_res.num_masks,
-        );
-        Ok((
-            self.tmodel
-                .new_bias(slice, mid_res.num_masks, mid_res.mask_num_elts),
-            seq_id_mapping,
-        ))
+        let bias = match mid_res.dtype.as_str() {
+            "f32" => {
+                let slice = shm.slice_at_byte_offset::<f32>(
+                    mid_res.first_mask_byte_offset,
+                    mid_res.mask_num_elts * mid_res.num_masks,
+                );
+                self.tmodel
+                    .new_bias(slice, mid_res.num_masks, mid_res.mask_num_elts)
+            }
+            "bits" => {
+                let slice = shm.slice_at_byte_offset::<u32>(
+                    mid_res.first_mask_byte_offset,
+                    mid_res.mask_num_elts / 32 * mid_res.num_masks,
+                );
+                self.tmodel
+                    .new_bias_bits(slice, mid_res.num_masks, mid_res.mask_num_elts)
+            }
+            dtype => bail!("unsupported bias dtype from aicirt: {dtype}"),
+        };
+        Ok((bias, seq_id_mapping))
     }
 
     fn check_expected(&mut self, mut logits: Vec<f32>, req_id: &str, seq: &mut Sequence) -> Token {
@@ -469,13 +692,43 @@ impl<ME: ModelExec> RllmEngine<ME> {
         next_token
     }
 
+    // reaching max_tokens or being stopped by the controller is a success
+    fn sub_gen_error(seq_out: &SeqOutput) -> Option<String> {
+        match seq_out.finish_reason {
+            Some(FinishReason::Failed)
+            | Some(FinishReason::Aborted)
+            | Some(FinishReason::Deadlock)
+            | Some(FinishReason::AiciOutOfFuel) => {}
+            _ => return None,
+        }
+        let reason = seq_out.finish_reason.as_ref().unwrap().short_name();
+        match seq_out.aici_logs.iter().rev().find(|l| !l.error.is_empty()) {
+            Some(l) => Some(format!("{reason}: {}", l.error)),
+            None => Some(reason),
+        }
+    }
+
     fn dropped_outputs(&mut self, sched_out: &mut SchedulerOutputs) -> Vec<RequestOutput> {
         let mut res = Vec::new();
 
-        sched_out
-            .dropped_seq_groups
-            .iter_mut()
-            .for_each(|sg| res.push(self.req_output(sg, true)));
+        for sg in sched_out.dropped_seq_groups.iter_mut() {
+            let outp = self.req_output(sg, true);
+            if let Some(link) = sg.sub_gen.take() {
+                let seq_out = &outp.seq_outputs[0];
+                let (text, mut error) = match self.seq_output_text(seq_out) {
+                    Ok(t) => (t, None),
+                    Err(e) => {
+                        log::warn!("sub-generation {}: {e}", sg.request_id);
+                        (String::new(), Some(e.to_string()))
+                    }
+                };
+                if error.is_none() {
+                    error = Self::sub_gen_error(seq_out);
+                }
+                self.sub_gen_finished(link, sg.usage.fuel_tokens(), text, error);
+            }
+            res.push(outp);
+        }
 
         res
     }
@@ -713,6 +966,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             .start_mid_process(AiciMidProcessReq {
                 ops: mid_ops,
                 freed: self.scheduler.get_freed_seq_ids(),
+                sub_gen_results: std::mem::take(&mut self.sub_gen_results),
             })?;
 
         Ok(())
@@ -720,10 +974,25 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
     pub fn run_to_completion(&mut self) {
         while self.num_pending_requests() > 0 {
+            self.finish_deadlocked();
             self.step().expect("step failed");
         }
     }
 
+    /// Checks if all requests wait for variables that nothing is going to change,
+    /// short of a new request.
+    pub fn all_suspended(&self) -> bool {
+        self.sub_gen_results.is_empty() && self.scheduler.all_suspended()
+    }
+
+    /// Finishes (with FinishReason::Deadlock) requests that are all suspended.
+    /// To be called when no new requests can arrive, or none came for a while.
+    pub fn finish_deadlocked(&mut self) {
+        if self.all_suspended() {
+            self.scheduler.finish_suspended(FinishReason::Deadlock);
+        }
+    }
+
     pub fn step(&mut self) -> Result<Vec<RequestOutput>> {
         let r = with_timer!(self.tim_step, self.step_inner());
 
@@ -759,9 +1028,24 @@ impl<ME: ModelExec> RllmEngine<ME> {
         // we run step_finished() regardless if model failed
         self.scheduler.step_finished(sched_out);
 
+        if !self.aici_resumed.is_empty() {
+            let resumed = std::mem::take(&mut self.aici_resumed);
+            self.scheduler.resume_seqs(&resumed);
+        }
+
+        if !self.sub_gen_fuel.is_empty() {
+            let mut fuel = std::mem::take(&mut self.sub_gen_fuel);
+            self.scheduler.for_each_sg(|sg| {
+                if let Some((used, reserved)) = fuel.remove(&sg.request_id) {
+                    sg.usage.sub_gen_fuel += used;
+                    sg.usage.sub_gen_reserved -= reserved;
+                }
+            });
+        }
+
         let outputs = outputs?;
         if outputs.is_empty() {
-            assert!(!self.scheduler.has_unfinished_seqs());
+            assert!(!self.scheduler.has_unfinished_seqs() || self.scheduler.all_suspended());
         }
 
         Ok(outputs)
@@ -783,6 +1067,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
         let t0 = Instant::now();
 
         while self.scheduler.has_unfinished_seqs() {
+            self.finish_deadlocked();
             let outp = self.step()?;
             if !outp.is_empty() {
                 assert!(outp.len() == 1);
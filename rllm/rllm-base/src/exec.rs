@@ -77,8 +77,16 @@ pub trait ModelExec: Sized {
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias;
     fn new_bias(&self, slice: &'static [f32], num_seqs: usize, vocab_size: usize)
         -> Self::AiciBias;
+    /// Bias given as packed bitsets (see BiasType::Bits) of vocab_size bits, one per sequence.
+    fn new_bias_bits(&self, bits: &[u32], num_seqs: usize, vocab_size: usize) -> Self::AiciBias;
 
     fn sample(&self, processor: &mut LogitsProcessor, logits: &Self::Tensor) -> Result<u32>;
+
+    /// Whether run() honors Sequence::attention_mask;
+    /// otherwise sequences whose controller hides any tokens fail.
+    fn supports_attention_mask(&self) -> bool {
+        false
+    }
 }
 
 pub trait TBlockSpaceManager<ME: ModelExec> {
@@ -7,14 +7,16 @@ use aici_abi::{
 use aicirt::{
     api::{
         AiciMidProcessReq, AiciMidProcessResp, AuthInfo, GetTagsResp, InstantiateReq, MkModuleReq,
-        MkModuleResp, SequenceResult, SetTagsReq, TokensResp,
+        MkModuleResp, ModuleInfoReq, ModuleInfoResp, SequenceResult, SetLimitsReq, SetTagsReq,
+        TokensResp,
     },
     futexshm::ClientChannel,
     msgchannel::MessageChannel,
     shm::{Shm, Unlink},
+    sockchannel::{token_from_env, SockAddr, SockStream, MAIN_CHANNEL, SIDE_CHANNEL},
     user_error,
 };
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use futures::future::select_all;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -26,10 +28,37 @@ use std::{
 };
 use tokio::{signal::unix::SignalKind, sync::oneshot};
 
+enum ReqChannel {
+    Shm(ClientChannel),
+    Socket(SockStream, usize),
+}
+
+impl ReqChannel {
+    fn send_req(&mut self, msg: &[u8]) -> Result<()> {
+        match self {
+            ReqChannel::Shm(ch) => ch.send_req(msg),
+            ReqChannel::Socket(s, _) => s.send_frame(msg),
+        }
+    }
+
+    // with spin set, busy-wait for the response in shared memory, instead of sleeping
+    fn recv_resp(&mut self, spin: bool) -> Result<Vec<u8>> {
+        match self {
+            ReqChannel::Shm(ch) => Ok(if spin {
+                ch.recv_resp(Duration::MAX)
+            } else {
+                ch.recv_resp2(Duration::ZERO, Duration::MAX)
+            }
+            .unwrap()),
+            ReqChannel::Socket(s, max_size) => s.recv_frame(*max_size),
+        }
+    }
+}
+
 pub struct CmdChannel {
     cmd_pending: bool,
-    cmd_ch: ClientChannel,
-    resp_ch: ClientChannel,
+    cmd_ch: ReqChannel,
+    resp_ch: ReqChannel,
     #[allow(dead_code)]
     busy_wait_duration: Duration,
 }
@@ -53,8 +82,24 @@ impl CmdChannel {
     ) -> Result<Self> {
         Ok(Self {
             cmd_pending: false,
-            cmd_ch: build_ch(&format!("{}cmd{}", pref, suff), json_size)?,
-            resp_ch: build_ch(&format!("{}resp{}", pref, suff), json_size)?,
+            cmd_ch: ReqChannel::Shm(build_ch(&format!("{}cmd{}", pref, suff), json_size)?),
+            resp_ch: ReqChannel::Shm(build_ch(&format!("{}resp{}", pref, suff), json_size)?),
+            busy_wait_duration,
+        })
+    }
+
+    pub fn connect(
+        addr: &SockAddr,
+        channel: &str,
+        token: &str,
+        json_size: usize,
+        busy_wait_duration: Duration,
+    ) -> Result<Self> {
+        let sock = SockStream::connect_channel(addr, channel, token)?;
+        Ok(Self {
+            cmd_pending: false,
+            cmd_ch: ReqChannel::Socket(sock.try_clone()?, json_size * M),
+            resp_ch: ReqChannel::Socket(sock, json_size * M),
             busy_wait_duration,
         })
     }
@@ -86,7 +131,7 @@ impl CmdChannel {
         R: for<'d> Deserialize<'d>,
     {
         assert!(self.cmd_pending);
-        let bytes = self.resp_ch.recv_resp(Duration::MAX).unwrap();
+        let bytes = self.resp_ch.recv_resp(true)?;
         self.cmd_pending = false;
         let mut resp: Value = serde_json::from_slice(&bytes)?;
         if resp["type"] != "ok" {
@@ -118,7 +163,7 @@ pub struct AiciRtIface {
     pub bin_shm: Shm,
     pub side_cmd: AsyncCmdChannel,
     #[allow(dead_code)]
-    child: Child,
+    child: Option<Child>,
 }
 
 pub struct Args {
@@ -126,9 +171,13 @@ pub struct Args {
     pub tokenizer: String,
     pub json_size: usize,
     pub bin_size: usize,
+    pub bias_dtype: String,
     pub shm_prefix: String,
     pub busy_wait_time: u64,
     pub add_args: Vec<String>,
+    /// Connect to aicirt started with --listen (unix:PATH, tcp:PORT or tcp:HOST:PORT),
+    /// instead of spawning it; the token is taken from $AICIRT_TOKEN.
+    pub connect: Option<String>,
 }
 
 pub fn kill_self() {
@@ -138,13 +187,7 @@ pub fn kill_self() {
 }
 
 impl AiciRtIface {
-    pub fn start_aicirt(args: &Args, tok_trie: &TokTrie) -> Result<Self> {
-        let busy_wait_time = Duration::from_millis(args.busy_wait_time);
-        let shm_name = MessageChannel::shm_name(&(args.shm_prefix.clone() + "bin"));
-        let cmd = CmdChannel::new(args.json_size, &args.shm_prefix, "", busy_wait_time)?;
-        let side_cmd = AsyncCmdChannel::new(args.json_size, &args.shm_prefix, "-side")?;
-        let bin_shm = Shm::new(&shm_name, args.bin_size * M, Unlink::Pre)?;
-
+    fn spawn_aicirt(args: &Args) -> Result<Child> {
         let mut cmd_bld = Command::new(&args.aicirt);
         cmd_bld
             .arg("--tokenizer")
@@ -153,6 +196,8 @@ impl AiciRtIface {
             .arg(&args.json_size.to_string())
             .arg("--bin-size")
             .arg(&args.bin_size.to_string())
+            .arg("--bias-dtype")
+            .arg(&args.bias_dtype)
             .arg("--name")
             .arg(&args.shm_prefix)
             .arg("--futex")
@@ -199,6 +244,43 @@ impl AiciRtIface {
             }
         });
 
+        Ok(child)
+    }
+
+    pub fn start_aicirt(args: &Args, tok_trie: &TokTrie) -> Result<Self> {
+        let busy_wait_time = Duration::from_millis(args.busy_wait_time);
+        let (cmd, side_cmd, bin_shm, child) = match &args.connect {
+            Some(addr) => {
+                let addr: SockAddr = addr.parse()?;
+                let token = token_from_env()?;
+                let cmd = CmdChannel::connect(
+                    &addr,
+                    MAIN_CHANNEL,
+                    &token,
+                    args.json_size,
+                    busy_wait_time,
+                )?;
+                let side_cmd = AsyncCmdChannel::from_cmd(CmdChannel::connect(
+                    &addr,
+                    SIDE_CHANNEL,
+                    &token,
+                    args.json_size,
+                    Duration::ZERO,
+                )?);
+                // aicirt sends masks inline; they are copied here
+                let bin_shm = Shm::anon(args.bin_size * M)?;
+                (cmd, side_cmd, bin_shm, None)
+            }
+            None => {
+                let shm_name = MessageChannel::shm_name(&(args.shm_prefix.clone() + "bin"));
+                let cmd = CmdChannel::new(args.json_size, &args.shm_prefix, "", busy_wait_time)?;
+                let side_cmd = AsyncCmdChannel::new(args.json_size, &args.shm_prefix, "-side")?;
+                let bin_shm = Shm::new(&shm_name, args.bin_size * M, Unlink::Pre)?;
+                let child = Self::spawn_aicirt(args)?;
+                (cmd, side_cmd, bin_shm, Some(child))
+            }
+        };
+
         let mut r = Self {
             cmd,
             side_cmd,
@@ -235,6 +317,16 @@ impl AiciRtIface {
     pub fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp> {
         assert!(self.pending_mid_size < usize::MAX);
         let r: AiciMidProcessResp = self.cmd.expect("async:mid_process")?;
+        if let Some(masks) = &r.inline_masks {
+            let bytes = masks.decode()?;
+            ensure!(
+                r.first_mask_byte_offset + bytes.len() <= self.bin_shm.len(),
+                "inline masks too large; increase --bin-size"
+            );
+            self.bin_shm
+                .slice_at_byte_offset::<u8>(r.first_mask_byte_offset, bytes.len())
+                .copy_from_slice(&bytes);
+        }
         // assert!(r.num_seqs == self.pending_mid_size);
         self.pending_mid_size = usize::MAX;
         Ok(r)
@@ -244,12 +336,16 @@ impl AiciRtIface {
 #[derive(Clone)]
 pub struct AsyncCmdChannel {
     pending_reqs: Arc<Mutex<HashMap<String, oneshot::Sender<Value>>>>,
-    cmd_ch: Arc<Mutex<ClientChannel>>,
+    cmd_ch: Arc<Mutex<ReqChannel>>,
 }
 
 impl AsyncCmdChannel {
     pub fn new(json_size: usize, pref: &str, suff: &str) -> Result<Self> {
         let cmd = CmdChannel::new(json_size, pref, suff, Duration::ZERO)?;
+        Ok(Self::from_cmd(cmd))
+    }
+
+    pub fn from_cmd(cmd: CmdChannel) -> Self {
         let pending_reqs = Arc::new(Mutex::new(
             HashMap::<String, oneshot::Sender<Value>>::default(),
         ));
@@ -257,7 +353,7 @@ impl AsyncCmdChannel {
             let mut resp_ch = cmd.resp_ch;
             let pending_reqs = pending_reqs.clone();
             thread::spawn(move || loop {
-                let resp = resp_ch.recv_resp2(Duration::ZERO, Duration::MAX).unwrap();
+                let resp = resp_ch.recv_resp(false).unwrap();
                 let resp: Value = serde_json::from_slice(&resp).unwrap();
                 let rid = resp["$rid"].as_str().unwrap().to_string();
                 let tx = pending_reqs.lock().unwrap().remove(&rid).unwrap();
@@ -265,16 +361,28 @@ impl AsyncCmdChannel {
             });
         }
 
-        Ok(Self {
+        Self {
             pending_reqs,
             cmd_ch: Arc::new(Mutex::new(cmd.cmd_ch)),
-        })
+        }
     }
 
     pub async fn set_tags(&self, req: SetTagsReq, authinfo: AuthInfo) -> Result<GetTagsResp> {
         self.exec("set_tags", req, authinfo).await
     }
 
+    pub async fn set_limits(&self, req: SetLimitsReq, authinfo: AuthInfo) -> Result<Value> {
+        self.exec("set_limits", req, authinfo).await
+    }
+
+    pub async fn module_info(
+        &self,
+        req: ModuleInfoReq,
+        authinfo: AuthInfo,
+    ) -> Result<ModuleInfoResp> {
+        self.exec("module_info", req, authinfo).await
+    }
+
     pub async fn get_tags(&self, authinfo: AuthInfo) -> Result<GetTagsResp> {
         self.exec("get_tags", json!({}), authinfo).await
     }
@@ -14,7 +14,7 @@ pub mod util;
 use config::AiciConfig;
 pub use engine::*;
 pub use exec::*;
-pub use logits::LogitsProcessor;
+pub use logits::{apply_bit_mask, LogitsProcessor};
 pub use scheduler::*;
 use std::sync::atomic::AtomicBool;
 
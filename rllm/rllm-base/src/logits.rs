@@ -33,3 +33,63 @@ impl LogitsProcessor {
         }
     }
 }
+
+/// Add a packed bitset mask (one bit per token, set = allowed) to logits.
+/// Disallowed tokens get -inf; tokens past the end of the mask are disallowed.
+pub fn apply_bit_mask(logits: &mut [f32], mask: &[u32]) {
+    let mut chunks = logits.chunks_mut(32);
+    // mask first, so zip() doesn't consume a chunk past its end
+    for (&word, chunk) in mask.iter().zip(&mut chunks) {
+        // most words are all-allowed or all-disallowed, so skip the per-bit loop for these
+        if word == u32::MAX {
+            continue;
+        } else if word == 0 {
+            chunk.fill(f32::NEG_INFINITY);
+        } else {
+            for (bit, l) in chunk.iter_mut().enumerate() {
+                if word & (1 << bit) == 0 {
+                    *l = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+    for chunk in chunks {
+        chunk.fill(f32::NEG_INFINITY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(logits: &[f32]) -> Vec<usize> {
+        (0..logits.len()).filter(|&i| logits[i] == 1.0).collect()
+    }
+
+    #[test]
+    fn bit_mask() {
+        let mut logits = vec![1.0f32; 96];
+        apply_bit_mask(&mut logits, &[u32::MAX, 0, 0b1001]);
+        let mut exp: Vec<usize> = (0..32).collect();
+        exp.extend([64, 67]);
+        assert_eq!(allowed(&logits), exp);
+        assert!(logits[32..64].iter().all(|&l| l == f32::NEG_INFINITY));
+    }
+
+    #[test]
+    fn bit_mask_partial_word() {
+        // vocab size not a multiple of 32; the bits past the end are ignored
+        let mut logits = vec![1.0f32; 40];
+        apply_bit_mask(&mut logits, &[1 << 31, u32::MAX]);
+        assert_eq!(allowed(&logits), vec![31, 32, 33, 34, 35, 36, 37, 38, 39]);
+    }
+
+    #[test]
+    fn bit_mask_too_short() {
+        // tokens past the end of the mask are disallowed
+        let mut logits = vec![1.0f32; 70];
+        apply_bit_mask(&mut logits, &[u32::MAX]);
+        assert_eq!(allowed(&logits), (0..32).collect::<Vec<_>>());
+        assert_eq!(logits[69], f32::NEG_INFINITY);
+    }
+}
@@ -363,6 +363,13 @@ impl<ME: ModelExec> Scheduler<ME> {
 
         log::debug!("preempting seq_group {} ({:?})", seq_group.request_id, mode);
 
+        // AICI is asked again, and will suspend them again if still waiting
+        for seq in seq_group.seqs.iter_mut() {
+            if seq.sched_phase == SchedulingPhase::Suspended {
+                seq.sched_phase = SchedulingPhase::Running;
+            }
+        }
+
         match mode {
             PreemptionMode::Swap => {
                 if !self.block_manager.can_swap_out(&seq_group) {
@@ -403,13 +410,37 @@ impl<ME: ModelExec> Scheduler<ME> {
         self.q_with(Queue::OnGpu, |seq_groups| {
             seq_groups.append(&mut outputs.next_seq_groups);
         });
+    }
+
+    /// Lets sequences suspended by AICI run again, see AiciMidProcessResp::resumed.
+    pub fn resume_seqs(&self, seq_ids: &[usize]) {
         self.for_each_seq(|seq| {
-            if seq.sched_phase == SchedulingPhase::Suspended {
+            if seq.sched_phase == SchedulingPhase::Suspended
+                && seq_ids.contains(&seq.seq_id.to_num())
+            {
                 seq.sched_phase = SchedulingPhase::Running;
             }
         });
     }
 
+    /// Checks if there are unfinished sequences, and all of them are suspended.
+    pub fn all_suspended(&self) -> bool {
+        let queues = self.queues.lock().unwrap();
+        queues.iter().any(|q| q.len() > 0)
+            && queues
+                .iter()
+                .all(|q| q.iter().all(|sg| sg.is_finished() || sg.is_suspended()))
+    }
+
+    /// Finishes all groups where all sequences are suspended.
+    pub fn finish_suspended(&mut self, reason: FinishReason) {
+        self.for_each_sg(|seq_group| {
+            if !seq_group.is_finished() && seq_group.is_suspended() {
+                self.set_phase(seq_group, SchedulingPhase::Finished(reason));
+            }
+        });
+    }
+
     pub fn schedule(&mut self) -> SchedulerOutputs {
         let mut outputs = SchedulerOutputs::new();
         self.step_drop_finished(&mut outputs);
@@ -446,7 +477,10 @@ impl<ME: ModelExec> Scheduler<ME> {
         if seq.is_finished() {
             return;
         }
-        if reason != FinishReason::AiciStop && seq.has_aici {
+        if reason != FinishReason::AiciStop
+            && reason != FinishReason::AiciStopSibling
+            && seq.has_aici
+        {
             seq.aici_logs.push(SequenceResult::from_error(format!(
                 "\nAbnormal finish: {:?}",
                 reason
@@ -14,6 +14,8 @@ pub enum FinishReason {
     FoundEos,
     /// Stopped by AICI.
     AiciStop,
+    /// Stopped by AICI controller of another sequence in the same request.
+    AiciStopSibling,
     /// Too many prompt/generation tokens in the current request (sequence group)
     AiciOutOfFuel,
     /// SamplingParams.max_tokens reached.
@@ -34,6 +36,7 @@ impl FinishReason {
             FinishReason::Aborted => "abort",
             FinishReason::Failed => "fail",
             FinishReason::AiciStop => "aici-stop",
+            FinishReason::AiciStopSibling => "aici-stop-sibling",
             FinishReason::Deadlock => "deadlock",
             FinishReason::AiciOutOfFuel => "aici-out-of-fuel",
         };
@@ -60,6 +63,10 @@ pub struct Sequence {
     pub num_kv_computed: usize,
     pub(crate) has_aici: bool,
     pub(crate) aici_sampling: Option<Branch<usize>>,
+    /// Attention mask requested by AICI, see MidProcessResult::attention_masks.
+    pub attention_mask: Vec<f32>,
+    /// Positions that were excluded from the KV cache when it was computed.
+    pub kv_masked: Vec<usize>,
     pub aici_logs: Vec<SequenceResult>,
     pub(crate) expected: Option<ExpectedGeneration>,
 
@@ -97,6 +104,8 @@ impl Sequence {
             has_aici: false,
             aici_logs: Vec::new(),
             aici_sampling: None,
+            attention_mask: Vec::new(),
+            kv_masked: Vec::new(),
             mid_op: None,
             expected: None,
         }
@@ -112,6 +121,52 @@ impl Sequence {
         self.num_kv_computed = self.get_len();
     }
 
+    /// Set `attention_mask` from the masks returned by the controller, for branch `idx`.
+    /// If no masks were returned, the previous mask stays in effect.
+    pub(crate) fn set_attention_mask(&mut self, masks: &[Vec<f32>], idx: usize) {
+        if let Some(mask) = masks.get(idx) {
+            self.attention_mask = mask.clone();
+        }
+    }
+
+    /// Positions hidden by `attention_mask`; the last token is never hidden.
+    pub fn masked_positions(&self) -> Vec<usize> {
+        self.attention_mask
+            .iter()
+            .take(self.get_len().saturating_sub(1))
+            .enumerate()
+            .filter(|(_, v)| **v < 0.5)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Drop the part of the KV cache that was computed with a different attention mask
+    /// than the current one, so that it gets re-computed.
+    /// Returns (sorted) positions that should not be added to the KV cache.
+    pub fn sync_kv_mask(&mut self, seq_mgr: &impl SequenceManager) -> Vec<usize> {
+        let masked = self.masked_positions();
+        let computed = self.num_kv_computed;
+        let mut old = self.kv_masked.iter().filter(|p| **p < computed).peekable();
+        let mut new = masked.iter().filter(|p| **p < computed).peekable();
+        let first_diff = loop {
+            match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) if a == b => {
+                    old.next();
+                    new.next();
+                }
+                (Some(a), Some(b)) => break Some(std::cmp::min(**a, **b)),
+                (Some(a), None) | (None, Some(a)) => break Some(**a),
+                (None, None) => break None,
+            }
+        };
+        if let Some(pos) = first_diff {
+            log::debug!("attention mask changed at {pos}; re-computing KV");
+            self.trim_computed_kv(pos, seq_mgr);
+        }
+        self.kv_masked = masked.clone();
+        masked
+    }
+
     fn trim_computed_kv(&mut self, v: usize, seq_mgr: &impl SequenceManager) {
         if self.num_kv_computed != v {
             assert!(self.num_kv_computed > v);
@@ -185,6 +240,8 @@ impl Sequence {
             has_aici: self.has_aici,
             aici_logs: Vec::new(),
             aici_sampling: None,
+            attention_mask: self.attention_mask.clone(),
+            kv_masked: self.kv_masked.clone(),
             expected: None,
             mid_op: None,
         }
@@ -258,6 +315,20 @@ pub struct SequenceGroup {
     pub logits_processor: LogitsProcessor,
     pub max_index: usize,
     pub usage: TokenUsage,
+    /// Set when this group was spawned by a controller of another request.
+    pub sub_gen: Option<SubGenLink>,
+}
+
+/// Links a sub-generation to its parent request, see aici_abi::spawn_generation().
+#[derive(Debug, Clone)]
+pub struct SubGenLink {
+    pub parent_req_id: String,
+    pub result_var: String,
+    pub error_var: String,
+    /// 1 for children of top-level requests.
+    pub depth: usize,
+    /// Fuel set aside in the parent's TokenUsage::sub_gen_reserved.
+    pub reserved_fuel: usize,
 }
 
 impl Debug for SequenceGroup {
@@ -344,6 +415,12 @@ pub struct SeqOutput {
 pub struct TokenUsage {
     pub gen_tokens: usize,
     pub prompt_tokens: usize,
+    /// Fuel used by finished sub-generations of this request.
+    #[serde(default)]
+    pub sub_gen_fuel: usize,
+    /// Fuel set aside for running sub-generations.
+    #[serde(skip)]
+    pub sub_gen_reserved: usize,
 }
 
 impl TokenUsage {
@@ -352,7 +429,7 @@ impl TokenUsage {
     }
 
     pub fn fuel_tokens(&self) -> usize {
-        2 * self.gen_tokens + self.prompt_tokens
+        2 * self.gen_tokens + self.prompt_tokens + self.sub_gen_fuel + self.sub_gen_reserved
     }
 }
 
@@ -363,3 +440,74 @@ pub struct RequestOutput {
     pub seq_outputs: Vec<SeqOutput>,
     pub is_final: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // records the trims
+    #[derive(Default)]
+    struct TrimLog(Mutex<Vec<usize>>);
+
+    impl SequenceManager for TrimLog {
+        fn new_sequence(&self) -> SeqId {
+            SeqId(1)
+        }
+        fn copy(&self, _src: SeqId, _dst: SeqId, _length: usize) {}
+        fn trim(&self, _seq: SeqId, length: usize) {
+            self.0.lock().unwrap().push(length);
+        }
+        fn delete(&self, _seq: SeqId) {}
+    }
+
+    fn seq(len: usize) -> Sequence {
+        Sequence::new(SeqId(0), &vec![1; len])
+    }
+
+    #[test]
+    fn keep_mask() {
+        let mut s = seq(4);
+        s.set_attention_mask(&[vec![1.0, 0.0]], 0);
+        assert_eq!(s.masked_positions(), vec![1]);
+        // no masks returned: keep the previous one
+        s.set_attention_mask(&[], 0);
+        assert_eq!(s.masked_positions(), vec![1]);
+        // empty mask: no masking
+        s.set_attention_mask(&[vec![0.0], vec![]], 1);
+        assert_eq!(s.masked_positions(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn masked_positions() {
+        let mut s = seq(3);
+        s.attention_mask = vec![0.0, 1.0, 0.0, 0.0];
+        // the last token is never hidden, and neither are the ones past the mask
+        assert_eq!(s.masked_positions(), vec![0]);
+    }
+
+    #[test]
+    fn sync_kv_mask() {
+        let mgr = TrimLog::default();
+        let mut s = seq(6);
+        s.attention_mask = vec![1.0, 1.0, 0.0];
+        assert_eq!(s.sync_kv_mask(&mgr), vec![2]);
+        s.sync_computed_kv();
+
+        // same mask: KV is kept
+        assert_eq!(s.sync_kv_mask(&mgr), vec![2]);
+        assert_eq!(s.num_kv_computed, 6);
+
+        // a new position hidden: re-compute from there
+        s.attention_mask = vec![1.0, 1.0, 0.0, 1.0, 0.0];
+        assert_eq!(s.sync_kv_mask(&mgr), vec![2, 4]);
+        assert_eq!(s.num_kv_computed, 4);
+        s.sync_computed_kv();
+
+        // un-hidden
+        s.attention_mask = vec![1.0, 0.0];
+        assert_eq!(s.sync_kv_mask(&mgr), vec![1]);
+        assert_eq!(s.num_kv_computed, 1);
+        assert_eq!(*mgr.0.lock().unwrap(), vec![4, 1]);
+    }
+}
@@ -1,4 +1,4 @@
-use aici_abi::StorageCmd;
+use aici_abi::{Event, ScriptError, StorageCmd};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,7 +42,14 @@ pub struct RunForkResponse {
     pub finish_reason: Option<String>,
     pub text: String,
     pub error: String,
+    /// Details of the error, when it's an exception in a script controller.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_error: Option<ScriptError>,
     pub logs: String,
     pub storage: Vec<StorageCmd>,
+    /// Structured output of the controller.
+    pub events: Vec<Event>,
     pub micros: u64,
+    /// Set when the controller ran out of its fuel budget.
+    pub out_of_fuel: bool,
 }
@@ -149,6 +149,7 @@ async fn run_controller(
                 sampling_params,
                 expected: None,
                 init_result,
+                sub_gen: None,
             });
 
             bail_if_error!(rx);
@@ -222,11 +223,21 @@ impl futures::Stream for Client {
                                 .map(|e| e.error.clone())
                                 .collect::<Vec<_>>()
                                 .join(""),
+                            script_error: choice
+                                .aici_logs
+                                .iter()
+                                .find_map(|e| e.script_error.clone()),
                             storage: choice
                                 .aici_logs
                                 .iter()
                                 .flat_map(|e| e.storage.clone())
                                 .collect::<Vec<_>>(),
+                            events: choice
+                                .aici_logs
+                                .iter()
+                                .flat_map(|e| e.events.clone())
+                                .collect::<Vec<_>>(),
+                            out_of_fuel: choice.aici_logs.iter().any(|e| e.out_of_fuel),
                         })
                         .collect(),
                 };
@@ -1,24 +1,27 @@
 use crate::{
     config::{ModelMeta, SamplingParams},
     iface::{kill_self, AiciRtIface, AsyncCmdChannel},
-    seq::RequestOutput,
+    seq::{RequestOutput, SubGenLink},
     util::apply_settings,
-    AddRequest, HashMap, LoaderArgs, ModelExec, RllmEngine,
+    AddRequest, HashMap, LoaderArgs, ModelExec, RllmEngine, SubGenRequest,
 };
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{middleware::Logger, web, App, HttpMessage, HttpServer};
 use aici_abi::toktrie::TokTrie;
 use aicirt::{
-    api::{AuthInfo, GetTagsResp, MkModuleReq, MkModuleResp, SetTagsReq},
+    api::{
+        AuthInfo, GetTagsResp, MkModuleReq, MkModuleResp, ModuleInfoReq, ModuleInfoResp,
+        ModuleMeta, SetLimitsReq, SetTagsReq,
+    },
     bintokens::{guess_tokenizer, list_tokenizers},
     set_max_priority, UserError,
 };
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use base64::Engine;
 use clap::Args;
 use std::{
     fmt::Display,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
 
@@ -26,6 +29,9 @@ mod api;
 mod completion;
 mod openai;
 
+// how long requests can be all suspended before they are finished as deadlocked
+const DEADLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub struct APIError {
     code: actix_web::http::StatusCode,
@@ -178,6 +184,10 @@ pub struct RllmCliArgs {
     #[arg(long, default_value = "32", help_heading = "AICI settings")]
     pub bin_size: usize,
 
+    /// How aicirt passes logit biases: bits (packed bitset, expanded here) or f32
+    #[arg(long, default_value = "bits", help_heading = "AICI settings")]
+    pub bias_dtype: String,
+
     /// How many milliseconds to spin-wait for a message over IPC and SHM.
     #[arg(long, default_value = "200", help_heading = "AICI settings")]
     pub busy_wait_time: u64,
@@ -190,6 +200,11 @@ pub struct RllmCliArgs {
     #[arg(long, short = 'A', help_heading = "AICI settings")]
     pub aicirt_arg: Vec<String>,
 
+    /// Connect to aicirt running with --listen (unix:PATH, tcp:PORT or tcp:HOST:PORT) instead of
+    /// starting it; $AICIRT_TOKEN has to be the same as for aicirt
+    #[arg(long, help_heading = "AICI settings")]
+    pub aicirt_connect: Option<String>,
+
     /// Specify test-cases (expected/*/*.safetensors)
     #[arg(long, help_heading = "Development")]
     pub test: Vec<String>,
@@ -234,16 +249,61 @@ async fn tag_controller(
     Ok(web::Json(r))
 }
 
+#[actix_web::post("/v1/controllers/limits")]
+async fn set_controller_limits(
+    req: actix_web::HttpRequest,
+    data: web::Data<AiciServerData>,
+    body: web::Json<SetLimitsReq>,
+) -> Result<web::Json<serde_json::Value>, APIError> {
+    let r = data
+        .side_cmd_ch
+        .set_limits(body.0, auth_info(&req))
+        .await
+        .map_err(APIError::just_msg)?;
+    Ok(web::Json(r))
+}
+
+// registered after /v1/controllers/tags, which would otherwise match here
+#[actix_web::get("/v1/controllers/{module_id}")]
+async fn get_controller_info(
+    req: actix_web::HttpRequest,
+    data: web::Data<AiciServerData>,
+    path: web::Path<String>,
+) -> Result<web::Json<ModuleInfoResp>, APIError> {
+    let r = data
+        .side_cmd_ch
+        .module_info(
+            ModuleInfoReq {
+                module_id: path.into_inner(),
+            },
+            auth_info(&req),
+        )
+        .await
+        .map_err(APIError::just_msg)?;
+    Ok(web::Json(r))
+}
+
 #[actix_web::post("/v1/controllers")]
 async fn upload_controller(
     req: actix_web::HttpRequest,
     data: web::Data<AiciServerData>,
+    meta: web::Query<ModuleMeta>,
     body: web::Bytes,
 ) -> Result<web::Json<MkModuleResp>, APIError> {
-    let binary = base64::engine::general_purpose::STANDARD.encode(body);
+    // JSON body is a full MkModuleReq (with data files); otherwise it's just the .wasm
+    let mk_req = if req.content_type() == "application/json" {
+        serde_json::from_slice::<MkModuleReq>(&body)
+            .map_err(|e| APIError::new(format!("invalid JSON body: {}", e)))?
+    } else {
+        MkModuleReq {
+            binary: base64::engine::general_purpose::STANDARD.encode(body),
+            meta: meta.into_inner(),
+            data: Default::default(),
+        }
+    };
     let r = data
         .side_cmd_ch
-        .mk_module(MkModuleReq { binary }, auth_info(&req))
+        .mk_module(mk_req, auth_info(&req))
         .await
         .map_err(APIError::just_msg)?;
     Ok(web::Json(r))
@@ -309,6 +369,7 @@ More info at: {url}"#
 
 pub enum InferenceReq {
     AddRequest(AddRequest),
+    SubGenFailed(SubGenLink, String),
 }
 
 type InferenceResult = Result<RequestOutput>;
@@ -336,29 +397,89 @@ impl InferenceWorker {
     }
 }
 
+// Instantiate the controller of a sub-generation and queue it.
+// This waits for aicirt, so it runs on the server runtime and not in the inference loop.
+fn start_sub_gen(
+    handle: &Arc<Mutex<InferenceWorker>>,
+    side_cmd_ch: &AsyncCmdChannel,
+    rt: &tokio::runtime::Handle,
+    sub: SubGenRequest,
+) {
+    let handle = handle.clone();
+    let side_cmd_ch = side_cmd_ch.clone();
+    rt.spawn(async move {
+        let mut req = sub.add_request;
+        let link = req.sub_gen.clone().unwrap();
+        let auth = AuthInfo {
+            user: sub.user,
+            is_admin: false,
+        };
+        let rx = match side_cmd_ch.instantiate(sub.instantiate, auth).await {
+            Ok(r) if r.error.is_empty() => {
+                let mut tokens = vec![];
+                req.init_result = Some(r.map_result(|r| tokens = r.prompt));
+                req.prompt = tokens;
+                handle.lock().unwrap().add_request(req)
+            }
+            Ok(r) => Err(anyhow!("{}", r.error)),
+            Err(e) => Err(e),
+        };
+        match rx {
+            // the engine passes the result to the parent; just drain the outputs
+            Ok(mut rx) => while rx.recv().await.is_some() {},
+            Err(e) => {
+                log::warn!("sub-generation of {}: {e}", link.parent_req_id);
+                // the parent may be waiting for the result, so don't drop it when the queue is full
+                let sender = handle.lock().unwrap().req_sender.clone();
+                let req = InferenceReq::SubGenFailed(link, e.to_string());
+                if sender.send(req).await.is_err() {
+                    log::warn!("inference loop is gone");
+                }
+            }
+        }
+    });
+}
+
 fn inference_loop<ME: ModelExec>(
     handle: Arc<Mutex<InferenceWorker>>,
     mut engine: RllmEngine<ME>,
     mut recv: Receiver<InferenceReq>,
     stats: Arc<Mutex<ServerStats>>,
     warmup_only: bool,
+    side_cmd_ch: AsyncCmdChannel,
+    rt: tokio::runtime::Handle,
 ) {
     loop {
         loop {
-            let req = if engine.num_pending_requests() > 0 {
-                recv.try_recv()
-            } else {
+            // suspended requests can only be woken up by new ones
+            let req = if engine.num_pending_requests() == 0 {
                 Ok(recv.blocking_recv().unwrap())
+            } else if engine.all_suspended() {
+                match rt.block_on(tokio::time::timeout(DEADLOCK_TIMEOUT, recv.recv())) {
+                    Ok(req) => Ok(req.unwrap()),
+                    Err(_) => {
+                        log::warn!("all requests suspended for {DEADLOCK_TIMEOUT:?}; finishing");
+                        engine.finish_deadlocked();
+                        break;
+                    }
+                }
+            } else {
+                recv.try_recv()
             };
             match req {
+                Ok(InferenceReq::SubGenFailed(link, e)) => engine.sub_gen_failed(link, e),
                 Ok(InferenceReq::AddRequest(req)) => {
                     let id = req.request_id.clone();
+                    let sub_gen = req.sub_gen.clone();
                     match engine.queue_request(req) {
                         Ok(_) => {
                             let mut stats = stats.lock().unwrap();
                             stats.num_requests += 1;
                         }
                         Err(e) => {
+                            if let Some(link) = sub_gen {
+                                engine.sub_gen_failed(link, e.to_string());
+                            }
                             let tx = handle.lock().unwrap().running.remove(&id).unwrap();
                             if let Err(e) = tx.try_send(Err(e)) {
                                 log::warn!("failed to send error to client {id}: {e}");
@@ -377,6 +498,10 @@ fn inference_loop<ME: ModelExec>(
             stats.num_tokens += 1;
         }
 
+        for sub in engine.take_sub_gen_reqs() {
+            start_sub_gen(&handle, &side_cmd_ch, &rt, sub);
+        }
+
         {
             let running = &mut handle.lock().unwrap().running;
             for outp in outputs {
@@ -466,6 +591,8 @@ fn spawn_inference_loop<ME: ModelExec>(
 
     let warmup = args.warmup.clone();
     let warmup_only = args.warmup_only.clone();
+    let side_cmd_ch = iface.side_cmd.clone();
+    let rt = tokio::runtime::Handle::current();
 
     std::thread::spawn(move || {
         set_max_priority();
@@ -498,7 +625,7 @@ fn spawn_inference_loop<ME: ModelExec>(
                     .unwrap();
             }
         }
-        inference_loop(handle, engine, recv, stats, warmup_only)
+        inference_loop(handle, engine, recv, stats, warmup_only, side_cmd_ch, rt)
     });
 
     handle_res
@@ -647,9 +774,11 @@ pub async fn server_main<ME: ModelExec>(
         tokenizer: loader_args.tokenizer.clone(),
         json_size: args.json_size,
         bin_size: args.bin_size,
+        bias_dtype: args.bias_dtype.clone(),
         shm_prefix,
         busy_wait_time: args.busy_wait_time,
         add_args: args.aicirt_arg.clone(),
+        connect: args.aicirt_connect.clone(),
     };
     let stats = Arc::new(Mutex::new(ServerStats {
         num_requests: 0,
@@ -679,6 +808,8 @@ pub async fn server_main<ME: ModelExec>(
             .service(completion::run_controller)
             .service(get_controllers_tags)
             .service(tag_controller)
+            .service(set_controller_limits)
+            .service(get_controller_info)
             .configure(|cfg| {
                 cfg.app_data(web::PayloadConfig::new(128 * 1024 * 1024))
                     .service(upload_controller);
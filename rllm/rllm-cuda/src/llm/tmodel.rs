@@ -10,7 +10,7 @@ use anyhow::Result;
 use rand::distributions::Distribution as _;
 use rllm::{config::RllmConfig, AiciBias, LogitsProcessor, ModelExec, SchedulerOutputs};
 use std::{sync::Arc, time::Instant};
-use tch::{Device, IndexOp, Tensor};
+use tch::{Device, IndexOp, Kind, Tensor};
 
 pub trait TModelInner {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor;
@@ -169,6 +169,35 @@ impl ModelExec for TModel {
         }
     }
 
+    fn new_bias_bits(&self, bits: &[u32], num_seqs: usize, vocab_size: usize) -> Self::AiciBias {
+        let _no_grad = tch::no_grad_guard();
+
+        // only copy the bits to the device, and expand them there;
+        // same semantics as rllm::apply_bit_mask()
+        let device = self.config.model.device;
+        let row_words = (vocab_size + 31) / 32;
+        assert!(bits.len() == num_seqs * row_words);
+        let words: Vec<i32> = bits.iter().map(|&w| w as i32).collect();
+        let words =
+            Tensor::from_slice(&words)
+                .to(device)
+                .reshape(&[num_seqs as i64, row_words as i64, 1]);
+        // the shift is arithmetic for i32, but we only look at the lowest bit
+        let shifts = Tensor::arange(32i64, (Kind::Int, device));
+        let disallowed = words
+            .bitwise_right_shift(&shifts)
+            .bitwise_and(1i64)
+            .eq(0i64)
+            .reshape(&[num_seqs as i64, (row_words * 32) as i64])
+            .narrow(1, 0, vocab_size as i64);
+        let tensor = Tensor::zeros(&[num_seqs as i64, vocab_size as i64], (Kind::Float, device))
+            .masked_fill(&disallowed, f64::NEG_INFINITY);
+        TchAiciBias {
+            vocab_size,
+            bias: Some(tensor),
+        }
+    }
+
     fn sample(&self, state: &mut LogitsProcessor, logits: &Tensor) -> Result<u32> {
         let _no_grad = tch::no_grad_guard();
 
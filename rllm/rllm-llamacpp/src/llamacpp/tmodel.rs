@@ -3,6 +3,7 @@ use anyhow::Result;
 use llama_cpp_low as cpp;
 use rand::distributions::Distribution as _;
 use rllm::{
+    apply_bit_mask,
     config::{ModelMeta, RllmConfig},
     seq::SchedulingPhase,
     AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
@@ -47,6 +48,10 @@ impl ModelExec for TModel {
     type ModelLoaderArgs = CppLoaderArgs;
     type SequenceManager = CppSequenceManager;
 
+    fn supports_attention_mask(&self) -> bool {
+        true
+    }
+
     fn run(
         &mut self,
         _vocab_size: usize,
@@ -64,6 +69,10 @@ impl ModelExec for TModel {
                     continue;
                 }
 
+                // masked tokens are never added to the KV cache; when the mask changes,
+                // the KV cache is re-computed from the first changed position
+                let masked = seq.sync_kv_mask(self.seq_mgr.as_ref());
+
                 let seq_len = seq.get_len();
                 let k_len = seq_len;
                 log::trace!("fwd seq: {seq:?}");
@@ -77,6 +86,9 @@ impl ModelExec for TModel {
 
                 let off = k_len - q_len;
                 for idx in off..off + q_len {
+                    if masked.binary_search(&idx).is_ok() {
+                        continue;
+                    }
                     let logits = idx + 1 == off + q_len;
                     if logits {
                         self.seq_id_to_idx
@@ -126,6 +138,7 @@ impl ModelExec for TModel {
         CppAiciBias {
             vocab_size,
             bias: None,
+            bits: None,
         }
     }
 
@@ -142,6 +155,17 @@ impl ModelExec for TModel {
         CppAiciBias {
             vocab_size,
             bias: Some(tensor),
+            bits: None,
+        }
+    }
+
+    fn new_bias_bits(&self, bits: &[u32], num_seqs: usize, vocab_size: usize) -> Self::AiciBias {
+        assert!(bits.len() == num_seqs * vocab_size / 32);
+        // no need to expand; it's applied directly to logits
+        CppAiciBias {
+            vocab_size,
+            bias: None,
+            bits: Some(bits.to_vec()),
         }
     }
 
@@ -263,10 +287,17 @@ impl TModel {
 pub struct CppAiciBias {
     pub vocab_size: usize,
     pub bias: Option<Tensor>,
+    pub bits: Option<Vec<u32>>,
 }
 
 impl AiciBias<Tensor> for CppAiciBias {
     fn apply(&self, logits: &mut Tensor, seq_id: usize) {
+        if let Some(bits) = &self.bits {
+            let row_words = self.vocab_size / 32;
+            let sp = seq_id * row_words;
+            apply_bit_mask(logits.as_mut_slice(), &bits[sp..sp + row_words]);
+            return;
+        }
         let bias = self.bias.as_ref().unwrap();
         let sp = seq_id * self.vocab_size;
         let logits = logits.as_mut_slice();
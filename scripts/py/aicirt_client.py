@@ -0,0 +1,172 @@
+# Test client for the socket transport of aicirt (see docs/aicirt-proto.md).
+#
+# Start aicirt with, e.g., `AICIRT_TOKEN=... aicirt --tokenizer llama --listen tcp:4242`, then:
+#
+#   AICIRT_TOKEN=... python3 scripts/py/aicirt_client.py tcp:4242 < requests.jsonl
+#
+# sends every line of stdin as a request and prints the responses. Lines starting
+# with `side ` go to the side channel. With --fuzz N, it instead sends N random
+# (mostly malformed) requests on both channels, checking that aicirt keeps answering.
+
+import argparse
+import json
+import os
+import random
+import socket
+import struct
+import sys
+
+
+def connect(addr: str, channel: str, token: str) -> socket.socket:
+    if addr.startswith("unix:"):
+        s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        s.connect(addr[5:])
+    elif addr.startswith("tcp:"):
+        host, _, port = addr[4:].rpartition(":")
+        s = socket.create_connection((host or "127.0.0.1", int(port)))
+        s.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+    else:
+        raise ValueError(
+            f"invalid address {addr!r}; expecting unix:PATH or tcp:[HOST:]PORT"
+        )
+    send_frame(s, channel.encode())
+    send_frame(s, token.encode())
+    return s
+
+
+def send_frame(s: socket.socket, msg: bytes):
+    s.sendall(struct.pack("<I", len(msg)) + msg)
+
+
+def recv_exact(s: socket.socket, n: int) -> bytes:
+    buf = b""
+    while len(buf) < n:
+        chunk = s.recv(n - len(buf))
+        if not chunk:
+            raise ConnectionError("aicirt closed the connection")
+        buf += chunk
+    return buf
+
+
+def recv_frame(s: socket.socket) -> bytes:
+    (n,) = struct.unpack("<I", recv_exact(s, 4))
+    return recv_exact(s, n)
+
+
+def request(s: socket.socket, msg: bytes) -> dict:
+    send_frame(s, msg)
+    return json.loads(recv_frame(s))
+
+
+def ping(s: socket.socket):
+    resp = request(s, b'{"op":"ping"}')
+    assert resp["type"] == "ok" and resp["data"]["pong"] == 1, resp
+
+
+# "stop" is left out, since it takes aicirt down on purpose
+OPS = [
+    "ping",
+    "tokens",
+    "mid_process",
+    "mk_module",
+    "set_tags",
+    "get_tags",
+    "set_limits",
+    "module_info",
+    "instantiate",
+    "no_such_op",
+]
+
+
+def random_value(rng: random.Random, depth: int = 0):
+    k = rng.randrange(8 if depth < 3 else 5)
+    if k == 0:
+        return None
+    if k == 1:
+        return rng.choice([True, False])
+    if k == 2:
+        return rng.choice([0, -1, 1, 2**31, 2**64, rng.randrange(-1000, 1000), 0.5])
+    if k == 3:
+        return "".join(chr(rng.randrange(1, 0x250)) for _ in range(rng.randrange(20)))
+    if k == 4:
+        return rng.choice(["", "run-00", "..", "/", "llama", "\u0000"])
+    if k == 5:
+        return [random_value(rng, depth + 1) for _ in range(rng.randrange(5))]
+    keys = ["id", "req_id", "ops", "tokens", "prompt", "module_id", "x"]
+    return {
+        rng.choice(keys): random_value(rng, depth + 1) for _ in range(rng.randrange(5))
+    }
+
+
+FIELDS = [
+    "ops",
+    "req_id",
+    "prompt",
+    "module_id",
+    "module_arg",
+    "freed",
+    "$rid",
+    "$auth",
+]
+
+
+def random_msg(rng: random.Random) -> bytes:
+    msg = {"op": rng.choice(OPS)}
+    for _ in range(rng.randrange(6)):
+        key = rng.choice(FIELDS)
+        msg[key] = random_value(rng)
+    data = json.dumps(msg).encode()
+    k = rng.randrange(4)
+    if k == 0 and data:
+        # truncated
+        data = data[: rng.randrange(len(data))]
+    elif k == 1:
+        # random bytes
+        data = bytes(rng.randrange(256) for _ in range(rng.randrange(64)))
+    return data
+
+
+def fuzz(main: socket.socket, side: socket.socket, n: int, seed: int):
+    rng = random.Random(seed)
+    for i in range(n):
+        s = rng.choice([main, side])
+        msg = random_msg(rng)
+        try:
+            resp = request(s, msg)
+        except Exception as e:
+            print(f"request {i} failed: {e}\n{msg!r}", file=sys.stderr)
+            raise
+        assert resp["type"] in ("ok", "error", "json-error"), resp
+    ping(main)
+    ping(side)
+    print(f"{n} requests OK")
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Talk to aicirt over --listen socket")
+    parser.add_argument("addr", help="unix:PATH, tcp:PORT or tcp:HOST:PORT")
+    parser.add_argument("--fuzz", type=int, metavar="N", help="send N random requests")
+    parser.add_argument("--seed", type=int, default=1, help="random seed for --fuzz")
+    args = parser.parse_args()
+
+    token = os.environ.get("AICIRT_TOKEN", "")
+    main_ch = connect(args.addr, "main", token)
+    side_ch = connect(args.addr, "side", token)
+
+    if args.fuzz is not None:
+        fuzz(main_ch, side_ch, args.fuzz, args.seed)
+        return
+
+    for line in sys.stdin:
+        line = line.strip()
+        if not line:
+            continue
+        s = main_ch
+        if line.startswith("side "):
+            s = side_ch
+            line = line[5:]
+        print(json.dumps(request(s, line.encode())))
+
+
+if __name__ == "__main__":
+    main()